@@ -23,6 +23,11 @@ use std::ops::{
     RangeBounds,
 };
 
+use value::{
+    values_to_bytes,
+    ConvexValue,
+};
+
 pub use self::{
     bounds::{
         End,
@@ -56,6 +61,106 @@ impl Interval {
         }
     }
 
+    /// Like `prefix`, for a borrowed key.
+    pub fn prefix_bytes(prefix: &[u8]) -> Self {
+        Self::prefix(BinaryKey::from(prefix.to_vec()))
+    }
+
+    /// Matches the index keys whose leading indexed fields are `values`, e.g.
+    /// `[Some(a)]` for all entries whose first indexed field is `a`.
+    pub fn prefix_of_values(values: &[Option<ConvexValue>]) -> Self {
+        Self::prefix(values_to_bytes(values).into())
+    }
+
+    /// Matches the index keys whose leading indexed fields are `prefix` and
+    /// whose next field is an `Int64` in `range`.
+    ///
+    /// `Int64`s and `Float64`s are different types in index key order, with
+    /// every `Int64` before every `Float64`, so this never matches a
+    /// `Float64` field. See `float64_range`.
+    pub fn int64_range(prefix: &[Option<ConvexValue>], range: impl RangeBounds<i64>) -> Self {
+        let bound = |bound: Bound<&i64>, unbounded: i64| match bound {
+            Bound::Included(n) => Bound::Included(ConvexValue::Int64(*n)),
+            Bound::Excluded(n) => Bound::Excluded(ConvexValue::Int64(*n)),
+            Bound::Unbounded => Bound::Included(ConvexValue::Int64(unbounded)),
+        };
+        Self::range_of_values(
+            prefix,
+            bound(range.start_bound(), i64::MIN),
+            bound(range.end_bound(), i64::MAX),
+        )
+    }
+
+    /// Matches the index keys whose leading indexed fields are `prefix` and
+    /// whose next field is a `Float64` in `range`, comparing numerically.
+    ///
+    /// Index keys order floats by IEEE-754 total order, so this adjusts the
+    /// bounds to treat `-0.0` and `0.0` as equal, and never matches NaN. The
+    /// bounds themselves can't be NaN. Like `int64_range`, this only matches
+    /// one type, so a field holding both `Int64`s and `Float64`s needs a scan
+    /// for each.
+    pub fn float64_range(
+        prefix: &[Option<ConvexValue>],
+        range: impl RangeBounds<f64>,
+    ) -> anyhow::Result<Self> {
+        // Zero bounds are widened (or narrowed) to cover both zeros, since
+        // `-0.0` sorts immediately before `0.0`.
+        let start = match range.start_bound() {
+            Bound::Included(n) if *n == 0. => Bound::Included(-0.),
+            Bound::Excluded(n) if *n == 0. => Bound::Excluded(0.),
+            Bound::Included(n) => Bound::Included(*n),
+            Bound::Excluded(n) => Bound::Excluded(*n),
+            Bound::Unbounded => Bound::Included(f64::NEG_INFINITY),
+        };
+        let end = match range.end_bound() {
+            Bound::Included(n) if *n == 0. => Bound::Included(0.),
+            Bound::Excluded(n) if *n == 0. => Bound::Excluded(-0.),
+            Bound::Included(n) => Bound::Included(*n),
+            Bound::Excluded(n) => Bound::Excluded(*n),
+            Bound::Unbounded => Bound::Included(f64::INFINITY),
+        };
+        for bound in [start, end] {
+            if let Bound::Included(n) | Bound::Excluded(n) = bound {
+                anyhow::ensure!(!n.is_nan(), "Float64 range bounds can't be NaN");
+            }
+        }
+        Ok(Self::range_of_values(
+            prefix,
+            start.map(ConvexValue::Float64),
+            end.map(ConvexValue::Float64),
+        ))
+    }
+
+    /// Matches the index keys whose leading indexed fields are `prefix` and
+    /// whose next field is between `start` and `end` in index key order.
+    fn range_of_values(
+        prefix: &[Option<ConvexValue>],
+        start: Bound<ConvexValue>,
+        end: Bound<ConvexValue>,
+    ) -> Self {
+        let key = |value: Option<ConvexValue>| {
+            let mut values = prefix.to_vec();
+            values.extend(value.map(Some));
+            BinaryKey::from(values_to_bytes(&values))
+        };
+        // Every key starting with a value's sort key has that value as its
+        // next field, since sort keys are self-delimiting.
+        let start = match start {
+            Bound::Included(value) => StartIncluded(key(Some(value))),
+            Bound::Excluded(value) => match key(Some(value)).increment() {
+                Some(start) => StartIncluded(start),
+                None => return Self::empty(),
+            },
+            Bound::Unbounded => StartIncluded(key(None)),
+        };
+        let end = match end {
+            Bound::Included(value) => End::after_prefix(&key(Some(value))),
+            Bound::Excluded(value) => End::Excluded(key(Some(value))),
+            Bound::Unbounded => End::after_prefix(&key(None)),
+        };
+        Self { start, end }
+    }
+
     pub const fn empty() -> Self {
         Self {
             start: StartIncluded(BinaryKey::min()),
@@ -111,6 +216,25 @@ impl Interval {
         self.end.is_adjacent(&other.start) || other.end.is_adjacent(&self.start)
     }
 
+    /// Returns the keys in either interval as disjoint, non-adjacent intervals
+    /// in ascending order: one if they overlap or touch, two if they don't,
+    /// and none if both are empty.
+    pub fn union(&self, other: &Self) -> Vec<Self> {
+        let mut set = IntervalSet::new();
+        set.add(self.clone());
+        set.add(other.clone());
+        set.iter().collect()
+    }
+
+    /// Returns the keys in `bounds` but not in this interval as disjoint
+    /// intervals in ascending order. There are two if this interval is
+    /// strictly inside `bounds`.
+    pub fn complement_within(&self, bounds: &Self) -> Vec<Self> {
+        let mut set = IntervalSet::new();
+        set.add(self.clone());
+        set.subtract_from_interval(bounds).iter().collect()
+    }
+
     /// When reading from self in order `order`, if we've just read `last_key`,
     /// returns (interval read, interval remaining).
     /// If self=[X, Y) and order=Asc, returns [X, last_key] and (last_key, Y).
@@ -241,10 +365,17 @@ impl RangeBounds<[u8]> for IntervalRef<'_> {
 
 #[cfg(test)]
 mod tests {
-    use std::collections::BTreeSet;
+    use std::{
+        collections::BTreeSet,
+        ops::Bound,
+    };
 
     use cmd_util::env::env_config;
     use proptest::prelude::*;
+    use value::{
+        values_to_bytes,
+        ConvexValue,
+    };
 
     use super::{
         bounds::{
@@ -256,6 +387,142 @@ mod tests {
         Interval,
     };
 
+    #[test]
+    fn test_prefix_bytes() {
+        let interval = Interval::prefix_bytes(&[1]);
+        assert!(interval.contains(&[1]));
+        assert!(interval.contains(&[1, 2]));
+        assert!(!interval.contains(&[0, 1]));
+        assert!(!interval.contains(&[2]));
+        assert_eq!(interval, Interval::prefix(BinaryKey::from(vec![1])));
+        // The empty prefix matches everything.
+        assert_eq!(Interval::prefix_bytes(&[]), Interval::all());
+    }
+
+    #[test]
+    fn test_prefix_of_values() {
+        let a = Some(ConvexValue::from(1.));
+        let b = Some(ConvexValue::from(2.));
+        let interval = Interval::prefix_of_values(std::slice::from_ref(&a));
+        assert!(interval.contains(&values_to_bytes(&[a.clone(), b.clone()])));
+        assert!(interval.contains(&values_to_bytes(&[a.clone(), None])));
+        assert!(!interval.contains(&values_to_bytes(&[b.clone(), a.clone()])));
+        assert!(!interval.contains(&values_to_bytes(&[None, a])));
+    }
+
+    #[test]
+    fn test_union() {
+        // Overlapping and adjacent intervals merge.
+        assert_eq!(
+            int_interval(1, 5).union(&int_interval(3, 8)),
+            vec![int_interval(1, 8)]
+        );
+        assert_eq!(
+            int_interval(3, 8).union(&int_interval(1, 3)),
+            vec![int_interval(1, 8)]
+        );
+        assert_eq!(
+            int_interval(1, 5).union(&int_interval_unbounded(2)),
+            vec![int_interval_unbounded(1)]
+        );
+        assert_eq!(
+            int_interval(6, 8).union(&int_interval(1, 3)),
+            vec![int_interval(1, 3), int_interval(6, 8)]
+        );
+        assert_eq!(
+            Interval::empty().union(&int_interval(1, 3)),
+            vec![int_interval(1, 3)]
+        );
+        assert_eq!(Interval::empty().union(&Interval::empty()), vec![]);
+    }
+
+    #[test]
+    fn test_complement_within() {
+        assert_eq!(
+            int_interval(3, 5).complement_within(&int_interval(1, 8)),
+            vec![int_interval(1, 3), int_interval(5, 8)]
+        );
+        assert_eq!(
+            int_interval(3, 5).complement_within(&Interval::all()),
+            vec![
+                Interval {
+                    start: StartIncluded(BinaryKey::min()),
+                    end: int_end(3),
+                },
+                int_interval_unbounded(5),
+            ]
+        );
+        assert_eq!(
+            int_interval(0, 5).complement_within(&int_interval(1, 8)),
+            vec![int_interval(5, 8)]
+        );
+        assert_eq!(
+            int_interval(0, 9).complement_within(&int_interval(1, 8)),
+            vec![]
+        );
+        assert_eq!(
+            Interval::empty().complement_within(&int_interval(1, 8)),
+            vec![int_interval(1, 8)]
+        );
+    }
+
+    #[test]
+    fn test_int64_range() {
+        let key = |n: i64| values_to_bytes(&[Some(ConvexValue::Int64(n)), None]);
+        let interval = Interval::int64_range(&[], -5..=10);
+        for n in [-5, -1, 0, 1, 10] {
+            assert!(interval.contains(&key(n)), "{n}");
+        }
+        for n in [i64::MIN, -6, 11, i64::MAX] {
+            assert!(!interval.contains(&key(n)), "{n}");
+        }
+        assert!(!interval.contains(&values_to_bytes(&[Some(ConvexValue::from(0.))])));
+
+        let interval = Interval::int64_range(&[], -5..10);
+        assert!(!interval.contains(&key(10)));
+        let interval = Interval::int64_range(&[], ..);
+        assert!(interval.contains(&key(i64::MIN)));
+        assert!(interval.contains(&key(i64::MAX)));
+        assert!(Interval::int64_range(&[], 10..5).is_empty());
+
+        // Only keys with the prefix match.
+        let a = Some(ConvexValue::from(1.));
+        let b = Some(ConvexValue::from(2.));
+        let interval = Interval::int64_range(std::slice::from_ref(&a), 0..);
+        assert!(interval.contains(&values_to_bytes(&[a, Some(ConvexValue::Int64(3))])));
+        assert!(!interval.contains(&values_to_bytes(&[b, Some(ConvexValue::Int64(3))])));
+    }
+
+    #[test]
+    fn test_float64_range() -> anyhow::Result<()> {
+        let key = |n: f64| values_to_bytes(&[Some(ConvexValue::from(n)), None]);
+        let interval = Interval::float64_range(&[], -2.5..=0.5)?;
+        for n in [-2.5, -1., -0., 0., 0.25, 0.5] {
+            assert!(interval.contains(&key(n)), "{n}");
+        }
+        for n in [f64::NEG_INFINITY, -2.51, 0.51, f64::INFINITY, f64::NAN] {
+            assert!(!interval.contains(&key(n)), "{n}");
+        }
+        assert!(!interval.contains(&values_to_bytes(&[Some(ConvexValue::Int64(0))])));
+
+        // Both zeros are included or excluded together.
+        let interval = Interval::float64_range(&[], 0.0..=0.0)?;
+        assert!(interval.contains(&key(-0.)) && interval.contains(&key(0.)));
+        let interval = Interval::float64_range(&[], -0.0..=-0.0)?;
+        assert!(interval.contains(&key(-0.)) && interval.contains(&key(0.)));
+        let interval = Interval::float64_range(&[], ..0.0)?;
+        assert!(!interval.contains(&key(-0.)) && !interval.contains(&key(0.)));
+        assert!(interval.contains(&key(-f64::MIN_POSITIVE)));
+        assert!(interval.contains(&key(f64::NEG_INFINITY)));
+        let interval = Interval::float64_range(&[], (Bound::Excluded(-0.0), Bound::Unbounded))?;
+        assert!(!interval.contains(&key(-0.)) && !interval.contains(&key(0.)));
+        assert!(interval.contains(&key(f64::INFINITY)));
+        assert!(!interval.contains(&key(f64::NAN)));
+
+        assert!(Interval::float64_range(&[], f64::NAN..).is_err());
+        Ok(())
+    }
+
     fn test_bounded_intervals(
         reference: BTreeSet<BinaryKey>,
         interval: Interval,
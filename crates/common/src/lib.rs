@@ -56,6 +56,9 @@ pub mod paths;
 pub mod pause;
 pub mod persistence;
 pub mod persistence_helpers;
+pub mod persistence_recording;
+pub mod persistence_streaming_writer;
+pub mod persistence_tee;
 pub mod pii;
 pub mod pool_stats;
 pub mod query;
@@ -16,13 +16,17 @@ use async_trait::async_trait;
 use enum_iterator::Sequence;
 use futures::{
     future,
-    stream::BoxStream,
+    stream::{
+        self,
+        BoxStream,
+    },
     try_join,
     StreamExt,
     TryStreamExt,
 };
 use serde_json::Value as JsonValue;
 use value::{
+    ConvexValue,
     InternalDocumentId,
     TabletId,
 };
@@ -34,7 +38,10 @@ use crate::{
         IndexKey,
         IndexKeyBytes,
     },
-    interval::Interval,
+    interval::{
+        Interval,
+        IntervalSet,
+    },
     knobs::DEFAULT_DOCUMENTS_PAGE_SIZE,
     persistence_helpers::RevisionPair,
     query::Order,
@@ -58,6 +65,105 @@ pub struct DocumentLogEntry {
     pub prev_ts: Option<Timestamp>,
 }
 
+impl DocumentLogEntry {
+    /// Starts building an entry, which is checked for consistency by
+    /// [`DocumentLogEntryBuilder::build`].
+    pub fn builder() -> DocumentLogEntryBuilder {
+        DocumentLogEntryBuilder::default()
+    }
+
+    /// Whether this revision has a top-level `field` that compares to `value`
+    /// with `op`. Deletions and documents without the field never match.
+    pub fn field_matches(&self, field: &str, op: CompareOp, value: &ConvexValue) -> bool {
+        self.value
+            .as_ref()
+            .and_then(|document| document.value().get(field))
+            .is_some_and(|field_value| op.compare(field_value, value))
+    }
+}
+
+/// Builds a [`DocumentLogEntry`], rejecting combinations of fields that
+/// can't describe a real revision.
+///
+/// An entry needs a `ts` and must be exactly one of a new `value` or
+/// `deleted`. The `id` may be left out when there's a value, in which case it
+/// is taken from the document.
+#[derive(Debug, Default)]
+pub struct DocumentLogEntryBuilder {
+    ts: Option<Timestamp>,
+    id: Option<InternalDocumentId>,
+    value: Option<ResolvedDocument>,
+    deleted: bool,
+    prev_ts: Option<Timestamp>,
+}
+
+impl DocumentLogEntryBuilder {
+    pub fn ts(mut self, ts: Timestamp) -> Self {
+        self.ts = Some(ts);
+        self
+    }
+
+    pub fn id(mut self, id: InternalDocumentId) -> Self {
+        self.id = Some(id);
+        self
+    }
+
+    /// The document as of `ts`.
+    pub fn value(mut self, value: ResolvedDocument) -> Self {
+        self.value = Some(value);
+        self
+    }
+
+    /// Marks the entry as a tombstone for a document deleted at `ts`.
+    pub fn deleted(mut self) -> Self {
+        self.deleted = true;
+        self
+    }
+
+    /// The timestamp of the document's previous revision, which must be
+    /// earlier than `ts`.
+    pub fn prev_ts(mut self, prev_ts: Timestamp) -> Self {
+        self.prev_ts = Some(prev_ts);
+        self
+    }
+
+    pub fn build(self) -> anyhow::Result<DocumentLogEntry> {
+        let ts = self
+            .ts
+            .ok_or_else(|| anyhow::anyhow!("DocumentLogEntry is missing a ts"))?;
+        match (&self.value, self.deleted) {
+            (Some(_), true) => anyhow::bail!("DocumentLogEntry can't be a tombstone with a value"),
+            (None, false) => anyhow::bail!("DocumentLogEntry needs either a value or deleted()"),
+            _ => {},
+        }
+        let id = match (self.id, &self.value) {
+            (Some(id), Some(value)) => {
+                anyhow::ensure!(
+                    id == value.id_with_table_id(),
+                    "DocumentLogEntry id {id} doesn't match its value's id {}",
+                    value.id_with_table_id()
+                );
+                id
+            },
+            (Some(id), None) => id,
+            (None, Some(value)) => value.id_with_table_id(),
+            (None, None) => anyhow::bail!("DocumentLogEntry tombstone is missing an id"),
+        };
+        if let Some(prev_ts) = self.prev_ts {
+            anyhow::ensure!(
+                prev_ts < ts,
+                "DocumentLogEntry prev_ts {prev_ts} isn't before its ts {ts}"
+            );
+        }
+        Ok(DocumentLogEntry {
+            ts,
+            id,
+            value: self.value,
+            prev_ts: self.prev_ts,
+        })
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct PersistenceIndexEntry {
     pub ts: Timestamp,
@@ -84,11 +190,20 @@ impl PersistenceIndexEntry {
 
 pub type DocumentStream<'a> = BoxStream<'a, anyhow::Result<DocumentLogEntry>>;
 
+/// Each item holds every entry sharing a timestamp.
+pub type DocumentBatchStream<'a> = BoxStream<'a, anyhow::Result<Vec<DocumentLogEntry>>>;
+
 pub type DocumentRevisionStream<'a> = BoxStream<'a, anyhow::Result<RevisionPair>>;
 
 /// No tombstones included
 pub type LatestDocumentStream<'a> = BoxStream<'a, anyhow::Result<LatestDocument>>;
 
+pub type DocumentIdStream<'a> = BoxStream<'a, anyhow::Result<InternalDocumentId>>;
+
+pub type TimestampStream<'a> = BoxStream<'a, anyhow::Result<Timestamp>>;
+
+pub type DocumentDiffStream<'a> = BoxStream<'a, anyhow::Result<DocumentDiff>>;
+
 pub type IndexStream<'a> = BoxStream<'a, anyhow::Result<(IndexKeyBytes, LatestDocument)>>;
 
 /// A `DocumentLogEntry` that is not a tombstone.
@@ -99,6 +214,95 @@ pub struct LatestDocument {
     pub prev_ts: Option<Timestamp>,
 }
 
+/// How a document differs between two timestamps. See
+/// `PersistenceReader::diff`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DocumentDiff {
+    pub id: InternalDocumentId,
+    /// The document as of the earlier timestamp, or `None` if it didn't
+    /// exist yet or was deleted.
+    pub before: Option<ResolvedDocument>,
+    /// The document as of the later timestamp, or `None` if it was deleted.
+    pub after: Option<ResolvedDocument>,
+}
+
+/// A row that `write` with `ConflictStrategy::Error` would fail on, because
+/// another row with the same key exists.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Conflict {
+    Document {
+        id: InternalDocumentId,
+        ts: Timestamp,
+    },
+    Index {
+        index_id: IndexId,
+        key: IndexKeyBytes,
+        ts: Timestamp,
+    },
+}
+
+/// Result of `Persistence::write_partial`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct WriteOutcome {
+    pub documents_written: usize,
+    pub indexes_written: usize,
+    /// The rows that were skipped because another row with the same key
+    /// exists.
+    pub conflicts: Vec<Conflict>,
+}
+
+/// Result of `Persistence::write_with_receipt`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct WriteReceipt {
+    pub documents_written: usize,
+    pub indexes_written: usize,
+    /// The largest timestamp of any row written, or `None` if the write was
+    /// empty.
+    pub max_ts: Option<Timestamp>,
+}
+
+impl WriteReceipt {
+    pub fn new(documents: &[DocumentLogEntry], indexes: &[PersistenceIndexEntry]) -> Self {
+        let max_ts = documents
+            .iter()
+            .map(|entry| entry.ts)
+            .chain(indexes.iter().map(|entry| entry.ts))
+            .max();
+        Self {
+            documents_written: documents.len(),
+            indexes_written: indexes.len(),
+            max_ts,
+        }
+    }
+}
+
+/// How `PersistenceReader::load_documents_where` compares a field to a value,
+/// using the total order of `ConvexValue`s.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Lte,
+    Gt,
+    Gte,
+}
+
+impl CompareOp {
+    /// Whether `left op right`.
+    pub fn compare(&self, left: &ConvexValue, right: &ConvexValue) -> bool {
+        let ordering = left.cmp(right);
+        match self {
+            CompareOp::Eq => ordering.is_eq(),
+            CompareOp::Ne => ordering.is_ne(),
+            CompareOp::Lt => ordering.is_lt(),
+            CompareOp::Lte => ordering.is_le(),
+            CompareOp::Gt => ordering.is_gt(),
+            CompareOp::Gte => ordering.is_ge(),
+        }
+    }
+}
+
 /// Indicates how write conflicts should be handled.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum ConflictStrategy {
@@ -108,6 +312,10 @@ pub enum ConflictStrategy {
     /// If the record being written already exists with the same key, overwrite
     /// the record.
     Overwrite,
+    /// If the record being written already exists with the same key, succeed
+    /// without writing if it's identical to the existing record and return an
+    /// error otherwise. This makes replaying a write idempotent.
+    Merge,
 }
 
 // When adding a new persistence global, make sure it's copied
@@ -215,6 +423,42 @@ pub trait Persistence: Sync + Send + 'static {
         conflict_strategy: ConflictStrategy,
     ) -> anyhow::Result<()>;
 
+    /// Like `write`, but returns a `WriteReceipt` for the rows committed. With
+    /// `ConflictStrategy::Merge` these include rows that already existed.
+    async fn write_with_receipt<'a>(
+        &self,
+        documents: &'a [DocumentLogEntry],
+        indexes: &'a [PersistenceIndexEntry],
+        conflict_strategy: ConflictStrategy,
+    ) -> anyhow::Result<WriteReceipt> {
+        self.write(documents, indexes, conflict_strategy).await?;
+        Ok(WriteReceipt::new(documents, indexes))
+    }
+
+    /// Returns the rows that `write` with `ConflictStrategy::Error` would
+    /// fail on, without writing anything. This includes rows that conflict
+    /// with earlier rows in the same call.
+    async fn check_write(
+        &self,
+        _documents: &[DocumentLogEntry],
+        _indexes: &[PersistenceIndexEntry],
+    ) -> anyhow::Result<Vec<Conflict>> {
+        anyhow::bail!("check_write is not supported by this persistence")
+    }
+
+    /// Like `write`, but writes the rows that don't conflict and skips the
+    /// ones that do, rather than failing the whole write. This is NOT atomic
+    /// with respect to the caller's batch: an index entry is written even if
+    /// its document was skipped, and vice versa. Callers are expected to
+    /// resolve the returned conflicts and retry just those rows.
+    async fn write_partial(
+        &self,
+        _documents: &[DocumentLogEntry],
+        _indexes: &[PersistenceIndexEntry],
+    ) -> anyhow::Result<WriteOutcome> {
+        anyhow::bail!("write_partial is not supported by this persistence")
+    }
+
     /// Writes global key-value data for the whole persistence.
     /// This is expected to be small data that does not make sense in a
     /// versioned or transaction context. See `PersistenceGlobalKey`.
@@ -244,6 +488,63 @@ pub trait Persistence: Sync + Send + 'static {
         chunk_size: usize,
     ) -> anyhow::Result<usize>;
 
+    /// Deletes document log entries and index entries with timestamps in
+    /// `range`, returning the number of document log entries removed. The
+    /// latest revision of a document is needed if it's live, or if it's a
+    /// deletion and older revisions survive before `range`, since removing it
+    /// would bring the document back. The same goes for the latest index
+    /// entry of each key. If `retain_latest` is set, those entries are kept;
+    /// otherwise the call fails without deleting anything if there are any.
+    async fn delete_range(
+        &self,
+        _range: TimestampRange,
+        _retain_latest: bool,
+    ) -> anyhow::Result<u64> {
+        anyhow::bail!("delete_range is not supported by this persistence")
+    }
+
+    /// Deletes every document log entry and index entry in one transaction,
+    /// leaving the persistence as if nothing had been written to it. Unlike
+    /// recreating it, this keeps the schema and any configuration in place.
+    async fn truncate(&self) -> anyhow::Result<()> {
+        anyhow::bail!("truncate is not supported by this persistence")
+    }
+
+    /// Replaces every entry of the index with ones recomputed from the
+    /// document log of `tablet_id`, in one transaction, and returns the
+    /// number of entries written. This is for repairing an index that has
+    /// become inconsistent with its documents.
+    ///
+    /// `key_fn` returns a document's full index key, including the trailing
+    /// document id, e.g. `IndexKey::to_bytes`. Every revision is replayed in
+    /// timestamp order, so the rebuilt index answers reads at any timestamp
+    /// still in the log, with deletions wherever a document's key changed or
+    /// it was deleted.
+    async fn rebuild_index(
+        &self,
+        _index_id: IndexId,
+        _tablet_id: TabletId,
+        _key_fn: &(dyn Fn(&ResolvedDocument) -> Vec<u8> + Send + Sync),
+    ) -> anyhow::Result<u64> {
+        anyhow::bail!("rebuild_index is not supported by this persistence")
+    }
+
+    /// Starts a transaction for grouping several writes so that they commit
+    /// or roll back together. See `PersistenceTransaction`.
+    async fn begin(&self) -> anyhow::Result<Box<dyn PersistenceTransaction>> {
+        anyhow::bail!("begin is not supported by this persistence")
+    }
+
+    /// Returns once every write that returned before this was called is in
+    /// durable storage, e.g. before acknowledging a write to a system that
+    /// can't be told to forget it. This costs at least one fsync and may
+    /// also move buffered data into place, which usually takes milliseconds
+    /// but can take much longer on slow disks, during which writes may be
+    /// blocked. It's meant for occasional critical writes, not every write.
+    async fn fsync_barrier(&self) -> anyhow::Result<()> {
+        anyhow::bail!("fsync_barrier is not supported by this persistence")
+    }
+
     // No-op by default. Persistence implementation can override.
     async fn shutdown(&self) -> anyhow::Result<()> {
         Ok(())
@@ -269,18 +570,71 @@ pub trait Persistence: Sync + Send + 'static {
         Ok(())
     }
 
+    /// Writes `documents` in transactions of up to `batch_size` entries,
+    /// returning the number of entries imported. Batches that were written
+    /// before an error remain written.
+    async fn import(
+        &self,
+        documents: BoxStream<'_, DocumentLogEntry>,
+        batch_size: usize,
+        conflict_strategy: ConflictStrategy,
+    ) -> anyhow::Result<u64> {
+        anyhow::ensure!(batch_size > 0, "batch_size must be positive");
+        let mut batches = documents.chunks(batch_size);
+        let mut imported = 0;
+        while let Some(batch) = batches.next().await {
+            self.write(&batch, &[], conflict_strategy).await?;
+            imported += batch.len() as u64;
+        }
+        Ok(imported)
+    }
+
     async fn finish_loading(&self) -> anyhow::Result<()> {
         Ok(())
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct TimestampRange {
     start_inclusive: Timestamp,
     end_inclusive: Timestamp,
 }
 
 impl TimestampRange {
+    /// The timestamps from `start` up to but not including `end`, so a range
+    /// whose bounds are equal is empty. Fails if `start` is after `end`.
+    pub fn new_half_open(start: Timestamp, end: Timestamp) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            start <= end,
+            "TimestampRange starts at {start}, after its end {end}"
+        );
+        Ok(Self::new(start..end))
+    }
+
+    /// The timestamps from `start` through `end`, both included, so a range
+    /// whose bounds are equal holds that one timestamp. Fails if `start` is
+    /// after `end`.
+    pub fn new_inclusive(start: Timestamp, end: Timestamp) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            start <= end,
+            "TimestampRange starts at {start}, after its end {end}"
+        );
+        Ok(Self::new(start..=end))
+    }
+
+    /// The timestamps strictly between `start` and `end`, so a range whose
+    /// bounds are equal or adjacent is empty. Fails if `start` is after
+    /// `end`.
+    pub fn new_exclusive(start: Timestamp, end: Timestamp) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            start <= end,
+            "TimestampRange starts at {start}, after its end {end}"
+        );
+        Ok(Self::new((Bound::Excluded(start), Bound::Excluded(end))))
+    }
+
+    /// The timestamps within `range`. Unlike the validating constructors above,
+    /// an inverted range is accepted and is empty.
     #[inline]
     pub fn new<T: RangeBounds<Timestamp>>(range: T) -> Self {
         let start_inclusive = match range.start_bound() {
@@ -356,14 +710,43 @@ impl TimestampRange {
     }
 
     #[inline]
-    pub fn intersect(&self, other: Self) -> Self {
-        Self {
+    pub fn is_empty(&self) -> bool {
+        self.start_inclusive > self.end_inclusive
+    }
+
+    /// Returns the timestamps in both ranges, or `None` if there are none.
+    /// Ranges that share a single timestamp intersect at that timestamp.
+    #[inline]
+    pub fn intersect(&self, other: &Self) -> Option<Self> {
+        let intersection = Self {
             start_inclusive: self.start_inclusive.max(other.start_inclusive),
             end_inclusive: self.end_inclusive.min(other.end_inclusive),
-        }
+        };
+        (!intersection.is_empty()).then_some(intersection)
     }
 }
 
+/// Several writes that commit atomically, started by `Persistence::begin`.
+/// Nothing written through the transaction is visible to readers until
+/// `commit`, and dropping it without committing rolls it back. Other writes
+/// to the same persistence may wait until the transaction finishes.
+#[async_trait]
+pub trait PersistenceTransaction: Send {
+    /// Like `Persistence::write`, but within the transaction. A failed write
+    /// is undone without affecting earlier writes in the transaction, which
+    /// can still be committed or rolled back.
+    async fn write(
+        &mut self,
+        documents: &[DocumentLogEntry],
+        indexes: &[PersistenceIndexEntry],
+        conflict_strategy: ConflictStrategy,
+    ) -> anyhow::Result<()>;
+
+    async fn commit(self: Box<Self>) -> anyhow::Result<()>;
+
+    async fn rollback(self: Box<Self>) -> anyhow::Result<()>;
+}
+
 #[async_trait]
 pub trait RetentionValidator: Sync + Send {
     /// Call optimistic_validate_snapshot *before* reading at the snapshot,
@@ -403,6 +786,63 @@ pub trait PersistenceReader: Send + Sync + 'static {
         retention_validator: Arc<dyn RetentionValidator>,
     ) -> DocumentStream<'_>;
 
+    /// Loads documents with timestamps strictly after `exclusive_ts`, e.g. to
+    /// follow the log by polling from the latest timestamp seen so far without
+    /// reading its entries again.
+    fn load_documents_after(
+        &self,
+        exclusive_ts: Timestamp,
+        order: Order,
+        page_size: u32,
+        retention_validator: Arc<dyn RetentionValidator>,
+    ) -> DocumentStream<'_> {
+        self.load_documents(
+            TimestampRange::greater_than(exclusive_ts),
+            order,
+            page_size,
+            retention_validator,
+        )
+    }
+
+    /// Like `load_documents`, but stops after `total_limit` entries across
+    /// the whole stream, if set, rather than per page. Implementations may
+    /// push the limit into the database so no more rows are read than are
+    /// returned.
+    fn load_documents_with_limit(
+        &self,
+        range: TimestampRange,
+        order: Order,
+        page_size: u32,
+        total_limit: Option<usize>,
+        retention_validator: Arc<dyn RetentionValidator>,
+    ) -> DocumentStream<'_> {
+        let stream = self.load_documents(range, order, page_size, retention_validator);
+        match total_limit {
+            Some(total_limit) => stream.take(total_limit).boxed(),
+            None => stream,
+        }
+    }
+
+    /// Like `load_documents`, but only yields revisions for which
+    /// `DocumentLogEntry::field_matches(field, op, value)`. Implementations
+    /// may push the comparison into the database, as long as the results are
+    /// the same.
+    fn load_documents_where(
+        &self,
+        range: TimestampRange,
+        field: &str,
+        op: CompareOp,
+        value: ConvexValue,
+        order: Order,
+        page_size: u32,
+        retention_validator: Arc<dyn RetentionValidator>,
+    ) -> DocumentStream<'_> {
+        let field = field.to_owned();
+        self.load_documents(range, order, page_size, retention_validator)
+            .try_filter(move |entry| future::ready(entry.field_matches(&field, op, &value)))
+            .boxed()
+    }
+
     /// Loads documents within the given table and the given timestamp range.
     ///
     /// page_size is how many documents to fetch with a single query. It doesn't
@@ -424,6 +864,132 @@ pub trait PersistenceReader: Send + Sync + 'static {
             .boxed()
     }
 
+    /// Like `load_documents`, but only yields the latest revision within
+    /// `range` of each document, skipping documents whose latest revision is a
+    /// deletion. Entries are sorted by `(ts, id)` in `order`.
+    fn load_live_documents(
+        &self,
+        range: TimestampRange,
+        order: Order,
+        page_size: u32,
+        retention_validator: Arc<dyn RetentionValidator>,
+    ) -> DocumentStream<'_> {
+        let revisions = self.load_documents(range, order, page_size, retention_validator);
+        crate::persistence_helpers::latest_live_revisions(revisions, order).boxed()
+    }
+
+    /// Loads the latest revision at or before `ts` of each document in the
+    /// table, skipping documents that are deleted as of `ts`. Entries are
+    /// sorted by `(ts, id)` in `order`.
+    fn load_snapshot(
+        &self,
+        tablet_id: TabletId,
+        ts: Timestamp,
+        order: Order,
+        page_size: u32,
+        retention_validator: Arc<dyn RetentionValidator>,
+    ) -> DocumentStream<'_> {
+        let revisions = self.load_documents_from_table(
+            tablet_id,
+            TimestampRange::snapshot(ts),
+            order,
+            page_size,
+            retention_validator,
+        );
+        crate::persistence_helpers::latest_live_revisions(revisions, order).boxed()
+    }
+
+    /// The documents with revisions after `ts_a` and at or before `ts_b`,
+    /// with their values as of each timestamp, sorted by id. Documents that
+    /// exist at neither, e.g. ones both created and deleted in between, are
+    /// skipped. The latest revision of every changed document is buffered in
+    /// memory before the first diff is yielded.
+    fn diff(&self, ts_a: Timestamp, ts_b: Timestamp) -> DocumentDiffStream<'_> {
+        crate::persistence_helpers::diff(self, ts_a, ts_b).boxed()
+    }
+
+    /// Like `load_documents`, but yields the entries of each timestamp
+    /// together, so a timestamp's entries are never split across items.
+    fn load_document_batches(
+        &self,
+        range: TimestampRange,
+        order: Order,
+        page_size: u32,
+        retention_validator: Arc<dyn RetentionValidator>,
+    ) -> DocumentBatchStream<'_> {
+        let documents = self.load_documents(range, order, page_size, retention_validator);
+        crate::persistence_helpers::group_by_timestamp(documents).boxed()
+    }
+
+    /// Loads every revision of a single document within the timestamp range,
+    /// including deletions, sorted by timestamp in `order`.
+    fn load_document_history(
+        &self,
+        id: InternalDocumentId,
+        range: TimestampRange,
+        order: Order,
+        retention_validator: Arc<dyn RetentionValidator>,
+    ) -> DocumentStream<'_> {
+        self.load_documents_from_table(
+            id.table(),
+            range,
+            order,
+            *DEFAULT_DOCUMENTS_PAGE_SIZE,
+            retention_validator,
+        )
+        .try_filter(move |doc| future::ready(doc.id == id))
+        .boxed()
+    }
+
+    /// Yields each distinct timestamp in `range` that has at least one
+    /// document log entry, sorted in `order`. The default implementation
+    /// loads the entries themselves.
+    fn distinct_timestamps(&self, range: TimestampRange, order: Order) -> TimestampStream<'_> {
+        self.load_document_batches(
+            range,
+            order,
+            *DEFAULT_DOCUMENTS_PAGE_SIZE,
+            Arc::new(NoopRetentionValidator),
+        )
+        .map_ok(|batch| batch[0].ts)
+        .boxed()
+    }
+
+    /// Counts the entries `load_documents` would return for the given
+    /// timestamp range. The default implementation streams and counts them,
+    /// so implementations should override it with something cheaper.
+    async fn count_documents(&self, range: TimestampRange) -> anyhow::Result<u64> {
+        self.load_documents(range, Order::Asc, 1000, Arc::new(NoopRetentionValidator))
+            .try_fold(0, |count, _| future::ready(Ok(count + 1)))
+            .await
+    }
+
+    /// Loads documents from each of the given tables within the timestamp
+    /// range, merged into a single stream that is globally sorted in `order`.
+    /// The tables are read concurrently.
+    fn load_documents_multi(
+        &self,
+        tablet_ids: &[TabletId],
+        range: TimestampRange,
+        order: Order,
+        page_size: u32,
+        retention_validator: Arc<dyn RetentionValidator>,
+    ) -> DocumentStream<'_> {
+        let streams = tablet_ids
+            .iter()
+            .map(|tablet_id| {
+                self.load_documents_from_table(
+                    *tablet_id,
+                    range,
+                    order,
+                    page_size,
+                    retention_validator.clone(),
+                )
+            })
+            .collect();
+        crate::persistence_helpers::merge_document_streams(streams, order).boxed()
+    }
+
     /// Loads revision pairs from the document log in the given timestamp range.
     ///
     /// If a tablet id is provided, the results are filtered to a single table.
@@ -466,6 +1032,117 @@ pub trait PersistenceReader: Send + Sync + 'static {
         retention_validator: Arc<dyn RetentionValidator>,
     ) -> anyhow::Result<BTreeMap<(InternalDocumentId, Timestamp), DocumentLogEntry>>;
 
+    /// Loads the latest revision at or before `ts` of each of `ids`,
+    /// positionally. The entry for a deleted document has no value, and ids
+    /// without any revisions at or before `ts` map to `None`.
+    async fn load_documents_by_ids(
+        &self,
+        ids: &[InternalDocumentId],
+        ts: Timestamp,
+    ) -> anyhow::Result<Vec<Option<DocumentLogEntry>>> {
+        let wanted: BTreeSet<_> = ids.iter().copied().collect();
+        let mut latest = BTreeMap::new();
+        let mut revisions = self.load_documents(
+            TimestampRange::new_inclusive(Timestamp::MIN, ts)?,
+            Order::Desc,
+            *DEFAULT_DOCUMENTS_PAGE_SIZE,
+            Arc::new(NoopRetentionValidator),
+        );
+        while latest.len() < wanted.len()
+            && let Some(entry) = revisions.try_next().await?
+        {
+            if wanted.contains(&entry.id) {
+                latest.entry(entry.id).or_insert(entry);
+            }
+        }
+        Ok(ids.iter().map(|id| latest.get(id).cloned()).collect())
+    }
+
+    /// A counter that increases with every successful `write`, `delete`,
+    /// `delete_tablet_documents`, `delete_range` and `truncate`, including
+    /// those through other handles to the same database. Cheap to poll, so
+    /// callers can use it to tell whether cached reads may be stale.
+    async fn generation(&self) -> anyhow::Result<u64> {
+        anyhow::bail!("generation is not supported by this persistence")
+    }
+
+    /// The distinct tablets with any entries in the document log, sorted.
+    /// The default implementation scans the whole log.
+    async fn tablet_ids(&self) -> anyhow::Result<Vec<TabletId>> {
+        let tablet_ids = self
+            .load_documents(
+                TimestampRange::all(),
+                Order::Asc,
+                *DEFAULT_DOCUMENTS_PAGE_SIZE,
+                Arc::new(NoopRetentionValidator),
+            )
+            .try_fold(BTreeSet::new(), |mut tablet_ids, entry| {
+                tablet_ids.insert(entry.id.table());
+                future::ready(Ok(tablet_ids))
+            })
+            .await?;
+        Ok(tablet_ids.into_iter().collect())
+    }
+
+    /// The number of entries in the document log for each tablet, counting
+    /// every revision the way `count_documents` does. Tablets without any
+    /// entries are absent. The default implementation scans the whole log.
+    async fn document_counts_by_tablet(&self) -> anyhow::Result<BTreeMap<TabletId, u64>> {
+        self.load_documents(
+            TimestampRange::all(),
+            Order::Asc,
+            *DEFAULT_DOCUMENTS_PAGE_SIZE,
+            Arc::new(NoopRetentionValidator),
+        )
+        .try_fold(BTreeMap::new(), |mut counts, entry| {
+            *counts.entry(entry.id.table()).or_insert(0) += 1;
+            future::ready(Ok(counts))
+        })
+        .await
+    }
+
+    /// The earliest timestamp in the document log, or `None` if it's empty.
+    async fn min_timestamp(&self) -> anyhow::Result<Option<Timestamp>> {
+        let mut stream = self.load_documents(
+            TimestampRange::all(),
+            Order::Asc,
+            1,
+            Arc::new(NoopRetentionValidator),
+        );
+        Ok(stream.try_next().await?.map(|entry| entry.ts))
+    }
+
+    /// The latest timestamp in the document log, or `None` if it's empty.
+    async fn max_timestamp(&self) -> anyhow::Result<Option<Timestamp>> {
+        let mut stream = self.load_documents(
+            TimestampRange::all(),
+            Order::Desc,
+            1,
+            Arc::new(NoopRetentionValidator),
+        );
+        Ok(stream.try_next().await?.map(|entry| entry.ts))
+    }
+
+    /// Load the latest revision of `id` at or before `ts`. A document deleted
+    /// as of `ts` is returned as an entry without a value; `None` means there
+    /// are no revisions of `id` at or before `ts`.
+    async fn load_document(
+        &self,
+        id: InternalDocumentId,
+        ts: Timestamp,
+    ) -> anyhow::Result<Option<DocumentLogEntry>> {
+        let mut revisions = self
+            .load_documents_from_table(
+                id.table(),
+                TimestampRange::new_inclusive(Timestamp::MIN, ts)?,
+                Order::Desc,
+                *DEFAULT_DOCUMENTS_PAGE_SIZE,
+                Arc::new(NoopRetentionValidator),
+            )
+            .try_filter(move |entry| future::ready(entry.id == id));
+        revisions.try_next().await
+    }
+
     /// Look up documents at exactly the specified prev_ts timestamps, returning
     /// a map where for each `DocumentPrevTsQuery` we have an entry only if
     /// a document exists at `(id, prev_ts)`.
@@ -477,6 +1154,9 @@ pub trait PersistenceReader: Send + Sync + 'static {
 
     /// Loads documentIds with respective timestamps that match the
     /// index query criteria.
+    /// `range` includes keys `k` with `start <= k`, and `k < end` unless the
+    /// end is unbounded, comparing keys bytewise. Intervals with `end <=
+    /// start` yield nothing; use `Interval::singleton` for a single key.
     /// `size_hint` is a best-effort estimate of the number of
     /// rows to be consumed from returned stream. This argument should only be
     /// used to tune batching in order to balance round trips and redundant
@@ -493,6 +1173,133 @@ pub trait PersistenceReader: Send + Sync + 'static {
         retention_validator: Arc<dyn RetentionValidator>,
     ) -> IndexStream<'_>;
 
+    /// Like `index_scan`, but scans every key in any of `ranges`. Overlapping
+    /// ranges are merged first, so each entry is yielded once, in `order`.
+    fn index_scan_intervals(
+        &self,
+        index_id: IndexId,
+        tablet_id: TabletId,
+        read_timestamp: Timestamp,
+        ranges: &[Interval],
+        order: Order,
+        size_hint: usize,
+        retention_validator: Arc<dyn RetentionValidator>,
+    ) -> IndexStream<'_> {
+        let mut set = IntervalSet::new();
+        for range in ranges {
+            set.add(range.clone());
+        }
+        let streams = order
+            .apply(set.iter().collect::<Vec<_>>().into_iter())
+            .map(|range| {
+                self.index_scan(
+                    index_id,
+                    tablet_id,
+                    read_timestamp,
+                    &range,
+                    order,
+                    size_hint,
+                    retention_validator.clone(),
+                )
+            })
+            .collect::<Vec<_>>();
+        stream::iter(streams).flatten().boxed()
+    }
+
+    /// Like `index_scan`, but only yields the id of each entry's document,
+    /// so implementations can avoid loading the documents themselves. Yields
+    /// at most `limit` ids if it's set.
+    fn index_scan_ids(
+        &self,
+        index_id: IndexId,
+        tablet_id: TabletId,
+        read_timestamp: Timestamp,
+        range: &Interval,
+        order: Order,
+        limit: Option<usize>,
+        retention_validator: Arc<dyn RetentionValidator>,
+    ) -> DocumentIdStream<'_> {
+        let ids = self
+            .index_scan(
+                index_id,
+                tablet_id,
+                read_timestamp,
+                range,
+                order,
+                limit.unwrap_or(*DEFAULT_DOCUMENTS_PAGE_SIZE as usize),
+                retention_validator,
+            )
+            .map_ok(|(_, document)| document.value.id_with_table_id());
+        match limit {
+            Some(limit) => ids.take(limit).boxed(),
+            None => ids.boxed(),
+        }
+    }
+
+    /// Like `index_scan`, but yields each entry's document as a
+    /// `DocumentLogEntry`, in index order, without the keys. Yields at most
+    /// `limit` documents if it's set. `index_scan` already reads each
+    /// document along with its entry, so this doesn't take another round
+    /// trip to fetch them.
+    fn index_scan_documents(
+        &self,
+        index_id: IndexId,
+        tablet_id: TabletId,
+        read_timestamp: Timestamp,
+        range: &Interval,
+        order: Order,
+        limit: Option<usize>,
+        retention_validator: Arc<dyn RetentionValidator>,
+    ) -> DocumentStream<'_> {
+        let documents = self
+            .index_scan(
+                index_id,
+                tablet_id,
+                read_timestamp,
+                range,
+                order,
+                limit.unwrap_or(*DEFAULT_DOCUMENTS_PAGE_SIZE as usize),
+                retention_validator,
+            )
+            .map_ok(|(_, document)| DocumentLogEntry {
+                ts: document.ts,
+                id: document.value.id_with_table_id(),
+                value: Some(document.value),
+                prev_ts: document.prev_ts,
+            });
+        match limit {
+            Some(limit) => documents.take(limit).boxed(),
+            None => documents.boxed(),
+        }
+    }
+
+    /// Whether `range` of the index has any entry at `read_timestamp`.
+    /// Implementations can answer this without loading any documents.
+    async fn index_exists(
+        &self,
+        index_id: IndexId,
+        tablet_id: TabletId,
+        read_timestamp: Timestamp,
+        range: &Interval,
+    ) -> anyhow::Result<bool> {
+        let mut ids = self.index_scan_ids(
+            index_id,
+            tablet_id,
+            read_timestamp,
+            range,
+            Order::Asc,
+            Some(1),
+            Arc::new(NoopRetentionValidator),
+        );
+        Ok(ids.try_next().await?.is_some())
+    }
+
+    /// A reader over a consistent view of the data as it is now, limited to
+    /// entries at or before `ts`. See `SnapshotReader`.
+    fn snapshot_at(&self, _ts: Timestamp) -> anyhow::Result<SnapshotReader> {
+        anyhow::bail!("snapshot_at is not supported by this persistence")
+    }
+
     async fn get_persistence_global(
         &self,
         key: PersistenceGlobalKey,
@@ -529,6 +1336,27 @@ pub trait PersistenceReader: Send + Sync + 'static {
         }
     }
 
+    /// Counts the entries in an index that are live (not deleted) at
+    /// `read_timestamp`. Intended for cheap selectivity estimates.
+    async fn index_entry_count(
+        &self,
+        index_id: IndexId,
+        tablet_id: TabletId,
+        read_timestamp: Timestamp,
+    ) -> anyhow::Result<u64> {
+        self.index_scan(
+            index_id,
+            tablet_id,
+            read_timestamp,
+            &Interval::all(),
+            Order::Asc,
+            1000,
+            Arc::new(NoopRetentionValidator),
+        )
+        .try_fold(0, |count, _| future::ready(Ok(count + 1)))
+        .await
+    }
+
     /// max_ts is the largest timestamp written to persistence.
     /// It's not necessarily safe to read snapshots at this timestamp.
     /// Use a RepeatableTimestamp constructor to find a safe timestamp for
@@ -628,7 +1456,9 @@ impl RepeatablePersistence {
     /// documents in the snapshot range.
     pub fn load_documents(&self, range: TimestampRange, order: Order) -> DocumentStream<'_> {
         self.reader.load_documents(
-            range.intersect(TimestampRange::snapshot(*self.upper_bound)),
+            range
+                .intersect(&TimestampRange::snapshot(*self.upper_bound))
+                .unwrap_or_else(TimestampRange::empty),
             order,
             *DEFAULT_DOCUMENTS_PAGE_SIZE,
             self.retention_validator.clone(),
@@ -645,7 +1475,9 @@ impl RepeatablePersistence {
     ) -> DocumentStream<'_> {
         self.reader.load_documents_from_table(
             tablet_id,
-            range.intersect(TimestampRange::snapshot(*self.upper_bound)),
+            range
+                .intersect(&TimestampRange::snapshot(*self.upper_bound))
+                .unwrap_or_else(TimestampRange::empty),
             order,
             *DEFAULT_DOCUMENTS_PAGE_SIZE,
             self.retention_validator.clone(),
@@ -662,7 +1494,9 @@ impl RepeatablePersistence {
     ) -> DocumentRevisionStream<'_> {
         self.reader.load_revision_pairs(
             tablet_id,
-            range.intersect(TimestampRange::snapshot(*self.upper_bound)),
+            range
+                .intersect(&TimestampRange::snapshot(*self.upper_bound))
+                .unwrap_or_else(TimestampRange::empty),
             order,
             *DEFAULT_DOCUMENTS_PAGE_SIZE,
             self.retention_validator.clone(),
@@ -790,6 +1624,75 @@ impl PersistenceSnapshot {
     }
 }
 
+/// A reader pinned to the data as of `ts`, returned by
+/// [`PersistenceReader::snapshot_at`]. Reads only see documents and index
+/// entries at or before `ts`, and the underlying reader holds a consistent
+/// view of the database, so writes made after the snapshot was taken (even
+/// at earlier timestamps) aren't seen either.
+#[derive(Clone)]
+pub struct SnapshotReader {
+    reader: Arc<dyn PersistenceReader>,
+    ts: Timestamp,
+}
+
+impl SnapshotReader {
+    /// `reader` must hold a consistent view for as long as it lives, e.g. by
+    /// keeping a read transaction open.
+    pub fn new(reader: Arc<dyn PersistenceReader>, ts: Timestamp) -> Self {
+        Self { reader, ts }
+    }
+
+    pub fn ts(&self) -> Timestamp {
+        self.ts
+    }
+
+    /// Same as [`PersistenceReader::load_documents`] but only including
+    /// documents at or before `ts`.
+    pub fn load_documents(
+        &self,
+        range: TimestampRange,
+        order: Order,
+        page_size: u32,
+        retention_validator: Arc<dyn RetentionValidator>,
+    ) -> DocumentStream<'_> {
+        self.reader.load_documents(
+            range
+                .intersect(&TimestampRange::snapshot(self.ts))
+                .unwrap_or_else(TimestampRange::empty),
+            order,
+            page_size,
+            retention_validator,
+        )
+    }
+
+    /// Same as [`PersistenceReader::index_scan`] but reading at `ts`.
+    pub fn index_scan(
+        &self,
+        index_id: IndexId,
+        tablet_id: TabletId,
+        range: &Interval,
+        order: Order,
+        size_hint: usize,
+        retention_validator: Arc<dyn RetentionValidator>,
+    ) -> IndexStream<'_> {
+        self.reader.index_scan(
+            index_id,
+            tablet_id,
+            self.ts,
+            range,
+            order,
+            size_hint,
+            retention_validator,
+        )
+    }
+
+    /// The pinned reader, for reads that `SnapshotReader` doesn't wrap. These
+    /// see the consistent view but aren't limited to `ts`.
+    pub fn reader(&self) -> &Arc<dyn PersistenceReader> {
+        &self.reader
+    }
+}
+
 /// Test-only snapshot validator that doesn't validate anything.
 /// Prod and most tests should use (Follower|Leader)RetentionManager,
 #[derive(Clone, Copy)]
@@ -894,6 +1797,202 @@ mod tests {
     use proptest::prelude::*;
 
     use super::*;
+    use crate::{
+        assert_obj,
+        document::CreationTime,
+        testing::{
+            persistence_test_suite::doc,
+            TestIdGenerator,
+            TestPersistence,
+        },
+        types::TableName,
+    };
+
+    fn range(start: i32, end: i32) -> TimestampRange {
+        TimestampRange::new(Timestamp::must(start)..Timestamp::must(end))
+    }
+
+    #[test]
+    fn test_timestamp_range_intersect() {
+        // Disjoint, including ranges that are merely adjacent.
+        assert_eq!(range(1, 3).intersect(&range(5, 7)), None);
+        assert_eq!(range(1, 3).intersect(&range(3, 5)), None);
+        // Touching at a single timestamp.
+        assert_eq!(
+            range(1, 4).intersect(&range(3, 5)),
+            Some(TimestampRange::at(Timestamp::must(3)))
+        );
+        // Fully nested, in either order.
+        assert_eq!(range(1, 10).intersect(&range(3, 5)), Some(range(3, 5)));
+        assert_eq!(range(3, 5).intersect(&range(1, 10)), Some(range(3, 5)));
+        // Partially overlapping.
+        assert_eq!(range(1, 5).intersect(&range(3, 8)), Some(range(3, 5)));
+        // Unbounded ranges, including at the MIN/MAX extremes.
+        assert_eq!(
+            TimestampRange::all().intersect(&range(3, 5)),
+            Some(range(3, 5))
+        );
+        assert_eq!(
+            TimestampRange::all().intersect(&TimestampRange::all()),
+            Some(TimestampRange::all())
+        );
+        assert_eq!(
+            TimestampRange::greater_than(Timestamp::must(4))
+                .intersect(&TimestampRange::snapshot(Timestamp::must(4))),
+            None
+        );
+        assert_eq!(
+            TimestampRange::empty().intersect(&TimestampRange::all()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_timestamp_range_new() -> anyhow::Result<()> {
+        let ts = Timestamp::must;
+        // Inverted.
+        assert!(TimestampRange::new_half_open(ts(5), ts(3)).is_err());
+        assert!(TimestampRange::new_inclusive(ts(5), ts(3)).is_err());
+        assert!(TimestampRange::new_exclusive(ts(5), ts(3)).is_err());
+        // Empty.
+        assert!(TimestampRange::new_half_open(ts(3), ts(3))?.is_empty());
+        assert!(TimestampRange::new_exclusive(ts(3), ts(3))?.is_empty());
+        assert!(TimestampRange::new_exclusive(ts(3), ts(4))?.is_empty());
+        // A single timestamp.
+        assert_eq!(
+            TimestampRange::new_inclusive(ts(3), ts(3))?,
+            TimestampRange::at(ts(3))
+        );
+        assert_eq!(
+            TimestampRange::new_half_open(ts(3), ts(4))?,
+            TimestampRange::at(ts(3))
+        );
+        assert_eq!(
+            TimestampRange::new_exclusive(ts(3), ts(5))?,
+            TimestampRange::at(ts(4))
+        );
+        // Longer ranges.
+        assert_eq!(TimestampRange::new_half_open(ts(3), ts(5))?, range(3, 5));
+        assert_eq!(TimestampRange::new_inclusive(ts(3), ts(5))?, range(3, 6));
+        assert_eq!(TimestampRange::new_exclusive(ts(3), ts(6))?, range(4, 6));
+        // Out at the extremes.
+        assert_eq!(
+            TimestampRange::new_inclusive(Timestamp::MIN, Timestamp::MAX)?,
+            TimestampRange::all()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_timestamp_range_contains() {
+        let r = range(3, 5);
+        assert!(!r.contains(Timestamp::must(2)));
+        assert!(r.contains(Timestamp::must(3)));
+        assert!(r.contains(Timestamp::must(4)));
+        assert!(!r.contains(Timestamp::must(5)));
+        assert!(TimestampRange::all().contains(Timestamp::MIN));
+        assert!(TimestampRange::all().contains(Timestamp::MAX));
+        assert!(!TimestampRange::empty().contains(Timestamp::MIN));
+    }
+
+    #[test]
+    fn test_document_log_entry_builder() -> anyhow::Result<()> {
+        let mut id_generator = TestIdGenerator::new();
+        let table: TableName = "table".parse()?;
+        let id = id_generator.user_generate(&table);
+        let document = ResolvedDocument::new(id, CreationTime::ONE, assert_obj!("value" => 1))?;
+
+        let entry = DocumentLogEntry::builder()
+            .ts(Timestamp::must(2))
+            .value(document.clone())
+            .prev_ts(Timestamp::must(1))
+            .build()?;
+        assert_eq!(
+            entry,
+            DocumentLogEntry {
+                ts: Timestamp::must(2),
+                id: id.into(),
+                value: Some(document.clone()),
+                prev_ts: Some(Timestamp::must(1)),
+            }
+        );
+        let tombstone = DocumentLogEntry::builder()
+            .ts(Timestamp::must(3))
+            .id(id.into())
+            .deleted()
+            .build()?;
+        assert_eq!(tombstone.value, None);
+        assert_eq!(tombstone.prev_ts, None);
+
+        // Missing fields.
+        assert!(DocumentLogEntry::builder()
+            .value(document.clone())
+            .build()
+            .is_err());
+        assert!(DocumentLogEntry::builder()
+            .ts(Timestamp::must(2))
+            .build()
+            .is_err());
+        assert!(DocumentLogEntry::builder()
+            .ts(Timestamp::must(2))
+            .deleted()
+            .build()
+            .is_err());
+        // A tombstone with a value.
+        assert!(DocumentLogEntry::builder()
+            .ts(Timestamp::must(2))
+            .value(document.clone())
+            .deleted()
+            .build()
+            .is_err());
+        // An id that doesn't match the value's.
+        assert!(DocumentLogEntry::builder()
+            .ts(Timestamp::must(2))
+            .id(id_generator.user_generate(&table).into())
+            .value(document.clone())
+            .build()
+            .is_err());
+        // A prev_ts that isn't before ts.
+        for prev_ts in [2, 3] {
+            assert!(DocumentLogEntry::builder()
+                .ts(Timestamp::must(2))
+                .value(document.clone())
+                .prev_ts(Timestamp::must(prev_ts))
+                .build()
+                .is_err());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_load_document_at_max_timestamp() -> anyhow::Result<()> {
+        let p = TestPersistence::new();
+        let mut id_generator = TestIdGenerator::new();
+        let table: TableName = str::parse("table")?;
+        let id = id_generator.user_generate(&table);
+        let other = id_generator.user_generate(&table);
+        let entries = [doc(id, 1, Some(1), None)?, doc(id, 2, Some(2), Some(1))?];
+        p.write(&entries, &[], ConflictStrategy::Error).await?;
+
+        // The defaults read up to and including `ts`, which has no successor
+        // at `Timestamp::MAX`.
+        let reader = p.reader();
+        assert_eq!(
+            reader.load_document(id.into(), Timestamp::MAX).await?,
+            Some(entries[1].clone())
+        );
+        assert_eq!(
+            reader.load_document(id.into(), Timestamp::must(1)).await?,
+            Some(entries[0].clone())
+        );
+        assert_eq!(
+            reader
+                .load_documents_by_ids(&[other.into(), id.into()], Timestamp::MAX)
+                .await?,
+            vec![None, Some(entries[1].clone())]
+        );
+        Ok(())
+    }
 
     proptest! {
         #![proptest_config(ProptestConfig { cases: 256 * env_config("CONVEX_PROPTEST_MULTIPLIER", 1), failure_persistence: None, .. ProptestConfig::default() })]
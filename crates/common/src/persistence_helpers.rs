@@ -1,7 +1,16 @@
-use std::sync::Arc;
+use std::{
+    collections::{
+        btree_map,
+        BTreeMap,
+    },
+    mem,
+    ops::Bound,
+    sync::Arc,
+};
 
 use anyhow::Context as _;
 use futures::{
+    future,
     Stream,
     TryStreamExt,
 };
@@ -10,13 +19,21 @@ use value::InternalDocumentId;
 
 use crate::{
     document::ResolvedDocument,
-    knobs::DOCUMENTS_IN_MEMORY,
+    knobs::{
+        DEFAULT_DOCUMENTS_PAGE_SIZE,
+        DOCUMENTS_IN_MEMORY,
+    },
     persistence::{
+        DocumentDiff,
         DocumentLogEntry,
         DocumentPrevTsQuery,
+        DocumentStream,
+        NoopRetentionValidator,
         PersistenceReader,
         RetentionValidator,
+        TimestampRange,
     },
+    query::Order,
     try_chunks::TryChunksExt,
     types::Timestamp,
 };
@@ -119,3 +136,120 @@ pub(crate) async fn persistence_reader_stream_revision_pairs<'a, P: PersistenceR
         }
     }
 }
+
+/// Exposed as PersistenceReader::diff.
+#[allow(clippy::needless_lifetimes)]
+#[try_stream(ok = DocumentDiff, error = anyhow::Error)]
+pub(crate) async fn diff<'a, P: PersistenceReader + ?Sized>(
+    reader: &'a P,
+    ts_a: Timestamp,
+    ts_b: Timestamp,
+) {
+    anyhow::ensure!(ts_a <= ts_b, "diff from {ts_a} back to {ts_b}");
+    let mut changes = reader.load_documents(
+        TimestampRange::new((Bound::Excluded(ts_a), Bound::Included(ts_b))),
+        Order::Asc,
+        *DEFAULT_DOCUMENTS_PAGE_SIZE,
+        Arc::new(NoopRetentionValidator),
+    );
+    // In ascending order, each document's last entry is its value at `ts_b`.
+    let mut after = BTreeMap::new();
+    while let Some(entry) = changes.try_next().await? {
+        after.insert(entry.id, entry.value);
+    }
+    let after: Vec<_> = after.into_iter().collect();
+    for chunk in after.chunks(*DOCUMENTS_IN_MEMORY) {
+        let ids: Vec<_> = chunk.iter().map(|(id, _)| *id).collect();
+        let before = reader.load_documents_by_ids(&ids, ts_a).await?;
+        for ((id, after), before) in chunk.iter().zip(before) {
+            let before = before.and_then(|entry| entry.value);
+            if before.is_none() && after.is_none() {
+                continue;
+            }
+            yield DocumentDiff {
+                id: *id,
+                before,
+                after: after.clone(),
+            };
+        }
+    }
+}
+
+/// Exposed as PersistenceReader::load_documents_multi. Each stream must
+/// already be sorted by `(ts, id)` in `order`.
+#[allow(clippy::needless_lifetimes)]
+#[try_stream(ok = DocumentLogEntry, error = anyhow::Error)]
+pub(crate) async fn merge_document_streams<'a>(mut streams: Vec<DocumentStream<'a>>, order: Order) {
+    // Fetch the first entry from every stream concurrently. After that, only
+    // the stream we just yielded from has to advance.
+    let mut heads =
+        future::try_join_all(streams.iter_mut().map(|stream| stream.try_next())).await?;
+    loop {
+        let candidates = heads
+            .iter()
+            .enumerate()
+            .filter_map(|(i, head)| head.as_ref().map(|entry| (entry.ts, entry.id, i)));
+        let next = match order {
+            Order::Asc => candidates.min(),
+            Order::Desc => candidates.max(),
+        };
+        let Some((_, _, i)) = next else {
+            break;
+        };
+        let entry = heads[i].take().expect("candidate head must exist");
+        heads[i] = streams[i].try_next().await?;
+        yield entry;
+    }
+}
+
+/// Exposed as PersistenceReader::load_document_batches. Entries with the same
+/// timestamp must be adjacent in `stream`, as they are in any stream sorted by
+/// timestamp.
+#[allow(clippy::needless_lifetimes)]
+#[try_stream(ok = Vec<DocumentLogEntry>, error = anyhow::Error)]
+pub(crate) async fn group_by_timestamp<'a>(mut stream: DocumentStream<'a>) {
+    let mut batch: Vec<DocumentLogEntry> = vec![];
+    while let Some(entry) = stream.try_next().await? {
+        if let Some(last) = batch.last()
+            && last.ts != entry.ts
+        {
+            yield mem::take(&mut batch);
+        }
+        batch.push(entry);
+    }
+    if !batch.is_empty() {
+        yield batch;
+    }
+}
+
+/// Used by PersistenceReader::load_snapshot and load_live_documents. Buffers
+/// the latest revision of each document in `stream`, then yields those that
+/// aren't deletions, sorted by `(ts, id)` in `order`.
+#[allow(clippy::needless_lifetimes)]
+#[try_stream(ok = DocumentLogEntry, error = anyhow::Error)]
+pub(crate) async fn latest_live_revisions<'a>(mut stream: DocumentStream<'a>, order: Order) {
+    let mut latest = BTreeMap::new();
+    while let Some(entry) = stream.try_next().await? {
+        match latest.entry(entry.id) {
+            btree_map::Entry::Vacant(e) => {
+                e.insert(entry);
+            },
+            btree_map::Entry::Occupied(mut e) => {
+                if e.get().ts < entry.ts {
+                    e.insert(entry);
+                }
+            },
+        }
+    }
+    let mut live: Vec<DocumentLogEntry> = latest
+        .into_values()
+        .filter(|entry| entry.value.is_some())
+        .collect();
+    live.sort_by_key(|entry| (entry.ts, entry.id));
+    if order == Order::Desc {
+        live.reverse();
+    }
+    for entry in live {
+        yield entry;
+    }
+}
@@ -0,0 +1,585 @@
+//! A `Persistence` that records every change to a file, so that the exact
+//! sequence of changes can be replayed into another persistence, e.g. to
+//! reproduce a production bug locally.
+
+use std::{
+    fmt::Display,
+    fs::{
+        File,
+        OpenOptions,
+    },
+    future::Future,
+    io::{
+        BufRead,
+        BufReader,
+        BufWriter,
+        Write,
+    },
+    path::Path,
+    sync::Arc,
+};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use serde_json::Value as JsonValue;
+use tokio::sync::Mutex;
+use value::{
+    ConvexValue,
+    InternalDocumentId,
+    InternalId,
+    TabletId,
+};
+
+use crate::{
+    document::ResolvedDocument,
+    index::{
+        IndexEntry,
+        IndexKeyBytes,
+    },
+    persistence::{
+        Conflict,
+        ConflictStrategy,
+        DocumentLogEntry,
+        Persistence,
+        PersistenceGlobalKey,
+        PersistenceIndexEntry,
+        PersistenceReader,
+        TimestampRange,
+        WriteOutcome,
+    },
+    types::{
+        IndexId,
+        Timestamp,
+    },
+};
+
+/// Forwards everything to `inner`, and appends each successful change to a
+/// log file that `replay` can apply to another persistence. Changes are
+/// serialized so that the log has them in the order `inner` applied them.
+///
+/// Every call that changes `inner` is recorded: writes, including
+/// `write_with_receipt` and imports, `write_partial`, persistence globals and
+/// deletions. `rebuild_index` and `begin` can't be recorded, so they fail.
+///
+/// A change is only appended once `inner` has applied it. If appending fails,
+/// the change is still reported as successful, since it can't be undone, but
+/// the log is then missing it, so every later change fails rather than
+/// leaving a log that doesn't reproduce `inner`.
+pub struct RecordingPersistence {
+    inner: Arc<dyn Persistence>,
+    log: Mutex<Log>,
+}
+
+struct Log {
+    file: BufWriter<File>,
+    /// Set once appending a change has failed.
+    incomplete: bool,
+}
+
+impl RecordingPersistence {
+    /// Records to `path`, appending to it if it already exists.
+    pub fn new(inner: Arc<dyn Persistence>, path: &Path) -> anyhow::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open replay log {}", path.display()))?;
+        Ok(Self {
+            inner,
+            log: Mutex::new(Log {
+                file: BufWriter::new(file),
+                incomplete: false,
+            }),
+        })
+    }
+
+    pub fn inner(&self) -> &Arc<dyn Persistence> {
+        &self.inner
+    }
+
+    /// Applies a change to `inner` with `apply`, then appends the change
+    /// `record` makes of its result to the log.
+    async fn record<T, F>(
+        &self,
+        apply: F,
+        record: impl FnOnce(&T) -> RecordedChange,
+    ) -> anyhow::Result<T>
+    where
+        F: Future<Output = anyhow::Result<T>>,
+    {
+        let mut log = self.log.lock().await;
+        anyhow::ensure!(
+            !log.incomplete,
+            "The replay log is missing an earlier change, so no more can be made"
+        );
+        let result = apply.await?;
+        if let Err(e) = log.append(&record(&result)) {
+            tracing::error!("Failed to append to the replay log: {e:#}");
+            log.incomplete = true;
+        }
+        Ok(result)
+    }
+}
+
+impl Log {
+    fn append(&mut self, change: &RecordedChange) -> anyhow::Result<()> {
+        serde_json::to_writer(&mut self.file, change)?;
+        self.file.write_all(b"\n")?;
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+/// Applies the changes recorded by a `RecordingPersistence` at `path` to
+/// `into`, in order, returning how many there were. Stops at the first change
+/// that fails, or that changes a different number of rows than it did when it
+/// was recorded, leaving the earlier ones applied.
+pub async fn replay(path: &Path, into: &dyn Persistence) -> anyhow::Result<usize> {
+    let file = File::open(path)
+        .with_context(|| format!("Failed to open replay log {}", path.display()))?;
+    let mut replayed = 0;
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        let change: RecordedChange = serde_json::from_str(&line)?;
+        change
+            .apply(into)
+            .await
+            .with_context(|| format!("Failed to replay change {replayed}"))?;
+        replayed += 1;
+    }
+    Ok(replayed)
+}
+
+/// One line of the log. Ids are in their string forms, values in their
+/// internal JSON form, index keys in hex, and timestamps as integers.
+#[derive(Serialize, Deserialize)]
+enum RecordedChange {
+    Write {
+        rows: RecordedRows,
+        conflict_strategy: RecordedConflictStrategy,
+    },
+    WritePartial {
+        rows: RecordedRows,
+        documents_written: usize,
+        indexes_written: usize,
+    },
+    PersistenceGlobal {
+        key: String,
+        value: JsonValue,
+    },
+    DeleteIndexEntries {
+        entries: Vec<RecordedIndexRow>,
+        deleted: usize,
+    },
+    Delete {
+        documents: Vec<(u64, String)>,
+        deleted: usize,
+    },
+    DeleteTabletDocuments {
+        tablet_id: String,
+        chunk_size: usize,
+        deleted: usize,
+    },
+    DeleteRange {
+        start_inclusive: u64,
+        end_exclusive: u64,
+        retain_latest: bool,
+        deleted: u64,
+    },
+    Truncate,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RecordedRows {
+    documents: Vec<RecordedDocument>,
+    indexes: Vec<RecordedIndexEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RecordedDocument {
+    ts: u64,
+    id: String,
+    value: Option<JsonValue>,
+    prev_ts: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RecordedIndexEntry {
+    ts: u64,
+    index_id: String,
+    key: String,
+    value: Option<String>,
+}
+
+/// A row of the `indexes` table, as `delete_index_entries` takes them.
+#[derive(Serialize, Deserialize)]
+struct RecordedIndexRow {
+    index_id: String,
+    key_prefix: String,
+    key_sha256: String,
+    ts: u64,
+    key_suffix: Option<String>,
+    deleted: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+enum RecordedConflictStrategy {
+    Error,
+    Overwrite,
+    Merge,
+}
+
+impl RecordedChange {
+    async fn apply(self, into: &dyn Persistence) -> anyhow::Result<()> {
+        match self {
+            RecordedChange::Write {
+                rows,
+                conflict_strategy,
+            } => {
+                let (documents, indexes) = rows.into_rows()?;
+                into.write(&documents, &indexes, conflict_strategy.into())
+                    .await?;
+            },
+            RecordedChange::WritePartial {
+                rows,
+                documents_written,
+                indexes_written,
+            } => {
+                // Replaying the earlier changes recreated the rows this
+                // skipped, so it skips them again.
+                let (documents, indexes) = rows.into_rows()?;
+                let outcome = into.write_partial(&documents, &indexes).await?;
+                anyhow::ensure!(
+                    outcome.documents_written == documents_written
+                        && outcome.indexes_written == indexes_written,
+                    "write_partial wrote {} documents and {} index entries, but {} and {} when \
+                     recorded",
+                    outcome.documents_written,
+                    outcome.indexes_written,
+                    documents_written,
+                    indexes_written
+                );
+            },
+            RecordedChange::PersistenceGlobal { key, value } => {
+                into.write_persistence_global(key.parse()?, value).await?;
+            },
+            RecordedChange::DeleteIndexEntries { entries, deleted } => {
+                let entries = entries
+                    .into_iter()
+                    .map(RecordedIndexRow::into_entry)
+                    .collect::<anyhow::Result<_>>()?;
+                check_deleted(into.delete_index_entries(entries).await?, deleted)?;
+            },
+            RecordedChange::Delete { documents, deleted } => {
+                let documents = documents
+                    .into_iter()
+                    .map(|(ts, id)| Ok((ts.try_into()?, id.parse()?)))
+                    .collect::<anyhow::Result<_>>()?;
+                check_deleted(into.delete(documents).await?, deleted)?;
+            },
+            RecordedChange::DeleteTabletDocuments {
+                tablet_id,
+                chunk_size,
+                deleted,
+            } => {
+                let tablet_id: TabletId = tablet_id.parse()?;
+                check_deleted(
+                    into.delete_tablet_documents(tablet_id, chunk_size).await?,
+                    deleted,
+                )?;
+            },
+            RecordedChange::DeleteRange {
+                start_inclusive,
+                end_exclusive,
+                retain_latest,
+                deleted,
+            } => {
+                let range = TimestampRange::new(
+                    Timestamp::try_from(start_inclusive)?..Timestamp::try_from(end_exclusive)?,
+                );
+                check_deleted(into.delete_range(range, retain_latest).await?, deleted)?;
+            },
+            RecordedChange::Truncate => into.truncate().await?,
+        }
+        Ok(())
+    }
+}
+
+fn check_deleted<N: PartialEq + Display>(deleted: N, recorded: N) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        deleted == recorded,
+        "Deleted {deleted} rows, but {recorded} when recorded"
+    );
+    Ok(())
+}
+
+impl RecordedRows {
+    fn new(documents: &[DocumentLogEntry], indexes: &[PersistenceIndexEntry]) -> Self {
+        let documents = documents
+            .iter()
+            .map(|entry| RecordedDocument {
+                ts: entry.ts.into(),
+                id: entry.id.into(),
+                value: entry
+                    .value
+                    .as_ref()
+                    .map(|document| document.value().to_internal_json()),
+                prev_ts: entry.prev_ts.map(u64::from),
+            })
+            .collect();
+        let indexes = indexes
+            .iter()
+            .map(|entry| RecordedIndexEntry {
+                ts: entry.ts.into(),
+                index_id: entry.index_id.into(),
+                key: hex::encode(&entry.key.0),
+                value: entry.value.map(String::from),
+            })
+            .collect();
+        Self { documents, indexes }
+    }
+
+    fn into_rows(self) -> anyhow::Result<(Vec<DocumentLogEntry>, Vec<PersistenceIndexEntry>)> {
+        let documents = self
+            .documents
+            .into_iter()
+            .map(|entry| {
+                let id: InternalDocumentId = entry.id.parse()?;
+                let value = entry
+                    .value
+                    .map(|value| {
+                        ResolvedDocument::from_database(id.table(), ConvexValue::try_from(value)?)
+                    })
+                    .transpose()?;
+                Ok(DocumentLogEntry {
+                    ts: entry.ts.try_into()?,
+                    id,
+                    value,
+                    prev_ts: entry.prev_ts.map(Timestamp::try_from).transpose()?,
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        let indexes = self
+            .indexes
+            .into_iter()
+            .map(|entry| {
+                Ok(PersistenceIndexEntry {
+                    ts: entry.ts.try_into()?,
+                    index_id: entry.index_id.parse::<InternalId>()?,
+                    key: IndexKeyBytes(hex::decode(&entry.key)?),
+                    value: entry.value.map(|id| id.parse()).transpose()?,
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok((documents, indexes))
+    }
+}
+
+impl RecordedIndexRow {
+    fn new(entry: &IndexEntry) -> Self {
+        Self {
+            index_id: entry.index_id.into(),
+            key_prefix: hex::encode(&entry.key_prefix),
+            key_sha256: hex::encode(&entry.key_sha256),
+            ts: entry.ts.into(),
+            key_suffix: entry.key_suffix.as_ref().map(hex::encode),
+            deleted: entry.deleted,
+        }
+    }
+
+    fn into_entry(self) -> anyhow::Result<IndexEntry> {
+        Ok(IndexEntry {
+            index_id: self.index_id.parse()?,
+            key_prefix: hex::decode(&self.key_prefix)?,
+            key_sha256: hex::decode(&self.key_sha256)?,
+            ts: self.ts.try_into()?,
+            key_suffix: self.key_suffix.map(hex::decode).transpose()?,
+            deleted: self.deleted,
+        })
+    }
+}
+
+impl From<ConflictStrategy> for RecordedConflictStrategy {
+    fn from(conflict_strategy: ConflictStrategy) -> Self {
+        match conflict_strategy {
+            ConflictStrategy::Error => Self::Error,
+            ConflictStrategy::Overwrite => Self::Overwrite,
+            ConflictStrategy::Merge => Self::Merge,
+        }
+    }
+}
+
+impl From<RecordedConflictStrategy> for ConflictStrategy {
+    fn from(conflict_strategy: RecordedConflictStrategy) -> Self {
+        match conflict_strategy {
+            RecordedConflictStrategy::Error => Self::Error,
+            RecordedConflictStrategy::Overwrite => Self::Overwrite,
+            RecordedConflictStrategy::Merge => Self::Merge,
+        }
+    }
+}
+
+#[async_trait]
+impl Persistence for RecordingPersistence {
+    fn is_fresh(&self) -> bool {
+        self.inner.is_fresh()
+    }
+
+    fn reader(&self) -> Arc<dyn PersistenceReader> {
+        self.inner.reader()
+    }
+
+    async fn write<'a>(
+        &self,
+        documents: &'a [DocumentLogEntry],
+        indexes: &'a [PersistenceIndexEntry],
+        conflict_strategy: ConflictStrategy,
+    ) -> anyhow::Result<()> {
+        self.record(
+            self.inner.write(documents, indexes, conflict_strategy),
+            |()| RecordedChange::Write {
+                rows: RecordedRows::new(documents, indexes),
+                conflict_strategy: conflict_strategy.into(),
+            },
+        )
+        .await
+    }
+
+    async fn check_write(
+        &self,
+        documents: &[DocumentLogEntry],
+        indexes: &[PersistenceIndexEntry],
+    ) -> anyhow::Result<Vec<Conflict>> {
+        self.inner.check_write(documents, indexes).await
+    }
+
+    async fn write_partial(
+        &self,
+        documents: &[DocumentLogEntry],
+        indexes: &[PersistenceIndexEntry],
+    ) -> anyhow::Result<WriteOutcome> {
+        self.record(self.inner.write_partial(documents, indexes), |outcome| {
+            RecordedChange::WritePartial {
+                rows: RecordedRows::new(documents, indexes),
+                documents_written: outcome.documents_written,
+                indexes_written: outcome.indexes_written,
+            }
+        })
+        .await
+    }
+
+    async fn write_persistence_global(
+        &self,
+        key: PersistenceGlobalKey,
+        value: JsonValue,
+    ) -> anyhow::Result<()> {
+        let recorded = value.clone();
+        self.record(self.inner.write_persistence_global(key, value), |()| {
+            RecordedChange::PersistenceGlobal {
+                key: key.into(),
+                value: recorded,
+            }
+        })
+        .await
+    }
+
+    async fn load_index_chunk(
+        &self,
+        cursor: Option<IndexEntry>,
+        chunk_size: usize,
+    ) -> anyhow::Result<Vec<IndexEntry>> {
+        self.inner.load_index_chunk(cursor, chunk_size).await
+    }
+
+    async fn delete_index_entries(&self, entries: Vec<IndexEntry>) -> anyhow::Result<usize> {
+        let recorded = entries.iter().map(RecordedIndexRow::new).collect();
+        self.record(self.inner.delete_index_entries(entries), |&deleted| {
+            RecordedChange::DeleteIndexEntries {
+                entries: recorded,
+                deleted,
+            }
+        })
+        .await
+    }
+
+    async fn delete(
+        &self,
+        documents: Vec<(Timestamp, InternalDocumentId)>,
+    ) -> anyhow::Result<usize> {
+        let recorded = documents
+            .iter()
+            .map(|&(ts, id)| (ts.into(), id.into()))
+            .collect();
+        self.record(self.inner.delete(documents), |&deleted| {
+            RecordedChange::Delete {
+                documents: recorded,
+                deleted,
+            }
+        })
+        .await
+    }
+
+    async fn delete_tablet_documents(
+        &self,
+        tablet_id: TabletId,
+        chunk_size: usize,
+    ) -> anyhow::Result<usize> {
+        self.record(
+            self.inner.delete_tablet_documents(tablet_id, chunk_size),
+            |&deleted| RecordedChange::DeleteTabletDocuments {
+                tablet_id: tablet_id.to_string(),
+                chunk_size,
+                deleted,
+            },
+        )
+        .await
+    }
+
+    async fn delete_range(
+        &self,
+        range: TimestampRange,
+        retain_latest: bool,
+    ) -> anyhow::Result<u64> {
+        self.record(self.inner.delete_range(range, retain_latest), |&deleted| {
+            RecordedChange::DeleteRange {
+                start_inclusive: range.min_timestamp_inclusive().into(),
+                end_exclusive: range.max_timestamp_exclusive().into(),
+                retain_latest,
+                deleted,
+            }
+        })
+        .await
+    }
+
+    async fn truncate(&self) -> anyhow::Result<()> {
+        self.record(self.inner.truncate(), |()| RecordedChange::Truncate)
+            .await
+    }
+
+    async fn rebuild_index(
+        &self,
+        _index_id: IndexId,
+        _tablet_id: TabletId,
+        _key_fn: &(dyn Fn(&ResolvedDocument) -> Vec<u8> + Send + Sync),
+    ) -> anyhow::Result<u64> {
+        // `key_fn` can't be written to the log, so the rebuilt entries
+        // couldn't be replayed.
+        anyhow::bail!("rebuild_index can't be recorded by RecordingPersistence")
+    }
+
+    async fn fsync_barrier(&self) -> anyhow::Result<()> {
+        self.inner.fsync_barrier().await
+    }
+
+    async fn shutdown(&self) -> anyhow::Result<()> {
+        self.inner.shutdown().await
+    }
+
+    async fn finish_loading(&self) -> anyhow::Result<()> {
+        self.inner.finish_loading().await
+    }
+}
@@ -0,0 +1,192 @@
+//! Batches writes to a `Persistence` for workloads that produce rows a few at
+//! a time, such as log ingestion, so that each commit covers many of them.
+
+use std::{
+    mem,
+    sync::{
+        atomic::{
+            AtomicBool,
+            Ordering,
+        },
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+use crate::{
+    persistence::{
+        ConflictStrategy,
+        DocumentLogEntry,
+        Persistence,
+        PersistenceIndexEntry,
+    },
+    runtime::tokio_spawn,
+    sync::{
+        Mutex,
+        Notify,
+    },
+};
+
+impl dyn Persistence {
+    /// Starts a `StreamingWriter` that commits once `flush_every` rows are
+    /// buffered or the oldest buffered row has waited `flush_interval`,
+    /// whichever comes first. Must be called within a tokio runtime.
+    pub fn streaming_writer(
+        self: Arc<Self>,
+        flush_every: usize,
+        flush_interval: Duration,
+    ) -> StreamingWriter {
+        StreamingWriter::new(self, flush_every, flush_interval)
+    }
+}
+
+/// Buffers rows pushed to it and writes them to the persistence in batches
+/// with `ConflictStrategy::Error`, in the order they were pushed.
+///
+/// A write that fails is not retried and its rows are dropped. If the write
+/// was started by `push` or `flush` its error is returned from that call.
+/// If it was started by the timer it's returned from the next call instead,
+/// without buffering that call's rows.
+///
+/// Dropping the writer flushes whatever is still buffered in the background,
+/// logging rather than returning any error, so call `flush` first to be sure
+/// everything was written.
+pub struct StreamingWriter {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    persistence: Arc<dyn Persistence>,
+    flush_every: usize,
+    flush_interval: Duration,
+    buffer: Mutex<Buffer>,
+    /// Wakes the timer when the buffer goes from empty to non-empty, or the
+    /// writer is dropped.
+    wake: Notify,
+    closed: AtomicBool,
+}
+
+#[derive(Default)]
+struct Buffer {
+    documents: Vec<DocumentLogEntry>,
+    indexes: Vec<PersistenceIndexEntry>,
+    /// When the oldest buffered row was pushed.
+    oldest: Option<Instant>,
+    /// The error from the last flush started by the timer, if nobody has
+    /// seen it yet.
+    error: Option<anyhow::Error>,
+}
+
+impl StreamingWriter {
+    fn new(
+        persistence: Arc<dyn Persistence>,
+        flush_every: usize,
+        flush_interval: Duration,
+    ) -> Self {
+        let shared = Arc::new(Shared {
+            persistence,
+            flush_every,
+            flush_interval,
+            buffer: Mutex::new(Buffer::default()),
+            wake: Notify::new(),
+            closed: AtomicBool::new(false),
+        });
+        tokio_spawn("streaming_writer", shared.clone().flush_on_interval());
+        Self { shared }
+    }
+
+    /// Buffers `documents` and `indexes`, writing everything buffered if that
+    /// reaches `flush_every` rows.
+    pub async fn push(
+        &self,
+        documents: impl IntoIterator<Item = DocumentLogEntry>,
+        indexes: impl IntoIterator<Item = PersistenceIndexEntry>,
+    ) -> anyhow::Result<()> {
+        let mut buffer = self.shared.buffer.lock().await;
+        if let Some(e) = buffer.error.take() {
+            return Err(e);
+        }
+        buffer.documents.extend(documents);
+        buffer.indexes.extend(indexes);
+        if buffer.len() >= self.shared.flush_every {
+            return self.shared.write(&mut buffer).await;
+        }
+        if buffer.oldest.is_none() && buffer.len() > 0 {
+            buffer.oldest = Some(Instant::now());
+            self.shared.wake.notify_one();
+        }
+        Ok(())
+    }
+
+    /// Writes everything buffered so far.
+    pub async fn flush(&self) -> anyhow::Result<()> {
+        let mut buffer = self.shared.buffer.lock().await;
+        if let Some(e) = buffer.error.take() {
+            return Err(e);
+        }
+        self.shared.write(&mut buffer).await
+    }
+
+    /// Number of rows waiting to be written.
+    pub async fn buffered(&self) -> usize {
+        self.shared.buffer.lock().await.len()
+    }
+}
+
+impl Drop for StreamingWriter {
+    fn drop(&mut self) {
+        self.shared.closed.store(true, Ordering::SeqCst);
+        self.shared.wake.notify_one();
+    }
+}
+
+impl Buffer {
+    fn len(&self) -> usize {
+        self.documents.len() + self.indexes.len()
+    }
+}
+
+impl Shared {
+    async fn write(&self, buffer: &mut Buffer) -> anyhow::Result<()> {
+        buffer.oldest = None;
+        let documents = mem::take(&mut buffer.documents);
+        let indexes = mem::take(&mut buffer.indexes);
+        if documents.is_empty() && indexes.is_empty() {
+            return Ok(());
+        }
+        self.persistence
+            .write(&documents, &indexes, ConflictStrategy::Error)
+            .await
+    }
+
+    /// Writes the buffer whenever its oldest row has waited `flush_interval`,
+    /// and once more after the writer is dropped.
+    async fn flush_on_interval(self: Arc<Self>) {
+        loop {
+            let deadline = {
+                let mut buffer = self.buffer.lock().await;
+                if self.closed.load(Ordering::SeqCst) {
+                    if let Err(e) = self.write(&mut buffer).await {
+                        tracing::error!("StreamingWriter failed to flush on drop: {e:#}");
+                    }
+                    return;
+                }
+                if let Some(oldest) = buffer.oldest
+                    && oldest.elapsed() >= self.flush_interval
+                    && let Err(e) = self.write(&mut buffer).await
+                {
+                    buffer.error = Some(e);
+                }
+                buffer.oldest.map(|oldest| oldest + self.flush_interval)
+            };
+            match deadline {
+                Some(deadline) => {
+                    let _ = tokio::time::timeout_at(deadline, self.wake.notified()).await;
+                },
+                None => self.wake.notified().await,
+            }
+        }
+    }
+}
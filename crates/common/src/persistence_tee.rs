@@ -0,0 +1,240 @@
+//! A `Persistence` that writes to two backends, e.g. while migrating from one
+//! to the other.
+
+use std::sync::{
+    atomic::{
+        AtomicU64,
+        Ordering,
+    },
+    Arc,
+};
+
+use async_trait::async_trait;
+use serde_json::Value as JsonValue;
+use value::{
+    InternalDocumentId,
+    TabletId,
+};
+
+use crate::{
+    document::ResolvedDocument,
+    index::IndexEntry,
+    persistence::{
+        Conflict,
+        ConflictStrategy,
+        DocumentLogEntry,
+        Persistence,
+        PersistenceGlobalKey,
+        PersistenceIndexEntry,
+        PersistenceReader,
+        TimestampRange,
+        WriteOutcome,
+    },
+    types::{
+        IndexId,
+        Timestamp,
+    },
+};
+
+/// Applies every write to `primary` and then, best-effort, to `secondary`.
+/// Reads only go to `primary`, and only its errors are returned; failures
+/// of `secondary` are logged and counted by `secondary_failures`.
+///
+/// A failed write to `secondary` isn't retried, so the two may diverge.
+/// Comparing them is up to the caller. Imports go through `write`, so they
+/// reach both.
+pub struct TeePersistence {
+    primary: Arc<dyn Persistence>,
+    secondary: Arc<dyn Persistence>,
+    secondary_failures: AtomicU64,
+}
+
+impl TeePersistence {
+    pub fn new(primary: Arc<dyn Persistence>, secondary: Arc<dyn Persistence>) -> Self {
+        Self {
+            primary,
+            secondary,
+            secondary_failures: AtomicU64::new(0),
+        }
+    }
+
+    pub fn primary(&self) -> &Arc<dyn Persistence> {
+        &self.primary
+    }
+
+    pub fn secondary(&self) -> &Arc<dyn Persistence> {
+        &self.secondary
+    }
+
+    /// Number of operations that succeeded on `primary` but failed on
+    /// `secondary`.
+    pub fn secondary_failures(&self) -> u64 {
+        self.secondary_failures.load(Ordering::Relaxed)
+    }
+
+    fn report_secondary<T>(&self, operation: &str, result: anyhow::Result<T>) {
+        if let Err(e) = result {
+            self.secondary_failures.fetch_add(1, Ordering::Relaxed);
+            tracing::warn!("Secondary persistence failed to {operation}: {e:#}");
+        }
+    }
+}
+
+#[async_trait]
+impl Persistence for TeePersistence {
+    fn is_fresh(&self) -> bool {
+        self.primary.is_fresh()
+    }
+
+    fn reader(&self) -> Arc<dyn PersistenceReader> {
+        self.primary.reader()
+    }
+
+    async fn write<'a>(
+        &self,
+        documents: &'a [DocumentLogEntry],
+        indexes: &'a [PersistenceIndexEntry],
+        conflict_strategy: ConflictStrategy,
+    ) -> anyhow::Result<()> {
+        self.primary
+            .write(documents, indexes, conflict_strategy)
+            .await?;
+        let result = self
+            .secondary
+            .write(documents, indexes, conflict_strategy)
+            .await;
+        self.report_secondary("write", result);
+        Ok(())
+    }
+
+    async fn check_write(
+        &self,
+        documents: &[DocumentLogEntry],
+        indexes: &[PersistenceIndexEntry],
+    ) -> anyhow::Result<Vec<Conflict>> {
+        self.primary.check_write(documents, indexes).await
+    }
+
+    async fn write_partial(
+        &self,
+        documents: &[DocumentLogEntry],
+        indexes: &[PersistenceIndexEntry],
+    ) -> anyhow::Result<WriteOutcome> {
+        let outcome = self.primary.write_partial(documents, indexes).await?;
+        let result = self.secondary.write_partial(documents, indexes).await;
+        self.report_secondary("write_partial", result);
+        Ok(outcome)
+    }
+
+    async fn write_persistence_global(
+        &self,
+        key: PersistenceGlobalKey,
+        value: JsonValue,
+    ) -> anyhow::Result<()> {
+        self.primary
+            .write_persistence_global(key, value.clone())
+            .await?;
+        let result = self.secondary.write_persistence_global(key, value).await;
+        self.report_secondary("write_persistence_global", result);
+        Ok(())
+    }
+
+    async fn load_index_chunk(
+        &self,
+        cursor: Option<IndexEntry>,
+        chunk_size: usize,
+    ) -> anyhow::Result<Vec<IndexEntry>> {
+        self.primary.load_index_chunk(cursor, chunk_size).await
+    }
+
+    async fn delete_index_entries(&self, entries: Vec<IndexEntry>) -> anyhow::Result<usize> {
+        let deleted = self.primary.delete_index_entries(entries.clone()).await?;
+        let result = self.secondary.delete_index_entries(entries).await;
+        self.report_secondary("delete_index_entries", result);
+        Ok(deleted)
+    }
+
+    async fn delete(
+        &self,
+        documents: Vec<(Timestamp, InternalDocumentId)>,
+    ) -> anyhow::Result<usize> {
+        let deleted = self.primary.delete(documents.clone()).await?;
+        let result = self.secondary.delete(documents).await;
+        self.report_secondary("delete", result);
+        Ok(deleted)
+    }
+
+    async fn delete_tablet_documents(
+        &self,
+        tablet_id: TabletId,
+        chunk_size: usize,
+    ) -> anyhow::Result<usize> {
+        let deleted = self
+            .primary
+            .delete_tablet_documents(tablet_id, chunk_size)
+            .await?;
+        let result = self
+            .secondary
+            .delete_tablet_documents(tablet_id, chunk_size)
+            .await;
+        self.report_secondary("delete_tablet_documents", result);
+        Ok(deleted)
+    }
+
+    async fn delete_range(
+        &self,
+        range: TimestampRange,
+        retain_latest: bool,
+    ) -> anyhow::Result<u64> {
+        let deleted = self.primary.delete_range(range, retain_latest).await?;
+        let result = self.secondary.delete_range(range, retain_latest).await;
+        self.report_secondary("delete_range", result);
+        Ok(deleted)
+    }
+
+    async fn truncate(&self) -> anyhow::Result<()> {
+        self.primary.truncate().await?;
+        let result = self.secondary.truncate().await;
+        self.report_secondary("truncate", result);
+        Ok(())
+    }
+
+    async fn rebuild_index(
+        &self,
+        index_id: IndexId,
+        tablet_id: TabletId,
+        key_fn: &(dyn Fn(&ResolvedDocument) -> Vec<u8> + Send + Sync),
+    ) -> anyhow::Result<u64> {
+        let written = self
+            .primary
+            .rebuild_index(index_id, tablet_id, key_fn)
+            .await?;
+        let result = self
+            .secondary
+            .rebuild_index(index_id, tablet_id, key_fn)
+            .await;
+        self.report_secondary("rebuild_index", result);
+        Ok(written)
+    }
+
+    async fn fsync_barrier(&self) -> anyhow::Result<()> {
+        self.primary.fsync_barrier().await?;
+        let result = self.secondary.fsync_barrier().await;
+        self.report_secondary("fsync_barrier", result);
+        Ok(())
+    }
+
+    async fn shutdown(&self) -> anyhow::Result<()> {
+        self.primary.shutdown().await?;
+        let result = self.secondary.shutdown().await;
+        self.report_secondary("shutdown", result);
+        Ok(())
+    }
+
+    async fn finish_loading(&self) -> anyhow::Result<()> {
+        self.primary.finish_loading().await?;
+        let result = self.secondary.finish_loading().await;
+        self.report_secondary("finish_loading", result);
+        Ok(())
+    }
+}
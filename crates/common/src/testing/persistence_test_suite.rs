@@ -156,6 +156,14 @@ macro_rules! run_persistence_test_suite {
             persistence_test_suite::query_index_range_long(::std::sync::Arc::new(p)).await
         }
 
+        #[tokio::test]
+        async fn test_persistence_query_index_order_with_shared_prefix() -> anyhow::Result<()> {
+            let $db = $create_db;
+            let p = $create_persistence;
+            persistence_test_suite::query_index_order_with_shared_prefix(::std::sync::Arc::new(p))
+                .await
+        }
+
         #[tokio::test]
         async fn test_persistence_query_multiple_indexes() -> anyhow::Result<()> {
             let $db = $create_db;
@@ -1188,6 +1196,82 @@ pub async fn query_index_range_long<P: Persistence>(p: Arc<P>) -> anyhow::Result
     query_index_range_with_prefix(p, long_prefix).await
 }
 
+// Index keys that share a long leading field and differ only in a later
+// field should still be ordered by the full key, with each key's latest
+// revision winning, in both directions.
+pub async fn query_index_order_with_shared_prefix<P: Persistence>(p: Arc<P>) -> anyhow::Result<()> {
+    let mut id_generator = TestIdGenerator::new();
+    let index_id = id_generator.system_generate(&INDEX_TABLE).internal_id();
+
+    let table: TableName = str::parse("table")?;
+    let tablet_id = id_generator.user_table_id(&table).tablet_id;
+    let fields: IndexedFields = vec!["prefix".parse()?, "suffix".parse()?].try_into()?;
+    let prefix: ConvexValue =
+        testing::generate_with::<Vec<u8>>(size_range(10000).lift()).try_into()?;
+
+    let mut documents = Vec::new();
+    let mut indexes = Vec::new();
+    for i in 0..10 {
+        let doc_id = id_generator.user_generate(&table);
+        let suffix: ConvexValue = testing::generate::<Vec<u8>>().try_into()?;
+        // Write each document twice under the same key so the scan has to pick
+        // the later revision.
+        for revision in [0, 100] {
+            let ts = Timestamp::must(i + revision);
+            let doc = ResolvedDocument::new(
+                doc_id,
+                CreationTime::ONE,
+                assert_obj!(
+                    "prefix" => prefix.clone(),
+                    "suffix" => suffix.clone(),
+                    "revision" => i64::from(revision),
+                ),
+            )?;
+            indexes.push(PersistenceIndexEntry {
+                ts,
+                index_id,
+                key: doc.index_key(&fields, p.reader().version()).to_bytes(),
+                value: Some(doc.id_with_table_id()),
+            });
+            documents.push(DocumentLogEntry {
+                ts,
+                id: doc.id_with_table_id(),
+                value: Some(doc),
+                prev_ts: None,
+            });
+        }
+    }
+    p.write(&documents, &indexes, ConflictStrategy::Error)
+        .await?;
+    id_generator.write_tables(p.clone()).await?;
+
+    let reader = p.reader();
+    let scan = |order| {
+        reader
+            .index_scan(
+                index_id,
+                tablet_id,
+                Timestamp::must(1000),
+                &Interval::all(),
+                order,
+                100,
+                Arc::new(NoopRetentionValidator),
+            )
+            .try_collect::<Vec<_>>()
+    };
+    let ascending = scan(Order::Asc).await?;
+    let mut descending = scan(Order::Desc).await?;
+
+    assert_eq!(ascending.len(), 10);
+    assert!(ascending.windows(2).all(|w| w[0].0 < w[1].0));
+    assert!(ascending
+        .iter()
+        .all(|(_, doc)| doc.ts >= Timestamp::must(100)));
+    descending.reverse();
+    assert_eq!(ascending, descending);
+    Ok(())
+}
+
 // Make sure we correctly filter using the index_id.
 pub async fn query_multiple_indexes<P: Persistence>(p: Arc<P>) -> anyhow::Result<()> {
     let table: TableName = str::parse("table")?;
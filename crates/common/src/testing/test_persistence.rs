@@ -99,6 +99,18 @@ impl Persistence for TestPersistence {
     ) -> anyhow::Result<()> {
         let mut inner = self.inner.lock();
         for update in documents {
+            if conflict_strategy == ConflictStrategy::Merge
+                && let Some(existing) = inner.log.get(&(update.ts, update.id))
+            {
+                anyhow::ensure!(
+                    *existing == (update.value.clone(), update.prev_ts),
+                    "Failed to merge document at ts {} with id {}: a different revision already \
+                     exists",
+                    update.ts,
+                    update.id
+                );
+                continue;
+            }
             anyhow::ensure!(
                 conflict_strategy == ConflictStrategy::Overwrite
                     || !inner.log.contains_key(&(update.ts, update.id)),
@@ -115,6 +127,22 @@ impl Persistence for TestPersistence {
         inner.is_fresh = false;
         for update in indexes {
             let index_key_bytes = update.key.clone();
+            if conflict_strategy == ConflictStrategy::Merge
+                && let Some(existing) = inner
+                    .index
+                    .get(&update.index_id)
+                    .and_then(|idx| idx.get(&(index_key_bytes.clone(), update.ts)))
+            {
+                anyhow::ensure!(
+                    *existing == update.value,
+                    "Failed to merge index {} entry at ts {} with key {:?}: a different entry \
+                     already exists",
+                    update.index_id,
+                    update.ts,
+                    index_key_bytes
+                );
+                continue;
+            }
             anyhow::ensure!(
                 conflict_strategy == ConflictStrategy::Overwrite
                     || !inner
@@ -78,6 +78,35 @@ impl Timestamp {
         Ok(Self(self.0 - nanos))
     }
 
+    /// Adds `delta` nanoseconds, returning `None` if the result would be past
+    /// `Timestamp::MAX`.
+    #[inline]
+    pub fn checked_add(&self, delta: u64) -> Option<Self> {
+        self.0
+            .checked_add(delta)
+            .filter(|nanos| *nanos <= Self::MAX.0)
+            .map(Self)
+    }
+
+    /// Subtracts `delta` nanoseconds, returning `None` if the result would be
+    /// before `Timestamp::MIN`.
+    #[inline]
+    pub fn checked_sub(&self, delta: u64) -> Option<Self> {
+        self.0.checked_sub(delta).map(Self)
+    }
+
+    /// Adds `delta` nanoseconds, clamping at `Timestamp::MAX`.
+    #[inline]
+    pub fn saturating_add(&self, delta: u64) -> Self {
+        self.checked_add(delta).unwrap_or(Self::MAX)
+    }
+
+    /// Subtracts `delta` nanoseconds, clamping at `Timestamp::MIN`.
+    #[inline]
+    pub fn saturating_sub(&self, delta: u64) -> Self {
+        self.checked_sub(delta).unwrap_or(Self::MIN)
+    }
+
     // This is similar to `self - base` but it works if `self` is before `base`.
     // Since Duration is always positive, `self - base` can overflow.
     pub fn secs_since_f64(self, base: Timestamp) -> f64 {
@@ -200,3 +229,27 @@ fn test_secs_since_f64_positive_zero() {
     // should be positive zero, not negative zero
     assert!(zero.total_cmp(&0.0).is_eq(), "{zero:?}");
 }
+
+#[test]
+fn test_checked_arithmetic() {
+    let max = u64::from(Timestamp::MAX);
+    assert_eq!(Timestamp::MAX.checked_add(0), Some(Timestamp::MAX));
+    assert_eq!(Timestamp::MAX.checked_add(1), None);
+    assert_eq!(Timestamp::MAX.checked_add(u64::MAX), None);
+    assert_eq!(Timestamp::MIN.checked_add(max), Some(Timestamp::MAX));
+    assert_eq!(Timestamp::MIN.checked_add(max + 1), None);
+    assert_eq!(Timestamp::must(5).checked_add(3), Some(Timestamp::must(8)));
+
+    assert_eq!(Timestamp::MIN.checked_sub(0), Some(Timestamp::MIN));
+    assert_eq!(Timestamp::MIN.checked_sub(1), None);
+    assert_eq!(Timestamp::MAX.checked_sub(max), Some(Timestamp::MIN));
+    assert_eq!(Timestamp::MAX.checked_sub(u64::MAX), None);
+    assert_eq!(Timestamp::must(5).checked_sub(3), Some(Timestamp::must(2)));
+
+    assert_eq!(Timestamp::MAX.saturating_add(1), Timestamp::MAX);
+    assert_eq!(Timestamp::must(1).saturating_add(u64::MAX), Timestamp::MAX);
+    assert_eq!(Timestamp::must(5).saturating_add(3), Timestamp::must(8));
+    assert_eq!(Timestamp::MIN.saturating_sub(1), Timestamp::MIN);
+    assert_eq!(Timestamp::must(1).saturating_sub(u64::MAX), Timestamp::MIN);
+    assert_eq!(Timestamp::must(5).saturating_sub(3), Timestamp::must(2));
+}
@@ -29,7 +29,10 @@ use postgres::{
     PostgresPersistence,
     PostgresReaderOptions,
 };
-use sqlite::SqlitePersistence;
+use sqlite::{
+    SqliteOptions,
+    SqlitePersistence,
+};
 use tokio_postgres::config::TargetSessionAttrs;
 
 #[derive(Copy, Clone, Debug)]
@@ -155,7 +158,11 @@ pub async fn connect_persistence<RT: Runtime>(
 ) -> anyhow::Result<Arc<dyn Persistence>> {
     match persistence_seed(db, db_spec, flags, instance_name, runtime)? {
         PersistenceSeed::Sqlite { db_spec, wal_mode } => {
-            let persistence = Arc::new(SqlitePersistence::new_with_options(&db_spec, wal_mode)?);
+            let options = SqliteOptions {
+                wal_mode,
+                ..Default::default()
+            };
+            let persistence = Arc::new(SqlitePersistence::new_with_options(&db_spec, options)?);
             tracing::info!("Connected to SQLite at {db_spec} (WAL mode: {wal_mode})");
             Ok(persistence as Arc<dyn Persistence>)
         },
@@ -211,7 +218,11 @@ pub async fn connect_persistence_reader<RT: Runtime>(
         runtime,
     )? {
         PersistenceSeed::Sqlite { db_spec, wal_mode } => {
-            Ok(Arc::new(SqlitePersistence::new_with_options(&db_spec, wal_mode)?) as Arc<dyn PersistenceReader>)
+            let options = SqliteOptions {
+                wal_mode,
+                ..Default::default()
+            };
+            Ok(Arc::new(SqlitePersistence::new_with_options(&db_spec, options)?) as Arc<dyn PersistenceReader>)
         },
         PersistenceSeed::Postgres { config, options } => {
             let options = PostgresReaderOptions {
@@ -389,6 +389,10 @@ impl<RT: Runtime> Persistence for MySqlPersistence<RT> {
         conflict_strategy: ConflictStrategy,
     ) -> anyhow::Result<()> {
         anyhow::ensure!(documents.len() <= sql::MAX_INSERT_SIZE);
+        anyhow::ensure!(
+            conflict_strategy != ConflictStrategy::Merge,
+            "ConflictStrategy::Merge is not supported by MySQL persistence"
+        );
         let mut write_size = 0;
         for update in documents {
             match &update.value {
@@ -420,7 +424,7 @@ impl<RT: Runtime> Persistence for MySqlPersistence<RT> {
                 for chunk in &mut document_chunks {
                     let chunk_bytes: usize = chunk.iter().map(|item| item.approx_size()).sum();
                     let insert_chunk_query = match conflict_strategy {
-                        ConflictStrategy::Error => {
+                        ConflictStrategy::Error | ConflictStrategy::Merge => {
                             sql::insert_document_chunk(chunk.len(), multitenant)
                         },
                         ConflictStrategy::Overwrite => {
@@ -472,7 +476,7 @@ impl<RT: Runtime> Persistence for MySqlPersistence<RT> {
                     let insert_overwrite_chunk_query =
                         sql::insert_overwrite_index_chunk(chunk.len(), multitenant);
                     let insert_index_chunk = match conflict_strategy {
-                        ConflictStrategy::Error => &insert_chunk_query,
+                        ConflictStrategy::Error | ConflictStrategy::Merge => &insert_chunk_query,
                         ConflictStrategy::Overwrite => &insert_overwrite_chunk_query,
                     };
                     let mut insert_index_chunk_params = Vec::with_capacity(
@@ -494,6 +494,10 @@ impl Persistence for PostgresPersistence {
         conflict_strategy: ConflictStrategy,
     ) -> anyhow::Result<()> {
         anyhow::ensure!(documents.len() <= MAX_INSERT_SIZE);
+        anyhow::ensure!(
+            conflict_strategy != ConflictStrategy::Merge,
+            "ConflictStrategy::Merge is not supported by Postgres persistence"
+        );
         let mut write_size = 0;
         for update in documents {
             match &update.value {
@@ -521,13 +525,13 @@ impl Persistence for PostgresPersistence {
             .transact(async move |tx| {
                 let (insert_documents, insert_indexes) = try_join!(
                     match conflict_strategy {
-                        ConflictStrategy::Error =>
+                        ConflictStrategy::Error | ConflictStrategy::Merge =>
                             tx.prepare_cached(sql::insert_document(multitenant)),
                         ConflictStrategy::Overwrite =>
                             tx.prepare_cached(sql::insert_overwrite_document(multitenant)),
                     },
                     match conflict_strategy {
-                        ConflictStrategy::Error =>
+                        ConflictStrategy::Error | ConflictStrategy::Merge =>
                             tx.prepare_cached(sql::insert_index(multitenant)),
                         ConflictStrategy::Overwrite =>
                             tx.prepare_cached(sql::insert_overwrite_index(multitenant)),
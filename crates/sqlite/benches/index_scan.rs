@@ -0,0 +1,108 @@
+// Run with: `cargo bench -p sqlite --bench index_scan`
+
+use std::sync::Arc;
+
+use common::{
+    bootstrap_model::index::INDEX_TABLE,
+    index::IndexKeyBytes,
+    interval::Interval,
+    persistence::{
+        ConflictStrategy,
+        NoopRetentionValidator,
+        Persistence,
+        PersistenceIndexEntry,
+    },
+    query::Order,
+    testing::{
+        persistence_test_suite::doc,
+        TestIdGenerator,
+    },
+    types::{
+        TableName,
+        Timestamp,
+    },
+};
+use criterion::{
+    criterion_group,
+    criterion_main,
+    BenchmarkId,
+    Criterion,
+};
+use futures::TryStreamExt;
+use sqlite::{
+    SqliteOptions,
+    SqlitePersistence,
+};
+use tempfile::TempDir;
+use tokio::runtime::Runtime;
+
+const NUM_ENTRIES: u32 = 10_000;
+
+/// Scans an index of `NUM_ENTRIES` entries, which takes
+/// `NUM_ENTRIES / chunk_size` queries.
+fn bench_index_scan(c: &mut Criterion) {
+    let rt = Runtime::new().expect("Failed to create Tokio runtime");
+    let db = TempDir::new().unwrap();
+    let path = db.path().join("bench.sqlite3");
+    let path = path.to_str().unwrap();
+
+    let mut id_generator = TestIdGenerator::new();
+    let index_id = id_generator.system_generate(&INDEX_TABLE).internal_id();
+    let table: TableName = str::parse("table").unwrap();
+    let tablet_id = id_generator.user_table_id(&table).tablet_id;
+    let mut documents = vec![];
+    let mut indexes = vec![];
+    for i in 0..NUM_ENTRIES {
+        let entry = doc(id_generator.user_generate(&table), 1, Some(i as i64), None).unwrap();
+        indexes.push(PersistenceIndexEntry {
+            ts: entry.ts,
+            index_id,
+            key: IndexKeyBytes(i.to_be_bytes().to_vec()),
+            value: Some(entry.id),
+        });
+        documents.push(entry);
+    }
+    rt.block_on(SqlitePersistence::new(path).unwrap().write(
+        &documents,
+        &indexes,
+        ConflictStrategy::Error,
+    ))
+    .unwrap();
+
+    let mut group = c.benchmark_group("index_scan");
+    group.throughput(criterion::Throughput::Elements(NUM_ENTRIES as u64));
+    group.sample_size(10);
+    for chunk_size in [1, 16, 256, NUM_ENTRIES as usize] {
+        let p = SqlitePersistence::new_with_options(
+            path,
+            SqliteOptions {
+                index_scan_chunk_size: Some(chunk_size),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        group.bench_with_input(BenchmarkId::from_parameter(chunk_size), &p, |b, p| {
+            b.to_async(&rt).iter(|| async {
+                let entries = p
+                    .reader()
+                    .index_scan(
+                        index_id,
+                        tablet_id,
+                        Timestamp::must(1),
+                        &Interval::all(),
+                        Order::Asc,
+                        100,
+                        Arc::new(NoopRetentionValidator),
+                    )
+                    .try_collect::<Vec<_>>()
+                    .await
+                    .unwrap();
+                assert_eq!(entries.len(), NUM_ENTRIES as usize);
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_index_scan);
+criterion_main!(benches);
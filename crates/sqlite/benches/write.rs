@@ -0,0 +1,76 @@
+// Run with: `cargo bench -p sqlite --bench write`
+
+use common::{
+    persistence::{
+        ConflictStrategy,
+        Persistence,
+    },
+    testing::{
+        persistence_test_suite::doc,
+        TestIdGenerator,
+    },
+    types::TableName,
+};
+use criterion::{
+    criterion_group,
+    criterion_main,
+    BenchmarkId,
+    Criterion,
+};
+use sqlite::{
+    SqliteOptions,
+    SqlitePersistence,
+};
+use tempfile::TempDir;
+use tokio::runtime::Runtime;
+
+const NUM_WRITES: i32 = 10_000;
+
+/// Writes `NUM_WRITES` single-document batches, so statement preparation
+/// dominates over the cost of the inserts themselves.
+fn bench_small_writes(c: &mut Criterion) {
+    let rt = Runtime::new().expect("Failed to create Tokio runtime");
+    let mut group = c.benchmark_group("small_writes");
+    group.throughput(criterion::Throughput::Elements(NUM_WRITES as u64));
+    group.sample_size(10);
+
+    // A zero-capacity cache re-prepares every statement, as if uncached.
+    for (name, statement_cache_capacity) in [("cached", None), ("uncached", Some(0))] {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(name),
+            &statement_cache_capacity,
+            |b, &statement_cache_capacity| {
+                b.to_async(&rt).iter(|| async {
+                    let db = TempDir::new().unwrap();
+                    let p = SqlitePersistence::new_with_options(
+                        db.path().join("bench.sqlite3").to_str().unwrap(),
+                        SqliteOptions {
+                            wal_mode: true,
+                            statement_cache_capacity,
+                            ..Default::default()
+                        },
+                    )
+                    .unwrap();
+                    let mut id_generator = TestIdGenerator::new();
+                    let table: TableName = str::parse("table").unwrap();
+                    for ts in 0..NUM_WRITES {
+                        let entry = doc(
+                            id_generator.user_generate(&table),
+                            ts,
+                            Some(ts as i64),
+                            None,
+                        )
+                        .unwrap();
+                        p.write(&[entry], &[], ConflictStrategy::Error)
+                            .await
+                            .unwrap();
+                    }
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_small_writes);
+criterion_main!(benches);
@@ -0,0 +1,102 @@
+//! Custom ordering for string index keys.
+//!
+//! Index keys are stored as blobs, which Sqlite always compares bytewise.
+//! When a collation is configured, `index_scan` additionally orders keys whose
+//! first field is a string by passing that string to the collation. Keys of
+//! other types, and keys the collation considers equal, keep their bytewise
+//! order.
+
+use std::{
+    cmp::Ordering,
+    fmt,
+    panic::AssertUnwindSafe,
+    sync::Arc,
+};
+
+use rusqlite::Connection;
+
+/// Type tag that starts the sort key of a string. See `value::sorting`.
+const STRING_TAG: u8 = 0x10;
+
+/// Terminates the sort key of a string.
+const TERMINATOR: char = '\0';
+
+/// A named comparison function for strings, registered on every connection
+/// with `Connection::create_collation`.
+#[derive(Clone)]
+pub struct Collation {
+    name: String,
+    compare: Arc<dyn Fn(&str, &str) -> Ordering + Send + Sync>,
+}
+
+impl Collation {
+    /// `name` must be a valid SQL identifier, since it's interpolated into
+    /// queries.
+    pub fn new(
+        name: impl Into<String>,
+        compare: impl Fn(&str, &str) -> Ordering + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            compare: Arc::new(compare),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            !self.name.is_empty()
+                && !self.name.starts_with(|c: char| c.is_ascii_digit())
+                && self
+                    .name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_'),
+            "Invalid collation name {:?}",
+            self.name
+        );
+        Ok(())
+    }
+
+    pub(crate) fn register(&self, connection: &Connection) -> anyhow::Result<()> {
+        // rusqlite catches panics in the comparison, and there's no state
+        // here that one could leave inconsistent. Calling through `Deref`
+        // makes the closure capture the wrapper rather than its field.
+        let compare = AssertUnwindSafe(self.compare.clone());
+        connection.create_collation(&self.name, move |a, b| {
+            (*compare)(first_string(a), first_string(b))
+        })?;
+        Ok(())
+    }
+
+    /// The `ORDER BY` terms for `key`, ahead of the bytewise tiebreak.
+    pub(crate) fn order_by(&self, key: &str, order: &str) -> String {
+        let name = &self.name;
+        // Ordering by the type tag first keeps keys of different types in
+        // their usual order, and only string keys are cast to text.
+        format!(
+            "substr({key}, 1, 1) {order}, CASE WHEN substr({key}, 1, 1) = x'{STRING_TAG:02x}' \
+             THEN CAST({key} AS TEXT) END COLLATE {name} {order}, "
+        )
+    }
+}
+
+impl fmt::Debug for Collation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Collation")
+            .field("name", &self.name)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Strips the leading type tag and anything after the string's terminator
+/// from a key that was cast to text.
+fn first_string(key: &str) -> &str {
+    let key = key.strip_prefix(char::from(STRING_TAG)).unwrap_or(key);
+    match key.find(TERMINATOR) {
+        Some(end) => &key[..end],
+        None => key,
+    }
+}
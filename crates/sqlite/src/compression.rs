@@ -0,0 +1,104 @@
+//! Optional compression of stored document values.
+//!
+//! Uncompressed values are stored as TEXT, as they always have been.
+//! Compressed values are stored as a BLOB whose first byte identifies the
+//! codec, so each row can be read back regardless of the setting it was
+//! written with.
+
+use rusqlite::{
+    types::{
+        FromSql,
+        FromSqlError,
+        FromSqlResult,
+        ToSqlOutput,
+        ValueRef,
+    },
+    ToSql,
+};
+
+/// How to compress serialized document values before storing them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    /// Zstandard at the given level, from 1 (fastest) to 22 (smallest).
+    Zstd {
+        level: i32,
+    },
+    Lz4,
+}
+
+const ZSTD_CODEC: u8 = 1;
+const LZ4_CODEC: u8 = 2;
+
+impl Compression {
+    pub(crate) fn encode<'a>(&self, json_value: &'a str) -> anyhow::Result<StoredValue<'a>> {
+        let (codec, compressed) = match *self {
+            Compression::None => return Ok(StoredValue::Text(json_value)),
+            Compression::Zstd { level } => {
+                (ZSTD_CODEC, zstd::encode_all(json_value.as_bytes(), level)?)
+            },
+            Compression::Lz4 => (
+                LZ4_CODEC,
+                lz4_flex::compress_prepend_size(json_value.as_bytes()),
+            ),
+        };
+        let mut blob = Vec::with_capacity(1 + compressed.len());
+        blob.push(codec);
+        blob.extend_from_slice(&compressed);
+        Ok(StoredValue::Blob(blob))
+    }
+}
+
+/// A document value as written to the `json_value` column.
+#[derive(Debug)]
+pub(crate) enum StoredValue<'a> {
+    Text(&'a str),
+    Blob(Vec<u8>),
+}
+
+impl StoredValue<'_> {
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            StoredValue::Text(text) => text.len(),
+            StoredValue::Blob(blob) => blob.len(),
+        }
+    }
+}
+
+impl ToSql for StoredValue<'_> {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(match self {
+            StoredValue::Text(text) => ToSqlOutput::Borrowed(ValueRef::Text(text.as_bytes())),
+            StoredValue::Blob(blob) => ToSqlOutput::Borrowed(ValueRef::Blob(blob)),
+        })
+    }
+}
+
+/// The serialized JSON read back from the `json_value` column, decompressed if
+/// necessary.
+pub(crate) struct StoredJson(pub(crate) String);
+
+impl FromSql for StoredJson {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value {
+            ValueRef::Text(_) => String::column_result(value).map(StoredJson),
+            ValueRef::Blob(blob) => decode(blob)
+                .map(StoredJson)
+                .map_err(|e| FromSqlError::Other(e.into())),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
+fn decode(blob: &[u8]) -> anyhow::Result<String> {
+    let Some((&codec, compressed)) = blob.split_first() else {
+        anyhow::bail!("Empty compressed document value");
+    };
+    let decompressed = match codec {
+        ZSTD_CODEC => zstd::decode_all(compressed)?,
+        LZ4_CODEC => lz4_flex::decompress_size_prepended(compressed)?,
+        _ => anyhow::bail!("Unknown compression codec {codec}"),
+    };
+    Ok(String::from_utf8(decompressed)?)
+}
@@ -0,0 +1,69 @@
+//! Errors specific to Sqlite persistence that callers may want to handle.
+//!
+//! Methods still return `anyhow::Error`; these are attached to it and can be
+//! recovered with `downcast_ref::<PersistenceError>()`. When one is attached
+//! to an underlying Sqlite error, that error remains available with
+//! `downcast_ref::<rusqlite::Error>()`.
+
+use common::persistence::Conflict;
+use rusqlite::ErrorCode;
+
+#[derive(thiserror::Error, Clone, Debug)]
+pub enum PersistenceError {
+    #[error("Database {0} could not be opened with the provided encryption key")]
+    InvalidEncryptionKey(String),
+    #[error("Timed out waiting to write")]
+    Timeout,
+    /// A write conflicted with a row that already exists, e.g. a document
+    /// revision written twice with `ConflictStrategy::Error`. Holds the
+    /// conflicting row when it's known, which it is for `write`.
+    #[error("Write conflicts with existing data")]
+    Conflict(Option<Conflict>),
+    /// A `WriterHandle`'s queue had no room for another write.
+    #[error("Write queue is full")]
+    QueueFull,
+    /// The database was locked by another connection, even after retrying.
+    #[error("Database is locked by another connection")]
+    Busy,
+    #[error("Database is corrupt: {0}")]
+    Corrupt(String),
+    #[error("Database {0} does not exist")]
+    NotFound(String),
+    #[error("No Sqlite VFS named {0:?} is registered")]
+    UnknownVfs(String),
+    #[error("I/O error accessing the database")]
+    Io,
+    /// The database was written by a newer version of this crate.
+    #[error(
+        "Database has schema version {0}, but at most {max} is supported",
+        max = crate::SCHEMA_VERSION
+    )]
+    UnsupportedSchemaVersion(u32),
+    /// An index scan read more entries than
+    /// `SqliteOptions::index_scan_max_rows_examined` allows.
+    #[error("Index scan examined more than {0} rows")]
+    BudgetExceeded(usize),
+}
+
+/// Attaches the `PersistenceError` matching a failed Sqlite call, if any.
+pub(crate) fn classify(e: anyhow::Error) -> anyhow::Error {
+    if e.downcast_ref::<PersistenceError>().is_some() {
+        return e;
+    }
+    let Some(code) = e
+        .downcast_ref::<rusqlite::Error>()
+        .and_then(|e| e.sqlite_error_code())
+    else {
+        return e;
+    };
+    let error = match code {
+        ErrorCode::ConstraintViolation => PersistenceError::Conflict(None),
+        ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked => PersistenceError::Busy,
+        ErrorCode::DatabaseCorrupt | ErrorCode::NotADatabase => {
+            PersistenceError::Corrupt(e.to_string())
+        },
+        ErrorCode::SystemIoFailure | ErrorCode::DiskFull => PersistenceError::Io,
+        _ => return e,
+    };
+    e.context(error)
+}
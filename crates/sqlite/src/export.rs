@@ -0,0 +1,126 @@
+//! Exporting the log of changes since a timestamp, so that another
+//! persistence can follow this one by applying them with `write`.
+
+use std::{
+    collections::BTreeMap,
+    sync::Arc,
+};
+
+use common::{
+    index::IndexKeyBytes,
+    knobs::DEFAULT_DOCUMENTS_PAGE_SIZE,
+    persistence::{
+        DocumentLogEntry,
+        NoopRetentionValidator,
+        PersistenceIndexEntry,
+        PersistenceReader,
+        TimestampRange,
+    },
+    query::Order,
+    types::Timestamp,
+    value::{
+        InternalDocumentId,
+        InternalId,
+        TabletId,
+    },
+};
+use futures::{
+    stream::BoxStream,
+    StreamExt,
+    TryStreamExt,
+};
+use futures_async_stream::try_stream;
+use rusqlite::params;
+
+use crate::SqlitePersistence;
+
+/// The document and index entries written at a single timestamp, in the
+/// form `Persistence::write` takes them.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ExportBatch {
+    pub documents: Vec<DocumentLogEntry>,
+    pub indexes: Vec<PersistenceIndexEntry>,
+}
+
+impl SqlitePersistence {
+    /// Yields every entry written after `ts`, one batch per timestamp in
+    /// ascending order. Writing each batch to a follower that has the
+    /// entries up to `ts` brings it up to date with this persistence.
+    pub fn export_since(&self, ts: Timestamp) -> BoxStream<'_, anyhow::Result<ExportBatch>> {
+        self.export_batches(TimestampRange::greater_than(ts))
+            .boxed()
+    }
+
+    #[allow(clippy::needless_lifetimes)]
+    #[try_stream(ok = ExportBatch, error = anyhow::Error)]
+    async fn export_batches(&self, range: TimestampRange) {
+        let mut batches: BTreeMap<Timestamp, ExportBatch> = BTreeMap::new();
+        for entry in self.load_index_entries(range)? {
+            batches.entry(entry.ts).or_default().indexes.push(entry);
+        }
+        let mut documents = self.load_documents(
+            range,
+            Order::Asc,
+            *DEFAULT_DOCUMENTS_PAGE_SIZE,
+            Arc::new(NoopRetentionValidator),
+        );
+        while let Some(entry) = documents.try_next().await? {
+            batches.entry(entry.ts).or_default().documents.push(entry);
+        }
+        for batch in batches.into_values() {
+            yield batch;
+        }
+    }
+
+    fn load_index_entries(
+        &self,
+        range: TimestampRange,
+    ) -> anyhow::Result<Vec<PersistenceIndexEntry>> {
+        self.with_read_connection(|connection| {
+            let mut stmt = connection.prepare_cached(&self.sql(LOAD_INDEX_ENTRIES))?;
+            let params = params![
+                &u64::from(range.min_timestamp_inclusive()),
+                &u64::from(range.max_timestamp_exclusive()),
+            ];
+            let rows = stmt.query_map(params, |row| {
+                Ok((
+                    row.get::<_, Vec<u8>>(0)?,
+                    row.get::<_, u64>(1)?,
+                    row.get::<_, Vec<u8>>(2)?,
+                    row.get::<_, u32>(3)? != 0,
+                    row.get::<_, Option<Vec<u8>>>(4)?,
+                    row.get::<_, Option<Vec<u8>>>(5)?,
+                ))
+            })?;
+            let mut entries = vec![];
+            for row in rows {
+                let (index_id, ts, key, deleted, table_id, document_id) = row?;
+                let value = if deleted {
+                    None
+                } else {
+                    let (table_id, document_id) = table_id.zip(document_id).ok_or_else(|| {
+                        anyhow::anyhow!("Missing document for index entry at {ts} {key:?}")
+                    })?;
+                    Some(InternalDocumentId::new(
+                        TabletId(table_id.try_into()?),
+                        InternalId::try_from(document_id)?,
+                    ))
+                };
+                entries.push(PersistenceIndexEntry {
+                    ts: Timestamp::try_from(ts)?,
+                    index_id: InternalId::try_from(index_id)?,
+                    key: IndexKeyBytes(key),
+                    value,
+                });
+            }
+            Ok(entries)
+        })
+    }
+}
+
+const LOAD_INDEX_ENTRIES: &str = r#"
+SELECT index_id, ts, key, deleted, table_id, document_id
+FROM indexes
+WHERE ts >= $1 AND ts < $2
+ORDER BY ts ASC, index_id ASC, key ASC
+"#;
@@ -0,0 +1,230 @@
+//! A `Persistence` wrapper that injects faults into writes, for testing how
+//! callers handle errors and slow disks without having to provoke them.
+
+use std::{
+    sync::Arc,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use common::{
+    document::ResolvedDocument,
+    index::IndexEntry,
+    persistence::{
+        Conflict,
+        ConflictStrategy,
+        DocumentLogEntry,
+        Persistence,
+        PersistenceGlobalKey,
+        PersistenceIndexEntry,
+        PersistenceReader,
+        PersistenceTransaction,
+        TimestampRange,
+        WriteOutcome,
+        WriteReceipt,
+    },
+    types::{
+        IndexId,
+        Timestamp,
+    },
+    value::{
+        InternalDocumentId,
+        TabletId,
+    },
+};
+use parking_lot::Mutex;
+use serde_json::Value as JsonValue;
+
+use crate::PersistenceError;
+
+/// Forwards everything to `inner`, except that writes can be made to fail or
+/// to wait first. Only `write`, `write_with_receipt`, `write_partial` and
+/// `write_persistence_global` are affected; a write that fails doesn't reach
+/// `inner` at all. Imports go through `write`, so they're affected too.
+pub struct FaultyPersistence {
+    inner: Arc<dyn Persistence>,
+    faults: Mutex<Faults>,
+}
+
+#[derive(Default)]
+struct Faults {
+    fail_next_write: bool,
+    fail_with: Option<PersistenceError>,
+    write_delay: Option<Duration>,
+}
+
+impl FaultyPersistence {
+    pub fn new(inner: Arc<dyn Persistence>) -> Self {
+        Self {
+            inner,
+            faults: Mutex::new(Faults::default()),
+        }
+    }
+
+    pub fn inner(&self) -> &Arc<dyn Persistence> {
+        &self.inner
+    }
+
+    /// Fails the next write with `PersistenceError::Io`.
+    pub fn fail_next_write(&self) {
+        self.faults.lock().fail_next_write = true;
+    }
+
+    /// Fails every write with `error` until `clear_faults` is called.
+    pub fn fail_with(&self, error: PersistenceError) {
+        self.faults.lock().fail_with = Some(error);
+    }
+
+    /// Waits `delay` before each write, including ones that then fail.
+    pub fn delay_writes(&self, delay: Duration) {
+        self.faults.lock().write_delay = Some(delay);
+    }
+
+    /// Removes every injected fault.
+    pub fn clear_faults(&self) {
+        *self.faults.lock() = Faults::default();
+    }
+
+    async fn inject(&self) -> anyhow::Result<()> {
+        let delay = self.faults.lock().write_delay;
+        if let Some(delay) = delay {
+            tokio::time::sleep(delay).await;
+        }
+        let mut faults = self.faults.lock();
+        if let Some(error) = &faults.fail_with {
+            anyhow::bail!(error.clone());
+        }
+        if faults.fail_next_write {
+            faults.fail_next_write = false;
+            anyhow::bail!(PersistenceError::Io);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Persistence for FaultyPersistence {
+    fn is_fresh(&self) -> bool {
+        self.inner.is_fresh()
+    }
+
+    fn reader(&self) -> Arc<dyn PersistenceReader> {
+        self.inner.reader()
+    }
+
+    async fn write<'a>(
+        &self,
+        documents: &'a [DocumentLogEntry],
+        indexes: &'a [PersistenceIndexEntry],
+        conflict_strategy: ConflictStrategy,
+    ) -> anyhow::Result<()> {
+        self.inject().await?;
+        self.inner
+            .write(documents, indexes, conflict_strategy)
+            .await
+    }
+
+    async fn write_with_receipt<'a>(
+        &self,
+        documents: &'a [DocumentLogEntry],
+        indexes: &'a [PersistenceIndexEntry],
+        conflict_strategy: ConflictStrategy,
+    ) -> anyhow::Result<WriteReceipt> {
+        self.inject().await?;
+        self.inner
+            .write_with_receipt(documents, indexes, conflict_strategy)
+            .await
+    }
+
+    async fn check_write(
+        &self,
+        documents: &[DocumentLogEntry],
+        indexes: &[PersistenceIndexEntry],
+    ) -> anyhow::Result<Vec<Conflict>> {
+        self.inner.check_write(documents, indexes).await
+    }
+
+    async fn write_partial(
+        &self,
+        documents: &[DocumentLogEntry],
+        indexes: &[PersistenceIndexEntry],
+    ) -> anyhow::Result<WriteOutcome> {
+        self.inject().await?;
+        self.inner.write_partial(documents, indexes).await
+    }
+
+    async fn write_persistence_global(
+        &self,
+        key: PersistenceGlobalKey,
+        value: JsonValue,
+    ) -> anyhow::Result<()> {
+        self.inject().await?;
+        self.inner.write_persistence_global(key, value).await
+    }
+
+    async fn load_index_chunk(
+        &self,
+        cursor: Option<IndexEntry>,
+        chunk_size: usize,
+    ) -> anyhow::Result<Vec<IndexEntry>> {
+        self.inner.load_index_chunk(cursor, chunk_size).await
+    }
+
+    async fn delete_index_entries(&self, entries: Vec<IndexEntry>) -> anyhow::Result<usize> {
+        self.inner.delete_index_entries(entries).await
+    }
+
+    async fn delete(
+        &self,
+        documents: Vec<(Timestamp, InternalDocumentId)>,
+    ) -> anyhow::Result<usize> {
+        self.inner.delete(documents).await
+    }
+
+    async fn delete_tablet_documents(
+        &self,
+        tablet_id: TabletId,
+        chunk_size: usize,
+    ) -> anyhow::Result<usize> {
+        self.inner
+            .delete_tablet_documents(tablet_id, chunk_size)
+            .await
+    }
+
+    async fn delete_range(
+        &self,
+        range: TimestampRange,
+        retain_latest: bool,
+    ) -> anyhow::Result<u64> {
+        self.inner.delete_range(range, retain_latest).await
+    }
+
+    async fn truncate(&self) -> anyhow::Result<()> {
+        self.inner.truncate().await
+    }
+
+    async fn rebuild_index(
+        &self,
+        index_id: IndexId,
+        tablet_id: TabletId,
+        key_fn: &(dyn Fn(&ResolvedDocument) -> Vec<u8> + Send + Sync),
+    ) -> anyhow::Result<u64> {
+        self.inner.rebuild_index(index_id, tablet_id, key_fn).await
+    }
+
+    async fn begin(&self) -> anyhow::Result<Box<dyn PersistenceTransaction>> {
+        self.inner.begin().await
+    }
+
+    async fn fsync_barrier(&self) -> anyhow::Result<()> {
+        self.inner.fsync_barrier().await
+    }
+
+    async fn shutdown(&self) -> anyhow::Result<()> {
+        self.inner.shutdown().await
+    }
+
+    async fn finish_loading(&self) -> anyhow::Result<()> {
+        self.inner.finish_loading().await
+    }
+}
@@ -0,0 +1,73 @@
+//! A cheap liveness and readiness probe.
+
+use rusqlite::Connection;
+
+use crate::{
+    maintenance,
+    SqlitePersistence,
+};
+
+/// A WAL this large means checkpoints haven't been keeping up with writes,
+/// e.g. because a reader has been holding a snapshot open. That's about 4GB
+/// with the default page size.
+pub const MAX_HEALTHY_WAL_PAGES: u64 = 1_000_000;
+
+/// Result of `SqlitePersistence::health`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HealthStatus {
+    pub ok: bool,
+    /// Number of frames in the WAL file, or `None` if it couldn't be read
+    /// without waiting for a write in progress.
+    pub wal_pages: Option<u64>,
+    /// Why the persistence is unhealthy, if it is.
+    pub last_error: Option<String>,
+}
+
+impl SqlitePersistence {
+    /// Checks that the database can be queried and that the WAL isn't
+    /// pathologically large.
+    ///
+    /// This never waits for a write in progress: the write connection is
+    /// only checked if it's idle, since a running write shows it's usable.
+    /// Without a read pool, reads share the write connection, so the WAL
+    /// isn't checked during a write either.
+    pub fn health(&self) -> HealthStatus {
+        match self.check_health() {
+            Ok(Some(wal_pages)) if wal_pages > MAX_HEALTHY_WAL_PAGES => HealthStatus {
+                ok: false,
+                wal_pages: Some(wal_pages),
+                last_error: Some(format!(
+                    "WAL has {wal_pages} pages, more than {MAX_HEALTHY_WAL_PAGES}"
+                )),
+            },
+            Ok(wal_pages) => HealthStatus {
+                ok: true,
+                wal_pages,
+                last_error: None,
+            },
+            Err(e) => HealthStatus {
+                ok: false,
+                wal_pages: None,
+                last_error: Some(format!("{e:#}")),
+            },
+        }
+    }
+
+    fn check_health(&self) -> anyhow::Result<Option<u64>> {
+        let mut wal_pages = None;
+        if let Some(inner) = self.inner.try_lock() {
+            wal_pages = Some(probe(&inner.connection)?);
+        }
+        if let Some(read_pool) = &self.read_pool {
+            wal_pages = Some(probe(&read_pool.checkout()?)?);
+        }
+        Ok(wal_pages)
+    }
+}
+
+/// Runs a trivial query on `connection`, returning the number of WAL pages.
+fn probe(connection: &Connection) -> anyhow::Result<u64> {
+    connection.query_row("SELECT 1", [], |_| Ok(()))?;
+    let page_size: u64 = connection.pragma_query_value(None, "page_size", |row| row.get(0))?;
+    Ok(maintenance::wal_pages(connection, page_size))
+}
@@ -1,19 +1,45 @@
 #![feature(try_blocks)]
 #![feature(coroutines)]
+mod collation;
+mod compression;
+mod error;
+mod export;
+mod faulty;
+mod health;
+mod maintenance;
+mod metrics;
+mod migrations;
+mod pool;
+mod transaction;
+mod write_queue;
 
 use std::{
+    borrow::Cow,
     cmp,
     collections::{
         BTreeMap,
         BTreeSet,
     },
-    path::Path,
-    sync::Arc,
+    ffi::CString,
+    fmt,
+    path::{
+        Path,
+        PathBuf,
+    },
+    sync::{
+        atomic::AtomicBool,
+        Arc,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
 };
 
 use anyhow::Context as _;
 use async_trait::async_trait;
 use common::{
+    backoff::Backoff,
     document::{
         InternalId,
         ResolvedDocument,
@@ -28,7 +54,10 @@ use common::{
         StartIncluded,
     },
     persistence::{
+        CompareOp,
+        Conflict,
         ConflictStrategy,
+        DocumentIdStream,
         DocumentLogEntry,
         DocumentPrevTsQuery,
         DocumentStream,
@@ -38,8 +67,13 @@ use common::{
         PersistenceGlobalKey,
         PersistenceIndexEntry,
         PersistenceReader,
+        PersistenceTransaction,
         RetentionValidator,
+        SnapshotReader,
         TimestampRange,
+        TimestampStream,
+        WriteOutcome,
+        WriteReceipt,
     },
     query::Order,
     runtime::CoopStreamExt as _,
@@ -55,62 +89,934 @@ use common::{
     },
 };
 use futures::{
+    future,
     stream,
     StreamExt,
+    TryStreamExt,
 };
 use futures_async_stream::try_stream;
 use parking_lot::Mutex;
 use rusqlite::{
     params,
-    types::Null,
+    params_from_iter,
+    types::Value as SqlValue,
     Connection,
+    ErrorCode,
+    OpenFlags,
+    OptionalExtension as _,
     Row,
     ToSql,
 };
 use serde::Deserialize as _;
 use serde_json::Value as JsonValue;
 
-// We only have a single Sqlite connection which does not allow async calls, so
-// we can't really make queries concurrent.
+pub use crate::{
+    collation::Collation,
+    compression::Compression,
+    error::PersistenceError,
+    export::ExportBatch,
+    faulty::FaultyPersistence,
+    health::{
+        HealthStatus,
+        MAX_HEALTHY_WAL_PAGES,
+    },
+    maintenance::{
+        AutoVacuum,
+        CheckpointHandle,
+        CheckpointMode,
+        CheckpointResult,
+        DatabaseStats,
+        IntegrityCheck,
+        IntegrityReport,
+        Synchronous,
+        TempStore,
+    },
+    metrics::MetricsRecorder,
+    migrations::SCHEMA_VERSION,
+    pool::ReadPoolStats,
+    write_queue::{
+        PendingWrite,
+        WriterHandle,
+    },
+};
+use crate::{
+    compression::StoredJson,
+    pool::{
+        PooledConnection,
+        ReadPool,
+    },
+    transaction::SqliteTransaction,
+};
+
+// Writes go through a single Sqlite connection which does not allow async
+// calls, so we can't really make them concurrent. Reads can optionally be
+// served from a pool of separate connections, which lets them proceed in
+// parallel with each other and (in WAL mode) with writes. Either way, a read
+// sees every write that returned before it started.
 pub struct SqlitePersistence {
     inner: Arc<Mutex<Inner>>,
+    /// Held by each write, and by a `SqliteTransaction` until it ends, so
+    /// writes wait for open transactions without blocking their thread.
+    /// `inner` itself is only locked for as long as a statement runs.
+    writer: Arc<tokio::sync::Mutex<()>>,
+    read_pool: Option<Arc<ReadPool>>,
+    compacting: Arc<AtomicBool>,
+    write_retries: u32,
+    write_retry_delay: Duration,
+    checkpoint_threshold_pages: Option<u64>,
+    busy_timeout: Duration,
+    metrics_recorder: Option<Arc<dyn MetricsRecorder>>,
+    compression: Compression,
+    validate_chain: bool,
+    max_rows_per_statement: usize,
+    index_scan_chunk_size: usize,
+    index_scan_max_rows_examined: Option<usize>,
+    index_key_collation: Option<Collation>,
+    documents_only: bool,
+    append_only: bool,
+    namespace: Option<String>,
+    /// Schema of an attached replica that every read goes to instead of the
+    /// main database. See `attach_reader`.
+    attached_schema: Option<String>,
+    /// A pooled connection holding open the read transaction that every read
+    /// goes to instead. See `snapshot_at`.
+    snapshot: Option<Arc<Mutex<PooledConnection>>>,
 }
 
 struct Inner {
     newly_created: bool,
     connection: Connection,
+    /// Estimated number of pages written since the last threshold-triggered
+    /// checkpoint.
+    pages_since_checkpoint: u64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SqliteOptions {
+    pub wal_mode: bool,
+    /// If set and `wal_mode` can't be enabled when opening the database,
+    /// e.g. because it's on a network filesystem that can't provide the
+    /// shared memory WAL needs, the database is opened with
+    /// `journal_mode=DELETE` instead, logging a warning and calling
+    /// `MetricsRecorder::record_wal_fallback`. Otherwise opening fails.
+    pub wal_fallback: bool,
+    /// Overrides how often the write connection syncs to disk. Defaults to
+    /// `Synchronous::Normal` in WAL mode and `Synchronous::Full` otherwise.
+    pub synchronous: Option<Synchronous>,
+    /// Number of dedicated read connections. If zero, reads share the write
+    /// connection. A pool is most useful in WAL mode, where readers don't
+    /// block the writer.
+    pub read_pool_size: usize,
+    /// Page size for a newly created database. Sqlite only applies this
+    /// before the first table is created, so opening an existing database
+    /// with a different page size is an error.
+    pub page_size: Option<u32>,
+    /// Auto-vacuum mode for a newly created database. Like `page_size`, this
+    /// can't be changed once the first table is created, so opening an
+    /// existing database with a different mode is an error. If unset, a new
+    /// database uses `AutoVacuum::None`.
+    pub auto_vacuum: Option<AutoVacuum>,
+    /// How long a connection waits for a lock held by another connection
+    /// before failing with `SQLITE_BUSY`. Applies to every connection,
+    /// including pooled readers. Defaults to rusqlite's 5 seconds.
+    pub busy_timeout: Option<Duration>,
+    /// Number of times `write` retries a transaction that failed with
+    /// `SQLITE_BUSY` or `SQLITE_LOCKED`.
+    pub write_retries: u32,
+    /// Delay before the first write retry. The delay doubles (with jitter)
+    /// after each failed attempt.
+    pub write_retry_delay: Duration,
+    /// Number of WAL pages after which Sqlite checkpoints automatically on
+    /// commit (`PRAGMA wal_autocheckpoint`). Zero disables automatic
+    /// checkpoints; `None` keeps Sqlite's default of 1000.
+    pub wal_autocheckpoint: Option<u32>,
+    /// Number of bytes the write connection truncates the WAL file back to
+    /// once a checkpoint has copied all of it into the database, so one
+    /// large write doesn't leave a large WAL behind (`PRAGMA
+    /// journal_size_limit`). The truncation happens when the next write
+    /// starts again from the beginning of the WAL, which needs a checkpoint
+    /// that no reader was in the way of. `None` keeps Sqlite's default of
+    /// never truncating.
+    pub journal_size_limit: Option<u64>,
+    /// If set, `write` runs a `PASSIVE` checkpoint once roughly this many
+    /// pages have been written since the last one. Page counts are estimated
+    /// from the size of the written rows.
+    pub checkpoint_threshold_pages: Option<u64>,
+    /// Number of prepared statements each connection keeps cached. Defaults
+    /// to enough for every statement issued by writes.
+    pub statement_cache_capacity: Option<usize>,
+    /// Number of bytes of the database file each connection memory-maps
+    /// (`PRAGMA mmap_size`), with zero disabling memory-mapped I/O. Applies
+    /// to every connection, including pooled readers. Mapped pages live in
+    /// the OS page cache, so they are shared between connections but also
+    /// compete with other processes for memory. `None` keeps Sqlite's
+    /// default.
+    pub mmap_size: Option<u64>,
+    /// Size of each connection's page cache (`PRAGMA cache_size`). A positive
+    /// value is a number of pages and a negative one a number of KiB, so
+    /// `-65536` is 64 MiB regardless of the page size. Applies to every
+    /// connection, including pooled readers, each of which has its own
+    /// cache. `None` keeps Sqlite's default of `-2000`, about 2 MB.
+    pub cache_size: Option<i64>,
+    /// Where Sqlite keeps temporary tables and indices, such as those used
+    /// by large sorts and `VACUUM` (`PRAGMA temp_store`). Applies to every
+    /// connection.
+    pub temp_store: TempStore,
+    /// Directory for temporary files when they are stored on disk. This is
+    /// `PRAGMA temp_store_directory`, which Sqlite keeps in a global
+    /// variable, so it applies to every connection in the process. `None`
+    /// keeps Sqlite's default, which honors `SQLITE_TMPDIR`.
+    pub temp_directory: Option<PathBuf>,
+    /// Name of a registered Sqlite VFS to open every connection through, e.g.
+    /// one for a network filesystem or for injecting faults in tests. `None`
+    /// uses Sqlite's default VFS.
+    pub vfs: Option<String>,
+    /// Called with the latency and size of each completed operation.
+    pub metrics_recorder: Option<Arc<dyn MetricsRecorder>>,
+    /// How thoroughly to check an existing database for corruption when
+    /// opening it. Defaults to `IntegrityCheck::Quick`.
+    pub integrity_check: IntegrityCheck,
+    /// How to compress document values on write. Values are decompressed
+    /// on read regardless of this setting, so it can be changed at any time.
+    pub compression: Compression,
+    /// If set, `write` checks that each document's `prev_ts` is the
+    /// timestamp of its previous revision (or `None` for its first revision),
+    /// and rejects the whole write otherwise.
+    pub validate_chain: bool,
+    /// Maximum number of rows `write` inserts with a single statement. Larger
+    /// writes are split across several statements in the same transaction,
+    /// so they still commit or fail as a whole. Each row binds 6 variables,
+    /// so this can be at most `MAX_ROWS_PER_STATEMENT`. Defaults to
+    /// `DEFAULT_MAX_ROWS_PER_STATEMENT`.
+    pub max_rows_per_statement: Option<usize>,
+    /// Number of entries `index_scan` fetches from Sqlite per query. Entries
+    /// are handed out one at a time from each chunk, and the next chunk is
+    /// fetched once the stream runs out. Defaults to
+    /// `DEFAULT_INDEX_SCAN_CHUNK_SIZE`. Scans are fetched in one chunk when
+    /// `index_key_collation` is set, since chunks resume after the last key
+    /// in bytewise order.
+    pub index_scan_chunk_size: Option<usize>,
+    /// If set, `index_scan` fails with `PersistenceError::BudgetExceeded`
+    /// once Sqlite has returned more than this many entries for a single
+    /// scan, after yielding the first ones. Chunks are shortened so that
+    /// Sqlite stops at the first entry over the budget, rather than reading
+    /// a whole chunk. This guards against runaway scans holding a
+    /// connection. Callers that stop reading a scan within the budget don't
+    /// see the error.
+    pub index_scan_max_rows_examined: Option<usize>,
+    /// If set, `index_scan` orders keys whose first field is a string by
+    /// comparing that string with this collation. The collation is registered
+    /// on every connection. Keys of other types, and keys the collation
+    /// considers equal, keep their bytewise order. Range bounds are still
+    /// compared bytewise, so this is mainly useful for scans over a whole
+    /// index or a prefix of it.
+    pub index_key_collation: Option<Collation>,
+    /// If set, index entries passed to `write`, `write_partial` and
+    /// `check_write` are ignored, `index_scan` and `index_scan_ids` return
+    /// nothing, and `rebuild_index` fails. The `indexes` table is still
+    /// created, just left empty, so the database can later be opened without
+    /// this option.
+    pub documents_only: bool,
+    /// If set, the document log can only grow: writes must use
+    /// `ConflictStrategy::Error`, so every revision is new, and can't contain
+    /// deletions, and `delete`, `delete_tablet_documents`, `delete_range` and
+    /// `truncate` fail. Index entries, including deletions of stale keys,
+    /// are written as usual.
+    pub append_only: bool,
+    /// Prefixes the names of the tables this crate creates, e.g.
+    /// `tenant_a_documents` for `tenant_a`, so that one file can hold several
+    /// datasets that don't see each other. Each namespace has its own schema
+    /// version and is migrated separately. Must be non-empty and consist of
+    /// ASCII letters, digits and underscores.
+    pub namespace: Option<String>,
+    /// Key for a SQLCipher-encrypted database. A new database is encrypted
+    /// with this key; an existing one must have been created with it.
+    #[cfg(feature = "sqlcipher")]
+    pub encryption_key: Option<EncryptionKey>,
+}
+
+/// Passphrase for a SQLCipher-encrypted database, redacted from `Debug`
+/// output.
+#[cfg(feature = "sqlcipher")]
+#[derive(Clone)]
+pub struct EncryptionKey(String);
+
+#[cfg(feature = "sqlcipher")]
+impl EncryptionKey {
+    pub fn new(key: impl Into<String>) -> Self {
+        Self(key.into())
+    }
+}
+
+#[cfg(feature = "sqlcipher")]
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "EncryptionKey(..)")
+    }
+}
+
+impl SqliteOptions {
+    /// Opens a read-write connection to `path` through the configured VFS.
+    fn open_connection(&self, path: &str) -> anyhow::Result<Connection> {
+        let Some(vfs) = &self.vfs else {
+            return Ok(Connection::open(path)?);
+        };
+        // Sqlite reports an unknown VFS as a generic error, so check first.
+        let name = CString::new(vfs.as_str())?;
+        // SAFETY: `name` is NUL-terminated and outlives the call, which only
+        // reads it.
+        let registered = !unsafe { rusqlite::ffi::sqlite3_vfs_find(name.as_ptr()) }.is_null();
+        anyhow::ensure!(registered, PersistenceError::UnknownVfs(vfs.clone()));
+        Ok(Connection::open_with_flags_and_vfs(
+            path,
+            OpenFlags::default(),
+            vfs,
+        )?)
+    }
+
+    /// Applies the settings that Sqlite tracks per connection rather than in
+    /// the database file.
+    #[cfg_attr(not(feature = "sqlcipher"), allow(unused_variables))]
+    fn configure_connection(&self, path: &str, connection: &Connection) -> anyhow::Result<()> {
+        // SQLCipher requires the key before any other statement touches the
+        // database.
+        #[cfg(feature = "sqlcipher")]
+        if let Some(EncryptionKey(key)) = &self.encryption_key {
+            connection.pragma_update(None, "key", key)?;
+            // The key isn't checked until the database is first read.
+            if let Err(e) =
+                connection.query_row("SELECT COUNT(*) FROM sqlite_master", [], |_| Ok(()))
+            {
+                if e.sqlite_error_code() == Some(ErrorCode::NotADatabase) {
+                    anyhow::bail!(PersistenceError::InvalidEncryptionKey(path.to_owned()));
+                }
+                return Err(e.into());
+            }
+        }
+        if let Some(busy_timeout) = self.busy_timeout {
+            connection.busy_timeout(busy_timeout)?;
+        }
+        connection.set_prepared_statement_cache_capacity(
+            self.statement_cache_capacity
+                .unwrap_or(DEFAULT_STATEMENT_CACHE_CAPACITY),
+        );
+        if let Some(wal_autocheckpoint) = self.wal_autocheckpoint {
+            connection.pragma_update(None, "wal_autocheckpoint", wal_autocheckpoint)?;
+        }
+        if let Some(mmap_size) = self.mmap_size {
+            connection.pragma_update(None, "mmap_size", mmap_size)?;
+        }
+        if let Some(cache_size) = self.cache_size {
+            connection.pragma_update(None, "cache_size", cache_size)?;
+        }
+        connection.pragma_update(None, "temp_store", self.temp_store.as_sql())?;
+        if let Some(temp_directory) = &self.temp_directory {
+            let temp_directory = temp_directory.to_str().ok_or_else(|| {
+                anyhow::anyhow!("Temp directory {temp_directory:?} isn't valid UTF-8")
+            })?;
+            connection.pragma_update(None, "temp_store_directory", temp_directory)?;
+        }
+        if let Some(collation) = &self.index_key_collation {
+            collation.register(connection)?;
+        }
+        Ok(())
+    }
 }
 
 impl SqlitePersistence {
     pub fn new(path: &str) -> anyhow::Result<Self> {
-        Self::new_with_options(path, false)
+        Self::new_with_options(path, SqliteOptions::default())
     }
 
-    pub fn new_with_options(path: &str, wal_mode: bool) -> anyhow::Result<Self> {
+    pub fn new_with_options(path: &str, options: SqliteOptions) -> anyhow::Result<Self> {
         let newly_created = !Path::new(path).exists();
-        let connection = Connection::open(path)?;
+        let connection = options.open_connection(path)?;
+        let mut persistence = Self::initialize(path, connection, newly_created, &options)?;
+        // Open the read pool only once the schema exists.
+        if options.read_pool_size > 0 {
+            persistence.read_pool = Some(Arc::new(ReadPool::open(options.read_pool_size, || {
+                let connection = options.open_connection(path)?;
+                options.configure_connection(path, &connection)?;
+                Ok(connection)
+            })?));
+        }
+        Ok(persistence)
+    }
+
+    /// Creates a persistence backed by a private in-memory database, which is
+    /// discarded when the persistence and all of its readers are dropped.
+    pub fn new_in_memory() -> anyhow::Result<Self> {
+        // Every connection to `:memory:` opens a separate, empty database, so
+        // readers must share the write connection rather than use a pool. WAL
+        // doesn't apply to in-memory databases either.
+        let connection = Connection::open_in_memory()?;
+        Self::initialize(":memory:", connection, true, &SqliteOptions::default())
+    }
+
+    fn initialize(
+        path: &str,
+        connection: Connection,
+        newly_created: bool,
+        options: &SqliteOptions,
+    ) -> anyhow::Result<Self> {
+        // The remaining options are applied per connection.
+        let &SqliteOptions {
+            wal_mode,
+            wal_fallback,
+            synchronous,
+            page_size,
+            auto_vacuum,
+            journal_size_limit,
+            integrity_check,
+            max_rows_per_statement,
+            index_scan_chunk_size,
+            ref metrics_recorder,
+            ..
+        } = options;
+        if let Some(max_rows_per_statement) = max_rows_per_statement {
+            anyhow::ensure!(
+                (1..=MAX_ROWS_PER_STATEMENT).contains(&max_rows_per_statement),
+                "max_rows_per_statement must be between 1 and {MAX_ROWS_PER_STATEMENT}, got \
+                 {max_rows_per_statement}"
+            );
+        }
+        anyhow::ensure!(
+            index_scan_chunk_size != Some(0),
+            "index_scan_chunk_size must be at least 1"
+        );
+        if let Some(collation) = &options.index_key_collation {
+            collation.validate()?;
+        }
+        if let Some(namespace) = &options.namespace {
+            anyhow::ensure!(
+                !namespace.is_empty()
+                    && namespace
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '_'),
+                "namespace must be non-empty ASCII letters, digits and underscores, got \
+                 {namespace:?}"
+            );
+        }
+        options.configure_connection(path, &connection)?;
+        if !newly_created {
+            maintenance::check_integrity_on_open(path, &connection, integrity_check)?;
+        }
+
+        // These must happen before anything (including enabling WAL) writes the
+        // database header.
+        let existing_tables: u32 =
+            connection.query_row("SELECT COUNT(*) FROM sqlite_master", [], |row| row.get(0))?;
+        if let Some(page_size) = page_size {
+            if existing_tables == 0 {
+                connection.pragma_update(None, "page_size", page_size)?;
+            } else {
+                let current: u32 =
+                    connection.pragma_query_value(None, "page_size", |row| row.get(0))?;
+                anyhow::ensure!(
+                    current == page_size,
+                    "Cannot change page_size of existing database {path} from {current} to \
+                     {page_size}"
+                );
+            }
+        }
+        if let Some(auto_vacuum) = auto_vacuum {
+            if existing_tables == 0 {
+                connection.pragma_update(None, "auto_vacuum", auto_vacuum.as_sql())?;
+            } else {
+                let current = maintenance::auto_vacuum(&connection)?;
+                anyhow::ensure!(
+                    current == auto_vacuum,
+                    "Cannot change auto_vacuum of existing database {path} from {current:?} to \
+                     {auto_vacuum:?}"
+                );
+            }
+        }
 
         // Enable WAL mode if requested
         if wal_mode {
-            connection.execute_batch("PRAGMA journal_mode=WAL;")?;
-            // Set synchronous to NORMAL for better performance with WAL
-            // (FULL is default but NORMAL is safe with WAL)
-            connection.execute_batch("PRAGMA synchronous=NORMAL;")?;
-            tracing::info!("SQLite WAL mode enabled for {}", path);
-        }
-
-        // Execute create tables unconditionally since they are idempotent.
-        connection.execute_batch(DOCUMENTS_INIT)?;
-        connection.execute_batch(INDEXES_INIT)?;
-        connection.execute_batch(PERSISTENCE_GLOBALS_INIT)?;
-        Ok(Self {
+            match enable_wal(&connection) {
+                Ok(()) => {
+                    // Set synchronous to NORMAL for better performance with WAL
+                    // (FULL is default but NORMAL is safe with WAL)
+                    connection.execute_batch("PRAGMA synchronous=NORMAL;")?;
+                    tracing::info!("SQLite WAL mode enabled for {}", path);
+                },
+                Err(e) if wal_fallback => {
+                    tracing::warn!(
+                        "Failed to enable WAL mode for {path}, falling back to \
+                         journal_mode=DELETE: {e:#}"
+                    );
+                    connection.execute_batch("PRAGMA journal_mode=DELETE;")?;
+                    if let Some(metrics_recorder) = metrics_recorder {
+                        metrics_recorder.record_wal_fallback(&e);
+                    }
+                },
+                Err(e) => return Err(e.context(format!("Failed to enable WAL mode for {path}"))),
+            }
+        }
+        if let Some(synchronous) = synchronous {
+            connection.pragma_update(None, "synchronous", synchronous.as_sql())?;
+        }
+        if let Some(journal_size_limit) = journal_size_limit {
+            connection.pragma_update(None, "journal_size_limit", journal_size_limit)?;
+        }
+
+        migrations::migrate(&connection, options.namespace.as_deref())?;
+        Ok(Self::from_connection(connection, newly_created, options))
+    }
+
+    /// Opens an existing database without the ability to write to it, e.g.
+    /// one that belongs to a replica. The file is opened read-only, so Sqlite
+    /// won't create a journal for it, and any write fails.
+    pub fn open_readonly(path: &str) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            Path::new(path).exists(),
+            PersistenceError::NotFound(path.to_owned())
+        );
+        let options = SqliteOptions::default();
+        let connection = Connection::open_with_flags(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+        options.configure_connection(path, &connection)?;
+        connection.execute_batch("PRAGMA query_only=ON;")?;
+        // We can't migrate a read-only database, but can at least refuse to
+        // misread a newer one.
+        migrations::check_schema_version(&connection, None)?;
+        Ok(Self::from_connection(connection, false, &options))
+    }
+
+    fn from_connection(
+        connection: Connection,
+        newly_created: bool,
+        options: &SqliteOptions,
+    ) -> Self {
+        let &SqliteOptions {
+            busy_timeout,
+            write_retries,
+            write_retry_delay,
+            checkpoint_threshold_pages,
+            ref metrics_recorder,
+            compression,
+            validate_chain,
+            max_rows_per_statement,
+            index_scan_chunk_size,
+            index_scan_max_rows_examined,
+            ref index_key_collation,
+            documents_only,
+            append_only,
+            ref namespace,
+            ..
+        } = options;
+        Self {
             inner: Arc::new(Mutex::new(Inner {
                 newly_created,
                 connection,
+                pages_since_checkpoint: 0,
             })),
+            writer: Arc::new(tokio::sync::Mutex::new(())),
+            read_pool: None,
+            compacting: Arc::new(AtomicBool::new(false)),
+            write_retries,
+            write_retry_delay,
+            checkpoint_threshold_pages,
+            busy_timeout: busy_timeout.unwrap_or(DEFAULT_BUSY_TIMEOUT),
+            metrics_recorder: metrics_recorder.clone(),
+            compression,
+            validate_chain,
+            max_rows_per_statement: max_rows_per_statement
+                .unwrap_or(DEFAULT_MAX_ROWS_PER_STATEMENT),
+            index_scan_chunk_size: index_scan_chunk_size.unwrap_or(DEFAULT_INDEX_SCAN_CHUNK_SIZE),
+            index_scan_max_rows_examined,
+            index_key_collation: index_key_collation.clone(),
+            documents_only,
+            append_only,
+            namespace: namespace.clone(),
+            attached_schema: None,
+            snapshot: None,
+        }
+    }
+
+    /// Another handle to the same database, sharing its connections.
+    fn handle(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            writer: self.writer.clone(),
+            read_pool: self.read_pool.clone(),
+            compacting: self.compacting.clone(),
+            write_retries: self.write_retries,
+            write_retry_delay: self.write_retry_delay,
+            checkpoint_threshold_pages: self.checkpoint_threshold_pages,
+            busy_timeout: self.busy_timeout,
+            metrics_recorder: self.metrics_recorder.clone(),
+            compression: self.compression,
+            validate_chain: self.validate_chain,
+            max_rows_per_statement: self.max_rows_per_statement,
+            index_scan_chunk_size: self.index_scan_chunk_size,
+            index_scan_max_rows_examined: self.index_scan_max_rows_examined,
+            index_key_collation: self.index_key_collation.clone(),
+            documents_only: self.documents_only,
+            append_only: self.append_only,
+            namespace: self.namespace.clone(),
+            attached_schema: self.attached_schema.clone(),
+            snapshot: self.snapshot.clone(),
+        }
+    }
+
+    /// Runs `f` with a read connection for queries this crate doesn't
+    /// provide, such as ad hoc analytics over the `documents` and `indexes`
+    /// tables. The connection is only lent for the duration of `f`, so it can't
+    /// outlive the pool. Writes through it fail: pooled connections are
+    /// read-only, and the write connection is made read-only while `f` runs.
+    /// Any transaction `f` leaves open is rolled back, so it can't hide later
+    /// writes from other reads.
+    pub fn with_connection<T>(
+        &self,
+        f: impl FnOnce(&Connection) -> rusqlite::Result<T>,
+    ) -> anyhow::Result<T> {
+        match &self.read_pool {
+            Some(pool) => Ok(f(&pool.checkout()?)?),
+            None => {
+                let inner = self.inner.lock();
+                // Ending `f`'s transaction would end a `SqliteTransaction` too.
+                anyhow::ensure!(
+                    inner.connection.is_autocommit(),
+                    "with_connection can't run while a transaction is open without a read pool"
+                );
+                let connection = ReadOnlyLoan::new(&inner.connection)?;
+                Ok(f(connection.0)?)
+            },
+        }
+    }
+
+    /// Returns the current utilization of the read pool, or `None` if reads
+    /// share the write connection.
+    pub fn read_pool_stats(&self) -> Option<ReadPoolStats> {
+        self.read_pool.as_ref().map(|pool| pool.stats())
+    }
+
+    /// Returns Sqlite's plan for the query `index_scan` runs over `interval`,
+    /// as reported by `EXPLAIN QUERY PLAN`. Each step is on its own line,
+    /// indented under the step it belongs to. This is for diagnosing slow
+    /// scans, e.g. to check that they search the `indexes` primary key rather
+    /// than scanning the whole table.
+    pub fn explain_index_scan(&self, interval: &Interval, order: Order) -> anyhow::Result<String> {
+        let bounded_above = matches!(interval.end, End::Excluded(_));
+        let query = format!(
+            "EXPLAIN QUERY PLAN {}",
+            self.index_scan_query(bounded_above, order)
+        );
+        self.with_read_connection(|connection| {
+            let mut stmt = connection.prepare(&query)?;
+            // The plan doesn't depend on the parameters' values.
+            let nulls = vec![rusqlite::types::Null; stmt.parameter_count()];
+            let steps = stmt
+                .query_map(rusqlite::params_from_iter(nulls), |row| {
+                    Ok((
+                        row.get::<_, i64>(0)?,
+                        row.get::<_, i64>(1)?,
+                        row.get::<_, String>(3)?,
+                    ))
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            // Parents are always listed before their children.
+            let mut depths = BTreeMap::new();
+            let mut plan = String::new();
+            for (id, parent, detail) in steps {
+                let depth = depths.get(&parent).map_or(0, |depth| depth + 1);
+                depths.insert(id, depth);
+                plan.push_str(&"  ".repeat(depth));
+                plan.push_str(&detail);
+                plan.push('\n');
+            }
+            Ok(plan)
+        })
+    }
+
+    fn _snapshot_at_inner(&self, ts: Timestamp) -> anyhow::Result<SnapshotReader> {
+        let Some(read_pool) = &self.read_pool else {
+            anyhow::bail!("snapshot_at requires a read pool");
+        };
+        let Some(connection) = read_pool.try_checkout_spare()? else {
+            let ReadPoolStats { size, in_use } = read_pool.stats();
+            anyhow::bail!(
+                "snapshot_at needs an idle read connection besides the last one, but {in_use} of \
+                 {size} are in use"
+            );
+        };
+        // Outside of WAL mode an open read transaction blocks all writes.
+        let journal_mode: String =
+            connection.pragma_query_value(None, "journal_mode", |row| row.get(0))?;
+        anyhow::ensure!(
+            journal_mode.eq_ignore_ascii_case("wal"),
+            "snapshot_at requires WAL mode, not journal mode {journal_mode}"
+        );
+        // A deferred transaction only takes its snapshot when it first reads,
+        // so read something now. If this fails, dropping the connection rolls
+        // the transaction back.
+        connection.execute_batch("BEGIN DEFERRED;")?;
+        connection.query_row("SELECT COUNT(*) FROM sqlite_master", [], |_| Ok(()))?;
+        let mut reader = self.handle();
+        reader.snapshot = Some(Arc::new(Mutex::new(connection)));
+        Ok(SnapshotReader::new(Arc::new(reader), ts))
+    }
+
+    fn _rebuild_index_inner(
+        &self,
+        index_id: IndexId,
+        tablet_id: TabletId,
+        key_fn: &(dyn Fn(&ResolvedDocument) -> Vec<u8> + Send + Sync),
+    ) -> anyhow::Result<u64> {
+        anyhow::ensure!(
+            !self.documents_only,
+            "rebuild_index isn't supported with documents_only"
+        );
+        let mut inner = self.inner.lock();
+        let tx = inner.connection.transaction()?;
+        tx.execute(&self.sql(DELETE_INDEX_ENTRIES), [&index_id[..]])?;
+
+        // Replay the table's revisions, tracking the key each document is
+        // currently indexed under so we know when to write a deletion.
+        let mut current_keys = BTreeMap::new();
+        let mut entries = vec![];
+        let mut stmt = tx.prepare(&self.sql(LOAD_TABLET_REVISIONS))?;
+        for row in stmt.query_map([&tablet_id.0[..]], load_document_row)? {
+            let (id, ts, document, _) = row_to_document(row)?;
+            let key = document.as_ref().map(key_fn);
+            let previous = match &key {
+                Some(key) => current_keys.insert(id, key.clone()),
+                None => current_keys.remove(&id),
+            };
+            if let Some(previous) = previous
+                && Some(&previous) != key.as_ref()
+            {
+                entries.push((ts, previous, None));
+            }
+            if let Some(key) = key {
+                entries.push((ts, key, Some(id)));
+            }
+        }
+        drop(stmt);
+
+        let mut insert_index_query = tx.prepare_cached(&self.sql(INSERT_INDEX))?;
+        for (ts, key, id) in &entries {
+            let (deleted, table_id, document_id) = match id {
+                None => (1, None, None),
+                Some(doc_id) => (0, Some(doc_id.table().0 .0), Some(doc_id.internal_id().0)),
+            };
+            insert_index_query.execute(params![
+                &index_id[..],
+                &u64::from(*ts),
+                key,
+                &deleted,
+                &table_id,
+                &document_id,
+            ])?;
+        }
+        drop(insert_index_query);
+        tx.prepare_cached(&self.sql(BUMP_GENERATION))?.execute([])?;
+        tx.commit()?;
+        Ok(entries.len() as u64)
+    }
+
+    /// Runs `query`, which selects the columns `load_document_row` reads, as
+    /// a `load_documents` stream over `range`.
+    fn load_documents_query(
+        &self,
+        query: &str,
+        params: &[&dyn ToSql],
+        range: TimestampRange,
+        retention_validator: Arc<dyn RetentionValidator>,
+    ) -> DocumentStream<'_> {
+        let start = Instant::now();
+        let triples = self.with_read_connection(|connection| {
+            let mut stmt = connection.prepare(query)?;
+
+            let mut entries = vec![];
+            for row in stmt.query_map(params, load_document_row)? {
+                let (document_id, ts, document, prev_ts) = row_to_document(row)?;
+                entries.push(Ok(DocumentLogEntry {
+                    ts,
+                    id: document_id,
+                    value: document,
+                    prev_ts,
+                }));
+            }
+            Ok(entries)
+        });
+        if let Ok(entries) = &triples
+            && let Some(metrics_recorder) = &self.metrics_recorder
+        {
+            metrics_recorder.record_load_documents(start.elapsed(), entries.len());
+        }
+        // load_documents isn't async so we have to validate snapshot as part of the
+        // stream.
+        let validate =
+            self.validate_document_snapshot(range.min_timestamp_inclusive(), retention_validator);
+        match triples {
+            Ok(s) => validate.chain(stream::iter(s).cooperative()).boxed(),
+            Err(e) => stream::once(async { Err(error::classify(e)) }).boxed(),
+        }
+    }
+
+    fn _index_exists_inner(
+        &self,
+        index_id: IndexId,
+        tablet_id: TabletId,
+        read_timestamp: Timestamp,
+        interval: &Interval,
+    ) -> anyhow::Result<bool> {
+        let params = live_index_entry_params(index_id, tablet_id, read_timestamp, interval)?;
+        let entries = self.live_index_entries(matches!(interval.end, End::Excluded(_)));
+        // `EXISTS` stops at the first key whose newest entry isn't a deletion.
+        let query = format!("SELECT EXISTS(SELECT 1 {entries})");
+        self.with_read_connection(|connection| {
+            Ok(connection.query_row(&query, params_from_iter(&params), |row| row.get(0))?)
         })
     }
 
+    fn _index_scan_ids_inner(
+        &self,
+        index_id: IndexId,
+        tablet_id: TabletId,
+        read_timestamp: Timestamp,
+        interval: &Interval,
+        order: Order,
+        limit: Option<usize>,
+    ) -> anyhow::Result<Vec<anyhow::Result<InternalDocumentId>>> {
+        let mut params = live_index_entry_params(index_id, tablet_id, read_timestamp, interval)?;
+        params.push(limit.map(|limit| limit as i64).into());
+        // Sqlite treats a negative limit as no limit.
+        let limit = format!(" LIMIT IFNULL(${}, -1)", params.len());
+
+        let entries = self.live_index_entries(matches!(interval.end, End::Excluded(_)));
+        let order = match order {
+            Order::Asc => "ASC",
+            Order::Desc => "DESC",
+        };
+        let collate = self.collate_key("B.key", order);
+        // Unlike `index_scan`, this never reads the documents table.
+        let query = format!(
+            r#"
+SELECT B.table_id, B.document_id
+{entries}
+ORDER BY {collate}B.key {order}{limit}
+"#,
+        );
+        self.with_read_connection(|connection| {
+            let mut stmt = connection.prepare(&query)?;
+            let rows = stmt.query_map(params_from_iter(&params), |row| {
+                Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, Vec<u8>>(1)?))
+            })?;
+            let mut ids = vec![];
+            for row in rows {
+                let (table, document_id) = row?;
+                let table = TabletId(table.try_into()?);
+                ids.push(Ok(InternalDocumentId::new(
+                    table,
+                    InternalId::try_from(document_id)?,
+                )));
+            }
+            Ok(ids)
+        })
+    }
+
+    /// Attaches a read-only replica of the database at `path` to every pooled
+    /// read connection as `schema_name`, and returns a reader whose reads all
+    /// go to the replica.
+    ///
+    /// The write connection never attaches the replica, so this requires a
+    /// read pool. Writes through `self` continue to go to the main database.
+    /// Connections that are checked out, e.g. by a `SnapshotReader`, attach
+    /// the replica when they're next checked out, so this doesn't wait for
+    /// them.
+    pub fn attach_reader(
+        &self,
+        path: &str,
+        schema_name: &str,
+    ) -> anyhow::Result<Arc<dyn PersistenceReader>> {
+        let Some(read_pool) = &self.read_pool else {
+            anyhow::bail!("attach_reader requires a read pool");
+        };
+        anyhow::ensure!(
+            !schema_name.is_empty()
+                && !schema_name.starts_with(|c: char| c.is_ascii_digit())
+                && schema_name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_'),
+            "Invalid schema name {schema_name:?}"
+        );
+        anyhow::ensure!(
+            Path::new(path).exists(),
+            PersistenceError::NotFound(path.to_owned())
+        );
+        // Opening the replica through a URI lets us attach it read-only.
+        let uri = format!(
+            "file:{}?mode=ro",
+            path.replace('%', "%25")
+                .replace('?', "%3f")
+                .replace('#', "%23")
+        );
+        read_pool.attach(&uri, schema_name)?;
+        let mut reader = self.handle();
+        reader.attached_schema = Some(schema_name.to_owned());
+        Ok(Arc::new(reader))
+    }
+
+    /// The name to query `table` by, prefixed with the namespace and
+    /// qualified with the attached schema if there are those.
+    fn table_name(&self, table: &str) -> String {
+        let table = namespaced_name(table, self.namespace.as_deref());
+        match &self.attached_schema {
+            Some(schema) => format!("{schema}.{table}"),
+            None => table,
+        }
+    }
+
+    /// `query` with its tables renamed for the namespace and qualified with
+    /// the attached schema, if there are those.
+    fn sql<'a>(&self, query: &'a str) -> Cow<'a, str> {
+        qualified(
+            query,
+            self.namespace.as_deref(),
+            self.attached_schema.as_deref(),
+        )
+    }
+
+    /// The `ORDER BY` terms that apply `index_key_collation` to `key`, if
+    /// there is one.
+    fn collate_key(&self, key: &str, order: &str) -> String {
+        match &self.index_key_collation {
+            Some(collation) => collation.order_by(key, order),
+            None => String::new(),
+        }
+    }
+
+    /// Runs `f` with a connection suitable for reads: the snapshot's
+    /// connection if there is one, otherwise a pooled connection if there is a
+    /// read pool, otherwise the write connection.
+    fn with_read_connection<T>(
+        &self,
+        f: impl FnOnce(&Connection) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        if let Some(snapshot) = &self.snapshot {
+            return f(&snapshot.lock());
+        }
+        match &self.read_pool {
+            Some(pool) => f(&pool.checkout()?),
+            None => {
+                let inner = self.inner.lock();
+                // It would show the read a `SqliteTransaction`'s uncommitted
+                // writes.
+                anyhow::ensure!(
+                    inner.connection.is_autocommit(),
+                    "Reads without a read pool can't run while a transaction is open"
+                );
+                f(&inner.connection)
+            },
+        }
+    }
+
     #[allow(clippy::needless_lifetimes)]
     #[try_stream(ok = T, error = anyhow::Error)]
     async fn validate_snapshot<T: 'static>(
@@ -131,81 +1037,90 @@ impl SqlitePersistence {
         retention_validator.validate_document_snapshot(ts).await?;
     }
 
-    fn _index_scan_inner(
-        &self,
-        index_id: IndexId,
-        tablet_id: TabletId,
-        read_timestamp: Timestamp,
-        interval: &Interval,
-        order: Order,
-    ) -> anyhow::Result<Vec<anyhow::Result<(IndexKeyBytes, LatestDocument)>>> {
-        let interval = interval.clone();
-        let index_id = &index_id[..];
-        let read_timestamp: u64 = read_timestamp.into();
-
-        let mut params = params![index_id, read_timestamp].to_vec();
-
-        let StartIncluded(ref start) = interval.start;
-        let start_bytes = &start[..];
-
-        params.push(&start_bytes);
-        let lower = format!(" AND key >= ${}", params.len());
-
-        let end_bytes = match interval.end {
-            End::Excluded(ref t) => Some(&t[..]),
-            End::Unbounded => None,
-        };
-        let upper = match end_bytes {
-            Some(ref t) => {
-                params.push(t);
-                format!(" AND key < ${}", params.len())
-            },
-            None => "".to_owned(),
-        };
-
-        let order = match order {
-            Order::Asc => "ASC",
-            Order::Desc => "DESC",
-        };
-        let query = format!(
+    /// The `FROM` clause the index reads share. It selects, as `B`, the newest
+    /// entry at or before the read timestamp for each key in the interval,
+    /// dropping keys whose newest entry is a deletion or belongs to another
+    /// tablet. Its parameters are the ones `live_index_entry_params` returns.
+    fn live_index_entries(&self, bounded_above: bool) -> String {
+        let upper = if bounded_above { " AND key < $5" } else { "" };
+        let indexes = self.table_name("indexes");
+        format!(
             r#"
-SELECT B.key, B.ts, B.document_id, C.table_id, C.json_value, C.prev_ts
 FROM (
     SELECT index_id, key, MAX(ts) as max_ts
-    FROM indexes
-    WHERE index_id = $1 AND ts <= $2{lower}{upper}
+    FROM {indexes}
+    WHERE index_id = $1 AND ts <= $3 AND key >= $4{upper}
     GROUP BY index_id, key
 ) A
-JOIN indexes B
+JOIN {indexes} B
 ON B.deleted is FALSE
 AND A.index_id = B.index_id
 AND A.key = B.key
 AND A.max_ts = B.ts
-LEFT JOIN documents C
+AND B.table_id = $2
+"#,
+        )
+    }
+
+    /// The query `index_scan` runs. Its parameters are those of
+    /// `live_index_entries`, followed by the maximum number of rows (or
+    /// `NULL` for no limit).
+    fn index_scan_query(&self, bounded_above: bool, order: Order) -> String {
+        let entries = self.live_index_entries(bounded_above);
+        let limit = if bounded_above { "$6" } else { "$5" };
+        let order = match order {
+            Order::Asc => "ASC",
+            Order::Desc => "DESC",
+        };
+        let collate = self.collate_key("B.key", order);
+        let documents = self.table_name("documents");
+        format!(
+            r#"
+SELECT B.key, B.ts, B.document_id, C.table_id, C.json_value, C.prev_ts
+{entries}
+LEFT JOIN {documents} C
 ON B.ts = C.ts
 AND B.table_id = c.table_id
 AND B.document_id = C.id
-ORDER BY B.key {order}
+ORDER BY {collate}B.key {order}
+LIMIT IFNULL({limit}, -1)
 "#,
-        );
+        )
+    }
 
-        let connection = &self.inner.lock().connection;
-        let mut stmt = connection.prepare(&query)?;
-        let row_iter = stmt.query_map(&params[..], |row| {
-            let key = IndexKeyBytes(row.get::<_, Vec<u8>>(0)?);
-            let ts = Timestamp::try_from(row.get::<_, u64>(1)?).expect("timestamp out of bounds");
-            let document_id = row.get::<_, Vec<u8>>(2)?;
-            let table: Option<Vec<u8>> = row.get(3)?;
-            let json_value: Option<String> = row.get(4)?;
-            let prev_ts: Option<Timestamp> = row
-                .get::<_, Option<u64>>(5)?
-                .map(|ts| Timestamp::try_from(ts).expect("prev_ts out of bounds"));
-
-            Ok((key, ts, document_id, table, json_value, prev_ts))
+    fn _index_scan_inner(
+        &self,
+        index_id: IndexId,
+        tablet_id: TabletId,
+        read_timestamp: Timestamp,
+        interval: &Interval,
+        order: Order,
+        limit: Option<usize>,
+    ) -> anyhow::Result<Vec<anyhow::Result<(IndexKeyBytes, LatestDocument)>>> {
+        let mut params = live_index_entry_params(index_id, tablet_id, read_timestamp, interval)?;
+        params.push(limit.map(|limit| limit as i64).into());
+        let query = self.index_scan_query(matches!(interval.end, End::Excluded(_)), order);
+
+        let rows = self.with_read_connection(|connection| {
+            let mut stmt = connection.prepare(&query)?;
+            let row_iter = stmt.query_map(params_from_iter(&params), |row| {
+                let key = IndexKeyBytes(row.get::<_, Vec<u8>>(0)?);
+                let ts =
+                    Timestamp::try_from(row.get::<_, u64>(1)?).expect("timestamp out of bounds");
+                let document_id = row.get::<_, Vec<u8>>(2)?;
+                let table: Option<Vec<u8>> = row.get(3)?;
+                let json_value = row.get::<_, Option<StoredJson>>(4)?.map(|v| v.0);
+                let prev_ts: Option<Timestamp> = row
+                    .get::<_, Option<u64>>(5)?
+                    .map(|ts| Timestamp::try_from(ts).expect("prev_ts out of bounds"));
+
+                Ok((key, ts, document_id, table, json_value, prev_ts))
+            })?;
+            Ok(row_iter.collect::<rusqlite::Result<Vec<_>>>()?)
         })?;
         let mut triples = vec![];
-        for row in row_iter {
-            let (key, ts, document_id, table, json_value, prev_ts) = row?;
+        for row in rows {
+            let (key, ts, document_id, table, json_value, prev_ts) = row;
             let table = table.ok_or_else(|| {
                 anyhow::anyhow!("Dangling index reference for {:?} {:?}", key, ts)
             })?;
@@ -229,22 +1144,572 @@ ORDER BY B.key {order}
         Ok(triples)
     }
 
+    /// Streams the entries in `interval` in chunks of `index_scan_chunk_size`,
+    /// starting each chunk after the last key of the previous one, and fails
+    /// once more than `index_scan_max_rows_examined` entries have been read.
+    ///
+    /// Unless `self` is a `SnapshotReader`, each chunk is its own read
+    /// transaction, so a scan spanning several chunks isn't a single snapshot:
+    /// later chunks see writes and deletions that committed after earlier ones
+    /// were read.
+    #[allow(clippy::needless_lifetimes)]
+    #[try_stream(ok = (IndexKeyBytes, LatestDocument), error = anyhow::Error)]
+    async fn index_scan_chunks(
+        &self,
+        index_id: IndexId,
+        tablet_id: TabletId,
+        read_timestamp: Timestamp,
+        interval: Interval,
+        order: Order,
+    ) {
+        let chunk_size = match self.index_key_collation {
+            Some(_) => None,
+            None => Some(self.index_scan_chunk_size),
+        };
+        let mut remaining = interval;
+        let mut metrics = IndexScanMetrics {
+            recorder: self.metrics_recorder.clone(),
+            elapsed: Duration::ZERO,
+            rows: 0,
+        };
+        loop {
+            // Read at most one entry past the budget, which is enough to tell
+            // that it's been exceeded.
+            let limit = match self.index_scan_max_rows_examined {
+                Some(max_rows) => {
+                    let allowed = max_rows.saturating_sub(metrics.rows).saturating_add(1);
+                    Some(chunk_size.map_or(allowed, |chunk_size| chunk_size.min(allowed)))
+                },
+                None => chunk_size,
+            };
+            let start = Instant::now();
+            let chunk = self
+                ._index_scan_inner(
+                    index_id,
+                    tablet_id,
+                    read_timestamp,
+                    &remaining,
+                    order,
+                    limit,
+                )
+                .map_err(error::classify);
+            metrics.elapsed += start.elapsed();
+            let chunk = chunk?;
+            metrics.rows += chunk.len();
+            if let Some(max_rows) = self.index_scan_max_rows_examined
+                && metrics.rows > max_rows
+            {
+                let within_budget = chunk.len() - (metrics.rows - max_rows);
+                for entry in chunk.into_iter().take(within_budget) {
+                    yield entry?;
+                }
+                anyhow::bail!(PersistenceError::BudgetExceeded(max_rows));
+            }
+            // A short chunk means there's nothing left to fetch.
+            let last_key = match limit {
+                Some(limit) if chunk.len() == limit => chunk
+                    .last()
+                    .and_then(|entry| entry.as_ref().ok())
+                    .map(|(key, _)| key.clone()),
+                _ => None,
+            };
+            for entry in chunk {
+                yield entry?;
+            }
+            let Some(last_key) = last_key else {
+                break;
+            };
+            (_, remaining) = remaining.split_after(last_key, order);
+        }
+    }
+
+    /// Like `write`, but fails with `PersistenceError::Timeout` if the write
+    /// hasn't committed within `timeout`, including time spent waiting for
+    /// locks and retrying. A timed out write is rolled back entirely.
+    pub async fn write_with_timeout(
+        &self,
+        documents: &[DocumentLogEntry],
+        indexes: &[PersistenceIndexEntry],
+        conflict_strategy: ConflictStrategy,
+        timeout: Duration,
+    ) -> anyhow::Result<()> {
+        let deadline = Instant::now() + timeout;
+        let Ok(_writer) = tokio::time::timeout(timeout, self.writer.lock()).await else {
+            return Err(PersistenceError::Timeout.into());
+        };
+        // Waiting for `inner` and for Sqlite's locks blocks, so each attempt
+        // runs on the blocking pool rather than parking a tokio worker for up
+        // to `timeout`. Attempts enforce the deadline themselves, since
+        // cancelling one from here wouldn't stop it from committing.
+        let persistence = Arc::new(self.handle());
+        let documents: Arc<[DocumentLogEntry]> = documents.into();
+        let indexes: Arc<[PersistenceIndexEntry]> = indexes.into();
+        self.retry_write(documents.len(), || {
+            let persistence = persistence.clone();
+            let documents = documents.clone();
+            let indexes = indexes.clone();
+            async move {
+                tokio::task::spawn_blocking(move || {
+                    persistence._write_inner(
+                        &documents,
+                        &indexes,
+                        conflict_strategy,
+                        Some(deadline),
+                    )
+                })
+                .await?
+            }
+        })
+        .await
+    }
+
+    /// Runs `write`, retrying it up to `write_retries` times while it fails
+    /// with `SQLITE_BUSY`, and records it as a write of `num_documents`
+    /// documents if it succeeds.
+    async fn retry_write<T, F>(
+        &self,
+        num_documents: usize,
+        mut write: impl FnMut() -> F,
+    ) -> anyhow::Result<T>
+    where
+        F: Future<Output = anyhow::Result<T>>,
+    {
+        let start = Instant::now();
+        let mut backoff = Backoff::new(self.write_retry_delay, self.max_write_retry_delay());
+        loop {
+            match write().await {
+                Err(e) if is_busy_error(&e) && backoff.failures() < self.write_retries => {
+                    let delay = backoff.fail(&mut rand::rng());
+                    tracing::warn!("Sqlite write failed with {e:#}, retrying in {delay:?}");
+                    tokio::time::sleep(delay).await;
+                },
+                result => {
+                    if result.is_ok()
+                        && let Some(metrics_recorder) = &self.metrics_recorder
+                    {
+                        metrics_recorder.record_write(start.elapsed(), num_documents);
+                    }
+                    return result.map_err(error::classify);
+                },
+            }
+        }
+    }
+
+    fn _write_inner(
+        &self,
+        documents: &[DocumentLogEntry],
+        indexes: &[PersistenceIndexEntry],
+        conflict_strategy: ConflictStrategy,
+        deadline: Option<Instant>,
+    ) -> anyhow::Result<()> {
+        let Some(deadline) = deadline else {
+            return self._write_locked(
+                &mut self.inner.lock(),
+                documents,
+                indexes,
+                conflict_strategy,
+            );
+        };
+        // The write is synchronous, so the deadline has to be enforced while
+        // waiting for both our own lock and Sqlite's.
+        let mut inner = self
+            .inner
+            .try_lock_until(deadline)
+            .ok_or(PersistenceError::Timeout)?;
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        inner
+            .connection
+            .busy_timeout(cmp::min(remaining, self.busy_timeout))?;
+        let result = self._write_locked(&mut inner, documents, indexes, conflict_strategy);
+        inner.connection.busy_timeout(self.busy_timeout)?;
+        match result {
+            Err(e) if is_busy_error(&e) && Instant::now() >= deadline => {
+                Err(PersistenceError::Timeout.into())
+            },
+            result => result,
+        }
+    }
+
+    /// The index entries a write should insert, which is none of them with
+    /// `documents_only`.
+    fn indexes_to_write<'a>(
+        &self,
+        indexes: &'a [PersistenceIndexEntry],
+    ) -> &'a [PersistenceIndexEntry] {
+        if self.documents_only {
+            &[]
+        } else {
+            indexes
+        }
+    }
+
+    /// Fails if `append_only` forbids writing `documents` with
+    /// `conflict_strategy`.
+    fn check_append_only(
+        &self,
+        documents: &[DocumentLogEntry],
+        conflict_strategy: ConflictStrategy,
+    ) -> anyhow::Result<()> {
+        if !self.append_only {
+            return Ok(());
+        }
+        anyhow::ensure!(
+            conflict_strategy == ConflictStrategy::Error,
+            "{conflict_strategy:?} writes aren't supported with append_only"
+        );
+        if let Some(entry) = documents.iter().find(|entry| entry.value.is_none()) {
+            anyhow::bail!(
+                "Deletion of {}@{} isn't supported with append_only",
+                entry.id,
+                entry.ts
+            );
+        }
+        Ok(())
+    }
+
+    fn _write_locked(
+        &self,
+        inner: &mut Inner,
+        documents: &[DocumentLogEntry],
+        indexes: &[PersistenceIndexEntry],
+        conflict_strategy: ConflictStrategy,
+    ) -> anyhow::Result<()> {
+        self.check_append_only(documents, conflict_strategy)?;
+        let indexes = self.indexes_to_write(indexes);
+        // A savepoint rather than a transaction, so that this also works
+        // inside a `SqliteTransaction`.
+        let tx = inner.connection.savepoint()?;
+        let mut bytes_written = 0;
+        let insert_document = self.sql(match conflict_strategy {
+            ConflictStrategy::Error => INSERT_DOCUMENT,
+            ConflictStrategy::Overwrite => INSERT_OVERWRITE_DOCUMENT,
+            ConflictStrategy::Merge => INSERT_IGNORE_DOCUMENT,
+        });
+        // Validating a document's chain reads its previous revision, which may
+        // be earlier in this write, so those rows are inserted one at a time.
+        let document_chunk_size = if self.validate_chain {
+            1
+        } else {
+            self.max_rows_per_statement
+        };
+        for chunk in documents.chunks(document_chunk_size) {
+            let mut json_values = Vec::with_capacity(chunk.len());
+            for update in chunk {
+                json_values.push(match &update.value {
+                    Some(document) => {
+                        assert_eq!(update.id, document.id_with_table_id());
+                        Some(document.value().json_serialize()?)
+                    },
+                    None => None,
+                });
+            }
+            let stored_values = json_values
+                .iter()
+                .map(|json_value| {
+                    json_value
+                        .as_deref()
+                        .map(|json_value| self.compression.encode(json_value))
+                        .transpose()
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            let rows = chunk
+                .iter()
+                .zip(&stored_values)
+                .map(|(update, stored_value)| {
+                    bytes_written +=
+                        ROW_OVERHEAD_BYTES + stored_value.as_ref().map_or(0, |v| v.len());
+                    let deleted = if update.value.is_some() { 0 } else { 1 };
+                    (
+                        update.id.internal_id().0,
+                        u64::from(update.ts),
+                        update.id.table().0 .0,
+                        stored_value,
+                        deleted,
+                        update.prev_ts.map(u64::from),
+                    )
+                })
+                .collect::<Vec<_>>();
+            if self.validate_chain {
+                for update in chunk {
+                    // Earlier entries in this write are already visible in `tx`.
+                    let actual_prev_ts: Option<u64> =
+                        tx.prepare_cached(&self.sql(GET_PREV_TS))?.query_row(
+                            params![
+                                &update.id.table().0[..],
+                                &update.id.internal_id()[..],
+                                &u64::from(update.ts),
+                            ],
+                            |row| row.get(0),
+                        )?;
+                    anyhow::ensure!(
+                        update.prev_ts.map(u64::from) == actual_prev_ts,
+                        "prev_ts {:?} of document {} at ts {} doesn't match its previous revision \
+                         at {:?}",
+                        update.prev_ts,
+                        update.id,
+                        update.ts,
+                        actual_prev_ts,
+                    );
+                }
+            }
+            let params = rows
+                .iter()
+                .flat_map(
+                    |(id, ts, table_id, stored_value, deleted, prev_ts)| -> [&dyn ToSql; 6] {
+                        [id, ts, table_id, stored_value, deleted, prev_ts]
+                    },
+                )
+                .collect::<Vec<_>>();
+            let inserted = match tx
+                .prepare_cached(&multi_row_insert(&insert_document, chunk.len()))?
+                .execute(&params[..])
+            {
+                Ok(inserted) => inserted,
+                Err(e) if e.sqlite_error_code() == Some(ErrorCode::ConstraintViolation) => {
+                    let conflict = find_document_conflict(&tx, self.namespace.as_deref(), chunk)?;
+                    return Err(
+                        anyhow::Error::from(e).context(PersistenceError::Conflict(conflict))
+                    );
+                },
+                Err(e) => return Err(e.into()),
+            };
+            // Only `Merge` ignores conflicts, and then only for identical rows.
+            if inserted == chunk.len() {
+                continue;
+            }
+            for (update, json_value) in chunk.iter().zip(&json_values) {
+                let (existing_value, existing_prev_ts): (Option<String>, Option<u64>) = tx
+                    .prepare_cached(&self.sql(GET_DOCUMENT_REVISION))?
+                    .query_row(
+                        params![
+                            &u64::from(update.ts),
+                            &update.id.table().0[..],
+                            &update.id.internal_id()[..],
+                        ],
+                        |row| {
+                            Ok((
+                                row.get::<_, Option<StoredJson>>(0)?.map(|v| v.0),
+                                row.get(1)?,
+                            ))
+                        },
+                    )?;
+                if existing_value != *json_value
+                    || existing_prev_ts != update.prev_ts.map(u64::from)
+                {
+                    return Err(anyhow::anyhow!(
+                        "Failed to merge document at ts {} with id {}: a different revision \
+                         already exists",
+                        update.ts,
+                        update.id
+                    )
+                    .context(PersistenceError::Conflict(Some(
+                        Conflict::Document {
+                            id: update.id,
+                            ts: update.ts,
+                        },
+                    ))));
+                }
+            }
+        }
+
+        let insert_index = self.sql(match conflict_strategy {
+            ConflictStrategy::Error => INSERT_INDEX,
+            ConflictStrategy::Overwrite => INSERT_OVERWRITE_INDEX,
+            ConflictStrategy::Merge => INSERT_IGNORE_INDEX,
+        });
+        for chunk in indexes.chunks(self.max_rows_per_statement) {
+            let rows = chunk
+                .iter()
+                .map(|update| {
+                    bytes_written += ROW_OVERHEAD_BYTES + update.key.0.len();
+                    let (deleted, table_id, document_id) = match update.value {
+                        None => (1, None, None),
+                        Some(doc_id) => {
+                            (0, Some(doc_id.table().0 .0), Some(doc_id.internal_id().0))
+                        },
+                    };
+                    (
+                        update.index_id.0,
+                        u64::from(update.ts),
+                        &update.key.0,
+                        deleted,
+                        table_id,
+                        document_id,
+                    )
+                })
+                .collect::<Vec<_>>();
+            let params = rows
+                .iter()
+                .flat_map(
+                    |(index_id, ts, key, deleted, table_id, document_id)| -> [&dyn ToSql; 6] {
+                        [index_id, ts, key, deleted, table_id, document_id]
+                    },
+                )
+                .collect::<Vec<_>>();
+            let inserted = match tx
+                .prepare_cached(&multi_row_insert(&insert_index, chunk.len()))?
+                .execute(&params[..])
+            {
+                Ok(inserted) => inserted,
+                Err(e) if e.sqlite_error_code() == Some(ErrorCode::ConstraintViolation) => {
+                    let conflict = find_index_conflict(&tx, self.namespace.as_deref(), chunk)?;
+                    return Err(
+                        anyhow::Error::from(e).context(PersistenceError::Conflict(conflict))
+                    );
+                },
+                Err(e) => return Err(e.into()),
+            };
+            if inserted == chunk.len() {
+                continue;
+            }
+            for update in chunk {
+                let key: &[u8] = &update.key.0;
+                let (table_id, document_id): (Option<Vec<u8>>, Option<Vec<u8>>) =
+                    tx.prepare_cached(&self.sql(GET_INDEX_ENTRY))?.query_row(
+                        params![&update.index_id[..], key, &u64::from(update.ts)],
+                        |row| Ok((row.get(0)?, row.get(1)?)),
+                    )?;
+                let expected = update
+                    .value
+                    .map(|doc_id| (doc_id.table().0.to_vec(), doc_id.internal_id().to_vec()));
+                if table_id.zip(document_id) != expected {
+                    return Err(anyhow::anyhow!(
+                        "Failed to merge index {} entry at ts {} with key {:?}: a different entry \
+                         already exists",
+                        update.index_id,
+                        update.ts,
+                        update.key
+                    )
+                    .context(PersistenceError::Conflict(Some(
+                        Conflict::Index {
+                            index_id: update.index_id,
+                            key: update.key.clone(),
+                            ts: update.ts,
+                        },
+                    ))));
+                }
+            }
+        }
+        tx.prepare_cached(&self.sql(BUMP_GENERATION))?.execute([])?;
+
+        tx.commit()?;
+
+        if let Some(threshold) = self.checkpoint_threshold_pages {
+            let page_size: u64 = inner
+                .connection
+                .pragma_query_value(None, "page_size", |row| row.get(0))?;
+            inner.pages_since_checkpoint += (bytes_written as u64).div_ceil(page_size);
+            // Within a `SqliteTransaction` nothing has committed yet, so
+            // leave the checkpoint to a later write.
+            if inner.pages_since_checkpoint >= threshold && inner.connection.is_autocommit() {
+                inner.pages_since_checkpoint = 0;
+                // The write itself has committed, so a failed checkpoint
+                // shouldn't fail it. The next threshold crossing will try again.
+                if let Err(e) = maintenance::checkpoint(&inner.connection, CheckpointMode::Passive)
+                {
+                    tracing::warn!("Sqlite checkpoint after write failed: {e:#}");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn _write_partial_locked(
+        &self,
+        inner: &mut Inner,
+        documents: &[DocumentLogEntry],
+        indexes: &[PersistenceIndexEntry],
+    ) -> anyhow::Result<WriteOutcome> {
+        let indexes = self.indexes_to_write(indexes);
+        // The rows that are written still commit together, and `validate_chain`
+        // isn't applied since conflicting revisions may be skipped. Like
+        // `_write_locked`, this uses a savepoint so that it works inside a
+        // `SqliteTransaction`.
+        let tx = inner.connection.savepoint()?;
+        let mut outcome = WriteOutcome::default();
+        let mut insert_document_query = tx.prepare_cached(&self.sql(INSERT_IGNORE_DOCUMENT))?;
+        for update in documents {
+            let (json_value, deleted) = match &update.value {
+                Some(document) => {
+                    assert_eq!(update.id, document.id_with_table_id());
+                    (Some(document.value().json_serialize()?), 0)
+                },
+                None => (None, 1),
+            };
+            let stored_value = json_value
+                .as_deref()
+                .map(|json_value| self.compression.encode(json_value))
+                .transpose()?;
+            let inserted = insert_document_query.execute(params![
+                &update.id.internal_id()[..],
+                &u64::from(update.ts),
+                &update.id.table().0[..],
+                &stored_value,
+                &deleted,
+                &update.prev_ts.map(u64::from),
+            ])?;
+            if inserted == 0 {
+                outcome.conflicts.push(Conflict::Document {
+                    id: update.id,
+                    ts: update.ts,
+                });
+            } else {
+                outcome.documents_written += 1;
+            }
+        }
+        drop(insert_document_query);
+        let mut insert_index_query = tx.prepare_cached(&self.sql(INSERT_IGNORE_INDEX))?;
+        for update in indexes {
+            let (deleted, table_id, document_id) = match update.value {
+                None => (1, None, None),
+                Some(doc_id) => (0, Some(doc_id.table().0 .0), Some(doc_id.internal_id().0)),
+            };
+            let inserted = insert_index_query.execute(params![
+                &update.index_id[..],
+                &u64::from(update.ts),
+                &update.key.0,
+                &deleted,
+                &table_id,
+                &document_id,
+            ])?;
+            if inserted == 0 {
+                outcome.conflicts.push(Conflict::Index {
+                    index_id: update.index_id,
+                    key: update.key.clone(),
+                    ts: update.ts,
+                });
+            } else {
+                outcome.indexes_written += 1;
+            }
+        }
+        drop(insert_index_query);
+        tx.prepare_cached(&self.sql(BUMP_GENERATION))?.execute([])?;
+        tx.commit()?;
+        Ok(outcome)
+    }
+
+    fn max_write_retry_delay(&self) -> Duration {
+        self.write_retry_delay
+            .checked_mul(2u32.saturating_pow(self.write_retries))
+            .unwrap_or(Duration::MAX)
+    }
+
     fn _get_persistence_global(
         &self,
         key: PersistenceGlobalKey,
     ) -> anyhow::Result<Option<JsonValue>> {
-        let connection = &self.inner.lock().connection;
-        let mut stmt = connection.prepare(GET_PERSISTENCE_GLOBAL)?;
         let key = String::from(key);
-        let params: Vec<&dyn ToSql> = vec![&key];
-        let mut row_iter = stmt.query_map(&params[..], |row| {
-            let json_value_str: String = row.get(0)?;
-            Ok(json_value_str)
+        let json_value_str = self.with_read_connection(|connection| {
+            let mut stmt = connection.prepare(&self.sql(GET_PERSISTENCE_GLOBAL))?;
+            let params: Vec<&dyn ToSql> = vec![&key];
+            let mut row_iter = stmt.query_map(&params[..], |row| {
+                let json_value_str: String = row.get(0)?;
+                Ok(json_value_str)
+            })?;
+            Ok(row_iter.next().transpose()?)
         })?;
-        row_iter
-            .next()
+        json_value_str
             .map(|json_value_str| {
-                let json_value_str = json_value_str?;
                 let mut json_deserializer = serde_json::Deserializer::from_str(&json_value_str);
                 // XXX: this is bad, but shapes can get much more nested than convex values
                 json_deserializer.disable_recursion_limit();
@@ -264,9 +1729,7 @@ impl Persistence for SqlitePersistence {
     }
 
     fn reader(&self) -> Arc<dyn PersistenceReader> {
-        Arc::new(Self {
-            inner: self.inner.clone(),
-        })
+        Arc::new(self.handle())
     }
 
     async fn write<'a>(
@@ -275,67 +1738,83 @@ impl Persistence for SqlitePersistence {
         indexes: &'a [PersistenceIndexEntry],
         conflict_strategy: ConflictStrategy,
     ) -> anyhow::Result<()> {
-        let mut inner = self.inner.lock();
-        let tx = inner.connection.transaction()?;
-        let mut insert_document_query = match conflict_strategy {
-            ConflictStrategy::Error => tx.prepare_cached(INSERT_DOCUMENT)?,
-            ConflictStrategy::Overwrite => tx.prepare_cached(INSERT_OVERWRITE_DOCUMENT)?,
-        };
+        let _writer = self.writer.lock().await;
+        self.retry_write(documents.len(), || async move {
+            self._write_inner(documents, indexes, conflict_strategy, None)
+        })
+        .await
+    }
 
+    async fn write_with_receipt<'a>(
+        &self,
+        documents: &'a [DocumentLogEntry],
+        indexes: &'a [PersistenceIndexEntry],
+        conflict_strategy: ConflictStrategy,
+    ) -> anyhow::Result<WriteReceipt> {
+        self.write(documents, indexes, conflict_strategy).await?;
+        Ok(WriteReceipt::new(documents, self.indexes_to_write(indexes)))
+    }
+
+    async fn check_write(
+        &self,
+        documents: &[DocumentLogEntry],
+        indexes: &[PersistenceIndexEntry],
+    ) -> anyhow::Result<Vec<Conflict>> {
+        let _writer = self.writer.lock().await;
+        let indexes = self.indexes_to_write(indexes);
+        let mut inner = self.inner.lock();
+        // Insert placeholder rows, which only need the primary key columns, so
+        // that conflicts within this call are detected too. The savepoint is
+        // always rolled back, and works inside a `SqliteTransaction` too.
+        let mut tx = inner.connection.savepoint()?;
+        let mut conflicts = vec![];
+        let mut insert_document_query = tx.prepare_cached(&self.sql(CHECK_WRITE_DOCUMENT))?;
         for update in documents {
-            let (json_value, deleted) = if let Some(document) = &update.value {
-                assert_eq!(update.id, document.id_with_table_id());
-                let json_value = document.value().json_serialize()?;
-                (Some(json_value), 0)
-            } else {
-                (None, 1)
-            };
-            insert_document_query.execute(params![
+            let inserted = insert_document_query.execute(params![
                 &update.id.internal_id()[..],
                 &u64::from(update.ts),
                 &update.id.table().0[..],
-                &json_value,
-                &deleted,
-                &update.prev_ts.map(u64::from),
             ])?;
+            if inserted == 0 {
+                conflicts.push(Conflict::Document {
+                    id: update.id,
+                    ts: update.ts,
+                });
+            }
         }
         drop(insert_document_query);
-
-        let mut insert_index_query = if conflict_strategy == ConflictStrategy::Overwrite {
-            tx.prepare_cached(INSERT_OVERWRITE_INDEX)?
-        } else {
-            tx.prepare_cached(INSERT_INDEX)?
-        };
+        let mut insert_index_query = tx.prepare_cached(&self.sql(CHECK_WRITE_INDEX))?;
         for update in indexes {
-            let index_id = update.index_id;
-            let key: &[u8] = &update.key.0;
-            match update.value {
-                None => {
-                    insert_index_query.execute(params![
-                        &index_id[..],
-                        &u64::from(update.ts),
-                        key,
-                        &1,
-                        &Null,
-                        &Null,
-                    ])?;
-                },
-                Some(doc_id) => {
-                    insert_index_query.execute(params![
-                        &index_id[..],
-                        &u64::from(update.ts),
-                        key,
-                        &0,
-                        &doc_id.table().0[..],
-                        &doc_id.internal_id()[..],
-                    ])?;
-                },
-            };
+            let inserted = insert_index_query.execute(params![
+                &update.index_id[..],
+                &u64::from(update.ts),
+                &update.key.0,
+            ])?;
+            if inserted == 0 {
+                conflicts.push(Conflict::Index {
+                    index_id: update.index_id,
+                    key: update.key.clone(),
+                    ts: update.ts,
+                });
+            }
         }
         drop(insert_index_query);
+        tx.rollback()?;
+        Ok(conflicts)
+    }
 
-        tx.commit()?;
-        Ok(())
+    async fn write_partial(
+        &self,
+        documents: &[DocumentLogEntry],
+        indexes: &[PersistenceIndexEntry],
+    ) -> anyhow::Result<WriteOutcome> {
+        // Conflicting rows are skipped rather than overwritten.
+        self.check_append_only(documents, ConflictStrategy::Error)?;
+        let _writer = self.writer.lock().await;
+        self.retry_write(documents.len(), || async move {
+            self._write_partial_locked(&mut self.inner.lock(), documents, indexes)
+        })
+        .await
     }
 
     async fn write_persistence_global(
@@ -343,9 +1822,10 @@ impl Persistence for SqlitePersistence {
         key: PersistenceGlobalKey,
         value: JsonValue,
     ) -> anyhow::Result<()> {
+        let _writer = self.writer.lock().await;
         let mut inner = self.inner.lock();
         let tx = inner.connection.transaction()?;
-        let mut write_query = tx.prepare_cached(WRITE_PERSISTENCE_GLOBAL)?;
+        let mut write_query = tx.prepare_cached(&self.sql(WRITE_PERSISTENCE_GLOBAL))?;
         let json_value = serde_json::to_string(&value)?;
         write_query.execute(params![&String::from(key), &json_value])?;
         drop(write_query);
@@ -358,8 +1838,9 @@ impl Persistence for SqlitePersistence {
         cursor: Option<IndexEntry>,
         chunk_size: usize,
     ) -> anyhow::Result<Vec<IndexEntry>> {
+        let _writer = self.writer.lock().await;
         let connection = &self.inner.lock().connection;
-        let mut walk_indexes = connection.prepare(WALK_INDEXES)?;
+        let mut walk_indexes = connection.prepare(&self.sql(WALK_INDEXES))?;
         let row_iter = walk_indexes.query_map([], |row| {
             let index_id: Vec<u8> = row.get(0)?;
             let key: Vec<u8> = row.get(1)?;
@@ -393,9 +1874,10 @@ impl Persistence for SqlitePersistence {
     }
 
     async fn delete_index_entries(&self, expired_rows: Vec<IndexEntry>) -> anyhow::Result<usize> {
+        let _writer = self.writer.lock().await;
         let mut inner = self.inner.lock();
         let tx = inner.connection.transaction()?;
-        let mut delete_index_query = tx.prepare_cached(DELETE_INDEX)?;
+        let mut delete_index_query = tx.prepare_cached(&self.sql(DELETE_INDEX))?;
         let mut count_deleted = 0;
 
         for IndexEntry {
@@ -417,9 +1899,11 @@ impl Persistence for SqlitePersistence {
         &self,
         documents: Vec<(Timestamp, InternalDocumentId)>,
     ) -> anyhow::Result<usize> {
+        anyhow::ensure!(!self.append_only, "delete isn't supported with append_only");
+        let _writer = self.writer.lock().await;
         let mut inner = self.inner.lock();
         let tx = inner.connection.transaction()?;
-        let mut delete_document_query = tx.prepare_cached(DELETE_DOCUMENT)?;
+        let mut delete_document_query = tx.prepare_cached(&self.sql(DELETE_DOCUMENT))?;
         let mut count_deleted = 0;
 
         for (ts, internal_id) in documents {
@@ -432,6 +1916,7 @@ impl Persistence for SqlitePersistence {
             ])?;
         }
         drop(delete_document_query);
+        tx.prepare_cached(&self.sql(BUMP_GENERATION))?.execute([])?;
         tx.commit()?;
         Ok(count_deleted)
     }
@@ -441,18 +1926,105 @@ impl Persistence for SqlitePersistence {
         tablet_id: TabletId,
         chunk_size: usize,
     ) -> anyhow::Result<usize> {
+        anyhow::ensure!(
+            !self.append_only,
+            "delete_tablet_documents isn't supported with append_only"
+        );
+        let _writer = self.writer.lock().await;
         let mut inner = self.inner.lock();
         let tx = inner.connection.transaction()?;
-        let mut delete_table_documents_query = tx.prepare_cached(DELETE_TABLE_DOCUMENTS)?;
+        let mut delete_table_documents_query =
+            tx.prepare_cached(&self.sql(DELETE_TABLE_DOCUMENTS))?;
         let count_deleted = delete_table_documents_query.execute(params![
             &tablet_id.0[..],
             &tablet_id.0[..],
             chunk_size,
         ])?;
         drop(delete_table_documents_query);
+        tx.prepare_cached(&self.sql(BUMP_GENERATION))?.execute([])?;
         tx.commit()?;
         Ok(count_deleted)
     }
+
+    async fn delete_range(
+        &self,
+        range: TimestampRange,
+        retain_latest: bool,
+    ) -> anyhow::Result<u64> {
+        anyhow::ensure!(
+            !self.append_only,
+            "delete_range isn't supported with append_only"
+        );
+        let params = params![
+            &u64::from(range.min_timestamp_inclusive()),
+            &u64::from(range.max_timestamp_exclusive()),
+        ];
+        let _writer = self.writer.lock().await;
+        let mut inner = self.inner.lock();
+        let tx = inner.connection.transaction()?;
+        tx.prepare_cached(&self.sql(DELETE_RANGE_INDEXES_RETAIN_LATEST))?
+            .execute(params)?;
+        let count_deleted = tx
+            .prepare_cached(&self.sql(DELETE_RANGE_DOCUMENTS_RETAIN_LATEST))?
+            .execute(params)?;
+        // Whatever is left in the range is needed, so without `retain_latest`
+        // the whole call fails, rolling back the deletes above.
+        if !retain_latest {
+            let retained: bool = tx
+                .prepare_cached(&self.sql(RANGE_HAS_ENTRIES))?
+                .query_row(params, |row| row.get(0))?;
+            anyhow::ensure!(
+                !retained,
+                "delete_range would delete the latest revision of a live document or key, or a \
+                 deletion older revisions depend on; pass retain_latest to keep them"
+            );
+        }
+        tx.prepare_cached(&self.sql(BUMP_GENERATION))?.execute([])?;
+        tx.commit()?;
+        Ok(count_deleted as u64)
+    }
+
+    async fn truncate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            !self.append_only,
+            "truncate isn't supported with append_only"
+        );
+        let _writer = self.writer.lock().await;
+        let mut inner = self.inner.lock();
+        let tx = inner.connection.transaction()?;
+        tx.execute_batch(&self.sql(TRUNCATE))?;
+        tx.execute(&self.sql(BUMP_GENERATION), [])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    async fn rebuild_index(
+        &self,
+        index_id: IndexId,
+        tablet_id: TabletId,
+        key_fn: &(dyn Fn(&ResolvedDocument) -> Vec<u8> + Send + Sync),
+    ) -> anyhow::Result<u64> {
+        let _writer = self.writer.lock().await;
+        self._rebuild_index_inner(index_id, tablet_id, key_fn)
+            .map_err(error::classify)
+    }
+
+    async fn begin(&self) -> anyhow::Result<Box<dyn PersistenceTransaction>> {
+        let transaction = SqliteTransaction::begin(self)
+            .await
+            .map_err(error::classify)?;
+        Ok(Box::new(transaction))
+    }
+
+    async fn fsync_barrier(&self) -> anyhow::Result<()> {
+        let _writer = self.writer.lock().await;
+        self.sync_database_file().map_err(error::classify)
+    }
+
+    async fn shutdown(&self) -> anyhow::Result<()> {
+        let _writer = self.writer.lock().await;
+        self.flush_wal()
+    }
 }
 
 #[async_trait]
@@ -464,33 +2036,177 @@ impl PersistenceReader for SqlitePersistence {
         _page_size: u32,
         retention_validator: Arc<dyn RetentionValidator>,
     ) -> DocumentStream<'_> {
-        let triples = try {
-            let connection = &self.inner.lock().connection;
-            let load_docs_query = load_docs(range, order);
-            let mut stmt = connection.prepare(load_docs_query.as_str())?;
+        let query = load_docs(&self.table_name("documents"), range, order, "");
+        self.load_documents_query(&query, &[], range, retention_validator)
+    }
+
+    fn load_documents_with_limit(
+        &self,
+        range: TimestampRange,
+        order: Order,
+        page_size: u32,
+        total_limit: Option<usize>,
+        retention_validator: Arc<dyn RetentionValidator>,
+    ) -> DocumentStream<'_> {
+        let Some(total_limit) = total_limit else {
+            return self.load_documents(range, order, page_size, retention_validator);
+        };
+        let mut query = load_docs(&self.table_name("documents"), range, order, "");
+        query.push_str("LIMIT $1");
+        let total_limit = i64::try_from(total_limit).unwrap_or(i64::MAX);
+        self.load_documents_query(&query, params![total_limit], range, retention_validator)
+    }
+
+    fn load_documents_where(
+        &self,
+        range: TimestampRange,
+        field: &str,
+        op: CompareOp,
+        value: ConvexValue,
+        order: Order,
+        page_size: u32,
+        retention_validator: Arc<dyn RetentionValidator>,
+    ) -> DocumentStream<'_> {
+        // Sqlite compares text bytewise, like `ConvexValue`s compare strings,
+        // so string comparisons can be pushed down. Other values are encoded
+        // in ways `json_extract` can't compare, so are only filtered below.
+        let stream = match &value {
+            ConvexValue::String(string) if !field.contains('"') => {
+                let sql_op = match op {
+                    CompareOp::Eq => "=",
+                    CompareOp::Ne => "!=",
+                    CompareOp::Lt => "<",
+                    CompareOp::Lte => "<=",
+                    CompareOp::Gt => ">",
+                    CompareOp::Gte => ">=",
+                };
+                let query = load_docs(
+                    &self.table_name("documents"),
+                    range,
+                    order,
+                    &load_docs_string_filter(sql_op),
+                );
+                let path = format!("$.\"{field}\"");
+                let string: &str = string;
+                self.load_documents_query(&query, params![path, string], range, retention_validator)
+            },
+            _ => self.load_documents(range, order, page_size, retention_validator),
+        };
+        let field = field.to_owned();
+        stream
+            .try_filter(move |entry| future::ready(entry.field_matches(&field, op, &value)))
+            .boxed()
+    }
+
+    fn load_snapshot(
+        &self,
+        tablet_id: TabletId,
+        ts: Timestamp,
+        order: Order,
+        _page_size: u32,
+        retention_validator: Arc<dyn RetentionValidator>,
+    ) -> DocumentStream<'_> {
+        let entries = self.with_read_connection(|connection| {
+            let mut stmt = connection.prepare_cached(&self.sql(&load_snapshot_query(order)))?;
+            let params = params![&tablet_id.0[..], &u64::from(ts)];
+            let mut entries = vec![];
+            for row in stmt.query_map(params, load_document_row)? {
+                let (id, ts, value, prev_ts) = row_to_document(row)?;
+                entries.push(Ok(DocumentLogEntry {
+                    ts,
+                    id,
+                    value,
+                    prev_ts,
+                }));
+            }
+            Ok(entries)
+        });
+        // Like index_scan, this reads the latest state as of `ts`.
+        let validate = self.validate_snapshot(ts, retention_validator);
+        match entries {
+            Ok(s) => validate.chain(stream::iter(s).cooperative()).boxed(),
+            Err(e) => stream::once(async { Err(error::classify(e)) }).boxed(),
+        }
+    }
 
+    fn load_document_history(
+        &self,
+        id: InternalDocumentId,
+        range: TimestampRange,
+        order: Order,
+        retention_validator: Arc<dyn RetentionValidator>,
+    ) -> DocumentStream<'_> {
+        let entries = self.with_read_connection(|connection| {
+            let mut stmt =
+                connection.prepare_cached(&self.sql(&load_document_history_query(order)))?;
+            let internal_id = id.internal_id();
+            let params = params![
+                &id.table().0[..],
+                &internal_id[..],
+                &u64::from(range.min_timestamp_inclusive()),
+                &u64::from(range.max_timestamp_exclusive()),
+            ];
             let mut entries = vec![];
-            for row in stmt.query_map([], load_document_row)? {
-                let (document_id, ts, document, prev_ts) = row_to_document(row)?;
+            for row in stmt.query_map(params, load_document_row)? {
+                let (id, ts, value, prev_ts) = row_to_document(row)?;
                 entries.push(Ok(DocumentLogEntry {
                     ts,
-                    id: document_id,
-                    value: document,
+                    id,
+                    value,
                     prev_ts,
                 }));
             }
-            entries
-        };
-        // load_documents isn't async so we have to validate snapshot as part of the
-        // stream.
+            Ok(entries)
+        });
         let validate =
             self.validate_document_snapshot(range.min_timestamp_inclusive(), retention_validator);
-        match triples {
+        match entries {
             Ok(s) => validate.chain(stream::iter(s).cooperative()).boxed(),
-            Err(e) => stream::once(async { Err(e) }).boxed(),
+            Err(e) => stream::once(async { Err(error::classify(e)) }).boxed(),
+        }
+    }
+
+    fn distinct_timestamps(&self, range: TimestampRange, order: Order) -> TimestampStream<'_> {
+        let timestamps = self.with_read_connection(|connection| {
+            let query = distinct_timestamps_query(&self.table_name("documents"), order);
+            let mut stmt = connection.prepare_cached(&query)?;
+            let params = params![
+                &u64::from(range.min_timestamp_inclusive()),
+                &u64::from(range.max_timestamp_exclusive()),
+            ];
+            let mut timestamps = vec![];
+            for row in stmt.query_map(params, |row| row.get::<_, u64>(0))? {
+                timestamps.push(Ok(Timestamp::try_from(row?)?));
+            }
+            Ok(timestamps)
+        });
+        match timestamps {
+            Ok(s) => stream::iter(s).cooperative().boxed(),
+            Err(e) => stream::once(async { Err(error::classify(e)) }).boxed(),
         }
     }
 
+    async fn count_documents(&self, range: TimestampRange) -> anyhow::Result<u64> {
+        self.with_read_connection(|connection| {
+            Ok(connection.query_row(&self.sql(&count_docs(range)), [], |row| row.get(0))?)
+        })
+    }
+
+    async fn index_entry_count(
+        &self,
+        index_id: IndexId,
+        tablet_id: TabletId,
+        read_timestamp: Timestamp,
+    ) -> anyhow::Result<u64> {
+        let params =
+            live_index_entry_params(index_id, tablet_id, read_timestamp, &Interval::all())?;
+        let query = format!("SELECT COUNT(*) {}", self.live_index_entries(false));
+        self.with_read_connection(|connection| {
+            let mut stmt = connection.prepare_cached(&query)?;
+            Ok(stmt.query_row(params_from_iter(&params), |row| row.get(0))?)
+        })
+    }
+
     async fn previous_revisions(
         &self,
         ids: BTreeSet<(InternalDocumentId, Timestamp)>,
@@ -498,11 +2214,10 @@ impl PersistenceReader for SqlitePersistence {
     ) -> anyhow::Result<BTreeMap<(InternalDocumentId, Timestamp), DocumentLogEntry>> {
         let mut out = BTreeMap::new();
         let mut min_ts = Timestamp::MAX;
-        {
-            let inner = self.inner.lock();
+        self.with_read_connection(|connection| {
             for (id, ts) in ids {
                 min_ts = cmp::min(ts, min_ts);
-                let mut stmt = inner.connection.prepare(PREV_REV_QUERY)?;
+                let mut stmt = connection.prepare_cached(&self.sql(PREV_REV_QUERY))?;
                 let internal_id = id.internal_id();
                 let params = params![&id.table().0[..], &internal_id[..], &u64::from(ts)];
                 let mut row_iter = stmt.query_map(params, load_document_row)?;
@@ -519,13 +2234,137 @@ impl PersistenceReader for SqlitePersistence {
                     );
                 }
             }
-        }
+            Ok(())
+        })?;
         retention_validator
             .validate_document_snapshot(min_ts)
             .await?;
         Ok(out)
     }
 
+    async fn load_documents_by_ids(
+        &self,
+        ids: &[InternalDocumentId],
+        ts: Timestamp,
+    ) -> anyhow::Result<Vec<Option<DocumentLogEntry>>> {
+        let keys: Vec<_> = ids
+            .iter()
+            .map(|id| (id.table().0, id.internal_id()))
+            .collect();
+        let ts = u64::from(ts);
+        let documents = self.table_name("documents");
+        let mut latest = BTreeMap::new();
+        self.with_read_connection(|connection| {
+            // Large id sets take several queries, which should all read the
+            // same snapshot. A snapshot's connection is already in one.
+            let _tx = if connection.is_autocommit() {
+                Some(connection.unchecked_transaction()?)
+            } else {
+                None
+            };
+            for chunk in keys.chunks(MAX_IDS_PER_QUERY) {
+                let mut stmt = connection
+                    .prepare_cached(&load_documents_by_ids_query(&documents, chunk.len()))?;
+                let chunk: Vec<(&[u8], &[u8])> = chunk
+                    .iter()
+                    .map(|(table_id, internal_id)| (&table_id[..], &internal_id[..]))
+                    .collect();
+                let mut params: Vec<&dyn ToSql> = vec![&ts];
+                for (table_id, internal_id) in &chunk {
+                    params.push(table_id);
+                    params.push(internal_id);
+                }
+                for row in stmt.query_map(&params[..], load_document_row)? {
+                    let (id, ts, value, prev_ts) = row_to_document(row)?;
+                    latest.insert(
+                        id,
+                        DocumentLogEntry {
+                            ts,
+                            id,
+                            value,
+                            prev_ts,
+                        },
+                    );
+                }
+            }
+            Ok(())
+        })?;
+        Ok(ids.iter().map(|id| latest.get(id).cloned()).collect())
+    }
+
+    async fn min_timestamp(&self) -> anyhow::Result<Option<Timestamp>> {
+        self.with_read_connection(|connection| {
+            let ts: Option<u64> =
+                connection.query_row(&self.sql(MIN_TIMESTAMP), [], |row| row.get(0))?;
+            ts.map(Timestamp::try_from).transpose()
+        })
+    }
+
+    async fn tablet_ids(&self) -> anyhow::Result<Vec<TabletId>> {
+        self.with_read_connection(|connection| {
+            let mut stmt = connection.prepare_cached(&self.sql(TABLET_IDS))?;
+            let mut tablet_ids = vec![];
+            for row in stmt.query_map([], |row| row.get::<_, Vec<u8>>(0))? {
+                tablet_ids.push(TabletId(row?.try_into()?));
+            }
+            Ok(tablet_ids)
+        })
+    }
+
+    async fn document_counts_by_tablet(&self) -> anyhow::Result<BTreeMap<TabletId, u64>> {
+        self.with_read_connection(|connection| {
+            let mut stmt = connection.prepare_cached(&self.sql(DOCUMENT_COUNTS_BY_TABLET))?;
+            let mut counts = BTreeMap::new();
+            for row in stmt.query_map([], |row| Ok((row.get::<_, Vec<u8>>(0)?, row.get(1)?)))? {
+                let (tablet_id, count) = row?;
+                counts.insert(TabletId(tablet_id.try_into()?), count);
+            }
+            Ok(counts)
+        })
+    }
+
+    async fn generation(&self) -> anyhow::Result<u64> {
+        self.with_read_connection(|connection| {
+            let generation: Option<u64> = connection
+                .query_row(&self.sql(GET_GENERATION), [], |row| row.get(0))
+                .optional()?;
+            Ok(generation.unwrap_or(0))
+        })
+    }
+
+    async fn max_timestamp(&self) -> anyhow::Result<Option<Timestamp>> {
+        self.with_read_connection(|connection| {
+            let ts: Option<u64> =
+                connection.query_row(&self.sql(MAX_TIMESTAMP), [], |row| row.get(0))?;
+            ts.map(Timestamp::try_from).transpose()
+        })
+    }
+
+    async fn load_document(
+        &self,
+        id: InternalDocumentId,
+        ts: Timestamp,
+    ) -> anyhow::Result<Option<DocumentLogEntry>> {
+        self.with_read_connection(|connection| {
+            let mut stmt = connection.prepare_cached(&self.sql(LOAD_DOCUMENT_QUERY))?;
+            let internal_id = id.internal_id();
+            let params = params![&id.table().0[..], &internal_id[..], &u64::from(ts)];
+            let mut row_iter = stmt.query_map(params, load_document_row)?;
+            row_iter
+                .next()
+                .map(|row| {
+                    let (id, ts, value, prev_ts) = row_to_document(row)?;
+                    Ok(DocumentLogEntry {
+                        ts,
+                        id,
+                        value,
+                        prev_ts,
+                    })
+                })
+                .transpose()
+        })
+    }
+
     async fn previous_revisions_of_documents(
         &self,
         ids: BTreeSet<DocumentPrevTsQuery>,
@@ -535,10 +2374,9 @@ impl PersistenceReader for SqlitePersistence {
         let min_ts = ids.iter().map(|DocumentPrevTsQuery { ts, .. }| *ts).min();
 
         let mut out = BTreeMap::new();
-        {
-            let inner = self.inner.lock();
+        self.with_read_connection(|connection| {
             for DocumentPrevTsQuery { id, ts, prev_ts } in ids {
-                let mut stmt = inner.connection.prepare(EXACT_REV_QUERY)?;
+                let mut stmt = connection.prepare_cached(&self.sql(EXACT_REV_QUERY))?;
                 let internal_id = id.internal_id();
                 let params = params![&id.table().0[..], &internal_id[..], &u64::from(prev_ts)];
                 let mut row_iter = stmt.query_map(params, load_document_row)?;
@@ -559,7 +2397,8 @@ impl PersistenceReader for SqlitePersistence {
                     );
                 }
             }
-        }
+            Ok(())
+        })?;
         if let Some(min_ts) = min_ts {
             retention_validator
                 .validate_document_snapshot(min_ts)
@@ -578,13 +2417,56 @@ impl PersistenceReader for SqlitePersistence {
         _size_hint: usize,
         retention_validator: Arc<dyn RetentionValidator>,
     ) -> IndexStream<'_> {
-        let triples = self._index_scan_inner(index_id, tablet_id, read_timestamp, interval, order);
         // index_scan isn't async so we have to validate snapshot as part of the stream.
         let validate = self.validate_snapshot(read_timestamp, retention_validator);
-        match triples {
-            Ok(s) => (validate.chain(stream::iter(s))).boxed(),
-            Err(e) => stream::once(async { Err(e) }).boxed(),
+        if self.documents_only {
+            return validate.boxed();
         }
+        let entries =
+            self.index_scan_chunks(index_id, tablet_id, read_timestamp, interval.clone(), order);
+        validate.chain(entries).boxed()
+    }
+
+    fn index_scan_ids(
+        &self,
+        index_id: IndexId,
+        tablet_id: TabletId,
+        read_timestamp: Timestamp,
+        interval: &Interval,
+        order: Order,
+        limit: Option<usize>,
+        retention_validator: Arc<dyn RetentionValidator>,
+    ) -> DocumentIdStream<'_> {
+        let validate = self.validate_snapshot(read_timestamp, retention_validator);
+        if self.documents_only {
+            return validate.boxed();
+        }
+        let ids =
+            self._index_scan_ids_inner(index_id, tablet_id, read_timestamp, interval, order, limit);
+        match ids {
+            Ok(ids) => validate.chain(stream::iter(ids)).boxed(),
+            Err(e) => stream::once(async { Err(error::classify(e)) }).boxed(),
+        }
+    }
+
+    async fn index_exists(
+        &self,
+        index_id: IndexId,
+        tablet_id: TabletId,
+        read_timestamp: Timestamp,
+        interval: &Interval,
+    ) -> anyhow::Result<bool> {
+        self._index_exists_inner(index_id, tablet_id, read_timestamp, interval)
+            .map_err(error::classify)
+    }
+
+    /// Requires a read pool in WAL mode. The snapshot keeps one of the pool's
+    /// connections in a read transaction until the returned reader and all of
+    /// its clones are dropped, and also stops checkpoints from moving past it.
+    /// Fails rather than take the pool's last idle connection, so snapshots
+    /// can't leave other reads waiting on them.
+    fn snapshot_at(&self, ts: Timestamp) -> anyhow::Result<SnapshotReader> {
+        self._snapshot_at_inner(ts).map_err(error::classify)
     }
 
     async fn get_persistence_global(
@@ -599,6 +2481,25 @@ impl PersistenceReader for SqlitePersistence {
     }
 }
 
+// Matches rusqlite's default for new connections.
+const DEFAULT_BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Comfortably more than the number of distinct statements, so a mix of reads
+// and writes doesn't evict the write statements from rusqlite's default of 16.
+const DEFAULT_STATEMENT_CACHE_CAPACITY: usize = 64;
+
+// Sqlite limits a statement to 32766 bound variables, and every insert binds 6
+// per row.
+pub const MAX_ROWS_PER_STATEMENT: usize = 32766 / 6;
+pub const DEFAULT_MAX_ROWS_PER_STATEMENT: usize = 500;
+pub const DEFAULT_INDEX_SCAN_CHUNK_SIZE: usize = 256;
+// Each id in `load_documents_by_ids` binds 2 variables, after the timestamp.
+const MAX_IDS_PER_QUERY: usize = (32766 - 1) / 2;
+
+// Rough per-row cost of the fixed-size columns and btree bookkeeping, used to
+// estimate how many pages a write touches.
+const ROW_OVERHEAD_BYTES: usize = 64;
+
 const DOCUMENTS_INIT: &str = r#"
 CREATE TABLE IF NOT EXISTS documents (
     id BLOB NOT NULL,
@@ -641,6 +2542,106 @@ CREATE TABLE IF NOT EXISTS persistence_globals (
 );
 "#;
 
+// A single row holding the number of committed writes.
+const GENERATION_INIT: &str = r#"
+CREATE TABLE IF NOT EXISTS generation (
+    id INTEGER NOT NULL CHECK (id = 0),
+    generation INTEGER NOT NULL,
+
+    PRIMARY KEY (id)
+);
+"#;
+
+// The tables and indexes that `SqliteOptions::namespace` renames.
+const NAMESPACED_NAMES: &[&str] = &[
+    "documents",
+    "documents_by_table_and_id",
+    "indexes",
+    "persistence_globals",
+    "generation",
+    "schema_version",
+];
+
+// The keywords that a table or index name follows in this crate's queries.
+const NAME_KEYWORDS: &[&str] = &["EXISTS", "FROM", "INTO", "JOIN", "ON", "TABLE", "UPDATE"];
+
+fn namespaced_name(name: &str, namespace: Option<&str>) -> String {
+    match namespace {
+        Some(namespace) => format!("{namespace}_{name}"),
+        None => name.to_owned(),
+    }
+}
+
+/// Renames the tables and indexes that `query` refers to for `namespace`.
+/// This isn't a general SQL rewriter: it relies on this crate's queries
+/// naming them only right after one of `NAME_KEYWORDS`, or to qualify a
+/// column, which also keeps it from renaming columns that share a name with
+/// a table.
+fn namespaced<'a>(query: &'a str, namespace: Option<&str>) -> Cow<'a, str> {
+    qualified(query, namespace, None)
+}
+
+/// Like `namespaced`, but also qualifies the tables that follow one of
+/// `NAME_KEYWORDS` with `schema`. Column qualifiers are left alone, since
+/// Sqlite resolves them by table name whatever the schema.
+fn qualified<'a>(query: &'a str, namespace: Option<&str>, schema: Option<&str>) -> Cow<'a, str> {
+    if namespace.is_none() && schema.is_none() {
+        return Cow::Borrowed(query);
+    }
+    let is_name_char = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let mut result = String::with_capacity(query.len());
+    let mut previous = "";
+    let mut rest = query;
+    while let Some(start) = rest.find(is_name_char) {
+        let (before, word) = rest.split_at(start);
+        let (word, after) = word.split_at(word.find(|c| !is_name_char(c)).unwrap_or(word.len()));
+        result.push_str(before);
+        if NAMESPACED_NAMES.contains(&word) {
+            let follows_keyword = NAME_KEYWORDS
+                .iter()
+                .any(|keyword| keyword.eq_ignore_ascii_case(previous));
+            if follows_keyword && let Some(schema) = schema {
+                result.push_str(schema);
+                result.push('.');
+            }
+            if (follows_keyword || after.starts_with('.'))
+                && let Some(namespace) = namespace
+            {
+                result.push_str(namespace);
+                result.push('_');
+            }
+        }
+        result.push_str(word);
+        previous = word;
+        rest = after;
+    }
+    result.push_str(rest);
+    Cow::Owned(result)
+}
+
+/// Switches `connection` to WAL mode and reads through the WAL index, which
+/// fails if the filesystem can't provide the shared memory it's kept in.
+/// Sqlite just keeps the current journal mode if the VFS doesn't support
+/// shared memory at all, so check that too.
+fn enable_wal(connection: &Connection) -> anyhow::Result<()> {
+    let journal_mode: String =
+        connection.query_row("PRAGMA journal_mode=WAL", [], |row| row.get(0))?;
+    anyhow::ensure!(
+        journal_mode.eq_ignore_ascii_case("wal"),
+        "Sqlite kept journal mode {journal_mode}"
+    );
+    connection.query_row("SELECT COUNT(*) FROM sqlite_master", [], |_| Ok(()))?;
+    Ok(())
+}
+
+fn is_busy_error(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<rusqlite::Error>()
+            .and_then(|e| e.sqlite_error_code()),
+        Some(ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked)
+    )
+}
+
 fn row_to_document(
     row: rusqlite::Result<(Vec<u8>, u64, Vec<u8>, Option<String>, bool, Option<u64>)>,
 ) -> anyhow::Result<(
@@ -667,7 +2668,7 @@ fn row_to_document(
     Ok((document_id, prev_ts, document, prev_prev_ts))
 }
 
-fn load_docs(range: TimestampRange, order: Order) -> String {
+fn load_docs(documents: &str, range: TimestampRange, order: Order, filter: &str) -> String {
     let order_str = match order {
         Order::Asc => " ORDER BY ts ASC, table_id ASC, id ASC ",
         Order::Desc => " ORDER BY ts DESC, table_id DESC, id DESC ",
@@ -675,36 +2676,294 @@ fn load_docs(range: TimestampRange, order: Order) -> String {
     format!(
         r#"
 SELECT id, ts, table_id, json_value, deleted, prev_ts
-FROM documents
-WHERE ts >= {} AND ts < {}
+FROM {}
+WHERE ts >= {} AND ts < {} {}
 {}
 "#,
+        documents,
         range.min_timestamp_inclusive(),
         range.max_timestamp_exclusive(),
+        filter,
         order_str,
     )
 }
 
+// A `load_docs` filter that skips uncompressed revisions whose field at JSON
+// path $1 is a string that doesn't compare to $2 with `op`. Compressed values
+// are BLOBs that `json_extract` can't read, so those are all kept.
+fn load_docs_string_filter(op: &str) -> String {
+    format!(
+        "AND (typeof(json_value) != 'text' OR json_type(json_value, $1) IS NOT 'text' OR \
+         json_extract(json_value, $1) {op} $2)"
+    )
+}
+
+fn distinct_timestamps_query(documents: &str, order: Order) -> String {
+    let order = match order {
+        Order::Asc => "ASC",
+        Order::Desc => "DESC",
+    };
+    format!(
+        r#"
+SELECT DISTINCT ts
+FROM {documents}
+WHERE ts >= $1 AND ts < $2
+ORDER BY ts {order}
+"#
+    )
+}
+
+// The subquery is served by the documents_by_table_and_id index. $1 is the
+// read timestamp, followed by a (table_id, id) pair per document.
+fn load_documents_by_ids_query(documents: &str, ids: usize) -> String {
+    let values = vec!["(?, ?)"; ids].join(", ");
+    format!(
+        r#"
+SELECT D.id, D.ts, D.table_id, D.json_value, D.deleted, D.prev_ts
+FROM {documents} D
+JOIN (
+    SELECT table_id, id, MAX(ts) AS max_ts
+    FROM {documents}
+    WHERE ts <= $1 AND (table_id, id) IN (VALUES {values})
+    GROUP BY table_id, id
+) L
+ON D.table_id = L.table_id
+AND D.id = L.id
+AND D.ts = L.max_ts
+"#
+    )
+}
+
+// The subquery is served by the documents_by_table_and_id index.
+fn load_snapshot_query(order: Order) -> String {
+    let order = match order {
+        Order::Asc => "ASC",
+        Order::Desc => "DESC",
+    };
+    format!(
+        r#"
+SELECT id, ts, table_id, json_value, deleted, prev_ts
+FROM documents D
+WHERE
+    table_id = $1 AND
+    ts <= $2 AND
+    deleted = 0 AND
+    ts = (
+        SELECT MAX(ts) FROM documents
+        WHERE table_id = D.table_id AND id = D.id AND ts <= $2
+    )
+ORDER BY ts {order}, id {order}
+"#
+    )
+}
+
+/// Uses the `documents_by_table_and_id` index, so only the requested document's
+/// revisions are read.
+fn load_document_history_query(order: Order) -> String {
+    let order = match order {
+        Order::Asc => "ASC",
+        Order::Desc => "DESC",
+    };
+    format!(
+        r#"
+SELECT id, ts, table_id, json_value, deleted, prev_ts
+FROM documents
+WHERE
+    table_id = $1 AND
+    id = $2 AND
+    ts >= $3 AND
+    ts < $4
+ORDER BY ts {order}
+"#
+    )
+}
+
+fn count_docs(range: TimestampRange) -> String {
+    format!(
+        "SELECT COUNT(*) FROM documents WHERE ts >= {} AND ts < {}",
+        range.min_timestamp_inclusive(),
+        range.max_timestamp_exclusive(),
+    )
+}
+
 fn load_document_row(
     row: &Row<'_>,
 ) -> rusqlite::Result<(Vec<u8>, u64, Vec<u8>, Option<String>, bool, Option<u64>)> {
     let id = row.get::<_, Vec<u8>>(0)?;
     let ts = row.get::<_, u64>(1)?;
     let table: Vec<u8> = row.get(2)?;
-    let json_value: Option<String> = row.get(3)?;
+    let json_value = row.get::<_, Option<StoredJson>>(3)?.map(|v| v.0);
     let deleted = row.get::<_, u32>(4)? != 0;
     let prev_ts: Option<u64> = row.get(5)?;
     Ok((id, ts, table, json_value, deleted, prev_ts))
 }
 
+// Both are answered from the primary key, which leads with ts.
+const MIN_TIMESTAMP: &str = "SELECT MIN(ts) FROM documents";
+const MAX_TIMESTAMP: &str = "SELECT MAX(ts) FROM documents";
+// Served by the documents_by_table_and_id index.
+const TABLET_IDS: &str = "SELECT DISTINCT table_id FROM documents ORDER BY table_id";
+const DOCUMENT_COUNTS_BY_TABLET: &str =
+    "SELECT table_id, COUNT(*) FROM documents GROUP BY table_id";
+const TRUNCATE: &str = r#"
+DELETE FROM documents;
+DELETE FROM indexes;
+"#;
+
+const GET_GENERATION: &str = "SELECT generation FROM generation WHERE id = 0";
+const BUMP_GENERATION: &str = "INSERT INTO generation VALUES (0, 1) ON CONFLICT (id) DO UPDATE \
+                               SET generation = generation + 1";
+
 const GET_PERSISTENCE_GLOBAL: &str = "SELECT json_value FROM persistence_globals WHERE key = ?";
 
+/// The write connection lent out by `SqlitePersistence::with_connection`,
+/// read-only until this is dropped. Dropping it ends any transaction left open
+/// and makes the connection writable again, even if the borrower panicked,
+/// so the write connection can't be left read-only.
+struct ReadOnlyLoan<'a>(&'a Connection);
+
+impl<'a> ReadOnlyLoan<'a> {
+    fn new(connection: &'a Connection) -> rusqlite::Result<Self> {
+        connection.execute_batch("PRAGMA query_only=ON;")?;
+        Ok(Self(connection))
+    }
+}
+
+impl Drop for ReadOnlyLoan<'_> {
+    fn drop(&mut self) {
+        pool::end_transaction(self.0);
+        if let Err(e) = self.0.execute_batch("PRAGMA query_only=OFF;") {
+            tracing::error!("Failed to make the Sqlite write connection writable again: {e:#}");
+        }
+    }
+}
+
+/// Records an index scan's metrics once it's dropped, so that scans which fail
+/// or which the consumer stops reading early are recorded too. Only time spent
+/// in Sqlite counts, not time spent by the consumer.
+struct IndexScanMetrics {
+    recorder: Option<Arc<dyn MetricsRecorder>>,
+    elapsed: Duration,
+    rows: usize,
+}
+
+impl Drop for IndexScanMetrics {
+    fn drop(&mut self) {
+        if let Some(recorder) = &self.recorder {
+            recorder.record_index_scan(self.elapsed, self.rows);
+        }
+    }
+}
+
+/// The parameters of `SqlitePersistence::live_index_entries` for `interval`.
+/// Sqlite compares blobs bytewise, matching `Interval`'s key order.
+fn live_index_entry_params(
+    index_id: IndexId,
+    tablet_id: TabletId,
+    read_timestamp: Timestamp,
+    interval: &Interval,
+) -> anyhow::Result<Vec<SqlValue>> {
+    let StartIncluded(ref start) = interval.start;
+    let mut params = vec![
+        SqlValue::Blob(index_id[..].to_vec()),
+        SqlValue::Blob(tablet_id.0[..].to_vec()),
+        SqlValue::Integer(u64::from(read_timestamp).try_into()?),
+        SqlValue::Blob(start.to_vec()),
+    ];
+    if let End::Excluded(ref end) = interval.end {
+        params.push(SqlValue::Blob(end.to_vec()));
+    }
+    Ok(params)
+}
+
+/// Extends a single-row `INSERT ... VALUES (?, ...)` statement to insert
+/// `rows` rows at once.
+fn multi_row_insert(insert: &str, rows: usize) -> String {
+    let (_, placeholders) = insert
+        .rsplit_once(" VALUES ")
+        .expect("INSERT statement must end in VALUES");
+    let mut statement = insert.to_owned();
+    for _ in 1..rows {
+        statement.push_str(", ");
+        statement.push_str(placeholders);
+    }
+    statement
+}
+
+/// Finds the row of `chunk` that failed to insert because another row with the
+/// same key exists, either already in the database or earlier in `chunk`.
+fn find_document_conflict(
+    connection: &Connection,
+    namespace: Option<&str>,
+    chunk: &[DocumentLogEntry],
+) -> anyhow::Result<Option<Conflict>> {
+    let mut seen = BTreeSet::new();
+    for update in chunk {
+        let exists = connection
+            .prepare_cached(&namespaced(GET_DOCUMENT_REVISION, namespace))?
+            .query_row(
+                params![
+                    &u64::from(update.ts),
+                    &update.id.table().0[..],
+                    &update.id.internal_id()[..],
+                ],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some();
+        if exists || !seen.insert((update.id, update.ts)) {
+            return Ok(Some(Conflict::Document {
+                id: update.id,
+                ts: update.ts,
+            }));
+        }
+    }
+    Ok(None)
+}
+
+/// Like `find_document_conflict`, for index entries.
+fn find_index_conflict(
+    connection: &Connection,
+    namespace: Option<&str>,
+    chunk: &[PersistenceIndexEntry],
+) -> anyhow::Result<Option<Conflict>> {
+    let mut seen = BTreeSet::new();
+    for update in chunk {
+        let exists = connection
+            .prepare_cached(&namespaced(GET_INDEX_ENTRY, namespace))?
+            .query_row(
+                params![&update.index_id[..], &update.key.0, &u64::from(update.ts)],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some();
+        if exists || !seen.insert((update.index_id, &update.key, update.ts)) {
+            return Ok(Some(Conflict::Index {
+                index_id: update.index_id,
+                key: update.key.clone(),
+                ts: update.ts,
+            }));
+        }
+    }
+    Ok(None)
+}
+
 const INSERT_DOCUMENT: &str = "INSERT INTO documents (id, ts, table_id, json_value, deleted, \
                                prev_ts) VALUES (?, ?, ?, ?, ?, ?)";
 const INSERT_OVERWRITE_DOCUMENT: &str = "INSERT OR REPLACE INTO documents (id, ts, table_id, \
                                          json_value, deleted, prev_ts) VALUES (?, ?, ?, ?, ?, ?)";
+const INSERT_IGNORE_DOCUMENT: &str = "INSERT OR IGNORE INTO documents (id, ts, table_id, \
+                                      json_value, deleted, prev_ts) VALUES (?, ?, ?, ?, ?, ?)";
+const CHECK_WRITE_DOCUMENT: &str = "INSERT OR IGNORE INTO documents (id, ts, table_id, \
+                                    json_value, deleted, prev_ts) VALUES (?, ?, ?, NULL, 1, NULL)";
+const CHECK_WRITE_INDEX: &str = "INSERT OR IGNORE INTO indexes VALUES (?, ?, ?, 1, NULL, NULL)";
+const GET_DOCUMENT_REVISION: &str =
+    "SELECT json_value, prev_ts FROM documents WHERE ts = ? AND table_id = ? AND id = ?";
 const INSERT_INDEX: &str = "INSERT INTO indexes VALUES (?, ?, ?, ?, ?, ?)";
 const INSERT_OVERWRITE_INDEX: &str = "INSERT OR REPLACE INTO indexes VALUES (?, ?, ?, ?, ?, ?)";
+const INSERT_IGNORE_INDEX: &str = "INSERT OR IGNORE INTO indexes VALUES (?, ?, ?, ?, ?, ?)";
+const GET_INDEX_ENTRY: &str =
+    "SELECT table_id, document_id FROM indexes WHERE index_id = ? AND key = ? AND ts = ?";
 const WRITE_PERSISTENCE_GLOBAL: &str = "INSERT OR REPLACE INTO persistence_globals VALUES (?, ?)";
 
 const WALK_INDEXES: &str =
@@ -712,11 +2971,61 @@ const WALK_INDEXES: &str =
 
 const DELETE_INDEX: &str = "DELETE FROM indexes WHERE index_id = ? AND ts <= ? AND key = ?";
 
+const DELETE_INDEX_ENTRIES: &str = "DELETE FROM indexes WHERE index_id = ?";
+
+const LOAD_TABLET_REVISIONS: &str = "SELECT id, ts, table_id, json_value, deleted, prev_ts FROM \
+                                     documents WHERE table_id = ? ORDER BY ts ASC, id ASC";
+
 const DELETE_DOCUMENT: &str = "DELETE FROM documents WHERE table_id = ? AND id = ? AND ts <= ?";
 
 const DELETE_TABLE_DOCUMENTS: &str = "DELETE FROM documents WHERE table_id = ? AND id IN (SELECT \
                                       id FROM documents WHERE table_id = ? LIMIT ?)";
 
+// Keeps each document's latest revision if it's live, or if it's a delete
+// and older revisions survive before the range, so the document doesn't come
+// back.
+const DELETE_RANGE_DOCUMENTS_RETAIN_LATEST: &str = r#"
+DELETE FROM documents
+WHERE ts >= $1 AND ts < $2
+AND NOT (
+    ts = (
+        SELECT MAX(ts) FROM documents D
+        WHERE D.table_id = documents.table_id AND D.id = documents.id
+    )
+    AND (
+        deleted = 0 OR EXISTS (
+            SELECT 1 FROM documents D
+            WHERE D.table_id = documents.table_id AND D.id = documents.id AND D.ts < $1
+        )
+    )
+)
+"#;
+
+// Like `DELETE_RANGE_DOCUMENTS_RETAIN_LATEST`, for the latest entry of each
+// index key.
+const DELETE_RANGE_INDEXES_RETAIN_LATEST: &str = r#"
+DELETE FROM indexes
+WHERE ts >= $1 AND ts < $2
+AND NOT (
+    ts = (
+        SELECT MAX(ts) FROM indexes I
+        WHERE I.index_id = indexes.index_id AND I.key = indexes.key
+    )
+    AND (
+        deleted = 0 OR EXISTS (
+            SELECT 1 FROM indexes I
+            WHERE I.index_id = indexes.index_id AND I.key = indexes.key AND I.ts < $1
+        )
+    )
+)
+"#;
+
+// Whether any entries in the range were retained.
+const RANGE_HAS_ENTRIES: &str = r#"
+SELECT EXISTS(SELECT 1 FROM documents WHERE ts >= $1 AND ts < $2)
+    OR EXISTS(SELECT 1 FROM indexes WHERE ts >= $1 AND ts < $2)
+"#;
+
 const PREV_REV_QUERY: &str = r#"
 SELECT id, ts, table_id, json_value, deleted, prev_ts
 FROM documents
@@ -728,6 +3037,22 @@ ORDER BY ts desc
 LIMIT 1
 "#;
 
+// Served by the documents_by_table_and_id index.
+const GET_PREV_TS: &str =
+    "SELECT MAX(ts) FROM documents WHERE table_id = $1 AND id = $2 AND ts < $3";
+
+// Served by the documents_by_table_and_id index.
+const LOAD_DOCUMENT_QUERY: &str = r#"
+SELECT id, ts, table_id, json_value, deleted, prev_ts
+FROM documents
+WHERE
+    table_id = $1 AND
+    id = $2 AND
+    ts <= $3
+ORDER BY ts desc
+LIMIT 1
+"#;
+
 const EXACT_REV_QUERY: &str = r#"
 SELECT id, ts, table_id, json_value, deleted, prev_ts
 FROM documents
@@ -0,0 +1,605 @@
+//! Operations for keeping the database file in shape, as opposed to reading
+//! and writing data.
+
+use std::{
+    fs::{
+        self,
+        File,
+    },
+    path::Path,
+    sync::{
+        atomic::{
+            AtomicBool,
+            Ordering,
+        },
+        Arc,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use parking_lot::MutexGuard;
+use rusqlite::{
+    backup::{
+        Backup,
+        StepResult,
+    },
+    Connection,
+    ErrorCode,
+};
+use tokio::{
+    task::JoinHandle,
+    time::MissedTickBehavior,
+};
+
+use crate::{
+    Inner,
+    PersistenceError,
+    SqlitePersistence,
+};
+
+/// How aggressively `checkpoint` moves WAL contents into the database file.
+/// See <https://www.sqlite.org/pragma.html#pragma_wal_checkpoint>.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CheckpointMode {
+    /// Checkpoint as much as possible without waiting on readers or writers.
+    Passive,
+    /// Wait for writers, then checkpoint everything.
+    Full,
+    /// Like `Full`, then wait for readers so the next writer restarts the
+    /// WAL from the beginning.
+    Restart,
+    /// Like `Restart`, and also truncate the WAL file to zero bytes.
+    Truncate,
+}
+
+impl CheckpointMode {
+    fn as_sql(&self) -> &'static str {
+        match self {
+            CheckpointMode::Passive => "PASSIVE",
+            CheckpointMode::Full => "FULL",
+            CheckpointMode::Restart => "RESTART",
+            CheckpointMode::Truncate => "TRUNCATE",
+        }
+    }
+}
+
+/// Outcome of a WAL checkpoint, as reported by `PRAGMA wal_checkpoint`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CheckpointResult {
+    /// Whether the checkpoint was blocked from completing by another
+    /// connection.
+    pub busy: bool,
+    /// Number of frames in the WAL, or -1 outside WAL mode.
+    pub log_pages: i64,
+    /// Number of those frames moved into the database, or -1 outside WAL
+    /// mode.
+    pub checkpointed_pages: i64,
+}
+
+/// A checkpoint loop started by `spawn_checkpoint_loop`, which stops when
+/// this is dropped.
+pub struct CheckpointHandle {
+    task: JoinHandle<()>,
+}
+
+impl Drop for CheckpointHandle {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Size of the database file, for capacity planning.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DatabaseStats {
+    pub page_count: u64,
+    pub page_size: u64,
+    /// Number of unused pages, which `compact` would reclaim.
+    pub freelist_count: u64,
+    /// `page_count * page_size`, which matches the size of the database file
+    /// once the WAL has been checkpointed.
+    pub file_size_bytes: u64,
+    /// Number of frames in the WAL file. Zero outside WAL mode.
+    pub wal_pages: u64,
+}
+
+/// How thoroughly to check the database for corruption when opening it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IntegrityCheck {
+    /// Don't check.
+    Skip,
+    /// `PRAGMA quick_check`, which is linear in the size of the database but
+    /// skips verifying that indexes match their tables.
+    #[default]
+    Quick,
+    /// `PRAGMA integrity_check`, which also verifies indexes.
+    Full,
+}
+
+/// Where Sqlite keeps temporary tables and indices.
+/// See <https://www.sqlite.org/pragma.html#pragma_temp_store>.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TempStore {
+    /// Use the compile-time default, which is `File` for the bundled Sqlite.
+    #[default]
+    Default,
+    /// Temporary files on disk.
+    File,
+    /// In memory, which is faster but counts against the process's memory.
+    Memory,
+}
+
+impl TempStore {
+    pub(crate) fn as_sql(&self) -> &'static str {
+        match self {
+            TempStore::Default => "DEFAULT",
+            TempStore::File => "FILE",
+            TempStore::Memory => "MEMORY",
+        }
+    }
+}
+
+/// What Sqlite does with pages freed by deletes.
+/// See <https://www.sqlite.org/pragma.html#pragma_auto_vacuum>.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AutoVacuum {
+    /// Freed pages stay on the freelist for reuse until `compact`.
+    None,
+    /// Freed pages are returned to the filesystem on every commit.
+    Full,
+    /// Freed pages stay on the freelist until `compact_incremental`.
+    Incremental,
+}
+
+impl AutoVacuum {
+    pub(crate) fn as_sql(&self) -> &'static str {
+        match self {
+            AutoVacuum::None => "NONE",
+            AutoVacuum::Full => "FULL",
+            AutoVacuum::Incremental => "INCREMENTAL",
+        }
+    }
+}
+
+/// How often Sqlite waits for writes to reach the disk, trading durability
+/// for throughput. See <https://www.sqlite.org/pragma.html#pragma_synchronous>.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Synchronous {
+    /// Never sync. A crash of the OS or a power loss can corrupt the
+    /// database, so this is only suitable for data that can be thrown away.
+    Off,
+    /// Sync at critical moments. In WAL mode, a power loss can roll back the
+    /// most recent commits but doesn't corrupt the database.
+    Normal,
+    /// Sync on every commit.
+    Full,
+    /// Like `Full`, and also sync the directory after deleting a rollback
+    /// journal.
+    Extra,
+}
+
+impl Synchronous {
+    pub(crate) fn as_sql(&self) -> &'static str {
+        match self {
+            Synchronous::Off => "OFF",
+            Synchronous::Normal => "NORMAL",
+            Synchronous::Full => "FULL",
+            Synchronous::Extra => "EXTRA",
+        }
+    }
+}
+
+/// Problems found by `verify_integrity`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IntegrityReport {
+    /// One message per problem, empty if the database is intact.
+    pub errors: Vec<String>,
+}
+
+impl IntegrityReport {
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+// See https://www.sqlite.org/fileformat.html#the_write_ahead_log
+const WAL_HEADER_BYTES: u64 = 32;
+const WAL_FRAME_HEADER_BYTES: u64 = 24;
+
+impl SqlitePersistence {
+    /// Reads page statistics for the database and its WAL.
+    pub fn stats(&self) -> anyhow::Result<DatabaseStats> {
+        let inner = self.lock_outside_transaction("stats")?;
+        let connection = &inner.connection;
+        let page_count: u64 =
+            connection.pragma_query_value(None, "page_count", |row| row.get(0))?;
+        let page_size: u64 = connection.pragma_query_value(None, "page_size", |row| row.get(0))?;
+        let freelist_count: u64 =
+            connection.pragma_query_value(None, "freelist_count", |row| row.get(0))?;
+        let wal_pages = wal_pages(connection, page_size);
+        Ok(DatabaseStats {
+            page_count,
+            page_size,
+            freelist_count,
+            file_size_bytes: page_count * page_size,
+            wal_pages,
+        })
+    }
+
+    /// Returns the number of bytes of the database file that reads may
+    /// memory-map, as configured by `SqliteOptions::mmap_size`.
+    pub fn mmap_size(&self) -> anyhow::Result<u64> {
+        self.with_read_connection(|connection| {
+            Ok(connection.pragma_query_value(None, "mmap_size", |row| row.get(0))?)
+        })
+    }
+
+    /// Returns the size of a read connection's page cache, as configured by
+    /// `SqliteOptions::cache_size`: pages if positive, KiB if negative.
+    pub fn cache_size(&self) -> anyhow::Result<i64> {
+        self.with_read_connection(|connection| {
+            Ok(connection.pragma_query_value(None, "cache_size", |row| row.get(0))?)
+        })
+    }
+
+    /// Returns where temporary tables and indices are kept, as configured by
+    /// `SqliteOptions::temp_store`.
+    pub fn temp_store(&self) -> anyhow::Result<TempStore> {
+        self.with_read_connection(|connection| {
+            let temp_store: u32 =
+                connection.pragma_query_value(None, "temp_store", |row| row.get(0))?;
+            match temp_store {
+                0 => Ok(TempStore::Default),
+                1 => Ok(TempStore::File),
+                2 => Ok(TempStore::Memory),
+                _ => anyhow::bail!("Unexpected temp_store {temp_store}"),
+            }
+        })
+    }
+
+    /// Returns the auto-vacuum mode of the database, as configured by
+    /// `SqliteOptions::auto_vacuum` when it was created.
+    pub fn auto_vacuum(&self) -> anyhow::Result<AutoVacuum> {
+        auto_vacuum(&self.inner.lock().connection)
+    }
+
+    /// Returns the synchronous level of the write connection, as configured
+    /// by `SqliteOptions::synchronous`. Reads never sync, so read connections
+    /// don't have one that matters.
+    pub fn synchronous(&self) -> anyhow::Result<Synchronous> {
+        let inner = self.inner.lock();
+        let synchronous: u32 = inner
+            .connection
+            .pragma_query_value(None, "synchronous", |row| row.get(0))?;
+        match synchronous {
+            0 => Ok(Synchronous::Off),
+            1 => Ok(Synchronous::Normal),
+            2 => Ok(Synchronous::Full),
+            3 => Ok(Synchronous::Extra),
+            _ => anyhow::bail!("Unexpected synchronous {synchronous}"),
+        }
+    }
+
+    /// Changes how many WAL pages trigger an automatic checkpoint on commit,
+    /// overriding `SqliteOptions::wal_autocheckpoint` until the persistence is
+    /// reopened. Zero disables automatic checkpoints. Applies to the write
+    /// connection now, and to each pooled connection when it's next checked
+    /// out, so this doesn't wait for readers.
+    pub fn set_wal_autocheckpoint(&self, pages: u32) -> anyhow::Result<()> {
+        self.inner
+            .lock()
+            .connection
+            .pragma_update(None, "wal_autocheckpoint", pages)?;
+        if let Some(read_pool) = &self.read_pool {
+            read_pool.set_wal_autocheckpoint(pages);
+        }
+        Ok(())
+    }
+
+    /// Returns the number of frames in the WAL file, or zero if there isn't
+    /// one. Sqlite reuses the file from the start once a checkpoint has
+    /// copied every frame, so this is the high-water mark since the WAL was
+    /// last reset rather than the number of frames still to checkpoint.
+    pub fn wal_frame_count(&self) -> anyhow::Result<u32> {
+        let inner = self.inner.lock();
+        let page_size: u64 = inner
+            .connection
+            .pragma_query_value(None, "page_size", |row| row.get(0))?;
+        Ok(u32::try_from(wal_pages(&inner.connection, page_size))?)
+    }
+
+    /// Checkpoints the WAL through the write connection, so callers don't
+    /// need a connection of their own.
+    pub fn checkpoint(&self, mode: CheckpointMode) -> anyhow::Result<CheckpointResult> {
+        let mut inner = self.lock_outside_transaction("checkpoint")?;
+        inner.pages_since_checkpoint = 0;
+        checkpoint(&inner.connection, mode)
+    }
+
+    /// Checkpoints the WAL with `mode` every `interval` on a tokio task,
+    /// until the returned handle is dropped. Results are logged and passed to
+    /// the `MetricsRecorder`, and failed checkpoints are retried on the next
+    /// tick.
+    ///
+    /// Checkpoints block on Sqlite, so each runs on tokio's blocking pool.
+    /// The loop shares this persistence's connections, so `close` can only
+    /// close them once the handle has been dropped and any checkpoint in
+    /// progress has finished.
+    pub fn spawn_checkpoint_loop(
+        &self,
+        interval: Duration,
+        mode: CheckpointMode,
+    ) -> CheckpointHandle {
+        let persistence = Arc::new(self.handle());
+        let task = tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            // The first tick completes immediately, and there's nothing to
+            // checkpoint yet.
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let start = Instant::now();
+                let checkpointer = persistence.clone();
+                let result = tokio::task::spawn_blocking(move || checkpointer.checkpoint(mode))
+                    .await
+                    .unwrap_or_else(|e| Err(e.into()));
+                match result {
+                    Ok(result) => {
+                        tracing::debug!("Sqlite checkpoint finished: {result:?}");
+                        if let Some(metrics_recorder) = &persistence.metrics_recorder {
+                            metrics_recorder.record_checkpoint(start.elapsed(), &result);
+                        }
+                    },
+                    Err(e) => tracing::warn!("Sqlite checkpoint failed: {e:#}"),
+                }
+            }
+        });
+        CheckpointHandle { task }
+    }
+
+    /// Checkpoints the WAL into the database file and closes every
+    /// connection, so that all committed writes are in the database file
+    /// itself and the WAL is removed.
+    ///
+    /// Connections shared with readers from `reader()` can't be closed here;
+    /// they close once the last reader is dropped. `Persistence::shutdown`
+    /// only checkpoints, so it may be called any number of times before this.
+    pub fn close(self) -> anyhow::Result<()> {
+        self.flush_wal()?;
+        let Self {
+            inner, read_pool, ..
+        } = self;
+        if let Some(read_pool) = read_pool
+            && let Ok(read_pool) = Arc::try_unwrap(read_pool)
+        {
+            read_pool.close()?;
+        }
+        // Sqlite removes the WAL when the last connection to the database
+        // closes, so the write connection goes last.
+        if let Ok(inner) = Arc::try_unwrap(inner) {
+            inner.into_inner().connection.close().map_err(|(_, e)| e)?;
+        }
+        Ok(())
+    }
+
+    /// Moves the entire WAL into the database file and truncates it.
+    pub(crate) fn flush_wal(&self) -> anyhow::Result<()> {
+        let CheckpointResult { busy, .. } = self.checkpoint(CheckpointMode::Truncate)?;
+        anyhow::ensure!(!busy, "WAL checkpoint was blocked by active connections");
+        Ok(())
+    }
+
+    /// Checkpoints the whole WAL with `CheckpointMode::Full` and fsyncs the
+    /// database file, so committed writes survive even if the WAL is lost.
+    /// The fsync happens regardless of `synchronous`, which may have skipped
+    /// the one Sqlite does after a checkpoint. Writes are blocked until it's
+    /// done. See `Persistence::fsync_barrier`.
+    pub(crate) fn sync_database_file(&self) -> anyhow::Result<()> {
+        let mut inner = self.inner.lock();
+        inner.pages_since_checkpoint = 0;
+        let CheckpointResult {
+            busy,
+            log_pages,
+            checkpointed_pages,
+        } = checkpoint(&inner.connection, CheckpointMode::Full)?;
+        anyhow::ensure!(
+            !busy && checkpointed_pages == log_pages,
+            "WAL checkpoint was blocked by active connections"
+        );
+        if let Some(path) = inner.connection.path()
+            && !path.is_empty()
+        {
+            File::open(path)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Copies the database to `dest` with SQLite's online backup API. The
+    /// copy can itself be opened as a `SqlitePersistence`.
+    ///
+    /// All pages are copied in a single step, so the copy reflects one
+    /// snapshot. With WAL mode and a read pool, writes may continue during the
+    /// backup; otherwise they are blocked until it finishes. Encrypted
+    /// databases can't be backed up this way.
+    pub fn backup_to(&self, dest: &Path) -> anyhow::Result<()> {
+        let mut destination = Connection::open(dest)?;
+        self.with_read_connection(|connection| {
+            let backup = Backup::new(connection, &mut destination)?;
+            match backup.step(-1)? {
+                StepResult::Done => Ok(()),
+                result => {
+                    anyhow::bail!("Backup to {} did not complete: {result:?}", dest.display())
+                },
+            }
+        })
+    }
+
+    /// Runs a full `PRAGMA integrity_check`. Corruption is reported in the
+    /// returned report rather than as an error.
+    pub fn verify_integrity(&self) -> anyhow::Result<IntegrityReport> {
+        let inner = self.lock_outside_transaction("verify_integrity")?;
+        match integrity_check(&inner.connection, IntegrityCheck::Full) {
+            Err(e) if is_corruption_error(&e) => Ok(IntegrityReport {
+                errors: vec![e.to_string()],
+            }),
+            result => result.map_err(anyhow::Error::from),
+        }
+    }
+
+    /// Rebuilds the database file with `VACUUM` and truncates the WAL,
+    /// returning the number of bytes reclaimed from the database.
+    ///
+    /// Readers may continue during compaction, but writes are blocked until
+    /// it finishes. Fails if another compaction is already in progress.
+    pub fn compact(&self) -> anyhow::Result<u64> {
+        self.run_compaction("VACUUM;")
+    }
+
+    /// Frees at most `pages` pages from the freelist, bounding the time
+    /// spent per call. This is only effective if the database was created
+    /// with incremental auto-vacuum; otherwise nothing is reclaimed.
+    pub fn compact_incremental(&self, pages: u32) -> anyhow::Result<u64> {
+        self.run_compaction(&format!("PRAGMA incremental_vacuum({pages});"))
+    }
+
+    fn run_compaction(&self, sql: &str) -> anyhow::Result<u64> {
+        let _guard = CompactionGuard::acquire(&self.compacting)?;
+        let inner = self.lock_outside_transaction("Compaction")?;
+        let size_before = database_size_bytes(&inner.connection)?;
+        inner.connection.execute_batch(sql)?;
+        // The vacuumed pages are written to the WAL, so checkpoint them back
+        // into the database file and shrink the WAL itself.
+        checkpoint(&inner.connection, CheckpointMode::Truncate)?;
+        let size_after = database_size_bytes(&inner.connection)?;
+        Ok(size_before.saturating_sub(size_after))
+    }
+
+    /// Locks the write connection, failing if a `SqliteTransaction` is open
+    /// on it, since `operation` would otherwise run inside the transaction and
+    /// see its uncommitted writes.
+    fn lock_outside_transaction(&self, operation: &str) -> anyhow::Result<MutexGuard<'_, Inner>> {
+        let inner = self.inner.lock();
+        anyhow::ensure!(
+            inner.connection.is_autocommit(),
+            "{operation} can't run while a transaction is open"
+        );
+        Ok(inner)
+    }
+}
+
+pub(crate) fn auto_vacuum(connection: &Connection) -> anyhow::Result<AutoVacuum> {
+    let auto_vacuum: u32 = connection.pragma_query_value(None, "auto_vacuum", |row| row.get(0))?;
+    match auto_vacuum {
+        0 => Ok(AutoVacuum::None),
+        1 => Ok(AutoVacuum::Full),
+        2 => Ok(AutoVacuum::Incremental),
+        _ => anyhow::bail!("Unexpected auto_vacuum {auto_vacuum}"),
+    }
+}
+
+/// Number of frames in the WAL file of `connection`'s database.
+pub(crate) fn wal_pages(connection: &Connection, page_size: u64) -> u64 {
+    // The WAL only exists on disk, and may not exist at all.
+    let wal_bytes = match connection.path() {
+        Some(path) if !path.is_empty() => {
+            fs::metadata(format!("{path}-wal")).map_or(0, |metadata| metadata.len())
+        },
+        _ => 0,
+    };
+    wal_bytes.saturating_sub(WAL_HEADER_BYTES) / (page_size + WAL_FRAME_HEADER_BYTES)
+}
+
+pub(crate) fn checkpoint(
+    connection: &Connection,
+    mode: CheckpointMode,
+) -> anyhow::Result<CheckpointResult> {
+    let sql = format!("PRAGMA wal_checkpoint({});", mode.as_sql());
+    let result = connection.query_row(&sql, [], |row| {
+        Ok(CheckpointResult {
+            busy: row.get::<_, i64>(0)? != 0,
+            log_pages: row.get(1)?,
+            checkpointed_pages: row.get(2)?,
+        })
+    })?;
+    Ok(result)
+}
+
+/// Fails with `PersistenceError::Corrupt` if `check` finds a problem.
+pub(crate) fn check_integrity_on_open(
+    path: &str,
+    connection: &Connection,
+    check: IntegrityCheck,
+) -> anyhow::Result<()> {
+    let report = match integrity_check(connection, check) {
+        Err(e) if is_corruption_error(&e) => {
+            anyhow::bail!(PersistenceError::Corrupt(format!("{path}: {e}")))
+        },
+        result => result?,
+    };
+    if !report.is_ok() {
+        anyhow::bail!(PersistenceError::Corrupt(format!(
+            "{path}: {}",
+            report.errors.join("; ")
+        )));
+    }
+    Ok(())
+}
+
+fn integrity_check(
+    connection: &Connection,
+    check: IntegrityCheck,
+) -> rusqlite::Result<IntegrityReport> {
+    let sql = match check {
+        IntegrityCheck::Skip => return Ok(IntegrityReport { errors: vec![] }),
+        IntegrityCheck::Quick => "PRAGMA quick_check;",
+        IntegrityCheck::Full => "PRAGMA integrity_check;",
+    };
+    let mut stmt = connection.prepare(sql)?;
+    let mut errors = stmt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    // An intact database produces a single "ok" row.
+    if errors == ["ok"] {
+        errors.clear();
+    }
+    Ok(IntegrityReport { errors })
+}
+
+/// Whether Sqlite gave up on reading the database because the file is
+/// damaged, as opposed to some other failure.
+fn is_corruption_error(e: &rusqlite::Error) -> bool {
+    matches!(
+        e.sqlite_error_code(),
+        Some(ErrorCode::DatabaseCorrupt | ErrorCode::NotADatabase)
+    )
+}
+
+/// Logical size of the main database, which is what `VACUUM` shrinks.
+pub(crate) fn database_size_bytes(connection: &Connection) -> anyhow::Result<u64> {
+    let page_count: u64 = connection.pragma_query_value(None, "page_count", |row| row.get(0))?;
+    let page_size: u64 = connection.pragma_query_value(None, "page_size", |row| row.get(0))?;
+    Ok(page_count * page_size)
+}
+
+struct CompactionGuard<'a>(&'a AtomicBool);
+
+impl<'a> CompactionGuard<'a> {
+    fn acquire(compacting: &'a AtomicBool) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            compacting
+                .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok(),
+            "Compaction already in progress"
+        );
+        Ok(Self(compacting))
+    }
+}
+
+impl Drop for CompactionGuard<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
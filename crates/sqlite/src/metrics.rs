@@ -0,0 +1,37 @@
+//! Hooks for exporting per-operation metrics.
+//!
+//! The crate doesn't depend on a particular metrics library. Instead, callers
+//! pass a `MetricsRecorder` in `SqliteOptions`, which is called once each
+//! operation completes successfully.
+
+use std::{
+    fmt,
+    time::Duration,
+};
+
+use crate::CheckpointResult;
+
+/// Receives the latency and size of completed operations. Every method
+/// defaults to doing nothing, so implementations only need to override the
+/// operations they care about.
+///
+/// Recorders are called synchronously on the thread running the operation,
+/// so they should be cheap.
+pub trait MetricsRecorder: fmt::Debug + Send + Sync {
+    /// A `write` committed `entries` document log entries, including time
+    /// spent retrying.
+    fn record_write(&self, _duration: Duration, _entries: usize) {}
+
+    /// `load_documents` read `rows` document log entries.
+    fn record_load_documents(&self, _duration: Duration, _rows: usize) {}
+
+    /// `index_scan` read `rows` index entries.
+    fn record_index_scan(&self, _duration: Duration, _rows: usize) {}
+
+    /// A checkpoint from `spawn_checkpoint_loop` finished with `result`.
+    fn record_checkpoint(&self, _duration: Duration, _result: &CheckpointResult) {}
+
+    /// WAL mode couldn't be enabled when opening the database, failing with
+    /// `error`, so `SqliteOptions::wal_fallback` opened it without.
+    fn record_wal_fallback(&self, _error: &anyhow::Error) {}
+}
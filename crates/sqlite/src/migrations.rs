@@ -0,0 +1,122 @@
+//! Versioning of the on-disk schema.
+//!
+//! The schema version is the number of migrations that have been applied to
+//! a database, and is stored in the `schema_version` table. Databases created
+//! before the table existed are at version 0. The first migrations only
+//! create tables that don't already exist, so they are safe to apply to those
+//! databases too.
+//!
+//! Each `SqliteOptions::namespace` has its own `schema_version` table, so the
+//! namespaces in a file are migrated independently.
+
+use rusqlite::{
+    Connection,
+    OptionalExtension as _,
+};
+
+use crate::{
+    namespaced,
+    namespaced_name,
+    PersistenceError,
+    SqlitePersistence,
+    DOCUMENTS_INIT,
+    GENERATION_INIT,
+    INDEXES_INIT,
+    PERSISTENCE_GLOBALS_INIT,
+};
+
+/// Each migration brings the schema from the version before it to the next,
+/// as one or more batches of statements.
+const MIGRATIONS: &[&[&str]] = &[
+    // 1: The original tables.
+    &[DOCUMENTS_INIT, INDEXES_INIT, PERSISTENCE_GLOBALS_INIT],
+    // 2: The write generation counter.
+    &[GENERATION_INIT],
+];
+
+/// The schema version this version of the crate creates and reads.
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+const SCHEMA_VERSION_INIT: &str = r#"
+CREATE TABLE IF NOT EXISTS schema_version (
+    id INTEGER NOT NULL CHECK (id = 0),
+    version INTEGER NOT NULL,
+
+    PRIMARY KEY (id)
+);
+"#;
+
+const SCHEMA_VERSION_EXISTS: &str =
+    "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = $1";
+const GET_SCHEMA_VERSION: &str = "SELECT version FROM schema_version WHERE id = 0";
+const SET_SCHEMA_VERSION: &str = "INSERT INTO schema_version VALUES (0, $1) ON CONFLICT (id) DO \
+                                  UPDATE SET version = excluded.version";
+
+impl SqlitePersistence {
+    /// Applies any migrations the database is missing. This already happens
+    /// when opening a database for writing, so it's only needed if another
+    /// process may have replaced the file since.
+    pub fn migrate(&self) -> anyhow::Result<()> {
+        let inner = self.inner.lock();
+        migrate(&inner.connection, self.namespace.as_deref())
+    }
+
+    /// The schema version of the database, which is `SCHEMA_VERSION` once it
+    /// has been migrated.
+    pub fn schema_version(&self) -> anyhow::Result<u32> {
+        self.with_read_connection(|connection| {
+            schema_version(connection, self.namespace.as_deref())
+        })
+    }
+}
+
+/// Brings the schema up to `SCHEMA_VERSION` in a single transaction, failing
+/// with `PersistenceError::UnsupportedSchemaVersion` if the database is
+/// already past it.
+pub(crate) fn migrate(connection: &Connection, namespace: Option<&str>) -> anyhow::Result<()> {
+    let tx = connection.unchecked_transaction()?;
+    let version = check_schema_version(&tx, namespace)?;
+    if version == SCHEMA_VERSION {
+        return Ok(());
+    }
+    for migration in &MIGRATIONS[version as usize..] {
+        for statements in *migration {
+            tx.execute_batch(&namespaced(statements, namespace))?;
+        }
+    }
+    tx.execute_batch(&namespaced(SCHEMA_VERSION_INIT, namespace))?;
+    tx.execute(&namespaced(SET_SCHEMA_VERSION, namespace), [SCHEMA_VERSION])?;
+    tx.commit()?;
+    tracing::info!("Migrated Sqlite schema from version {version} to {SCHEMA_VERSION}");
+    Ok(())
+}
+
+/// Reads the schema version, failing if this crate can't read it.
+pub(crate) fn check_schema_version(
+    connection: &Connection,
+    namespace: Option<&str>,
+) -> anyhow::Result<u32> {
+    let version = schema_version(connection, namespace)?;
+    anyhow::ensure!(
+        version <= SCHEMA_VERSION,
+        PersistenceError::UnsupportedSchemaVersion(version)
+    );
+    Ok(version)
+}
+
+fn schema_version(connection: &Connection, namespace: Option<&str>) -> anyhow::Result<u32> {
+    let exists: bool = connection.query_row(
+        SCHEMA_VERSION_EXISTS,
+        [namespaced_name("schema_version", namespace)],
+        |row| row.get(0),
+    )?;
+    if !exists {
+        return Ok(0);
+    }
+    let version: Option<u32> = connection
+        .query_row(&namespaced(GET_SCHEMA_VERSION, namespace), [], |row| {
+            row.get(0)
+        })
+        .optional()?;
+    Ok(version.unwrap_or(0))
+}
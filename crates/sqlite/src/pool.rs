@@ -0,0 +1,260 @@
+//! A fixed-size pool of read connections.
+//!
+//! Sqlite connections are synchronous, so a reader checking out a connection
+//! blocks until one is returned to the pool. Connections are opened eagerly
+//! and live for as long as the pool does.
+//!
+//! A connection is always returned to the pool outside of a transaction, so
+//! every checkout starts a new read transaction that sees all writes
+//! committed before it.
+//!
+//! Changes to the connections' configuration, such as attached databases or
+//! `wal_autocheckpoint`, are
+//! recorded in the pool and applied to each connection when it's next checked
+//! out, so making one never waits for a connection to be returned.
+
+use std::{
+    ops::Deref,
+    sync::Arc,
+};
+
+use parking_lot::{
+    Condvar,
+    Mutex,
+};
+use rusqlite::Connection;
+
+pub(crate) struct ReadPool {
+    size: usize,
+    idle: Mutex<Idle>,
+    returned: Condvar,
+}
+
+struct Idle {
+    connections: Vec<IdleConnection>,
+    /// Databases to attach to every connection, in order, as (schema name,
+    /// URI) pairs.
+    attached: Vec<(String, String)>,
+    /// The `wal_autocheckpoint` to set on every connection, if it's been
+    /// changed since the pool was opened.
+    wal_autocheckpoint: Option<u32>,
+}
+
+struct IdleConnection {
+    connection: Connection,
+    /// How many of `Idle::attached` have been attached to `connection`.
+    attached: usize,
+    wal_autocheckpoint: Option<u32>,
+}
+
+impl IdleConnection {
+    /// Brings this connection up to date with the pool's configuration.
+    fn configure(&mut self, idle: &Idle) -> anyhow::Result<()> {
+        if self.wal_autocheckpoint != idle.wal_autocheckpoint
+            && let Some(pages) = idle.wal_autocheckpoint
+        {
+            self.connection
+                .pragma_update(None, "wal_autocheckpoint", pages)?;
+            self.wal_autocheckpoint = Some(pages);
+        }
+        self.attach(&idle.attached)
+    }
+
+    /// Attaches whichever of `attached` this connection hasn't yet.
+    fn attach(&mut self, attached: &[(String, String)]) -> anyhow::Result<()> {
+        for (schema_name, uri) in &attached[self.attached..] {
+            self.connection
+                .execute(&format!("ATTACH DATABASE ?1 AS {schema_name}"), [uri])?;
+            self.attached += 1;
+        }
+        Ok(())
+    }
+}
+
+impl Idle {
+    /// Pops an idle connection, brought up to date with the pool's
+    /// configuration. If that fails, the connection stays idle.
+    fn pop(&mut self) -> Option<anyhow::Result<IdleConnection>> {
+        let mut connection = self.connections.pop()?;
+        Some(match connection.configure(self) {
+            Ok(()) => Ok(connection),
+            Err(e) => {
+                self.connections.push(connection);
+                Err(e)
+            },
+        })
+    }
+}
+
+/// Utilization of the read connection pool at a point in time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReadPoolStats {
+    /// Total number of read connections owned by the pool.
+    pub size: usize,
+    /// Number of read connections currently checked out.
+    pub in_use: usize,
+}
+
+impl ReadPool {
+    /// Opens `size` connections with `open`, which should return them fully
+    /// configured.
+    pub(crate) fn open(
+        size: usize,
+        open: impl Fn() -> anyhow::Result<Connection>,
+    ) -> anyhow::Result<Self> {
+        let mut idle = Vec::with_capacity(size);
+        for _ in 0..size {
+            let connection = open()?;
+            // Pooled connections are only ever used for reads, so make sure a bug
+            // can't accidentally write through them.
+            connection.execute_batch("PRAGMA query_only=ON;")?;
+            idle.push(IdleConnection {
+                connection,
+                attached: 0,
+                wal_autocheckpoint: None,
+            });
+        }
+        Ok(Self {
+            size,
+            idle: Mutex::new(Idle {
+                connections: idle,
+                attached: vec![],
+                wal_autocheckpoint: None,
+            }),
+            returned: Condvar::new(),
+        })
+    }
+
+    /// Check out a connection, blocking until one is available. Fails if the
+    /// connection can't be brought up to date with the pool's configuration.
+    pub(crate) fn checkout(self: &Arc<Self>) -> anyhow::Result<PooledConnection> {
+        let mut idle = self.idle.lock();
+        loop {
+            if let Some(connection) = idle.pop() {
+                return Ok(PooledConnection {
+                    pool: self.clone(),
+                    connection: Some(connection?),
+                });
+            }
+            self.returned.wait(&mut idle);
+        }
+    }
+
+    /// Checks out a connection to hold indefinitely, without waiting. Fails
+    /// if that would take the last idle connection, since reads would then
+    /// wait on the holder rather than on a query.
+    pub(crate) fn try_checkout_spare(self: &Arc<Self>) -> anyhow::Result<Option<PooledConnection>> {
+        let mut idle = self.idle.lock();
+        if idle.connections.len() < 2 {
+            return Ok(None);
+        }
+        let connection = idle.pop().transpose()?;
+        Ok(Some(PooledConnection {
+            pool: self.clone(),
+            connection,
+        }))
+    }
+
+    /// Attaches the database at `uri` to every connection as `schema_name`.
+    /// Idle connections attach it now, so that a bad `uri` fails here rather
+    /// than in a later read, and checked out ones when they're next checked
+    /// out.
+    pub(crate) fn attach(&self, uri: &str, schema_name: &str) -> anyhow::Result<()> {
+        let mut idle = self.idle.lock();
+        anyhow::ensure!(
+            !idle.attached.iter().any(|(name, _)| name == schema_name),
+            "A database is already attached as {schema_name}"
+        );
+        idle.attached.push((schema_name.to_owned(), uri.to_owned()));
+        let Idle {
+            connections,
+            attached,
+            ..
+        } = &mut *idle;
+        let mut result = Ok(());
+        for connection in connections.iter_mut() {
+            result = connection.attach(attached);
+            if result.is_err() {
+                break;
+            }
+        }
+        if result.is_err() {
+            // Undo the attachment wherever it succeeded, so the pool's
+            // connections all agree with `attached` again.
+            attached.pop();
+            for connection in connections.iter_mut() {
+                if connection.attached > attached.len() {
+                    connection
+                        .connection
+                        .execute_batch(&format!("DETACH DATABASE {schema_name};"))?;
+                    connection.attached -= 1;
+                }
+            }
+        }
+        result
+    }
+
+    /// Sets `wal_autocheckpoint` on every connection, each when it's next
+    /// checked out.
+    pub(crate) fn set_wal_autocheckpoint(&self, pages: u32) {
+        self.idle.lock().wal_autocheckpoint = Some(pages);
+    }
+
+    /// Closes every connection. Since checked out connections hold a
+    /// reference to the pool, they have all been returned by the time this can
+    /// be called.
+    pub(crate) fn close(self) -> anyhow::Result<()> {
+        for IdleConnection { connection, .. } in self.idle.into_inner().connections {
+            connection.close().map_err(|(_, e)| e)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn stats(&self) -> ReadPoolStats {
+        let idle = self.idle.lock().connections.len();
+        ReadPoolStats {
+            size: self.size,
+            in_use: self.size - idle,
+        }
+    }
+}
+
+/// A connection checked out of a `ReadPool`, returned to the pool on drop.
+pub(crate) struct PooledConnection {
+    pool: Arc<ReadPool>,
+    connection: Option<IdleConnection>,
+}
+
+impl Deref for PooledConnection {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        &self
+            .connection
+            .as_ref()
+            .expect("PooledConnection used after drop")
+            .connection
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        if let Some(connection) = self.connection.take() {
+            end_transaction(&connection.connection);
+            self.pool.idle.lock().connections.push(connection);
+            self.pool.returned.notify_one();
+        }
+    }
+}
+
+/// Rolls back a transaction left open on `connection`, e.g. by a caller of
+/// `SqlitePersistence::with_connection`. In WAL mode an open read transaction
+/// keeps reading the snapshot it started with, so a connection returned with
+/// one would hide later writes from whoever checks it out next.
+pub(crate) fn end_transaction(connection: &Connection) {
+    if !connection.is_autocommit()
+        && let Err(e) = connection.execute_batch("ROLLBACK;")
+    {
+        tracing::warn!("Failed to roll back a transaction left open on a Sqlite connection: {e:#}");
+    }
+}
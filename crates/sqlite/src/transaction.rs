@@ -0,0 +1,92 @@
+//! Transactions spanning several writes, for `Persistence::begin`.
+
+use async_trait::async_trait;
+use common::persistence::{
+    ConflictStrategy,
+    DocumentLogEntry,
+    PersistenceIndexEntry,
+    PersistenceTransaction,
+};
+use tokio::sync::OwnedMutexGuard;
+
+use crate::{
+    error,
+    pool,
+    SqlitePersistence,
+};
+
+/// A Sqlite transaction open on the write connection. It holds the
+/// persistence's writer lock until it's dropped, so other writes wait for it
+/// asynchronously. The connection itself is only locked while each statement
+/// runs, so reads that share it because there's no read pool fail until the
+/// transaction ends rather than see its uncommitted writes.
+pub(crate) struct SqliteTransaction {
+    persistence: SqlitePersistence,
+    _writer: OwnedMutexGuard<()>,
+}
+
+impl SqliteTransaction {
+    pub(crate) async fn begin(persistence: &SqlitePersistence) -> anyhow::Result<Self> {
+        let writer = persistence.writer.clone().lock_owned().await;
+        // Taking Sqlite's write lock up front means a busy database fails
+        // here rather than partway through the transaction.
+        persistence
+            .inner
+            .lock()
+            .connection
+            .execute_batch("BEGIN IMMEDIATE;")?;
+        Ok(Self {
+            persistence: persistence.handle(),
+            _writer: writer,
+        })
+    }
+}
+
+#[async_trait]
+impl PersistenceTransaction for SqliteTransaction {
+    async fn write(
+        &mut self,
+        documents: &[DocumentLogEntry],
+        indexes: &[PersistenceIndexEntry],
+        conflict_strategy: ConflictStrategy,
+    ) -> anyhow::Result<()> {
+        // Each write is a savepoint within the transaction, so a failed one
+        // is rolled back on its own.
+        self.persistence
+            ._write_locked(
+                &mut self.persistence.inner.lock(),
+                documents,
+                indexes,
+                conflict_strategy,
+            )
+            .map_err(error::classify)
+    }
+
+    async fn commit(self: Box<Self>) -> anyhow::Result<()> {
+        // If the commit fails the transaction is still open, and dropping
+        // `self` rolls it back.
+        self.persistence
+            .inner
+            .lock()
+            .connection
+            .execute_batch("COMMIT;")
+            .map_err(|e| error::classify(e.into()))
+    }
+
+    async fn rollback(self: Box<Self>) -> anyhow::Result<()> {
+        self.persistence
+            .inner
+            .lock()
+            .connection
+            .execute_batch("ROLLBACK;")?;
+        Ok(())
+    }
+}
+
+impl Drop for SqliteTransaction {
+    fn drop(&mut self) {
+        // Does nothing once the transaction has committed or rolled back. The
+        // writer lock is released after this, once the fields are dropped.
+        pool::end_transaction(&self.persistence.inner.lock().connection);
+    }
+}
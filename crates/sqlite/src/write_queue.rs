@@ -0,0 +1,121 @@
+//! A bounded queue in front of `write`, so that a producer that outpaces the
+//! disk is slowed down instead of buffering without limit.
+
+use common::persistence::{
+    ConflictStrategy,
+    DocumentLogEntry,
+    PersistenceIndexEntry,
+};
+use tokio::sync::{
+    mpsc,
+    oneshot,
+};
+
+use crate::{
+    PersistenceError,
+    SqlitePersistence,
+};
+
+/// Queues writes for a background task that applies them in order. Cloning
+/// the handle shares the queue; the task exits once every clone is dropped
+/// and the queued writes have been applied.
+#[derive(Clone)]
+pub struct WriterHandle {
+    sender: mpsc::Sender<QueuedWrite>,
+}
+
+struct QueuedWrite {
+    documents: Vec<DocumentLogEntry>,
+    indexes: Vec<PersistenceIndexEntry>,
+    conflict_strategy: ConflictStrategy,
+    done: oneshot::Sender<anyhow::Result<()>>,
+}
+
+/// A write accepted by `WriterHandle::try_write`.
+pub struct PendingWrite {
+    done: oneshot::Receiver<anyhow::Result<()>>,
+}
+
+impl PendingWrite {
+    /// Waits for the write to commit or fail.
+    pub async fn wait(self) -> anyhow::Result<()> {
+        self.done
+            .await
+            .map_err(|_| anyhow::anyhow!("Write queue task exited before applying the write"))?
+    }
+}
+
+impl SqlitePersistence {
+    /// Starts a tokio task that applies writes queued through the returned
+    /// handle, holding at most `queue_depth` writes that haven't started yet.
+    pub fn writer_handle(&self, queue_depth: usize) -> anyhow::Result<WriterHandle> {
+        anyhow::ensure!(queue_depth > 0, "queue_depth must be positive");
+        let (sender, mut receiver) = mpsc::channel::<QueuedWrite>(queue_depth);
+        let persistence = self.handle();
+        tokio::spawn(async move {
+            while let Some(write) = receiver.recv().await {
+                let result = persistence
+                    .write_with_deadline(
+                        &write.documents,
+                        &write.indexes,
+                        write.conflict_strategy,
+                        None,
+                    )
+                    .await;
+                // The caller may have stopped waiting for the result.
+                let _ = write.done.send(result);
+            }
+        });
+        Ok(WriterHandle { sender })
+    }
+}
+
+impl WriterHandle {
+    /// Queues a write without waiting, failing with
+    /// `PersistenceError::QueueFull` if the queue has no room.
+    pub fn try_write(
+        &self,
+        documents: Vec<DocumentLogEntry>,
+        indexes: Vec<PersistenceIndexEntry>,
+        conflict_strategy: ConflictStrategy,
+    ) -> anyhow::Result<PendingWrite> {
+        let (write, pending) = queued_write(documents, indexes, conflict_strategy);
+        match self.sender.try_send(write) {
+            Ok(()) => Ok(pending),
+            Err(mpsc::error::TrySendError::Full(_)) => anyhow::bail!(PersistenceError::QueueFull),
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                anyhow::bail!("Write queue task has exited")
+            },
+        }
+    }
+
+    /// Waits for room in the queue, then for the write to commit or fail.
+    pub async fn write(
+        &self,
+        documents: Vec<DocumentLogEntry>,
+        indexes: Vec<PersistenceIndexEntry>,
+        conflict_strategy: ConflictStrategy,
+    ) -> anyhow::Result<()> {
+        let (write, pending) = queued_write(documents, indexes, conflict_strategy);
+        self.sender
+            .send(write)
+            .await
+            .map_err(|_| anyhow::anyhow!("Write queue task has exited"))?;
+        pending.wait().await
+    }
+}
+
+fn queued_write(
+    documents: Vec<DocumentLogEntry>,
+    indexes: Vec<PersistenceIndexEntry>,
+    conflict_strategy: ConflictStrategy,
+) -> (QueuedWrite, PendingWrite) {
+    let (done, receiver) = oneshot::channel();
+    let write = QueuedWrite {
+        documents,
+        indexes,
+        conflict_strategy,
+        done,
+    };
+    (write, PendingWrite { done: receiver })
+}
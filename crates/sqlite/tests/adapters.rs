@@ -0,0 +1,328 @@
+mod helpers;
+
+use std::{
+    sync::Arc,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use common::{
+    bootstrap_model::index::INDEX_TABLE,
+    index::IndexKeyBytes,
+    interval::Interval,
+    persistence::{
+        ConflictStrategy,
+        NoopRetentionValidator,
+        Persistence,
+        PersistenceGlobalKey,
+        PersistenceIndexEntry,
+        PersistenceReader,
+    },
+    persistence_recording::{
+        replay,
+        RecordingPersistence,
+    },
+    persistence_tee::TeePersistence,
+    query::Order,
+    testing::{
+        persistence_test_suite::doc,
+        TestIdGenerator,
+    },
+    types::{
+        IndexId,
+        TableName,
+        Timestamp,
+    },
+    value::{
+        InternalDocumentId,
+        TabletId,
+    },
+};
+use futures::TryStreamExt;
+use serde_json::Value as JsonValue;
+use sqlite::{
+    FaultyPersistence,
+    PersistenceError,
+    SqlitePersistence,
+};
+use tempfile::TempDir;
+
+use crate::helpers::load_all;
+
+async fn index_entries(
+    reader: &dyn PersistenceReader,
+    index_id: IndexId,
+    tablet_id: TabletId,
+) -> anyhow::Result<Vec<(IndexKeyBytes, Timestamp)>> {
+    Ok(reader
+        .index_scan(
+            index_id,
+            tablet_id,
+            Timestamp::must(3),
+            &Interval::all(),
+            Order::Asc,
+            100,
+            Arc::new(NoopRetentionValidator),
+        )
+        .map_ok(|(key, document)| (key, document.ts))
+        .try_collect()
+        .await?)
+}
+
+#[tokio::test]
+async fn test_record_and_replay() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let log_path = db.path().join("writes.jsonl");
+    let original = Arc::new(SqlitePersistence::new_in_memory()?);
+    let recording = RecordingPersistence::new(original.clone(), &log_path)?;
+    let mut id_generator = TestIdGenerator::new();
+    let index_id = id_generator.system_generate(&INDEX_TABLE).internal_id();
+    let table: TableName = str::parse("table")?;
+    let tablet_id = id_generator.user_table_id(&table).tablet_id;
+    let a = id_generator.user_generate(&table);
+    let b = id_generator.user_generate(&table);
+    let index_entry = |ts: i32, key: u8, value: Option<InternalDocumentId>| PersistenceIndexEntry {
+        ts: Timestamp::must(ts),
+        index_id,
+        key: IndexKeyBytes(vec![key]),
+        value,
+    };
+
+    recording
+        .write(
+            &[doc(a, 1, Some(1), None)?, doc(b, 1, Some(2), None)?],
+            &[
+                index_entry(1, 1, Some(a.into())),
+                index_entry(1, 2, Some(b.into())),
+            ],
+            ConflictStrategy::Error,
+        )
+        .await?;
+    recording
+        .write(
+            &[doc(a, 2, Some(3), Some(1))?],
+            &[index_entry(2, 1, None), index_entry(2, 3, Some(a.into()))],
+            ConflictStrategy::Error,
+        )
+        .await?;
+    // Overwrites the revision of `a` at 2, and deletes `b`.
+    recording
+        .write(
+            &[doc(a, 2, Some(4), Some(1))?, doc(b, 3, None, Some(1))?],
+            &[index_entry(3, 2, None)],
+            ConflictStrategy::Overwrite,
+        )
+        .await?;
+    // A failed write isn't recorded.
+    assert!(recording
+        .write(&[doc(a, 1, Some(5), None)?], &[], ConflictStrategy::Error)
+        .await
+        .is_err());
+    // Only the revision of `b` at 4 is new, so it's the only one written.
+    let outcome = recording
+        .write_partial(
+            &[doc(a, 1, Some(6), None)?, doc(b, 4, Some(7), Some(3))?],
+            &[index_entry(4, 2, Some(b.into()))],
+        )
+        .await?;
+    assert_eq!(outcome.documents_written, 1);
+    assert_eq!(
+        recording
+            .delete(vec![(Timestamp::must(1), a.into())])
+            .await?,
+        1
+    );
+    recording
+        .write_persistence_global(
+            PersistenceGlobalKey::MaxRepeatableTimestamp,
+            JsonValue::from(4),
+        )
+        .await?;
+
+    let replayed = SqlitePersistence::new_in_memory()?;
+    assert_eq!(replay(&log_path, &replayed).await?, 6);
+    let expected = original
+        .reader()
+        .load_all_documents()
+        .try_collect::<Vec<_>>()
+        .await?;
+    assert_eq!(expected.len(), 4);
+    assert_eq!(
+        replayed
+            .reader()
+            .load_all_documents()
+            .try_collect::<Vec<_>>()
+            .await?,
+        expected
+    );
+    assert_eq!(
+        index_entries(replayed.reader().as_ref(), index_id, tablet_id).await?,
+        index_entries(original.reader().as_ref(), index_id, tablet_id).await?
+    );
+    assert_eq!(
+        replayed
+            .reader()
+            .get_persistence_global(PersistenceGlobalKey::MaxRepeatableTimestamp)
+            .await?,
+        Some(JsonValue::from(4))
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_record_truncate() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let log_path = db.path().join("writes.jsonl");
+    let recording =
+        RecordingPersistence::new(Arc::new(SqlitePersistence::new_in_memory()?), &log_path)?;
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let a = id_generator.user_generate(&table);
+    recording
+        .write(&[doc(a, 1, Some(1), None)?], &[], ConflictStrategy::Error)
+        .await?;
+    recording.truncate().await?;
+
+    let replayed = SqlitePersistence::new_in_memory()?;
+    assert_eq!(replay(&log_path, &replayed).await?, 2);
+    assert!(replayed
+        .reader()
+        .load_all_documents()
+        .try_collect::<Vec<_>>()
+        .await?
+        .is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_tee_persistence() -> anyhow::Result<()> {
+    let primary = Arc::new(SqlitePersistence::new_in_memory()?);
+    let secondary = Arc::new(SqlitePersistence::new_in_memory()?);
+    let tee = TeePersistence::new(primary.clone(), secondary.clone());
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+
+    let entry = doc(id_generator.user_generate(&table), 1, Some(1), None)?;
+    tee.write(&[entry.clone()], &[], ConflictStrategy::Error)
+        .await?;
+    for p in [&primary, &secondary] {
+        assert_eq!(
+            p.reader()
+                .load_all_documents()
+                .try_collect::<Vec<_>>()
+                .await?,
+            vec![entry.clone()]
+        );
+    }
+
+    // Reads come from the primary only.
+    let secondary_only_id = id_generator.user_generate(&table);
+    let secondary_only = doc(secondary_only_id, 2, Some(2), None)?;
+    secondary
+        .write(&[secondary_only.clone()], &[], ConflictStrategy::Error)
+        .await?;
+    assert_eq!(
+        tee.reader()
+            .load_all_documents()
+            .try_collect::<Vec<_>>()
+            .await?,
+        vec![entry.clone()]
+    );
+
+    // A failure on the secondary is counted but doesn't fail the write.
+    assert_eq!(tee.secondary_failures(), 0);
+    tee.write(&[secondary_only.clone()], &[], ConflictStrategy::Error)
+        .await?;
+    assert_eq!(tee.secondary_failures(), 1);
+    assert_eq!(
+        primary
+            .reader()
+            .load_all_documents()
+            .try_collect::<Vec<_>>()
+            .await?,
+        vec![entry, secondary_only]
+    );
+
+    // A failure on the primary is returned without touching the secondary.
+    let fresh = doc(id_generator.user_generate(&table), 3, Some(3), None)?;
+    let conflicting = doc(secondary_only_id, 2, Some(4), None)?;
+    assert!(tee
+        .write(&[fresh.clone(), conflicting], &[], ConflictStrategy::Error)
+        .await
+        .is_err());
+    assert_eq!(
+        secondary
+            .reader()
+            .load_all_documents()
+            .try_collect::<Vec<_>>()
+            .await?
+            .len(),
+        2
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_fail_with() -> anyhow::Result<()> {
+    let p = FaultyPersistence::new(Arc::new(SqlitePersistence::new_in_memory()?));
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let entry = doc(id_generator.user_generate(&table), 1, Some(1), None)?;
+
+    p.fail_with(PersistenceError::Busy);
+    for _ in 0..2 {
+        let e = p
+            .write(&[entry.clone()], &[], ConflictStrategy::Error)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<PersistenceError>(),
+            Some(PersistenceError::Busy)
+        ));
+    }
+    assert_eq!(load_all(p.reader().as_ref()).await?.len(), 0);
+
+    p.clear_faults();
+    p.write(&[entry], &[], ConflictStrategy::Error).await?;
+    assert_eq!(load_all(p.reader().as_ref()).await?.len(), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_fail_next_write() -> anyhow::Result<()> {
+    let p = FaultyPersistence::new(Arc::new(SqlitePersistence::new_in_memory()?));
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let entry = doc(id_generator.user_generate(&table), 1, Some(1), None)?;
+
+    p.fail_next_write();
+    let e = p
+        .write(&[entry.clone()], &[], ConflictStrategy::Error)
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        e.downcast_ref::<PersistenceError>(),
+        Some(PersistenceError::Io)
+    ));
+    // Only the one write fails.
+    p.write(&[entry], &[], ConflictStrategy::Error).await?;
+    assert_eq!(load_all(p.reader().as_ref()).await?.len(), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_delay_writes() -> anyhow::Result<()> {
+    let p = FaultyPersistence::new(Arc::new(SqlitePersistence::new_in_memory()?));
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let entry = doc(id_generator.user_generate(&table), 1, Some(1), None)?;
+
+    p.delay_writes(Duration::from_millis(100));
+    let start = Instant::now();
+    p.write(&[entry], &[], ConflictStrategy::Error).await?;
+    assert!(start.elapsed() >= Duration::from_millis(100));
+    assert_eq!(load_all(p.reader().as_ref()).await?.len(), 1);
+    Ok(())
+}
@@ -0,0 +1,727 @@
+mod helpers;
+
+use std::{
+    panic::{
+        self,
+        AssertUnwindSafe,
+    },
+    sync::Arc,
+};
+
+use common::{
+    bootstrap_model::index::INDEX_TABLE,
+    index::IndexKeyBytes,
+    interval::Interval,
+    persistence::{
+        ConflictStrategy,
+        DocumentLogEntry,
+        NoopRetentionValidator,
+        Persistence,
+        PersistenceIndexEntry,
+        TimestampRange,
+    },
+    query::Order,
+    run_persistence_test_suite,
+    testing::{
+        persistence_test_suite::{
+            self,
+            doc,
+        },
+        TestIdGenerator,
+    },
+    types::{
+        IndexId,
+        TableName,
+        Timestamp,
+    },
+};
+use futures::TryStreamExt;
+use sqlite::{
+    ReadPoolStats,
+    SqliteOptions,
+    SqlitePersistence,
+    SCHEMA_VERSION,
+};
+use tempfile::TempDir;
+
+use crate::helpers::load_all;
+
+fn pooled_options() -> SqliteOptions {
+    SqliteOptions {
+        wal_mode: true,
+        read_pool_size: 4,
+        ..Default::default()
+    }
+}
+
+run_persistence_test_suite!(
+    db,
+    TempDir::new()?,
+    SqlitePersistence::new_with_options(
+        db.path()
+            .join("convex_local_backend.sqlite3")
+            .to_str()
+            .unwrap(),
+        pooled_options(),
+    )?
+);
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_concurrent_pooled_reads() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("pooled.sqlite3");
+    let p = SqlitePersistence::new_with_options(path.to_str().unwrap(), pooled_options())?;
+    assert_eq!(
+        p.read_pool_stats(),
+        Some(ReadPoolStats { size: 4, in_use: 0 })
+    );
+
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let entries = (0..10)
+        .map(|ts| {
+            doc(
+                id_generator.user_generate(&table),
+                ts,
+                Some(ts as i64),
+                None,
+            )
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    p.write(&entries, &[], ConflictStrategy::Error).await?;
+
+    let handles = (0..16)
+        .map(|_| {
+            let reader = p.reader();
+            tokio::spawn(async move {
+                reader
+                    .load_documents(
+                        TimestampRange::all(),
+                        Order::Asc,
+                        100,
+                        Arc::new(NoopRetentionValidator),
+                    )
+                    .try_collect::<Vec<_>>()
+                    .await
+            })
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        assert_eq!(handle.await??.len(), 10);
+    }
+
+    // Every connection should have been returned to the pool.
+    assert_eq!(
+        p.read_pool_stats(),
+        Some(ReadPoolStats { size: 4, in_use: 0 })
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_no_pool_by_default() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("unpooled.sqlite3");
+    let p = SqlitePersistence::new(path.to_str().unwrap())?;
+    assert_eq!(p.read_pool_stats(), None);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_read_your_writes() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("read_your_writes.sqlite3");
+    // A single pooled connection, so every read reuses the one that
+    // `with_connection` leaves a transaction open on below.
+    let p = SqlitePersistence::new_with_options(
+        path.to_str().unwrap(),
+        SqliteOptions {
+            read_pool_size: 1,
+            ..pooled_options()
+        },
+    )?;
+    p.with_connection(|connection| {
+        connection.execute_batch("BEGIN;")?;
+        connection.query_row("SELECT COUNT(*) FROM documents", [], |row| {
+            row.get::<_, i64>(0)
+        })
+    })?;
+
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    for ts in 0..20 {
+        let entry = doc(
+            id_generator.user_generate(&table),
+            ts,
+            Some(ts as i64),
+            None,
+        )?;
+        p.write(&[entry.clone()], &[], ConflictStrategy::Error)
+            .await?;
+        let loaded = p
+            .reader()
+            .load_documents(
+                TimestampRange::all(),
+                Order::Desc,
+                100,
+                Arc::new(NoopRetentionValidator),
+            )
+            .try_collect::<Vec<_>>()
+            .await?;
+        assert_eq!(loaded.len(), ts as usize + 1);
+        assert_eq!(loaded[0], entry);
+    }
+    Ok(())
+}
+
+/// Writes a new document at `ts`, indexed by its timestamp.
+async fn write(
+    p: &SqlitePersistence,
+    id_generator: &mut TestIdGenerator,
+    index_id: IndexId,
+    ts: i32,
+) -> anyhow::Result<DocumentLogEntry> {
+    let table: TableName = str::parse("table")?;
+    let entry = doc(
+        id_generator.user_generate(&table),
+        ts,
+        Some(ts as i64),
+        None,
+    )?;
+    let index_entry = PersistenceIndexEntry {
+        ts: entry.ts,
+        index_id,
+        key: IndexKeyBytes(vec![ts as u8]),
+        value: Some(entry.id),
+    };
+    p.write(&[entry.clone()], &[index_entry], ConflictStrategy::Error)
+        .await?;
+    Ok(entry)
+}
+
+#[tokio::test]
+async fn test_snapshot_at() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("snapshot.sqlite3");
+    let p = SqlitePersistence::new_with_options(
+        path.to_str().unwrap(),
+        SqliteOptions {
+            wal_mode: true,
+            read_pool_size: 2,
+            ..Default::default()
+        },
+    )?;
+    let mut id_generator = TestIdGenerator::new();
+    let index_id = id_generator.system_generate(&INDEX_TABLE).internal_id();
+    let table: TableName = str::parse("table")?;
+    let tablet_id = id_generator.user_table_id(&table).tablet_id;
+
+    let mut expected = vec![];
+    for ts in 1..=3 {
+        expected.push(write(&p, &mut id_generator, index_id, ts).await?);
+    }
+
+    let snapshot = p.reader().snapshot_at(Timestamp::must(5))?;
+    assert_eq!(snapshot.ts(), Timestamp::must(5));
+    // Written after the snapshot was taken, both at and after its timestamp.
+    let later = write(&p, &mut id_generator, index_id, 4).await?;
+    write(&p, &mut id_generator, index_id, 6).await?;
+
+    let loaded = snapshot
+        .load_documents(
+            TimestampRange::all(),
+            Order::Asc,
+            100,
+            Arc::new(NoopRetentionValidator),
+        )
+        .try_collect::<Vec<_>>()
+        .await?;
+    assert_eq!(loaded, expected);
+    let scanned = snapshot
+        .index_scan(
+            index_id,
+            tablet_id,
+            &Interval::all(),
+            Order::Asc,
+            100,
+            Arc::new(NoopRetentionValidator),
+        )
+        .try_collect::<Vec<_>>()
+        .await?;
+    assert_eq!(
+        scanned
+            .into_iter()
+            .map(|(_, document)| document.ts)
+            .collect::<Vec<_>>(),
+        vec![Timestamp::must(1), Timestamp::must(2), Timestamp::must(3)]
+    );
+    // Reads that `SnapshotReader` doesn't wrap reuse the snapshot's read
+    // transaction.
+    let ids = [expected[0].id, later.id];
+    assert_eq!(
+        snapshot
+            .reader()
+            .load_documents_by_ids(&ids, Timestamp::MAX)
+            .await?,
+        vec![Some(expected[0].clone()), None]
+    );
+
+    // Other readers see everything, and dropping the snapshot returns its
+    // connection to the pool.
+    assert_eq!(
+        p.reader()
+            .load_all_documents()
+            .try_collect::<Vec<_>>()
+            .await?
+            .len(),
+        5
+    );
+    drop(snapshot);
+    assert_eq!(p.read_pool_stats().unwrap().in_use, 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_snapshot_at_requires_read_pool() -> anyhow::Result<()> {
+    let p = SqlitePersistence::new_in_memory()?;
+    assert!(p.reader().snapshot_at(Timestamp::must(1)).is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_snapshot_at_keeps_a_connection_for_reads() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("spare.sqlite3");
+    let p = SqlitePersistence::new_with_options(
+        path.to_str().unwrap(),
+        SqliteOptions {
+            wal_mode: true,
+            read_pool_size: 2,
+            ..Default::default()
+        },
+    )?;
+    let snapshot = p.reader().snapshot_at(Timestamp::must(1))?;
+    // A second snapshot would take the last connection that reads can use.
+    assert!(p.reader().snapshot_at(Timestamp::must(1)).is_err());
+    assert!(p.reader().max_timestamp().await?.is_none());
+    drop(snapshot);
+    p.reader().snapshot_at(Timestamp::must(1))?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_attach_reader() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("primary.sqlite3");
+    let p = SqlitePersistence::new_with_options(
+        path.to_str().unwrap(),
+        SqliteOptions {
+            wal_mode: true,
+            read_pool_size: 2,
+            ..Default::default()
+        },
+    )?;
+
+    let mut id_generator = TestIdGenerator::new();
+    let index_id = id_generator.system_generate(&INDEX_TABLE).internal_id();
+    let table: TableName = str::parse("table")?;
+    let tablet_id = id_generator.user_table_id(&table).tablet_id;
+    let entries = (1..=3)
+        .map(|ts| {
+            doc(
+                id_generator.user_generate(&table),
+                ts,
+                Some(ts as i64),
+                None,
+            )
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let indexes: Vec<_> = entries
+        .iter()
+        .map(|entry| PersistenceIndexEntry {
+            ts: entry.ts,
+            index_id,
+            key: IndexKeyBytes(vec![u64::from(entry.ts) as u8]),
+            value: Some(entry.id),
+        })
+        .collect();
+    p.write(&entries, &indexes, ConflictStrategy::Error).await?;
+
+    let replica_path = db.path().join("replica.sqlite3");
+    p.backup_to(&replica_path)?;
+    let replica = p.attach_reader(replica_path.to_str().unwrap(), "replica")?;
+
+    // Writes continue on the primary, but aren't in the replica.
+    let later = doc(id_generator.user_generate(&table), 4, Some(4), None)?;
+    let later_index = PersistenceIndexEntry {
+        ts: later.ts,
+        index_id,
+        key: IndexKeyBytes(vec![4]),
+        value: Some(later.id),
+    };
+    p.write(&[later.clone()], &[later_index], ConflictStrategy::Error)
+        .await?;
+
+    let mut all_entries = entries.clone();
+    all_entries.push(later);
+    assert_eq!(load_all(replica.as_ref()).await?, entries);
+    assert_eq!(load_all(p.reader().as_ref()).await?, all_entries);
+
+    let keys = replica
+        .index_scan(
+            index_id,
+            tablet_id,
+            Timestamp::must(4),
+            &Interval::all(),
+            Order::Asc,
+            100,
+            Arc::new(NoopRetentionValidator),
+        )
+        .map_ok(|(key, _)| key)
+        .try_collect::<Vec<_>>()
+        .await?;
+    assert_eq!(
+        keys,
+        indexes
+            .iter()
+            .map(|entry| entry.key.clone())
+            .collect::<Vec<_>>()
+    );
+
+    // The replica's other reads don't see the later write either.
+    assert_eq!(replica.max_ts().await?, Some(Timestamp::must(3)));
+    assert_eq!(replica.count_documents(TimestampRange::all()).await?, 3);
+    assert_eq!(
+        replica
+            .index_entry_count(index_id, tablet_id, Timestamp::must(4))
+            .await?,
+        3
+    );
+    assert!(
+        !replica
+            .index_exists(
+                index_id,
+                tablet_id,
+                Timestamp::must(4),
+                &Interval::prefix_bytes(&[4])
+            )
+            .await?
+    );
+    assert!(replica.generation().await? < p.reader().generation().await?);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_attach_reader_requires_read_pool() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("unpooled.sqlite3");
+    let p = SqlitePersistence::new(path.to_str().unwrap())?;
+    let replica_path = db.path().join("replica.sqlite3");
+    p.backup_to(&replica_path)?;
+    assert!(p
+        .attach_reader(replica_path.to_str().unwrap(), "replica")
+        .is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_attach_reader_with_connection_checked_out() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("primary.sqlite3");
+    let p = SqlitePersistence::new_with_options(
+        path.to_str().unwrap(),
+        SqliteOptions {
+            wal_mode: true,
+            read_pool_size: 3,
+            ..Default::default()
+        },
+    )?;
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let entry = doc(id_generator.user_generate(&table), 1, Some(1), None)?;
+    p.write(&[entry.clone()], &[], ConflictStrategy::Error)
+        .await?;
+    let replica_path = db.path().join("replica.sqlite3");
+    p.backup_to(&replica_path)?;
+
+    // The snapshot holds a pooled connection, which attaches the replica only
+    // once it's returned.
+    let snapshot = p.reader().snapshot_at(Timestamp::must(1))?;
+    let replica = p.attach_reader(replica_path.to_str().unwrap(), "replica")?;
+    assert_eq!(load_all(replica.as_ref()).await?, vec![entry.clone()]);
+    assert!(p
+        .attach_reader(replica_path.to_str().unwrap(), "replica")
+        .is_err());
+    drop(snapshot);
+    for _ in 0..3 {
+        assert_eq!(load_all(replica.as_ref()).await?, vec![entry.clone()]);
+    }
+    Ok(())
+}
+
+async fn check_with_connection(p: SqlitePersistence) -> anyhow::Result<()> {
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let entries = (1..=3)
+        .map(|ts| doc(id_generator.user_generate(&table), ts, Some(1), None))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    p.write(&entries, &[], ConflictStrategy::Error).await?;
+
+    let count: u64 = p.with_connection(|connection| {
+        connection.query_row("SELECT COUNT(*) FROM documents", [], |row| row.get(0))
+    })?;
+    assert_eq!(
+        count,
+        p.reader().count_documents(TimestampRange::all()).await?
+    );
+    assert_eq!(count, 3);
+
+    // The connection can't be used to write.
+    assert!(p
+        .with_connection(|connection| connection.execute("DELETE FROM documents", []))
+        .is_err());
+    // But writes through the persistence still work afterwards.
+    let entry = doc(id_generator.user_generate(&table), 4, Some(1), None)?;
+    p.write(&[entry], &[], ConflictStrategy::Error).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_with_connection() -> anyhow::Result<()> {
+    check_with_connection(SqlitePersistence::new_in_memory()?).await
+}
+
+#[tokio::test]
+async fn test_with_connection_panic() -> anyhow::Result<()> {
+    let p = SqlitePersistence::new_in_memory()?;
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        p.with_connection(|_| -> rusqlite::Result<()> { panic!("borrower panicked") })
+    }));
+    assert!(result.is_err());
+    // The write connection was made writable again.
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let entry = doc(id_generator.user_generate(&table), 1, Some(1), None)?;
+    p.write(&[entry], &[], ConflictStrategy::Error).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_with_pooled_connection() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("with_connection.sqlite3");
+    let p = SqlitePersistence::new_with_options(
+        path.to_str().unwrap(),
+        SqliteOptions {
+            wal_mode: true,
+            read_pool_size: 2,
+            ..Default::default()
+        },
+    )?;
+    check_with_connection(p).await
+}
+
+// `run_persistence_test_suite!` includes a test that reopens the persistence,
+// which can't work for an in-memory database, so run the rest individually.
+
+#[tokio::test]
+async fn test_in_memory_write_and_load() -> anyhow::Result<()> {
+    persistence_test_suite::write_and_load(Arc::new(SqlitePersistence::new_in_memory()?)).await
+}
+
+#[tokio::test]
+async fn test_in_memory_overwrite_document() -> anyhow::Result<()> {
+    persistence_test_suite::overwrite_document(Arc::new(SqlitePersistence::new_in_memory()?)).await
+}
+
+#[tokio::test]
+async fn test_in_memory_query_index_at_ts() -> anyhow::Result<()> {
+    persistence_test_suite::query_index_at_ts(Arc::new(SqlitePersistence::new_in_memory()?)).await
+}
+
+#[tokio::test]
+async fn test_in_memory_query_index_range_short() -> anyhow::Result<()> {
+    persistence_test_suite::query_index_range_short(Arc::new(SqlitePersistence::new_in_memory()?))
+        .await
+}
+
+#[tokio::test]
+async fn test_in_memory_previous_revisions() -> anyhow::Result<()> {
+    persistence_test_suite::persistence_previous_revisions(Arc::new(
+        SqlitePersistence::new_in_memory()?,
+    ))
+    .await
+}
+
+#[tokio::test]
+async fn test_in_memory_global() -> anyhow::Result<()> {
+    persistence_test_suite::persistence_global(Arc::new(SqlitePersistence::new_in_memory()?)).await
+}
+
+#[tokio::test]
+async fn test_in_memory_reader_shares_database() -> anyhow::Result<()> {
+    let p = SqlitePersistence::new_in_memory()?;
+    assert!(p.is_fresh());
+    let reader = p.reader();
+
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let entry = doc(id_generator.user_generate(&table), 1, Some(1), None)?;
+    p.write(&[entry.clone()], &[], ConflictStrategy::Error)
+        .await?;
+
+    let documents: Vec<_> = reader.load_all_documents().try_collect().await?;
+    assert_eq!(documents, vec![entry]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_open_readonly() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("replica.sqlite3");
+    let path = path.to_str().unwrap();
+
+    let mut id_generator = TestIdGenerator::new();
+    let index_id = id_generator.system_generate(&INDEX_TABLE).internal_id();
+    let table: TableName = str::parse("table")?;
+    let tablet_id = id_generator.user_table_id(&table).tablet_id;
+    let entry = doc(id_generator.user_generate(&table), 1, Some(1), None)?;
+    let index_entry = PersistenceIndexEntry {
+        ts: entry.ts,
+        index_id,
+        key: IndexKeyBytes(vec![1]),
+        value: Some(entry.id),
+    };
+    SqlitePersistence::new(path)?
+        .write(
+            &[entry.clone()],
+            &[index_entry.clone()],
+            ConflictStrategy::Error,
+        )
+        .await?;
+
+    let p = SqlitePersistence::open_readonly(path)?;
+    let reader = p.reader();
+    let documents = reader
+        .load_documents(
+            TimestampRange::all(),
+            Order::Asc,
+            100,
+            Arc::new(NoopRetentionValidator),
+        )
+        .try_collect::<Vec<_>>()
+        .await?;
+    assert_eq!(documents, vec![entry.clone()]);
+    let keys = reader
+        .index_scan(
+            index_id,
+            tablet_id,
+            Timestamp::must(1),
+            &Interval::all(),
+            Order::Asc,
+            100,
+            Arc::new(NoopRetentionValidator),
+        )
+        .try_collect::<Vec<_>>()
+        .await?;
+    assert_eq!(keys.len(), 1);
+    assert_eq!(keys[0].0, index_entry.key);
+
+    let later = doc(id_generator.user_generate(&table), 2, Some(2), None)?;
+    assert!(p
+        .write(&[later], &[], ConflictStrategy::Error)
+        .await
+        .is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_open_readonly_missing_file() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("missing.sqlite3");
+    assert!(SqlitePersistence::open_readonly(path.to_str().unwrap()).is_err());
+    assert!(!path.exists());
+    Ok(())
+}
+
+fn open(path: &str, namespace: Option<&str>) -> anyhow::Result<SqlitePersistence> {
+    SqlitePersistence::new_with_options(
+        path,
+        SqliteOptions {
+            namespace: namespace.map(str::to_owned),
+            ..Default::default()
+        },
+    )
+}
+
+#[tokio::test]
+async fn test_namespaces_are_isolated() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("namespace.sqlite3");
+    let path = path.to_str().unwrap();
+    let a = open(path, Some("tenant_a"))?;
+    let b = open(path, Some("tenant_b"))?;
+    let mut id_generator = TestIdGenerator::new();
+    let index_id: IndexId = id_generator.system_generate(&INDEX_TABLE).internal_id();
+    let table: TableName = str::parse("table")?;
+    let tablet_id = id_generator.user_table_id(&table).tablet_id;
+
+    let a_entry = doc(id_generator.user_generate(&table), 1, Some(1), None)?;
+    let a_index_entry = PersistenceIndexEntry {
+        ts: a_entry.ts,
+        index_id,
+        key: IndexKeyBytes(vec![1]),
+        value: Some(a_entry.id),
+    };
+    a.write(
+        &[a_entry.clone()],
+        &[a_index_entry],
+        ConflictStrategy::Error,
+    )
+    .await?;
+    let b_entries = vec![
+        doc(id_generator.user_generate(&table), 1, Some(2), None)?,
+        doc(id_generator.user_generate(&table), 2, Some(3), None)?,
+    ];
+    b.write(&b_entries, &[], ConflictStrategy::Error).await?;
+
+    assert_eq!(load_all(&a).await?, vec![a_entry]);
+    assert_eq!(load_all(&b).await?, b_entries);
+    let scanned = b
+        .reader()
+        .index_scan(
+            index_id,
+            tablet_id,
+            Timestamp::must(2),
+            &Interval::all(),
+            Order::Asc,
+            100,
+            Arc::new(NoopRetentionValidator),
+        )
+        .try_collect::<Vec<_>>()
+        .await?;
+    assert!(scanned.is_empty());
+    assert_eq!(a.schema_version()?, SCHEMA_VERSION);
+    assert_eq!(b.schema_version()?, SCHEMA_VERSION);
+
+    // Truncating one namespace leaves the other alone.
+    a.truncate().await?;
+    assert!(load_all(&a).await?.is_empty());
+    assert_eq!(load_all(&b).await?, b_entries);
+
+    // Without a namespace the file's default tables are used, which are
+    // separate from both.
+    let unnamespaced = open(path, None)?;
+    assert!(load_all(&unnamespaced).await?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn test_invalid_namespace() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("namespace.sqlite3");
+    for namespace in ["", "tenant-a", "a; DROP TABLE documents"] {
+        assert!(open(path.to_str().unwrap(), Some(namespace)).is_err());
+    }
+    Ok(())
+}
@@ -0,0 +1,86 @@
+#![cfg(feature = "sqlcipher")]
+
+use std::sync::Arc;
+
+use common::{
+    persistence::{
+        ConflictStrategy,
+        NoopRetentionValidator,
+        Persistence,
+        TimestampRange,
+    },
+    query::Order,
+    testing::{
+        persistence_test_suite::doc,
+        TestIdGenerator,
+    },
+    types::TableName,
+};
+use futures::TryStreamExt;
+use sqlite::{
+    EncryptionKey,
+    PersistenceError,
+    SqliteOptions,
+    SqlitePersistence,
+};
+use tempfile::TempDir;
+
+fn encrypted_options(key: &str) -> SqliteOptions {
+    SqliteOptions {
+        encryption_key: Some(EncryptionKey::new(key)),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn test_encrypted_round_trip() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("encrypted.sqlite3");
+    let path = path.to_str().unwrap();
+
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let entry = doc(id_generator.user_generate(&table), 1, Some(5), None)?;
+    {
+        let p = SqlitePersistence::new_with_options(path, encrypted_options("hunter2"))?;
+        p.write(&[entry.clone()], &[], ConflictStrategy::Error)
+            .await?;
+    }
+
+    // The file shouldn't be readable as a plain Sqlite database.
+    assert!(SqlitePersistence::new(path).is_err());
+
+    let p = SqlitePersistence::new_with_options(path, encrypted_options("hunter2"))?;
+    let docs = p
+        .reader()
+        .load_documents(
+            TimestampRange::all(),
+            Order::Asc,
+            10,
+            Arc::new(NoopRetentionValidator),
+        )
+        .try_collect::<Vec<_>>()
+        .await?;
+    assert_eq!(docs, vec![entry]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_wrong_key() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("encrypted.sqlite3");
+    let path = path.to_str().unwrap();
+    drop(SqlitePersistence::new_with_options(
+        path,
+        encrypted_options("hunter2"),
+    )?);
+
+    let err = SqlitePersistence::new_with_options(path, encrypted_options("wrong"))
+        .err()
+        .expect("Opened database with the wrong key");
+    assert!(matches!(
+        err.downcast_ref::<PersistenceError>(),
+        Some(PersistenceError::InvalidEncryptionKey(_))
+    ));
+    Ok(())
+}
@@ -0,0 +1,45 @@
+//! Fixtures shared by the integration tests. Named `helpers` rather than
+//! `common` so it doesn't shadow the `common` crate.
+#![allow(dead_code)]
+
+use std::time::Duration;
+
+use common::persistence::{
+    DocumentLogEntry,
+    PersistenceReader,
+};
+use futures::TryStreamExt;
+use parking_lot::Mutex;
+use sqlite::MetricsRecorder;
+
+/// Every document log entry, in timestamp order.
+pub async fn load_all(reader: &dyn PersistenceReader) -> anyhow::Result<Vec<DocumentLogEntry>> {
+    reader.load_all_documents().try_collect().await
+}
+
+/// Keeps what each metric reported, in order.
+#[derive(Debug, Default)]
+pub struct MockRecorder {
+    pub writes: Mutex<Vec<usize>>,
+    pub load_documents: Mutex<Vec<usize>>,
+    pub index_scans: Mutex<Vec<usize>>,
+    pub wal_fallbacks: Mutex<Vec<String>>,
+}
+
+impl MetricsRecorder for MockRecorder {
+    fn record_write(&self, _duration: Duration, entries: usize) {
+        self.writes.lock().push(entries);
+    }
+
+    fn record_load_documents(&self, _duration: Duration, rows: usize) {
+        self.load_documents.lock().push(rows);
+    }
+
+    fn record_index_scan(&self, _duration: Duration, rows: usize) {
+        self.index_scans.lock().push(rows);
+    }
+
+    fn record_wal_fallback(&self, error: &anyhow::Error) {
+        self.wal_fallbacks.lock().push(format!("{error:#}"));
+    }
+}
@@ -0,0 +1,1103 @@
+mod helpers;
+
+use std::{
+    collections::BTreeMap,
+    sync::Arc,
+};
+
+use common::{
+    bootstrap_model::index::INDEX_TABLE,
+    document::ResolvedDocument,
+    index::{
+        IndexKey,
+        IndexKeyBytes,
+    },
+    interval::{
+        BinaryKey,
+        End,
+        Interval,
+        StartIncluded,
+    },
+    persistence::{
+        ConflictStrategy,
+        NoopRetentionValidator,
+        Persistence,
+        PersistenceIndexEntry,
+        PersistenceReader,
+    },
+    query::Order,
+    testing::{
+        persistence_test_suite::doc,
+        TestIdGenerator,
+    },
+    types::{
+        IndexId,
+        TableName,
+        Timestamp,
+    },
+    value::{
+        values_to_bytes,
+        ConvexValue,
+        FieldName,
+        TabletId,
+    },
+};
+use futures::{
+    StreamExt,
+    TryStreamExt,
+};
+use sqlite::{
+    Collation,
+    PersistenceError,
+    SqliteOptions,
+    SqlitePersistence,
+};
+use tempfile::TempDir;
+
+const KEYS: [&[u8]; 4] = [&[1], &[2], &[2, 0], &[3]];
+
+/// Writes one document per key to a single index.
+async fn write_keys(keys: &[&[u8]]) -> anyhow::Result<(SqlitePersistence, IndexId, TabletId)> {
+    let p = SqlitePersistence::new_in_memory()?;
+    let mut id_generator = TestIdGenerator::new();
+    let index_id = id_generator.system_generate(&INDEX_TABLE).internal_id();
+    let table: TableName = str::parse("table")?;
+    let tablet_id = id_generator.user_table_id(&table).tablet_id;
+
+    let mut documents = vec![];
+    let mut indexes = vec![];
+    for (i, key) in keys.iter().enumerate() {
+        let entry = doc(id_generator.user_generate(&table), 1, Some(i as i64), None)?;
+        indexes.push(PersistenceIndexEntry {
+            ts: entry.ts,
+            index_id,
+            key: IndexKeyBytes(key.to_vec()),
+            value: Some(entry.id),
+        });
+        documents.push(entry);
+    }
+    p.write(&documents, &indexes, ConflictStrategy::Error)
+        .await?;
+    Ok((p, index_id, tablet_id))
+}
+
+/// A tablet other than the one `write_keys` writes to.
+fn other_tablet_id() -> anyhow::Result<TabletId> {
+    let mut id_generator = TestIdGenerator::new();
+    id_generator.system_generate(&INDEX_TABLE);
+    id_generator.user_table_id(&str::parse("table")?);
+    Ok(id_generator.user_table_id(&str::parse("other")?).tablet_id)
+}
+
+async fn scan(
+    p: &SqlitePersistence,
+    index_id: IndexId,
+    tablet_id: TabletId,
+    interval: Interval,
+) -> anyhow::Result<Vec<Vec<u8>>> {
+    let reader = p.reader();
+    let results = reader
+        .index_scan(
+            index_id,
+            tablet_id,
+            Timestamp::must(1),
+            &interval,
+            Order::Asc,
+            100,
+            Arc::new(NoopRetentionValidator),
+        )
+        .try_collect::<Vec<_>>()
+        .await?;
+    Ok(results.into_iter().map(|(key, _)| key.0).collect())
+}
+
+fn interval(start: &[u8], end: Option<&[u8]>) -> Interval {
+    Interval {
+        start: StartIncluded(BinaryKey::from(start.to_vec())),
+        end: match end {
+            Some(end) => End::Excluded(BinaryKey::from(end.to_vec())),
+            None => End::Unbounded,
+        },
+    }
+}
+
+#[tokio::test]
+async fn test_index_scan_bounds() -> anyhow::Result<()> {
+    let (p, index_id, tablet_id) = write_keys(&KEYS).await?;
+
+    // The start is inclusive and the end is exclusive.
+    assert_eq!(
+        scan(&p, index_id, tablet_id, interval(&[2], Some(&[3]))).await?,
+        vec![vec![2], vec![2, 0]]
+    );
+    // Keys extending the start still sort after it.
+    assert_eq!(
+        scan(&p, index_id, tablet_id, interval(&[2, 0], Some(&[3]))).await?,
+        vec![vec![2, 0]]
+    );
+    assert_eq!(
+        scan(&p, index_id, tablet_id, interval(&[2], None)).await?,
+        vec![vec![2], vec![2, 0], vec![3]]
+    );
+    assert_eq!(
+        scan(&p, index_id, tablet_id, Interval::all()).await?,
+        KEYS.iter().map(|key| key.to_vec()).collect::<Vec<_>>()
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_index_scan_empty_interval() -> anyhow::Result<()> {
+    let (p, index_id, tablet_id) = write_keys(&KEYS).await?;
+    assert!(scan(&p, index_id, tablet_id, Interval::empty())
+        .await?
+        .is_empty());
+    assert!(scan(&p, index_id, tablet_id, interval(&[2], Some(&[2])))
+        .await?
+        .is_empty());
+    // An inverted interval is empty rather than an error.
+    assert!(scan(&p, index_id, tablet_id, interval(&[3], Some(&[1])))
+        .await?
+        .is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_index_scan_singleton() -> anyhow::Result<()> {
+    let (p, index_id, tablet_id) = write_keys(&KEYS).await?;
+    assert_eq!(
+        scan(
+            &p,
+            index_id,
+            tablet_id,
+            Interval::singleton(BinaryKey::from(vec![2]))
+        )
+        .await?,
+        vec![vec![2]]
+    );
+    assert!(scan(
+        &p,
+        index_id,
+        tablet_id,
+        Interval::singleton(BinaryKey::from(vec![4]))
+    )
+    .await?
+    .is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_index_scan_prefix() -> anyhow::Result<()> {
+    let (p, index_id, tablet_id) = write_keys(&[&[1], &[1, 2], &[2]]).await?;
+    assert_eq!(
+        scan(&p, index_id, tablet_id, Interval::prefix_bytes(&[1])).await?,
+        vec![vec![1], vec![1, 2]]
+    );
+    assert_eq!(
+        scan(&p, index_id, tablet_id, Interval::prefix_bytes(&[1, 2])).await?,
+        vec![vec![1, 2]]
+    );
+    assert_eq!(
+        scan(&p, index_id, tablet_id, Interval::prefix_bytes(&[])).await?,
+        vec![vec![1], vec![1, 2], vec![2]]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_index_scan_ids() -> anyhow::Result<()> {
+    let (p, index_id, tablet_id) = write_keys(&KEYS).await?;
+    // Delete the entry for [2] at a later timestamp.
+    let deletion = PersistenceIndexEntry {
+        ts: Timestamp::must(2),
+        index_id,
+        key: IndexKeyBytes(vec![2]),
+        value: None,
+    };
+    p.write(&[], &[deletion], ConflictStrategy::Error).await?;
+
+    let reader = p.reader();
+    for ts in [1, 2] {
+        for interval in [Interval::all(), interval(&[2], None)] {
+            for order in [Order::Asc, Order::Desc] {
+                let expected = reader
+                    .index_scan(
+                        index_id,
+                        tablet_id,
+                        Timestamp::must(ts),
+                        &interval,
+                        order,
+                        100,
+                        Arc::new(NoopRetentionValidator),
+                    )
+                    .map_ok(|(_, document)| document.value.id_with_table_id())
+                    .try_collect::<Vec<_>>()
+                    .await?;
+                let ids = reader
+                    .index_scan_ids(
+                        index_id,
+                        tablet_id,
+                        Timestamp::must(ts),
+                        &interval,
+                        order,
+                        None,
+                        Arc::new(NoopRetentionValidator),
+                    )
+                    .try_collect::<Vec<_>>()
+                    .await?;
+                assert_eq!(ids, expected);
+
+                let limited = reader
+                    .index_scan_ids(
+                        index_id,
+                        tablet_id,
+                        Timestamp::must(ts),
+                        &interval,
+                        order,
+                        Some(1),
+                        Arc::new(NoopRetentionValidator),
+                    )
+                    .try_collect::<Vec<_>>()
+                    .await?;
+                assert_eq!(limited, expected[..1]);
+            }
+        }
+    }
+    // Entries of other tablets aren't included.
+    let ids = reader
+        .index_scan_ids(
+            index_id,
+            other_tablet_id()?,
+            Timestamp::must(1),
+            &Interval::all(),
+            Order::Asc,
+            None,
+            Arc::new(NoopRetentionValidator),
+        )
+        .try_collect::<Vec<_>>()
+        .await?;
+    assert!(ids.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_index_scan_latest_version() -> anyhow::Result<()> {
+    let p = SqlitePersistence::new_in_memory()?;
+    let mut id_generator = TestIdGenerator::new();
+    let index_id = id_generator.system_generate(&INDEX_TABLE).internal_id();
+    let table: TableName = str::parse("table")?;
+    let tablet_id = id_generator.user_table_id(&table).tablet_id;
+    let id = id_generator.user_generate(&table);
+    let key = IndexKeyBytes(vec![5]);
+    let v1 = doc(id, 1, Some(1), None)?;
+    let v2 = doc(id, 3, Some(2), Some(1))?;
+    let index_entry = |ts, value| PersistenceIndexEntry {
+        ts: Timestamp::must(ts),
+        index_id,
+        key: key.clone(),
+        value,
+    };
+    p.write(
+        &[v1.clone(), v2.clone()],
+        &[
+            index_entry(1, Some(v1.id)),
+            index_entry(3, Some(v2.id)),
+            index_entry(5, None),
+        ],
+        ConflictStrategy::Error,
+    )
+    .await?;
+
+    let reader = p.reader();
+    for (read_ts, expected) in [
+        (1, Some(v1.clone())),
+        (2, Some(v1)),
+        (3, Some(v2.clone())),
+        (4, Some(v2)),
+        (5, None),
+        (6, None),
+    ] {
+        let results = reader
+            .index_scan(
+                index_id,
+                tablet_id,
+                Timestamp::must(read_ts),
+                &Interval::all(),
+                Order::Asc,
+                100,
+                Arc::new(NoopRetentionValidator),
+            )
+            .try_collect::<Vec<_>>()
+            .await?;
+        let expected = expected
+            .map(|entry| (key.clone(), entry.ts, entry.value.unwrap()))
+            .into_iter()
+            .collect::<Vec<_>>();
+        assert_eq!(
+            results
+                .into_iter()
+                .map(|(key, document)| (key, document.ts, document.value))
+                .collect::<Vec<_>>(),
+            expected,
+            "read_ts {read_ts}"
+        );
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_index_scan_chunks() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("chunks.sqlite3");
+    let open = |index_scan_chunk_size| {
+        SqlitePersistence::new_with_options(
+            path.to_str().unwrap(),
+            SqliteOptions {
+                index_scan_chunk_size: Some(index_scan_chunk_size),
+                ..Default::default()
+            },
+        )
+    };
+
+    // 1000 keys, some of which are updated or deleted at a later timestamp.
+    let p = open(1_000_000)?;
+    let mut id_generator = TestIdGenerator::new();
+    let index_id = id_generator.system_generate(&INDEX_TABLE).internal_id();
+    let table: TableName = str::parse("table")?;
+    let tablet_id = id_generator.user_table_id(&table).tablet_id;
+    let mut documents = vec![];
+    let mut indexes = vec![];
+    for i in 0..1000u16 {
+        let id = id_generator.user_generate(&table);
+        let key = IndexKeyBytes(i.to_be_bytes().to_vec());
+        let entry = doc(id, 1, Some(i as i64), None)?;
+        indexes.push(PersistenceIndexEntry {
+            ts: entry.ts,
+            index_id,
+            key: key.clone(),
+            value: Some(entry.id),
+        });
+        documents.push(entry);
+        let update = if i % 7 == 0 {
+            Some(i as i64 + 1000)
+        } else if i % 10 == 0 {
+            None
+        } else {
+            continue;
+        };
+        let entry = doc(id, 2, update, Some(1))?;
+        indexes.push(PersistenceIndexEntry {
+            ts: entry.ts,
+            index_id,
+            key,
+            value: update.map(|_| entry.id),
+        });
+        documents.push(entry);
+    }
+    p.write(&documents, &indexes, ConflictStrategy::Error)
+        .await?;
+
+    let scan = |p: SqlitePersistence, interval: Interval, order| async move {
+        p.reader()
+            .index_scan(
+                index_id,
+                tablet_id,
+                Timestamp::must(2),
+                &interval,
+                order,
+                100,
+                Arc::new(NoopRetentionValidator),
+            )
+            .try_collect::<Vec<_>>()
+            .await
+    };
+    let intervals = [
+        Interval::all(),
+        interval(&[1, 0], Some(&[2, 0])),
+        Interval::prefix_bytes(&[3]),
+    ];
+    for interval in intervals {
+        for order in [Order::Asc, Order::Desc] {
+            // A single chunk fetches the whole scan with one query.
+            let expected = scan(open(1_000_000)?, interval.clone(), order).await?;
+            assert!(!expected.is_empty());
+            for chunk_size in [1, 7, 256, expected.len(), expected.len() + 1] {
+                let results = scan(open(chunk_size)?, interval.clone(), order).await?;
+                assert_eq!(results, expected, "chunk_size {chunk_size}, {order:?}");
+            }
+        }
+    }
+    let all = scan(open(256)?, Interval::all(), Order::Asc).await?;
+    // Multiples of 10 are deleted, except those that are also multiples of 7.
+    assert_eq!(all.len(), 1000 - (100 - 15));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_index_scan_numeric_range() -> anyhow::Result<()> {
+    let key = |value: ConvexValue| values_to_bytes(&[Some(value)]);
+    let ints = [-20, -3, 0, 7, 42].map(|n: i64| key(ConvexValue::Int64(n)));
+    let floats = [-10.5, -2.25, -0., 0., 3.75, 49.9, 50., 50.5].map(|n: f64| key(n.into()));
+    let keys = ints
+        .iter()
+        .chain(&floats)
+        .map(|key| &key[..])
+        .collect::<Vec<_>>();
+    let (p, index_id, tablet_id) = write_keys(&keys).await?;
+
+    // Keys are returned in index order, and ints sort before floats.
+    assert_eq!(
+        scan(
+            &p,
+            index_id,
+            tablet_id,
+            Interval::float64_range(&[], -5.0..=50.0)?
+        )
+        .await?,
+        floats[1..7]
+    );
+    assert_eq!(
+        scan(
+            &p,
+            index_id,
+            tablet_id,
+            Interval::float64_range(&[], ..0.0)?
+        )
+        .await?,
+        floats[..2]
+    );
+    assert_eq!(
+        scan(&p, index_id, tablet_id, Interval::int64_range(&[], -5..)).await?,
+        ints[1..]
+    );
+    assert_eq!(
+        scan(&p, index_id, tablet_id, Interval::int64_range(&[], -20..0)).await?,
+        ints[..2]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_index_exists() -> anyhow::Result<()> {
+    let (p, index_id, tablet_id) = write_keys(&KEYS).await?;
+    // Delete the entry for [3] at a later timestamp.
+    let deletion = PersistenceIndexEntry {
+        ts: Timestamp::must(2),
+        index_id,
+        key: IndexKeyBytes(vec![3]),
+        value: None,
+    };
+    p.write(&[], &[deletion], ConflictStrategy::Error).await?;
+
+    let reader = p.reader();
+    for (ts, interval, expected) in [
+        (1, Interval::all(), true),
+        (1, interval(&[2], Some(&[3])), true),
+        (1, interval(&[3], None), true),
+        (2, interval(&[3], None), false),
+        (2, interval(&[2, 1], None), false),
+        (1, interval(&[4], None), false),
+        (1, Interval::empty(), false),
+        // Nothing was written before the first timestamp.
+        (0, Interval::all(), false),
+    ] {
+        assert_eq!(
+            reader
+                .index_exists(index_id, tablet_id, Timestamp::must(ts), &interval)
+                .await?,
+            expected,
+            "ts {ts}, {interval:?}"
+        );
+    }
+    // Entries of other tablets don't count.
+    assert!(
+        !reader
+            .index_exists(
+                index_id,
+                other_tablet_id()?,
+                Timestamp::must(1),
+                &Interval::all()
+            )
+            .await?
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_explain_index_scan() -> anyhow::Result<()> {
+    let (p, ..) = write_keys(&KEYS).await?;
+    for interval in [Interval::all(), interval(&[1], Some(&[3]))] {
+        for order in [Order::Asc, Order::Desc] {
+            let plan = p.explain_index_scan(&interval, order)?;
+            // Finding the newest entry for each key searches the primary key
+            // of `indexes` by index id and key range.
+            assert!(
+                plan.contains("INDEX sqlite_autoindex_indexes_1 (index_id=? AND key>?"),
+                "{plan}"
+            );
+            assert!(!plan.contains("SCAN indexes"), "{plan}");
+        }
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_index_scan_intervals() -> anyhow::Result<()> {
+    let keys: [&[u8]; 6] = [&[1], &[2], &[3], &[4], &[5], &[6]];
+    let (p, index_id, tablet_id) = write_keys(&keys).await?;
+    let reader = p.reader();
+    // Disjoint, overlapping and empty ranges, out of order.
+    let ranges = [
+        interval(&[5], None),
+        interval(&[1], Some(&[3])),
+        interval(&[2], Some(&[3])),
+        Interval::empty(),
+    ];
+    for (order, expected) in [
+        (Order::Asc, vec![vec![1], vec![2], vec![5], vec![6]]),
+        (Order::Desc, vec![vec![6], vec![5], vec![2], vec![1]]),
+    ] {
+        let results = reader
+            .index_scan_intervals(
+                index_id,
+                tablet_id,
+                Timestamp::must(1),
+                &ranges,
+                order,
+                100,
+                Arc::new(NoopRetentionValidator),
+            )
+            .try_collect::<Vec<_>>()
+            .await?;
+        assert_eq!(
+            results
+                .into_iter()
+                .map(|(key, _)| key.0)
+                .collect::<Vec<_>>(),
+            expected
+        );
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_index_scan_max_rows_examined() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("budget.sqlite3");
+    let p = SqlitePersistence::new_with_options(
+        path.to_str().unwrap(),
+        SqliteOptions {
+            index_scan_chunk_size: Some(4),
+            index_scan_max_rows_examined: Some(10),
+            ..Default::default()
+        },
+    )?;
+    let mut id_generator = TestIdGenerator::new();
+    let index_id = id_generator.system_generate(&INDEX_TABLE).internal_id();
+    let table: TableName = str::parse("table")?;
+    let tablet_id = id_generator.user_table_id(&table).tablet_id;
+    let mut documents = vec![];
+    let mut indexes = vec![];
+    for i in 0..100 {
+        let entry = doc(id_generator.user_generate(&table), 1, Some(i), None)?;
+        indexes.push(PersistenceIndexEntry {
+            ts: entry.ts,
+            index_id,
+            key: IndexKeyBytes(vec![i as u8]),
+            value: Some(entry.id),
+        });
+        documents.push(entry);
+    }
+    p.write(&documents, &indexes, ConflictStrategy::Error)
+        .await?;
+
+    let reader = p.reader();
+    let scan = |interval| {
+        reader
+            .index_scan(
+                index_id,
+                tablet_id,
+                Timestamp::must(1),
+                &interval,
+                Order::Asc,
+                100,
+                Arc::new(NoopRetentionValidator),
+            )
+            .collect::<Vec<_>>()
+    };
+
+    // The entries within the budget are yielded before the error, which ends
+    // the stream.
+    let results = scan(Interval::all()).await;
+    assert_eq!(results.len(), 11);
+    for (i, result) in results[..10].iter().enumerate() {
+        let (key, _) = result.as_ref().unwrap();
+        assert_eq!(key.0, vec![i as u8]);
+    }
+    let err = results[10].as_ref().unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<PersistenceError>(),
+        Some(PersistenceError::BudgetExceeded(10))
+    ));
+
+    // Scans within the budget aren't affected.
+    let results = scan(Interval {
+        start: StartIncluded(BinaryKey::from(vec![20])),
+        end: End::Excluded(BinaryKey::from(vec![30])),
+    })
+    .await;
+    assert_eq!(results.len(), 10);
+    assert!(results.iter().all(Result::is_ok));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_index_scan_documents() -> anyhow::Result<()> {
+    let p = SqlitePersistence::new_in_memory()?;
+    let mut id_generator = TestIdGenerator::new();
+    let index_id = id_generator.system_generate(&INDEX_TABLE).internal_id();
+    let table: TableName = str::parse("table")?;
+    let tablet_id = id_generator.user_table_id(&table).tablet_id;
+
+    let ids: Vec<_> = (0..5).map(|_| id_generator.user_generate(&table)).collect();
+    let mut documents = vec![];
+    let mut indexes = vec![];
+    for (i, id) in ids.iter().enumerate() {
+        documents.push(doc(*id, 1, Some(i as i64), None)?);
+        indexes.push(PersistenceIndexEntry {
+            ts: Timestamp::must(1),
+            index_id,
+            key: IndexKeyBytes(vec![i as u8]),
+            value: Some((*id).into()),
+        });
+    }
+    // Update the first document in place and delete the second.
+    documents.push(doc(ids[0], 2, Some(10), Some(1))?);
+    documents.push(doc(ids[1], 2, None, Some(1))?);
+    indexes.push(PersistenceIndexEntry {
+        ts: Timestamp::must(2),
+        index_id,
+        key: IndexKeyBytes(vec![0]),
+        value: Some(ids[0].into()),
+    });
+    indexes.push(PersistenceIndexEntry {
+        ts: Timestamp::must(2),
+        index_id,
+        key: IndexKeyBytes(vec![1]),
+        value: None,
+    });
+    p.write(&documents, &indexes, ConflictStrategy::Error)
+        .await?;
+
+    let reader = p.reader();
+    let intervals = [
+        Interval::all(),
+        Interval {
+            start: StartIncluded(BinaryKey::from(vec![1])),
+            end: End::Excluded(BinaryKey::from(vec![4])),
+        },
+    ];
+    for interval in &intervals {
+        for order in [Order::Asc, Order::Desc] {
+            for ts in [1, 2] {
+                let ts = Timestamp::must(ts);
+                let expected = reader
+                    .index_scan(
+                        index_id,
+                        tablet_id,
+                        ts,
+                        interval,
+                        order,
+                        100,
+                        Arc::new(NoopRetentionValidator),
+                    )
+                    .map_ok(|(_, document)| {
+                        (
+                            document.ts,
+                            document.value.id_with_table_id(),
+                            document.value,
+                        )
+                    })
+                    .try_collect::<Vec<_>>()
+                    .await?;
+                let scanned = reader
+                    .index_scan_documents(
+                        index_id,
+                        tablet_id,
+                        ts,
+                        interval,
+                        order,
+                        None,
+                        Arc::new(NoopRetentionValidator),
+                    )
+                    .try_collect::<Vec<_>>()
+                    .await?;
+                assert_eq!(
+                    scanned
+                        .iter()
+                        .map(|entry| (entry.ts, entry.id, entry.value.clone().unwrap()))
+                        .collect::<Vec<_>>(),
+                    expected
+                );
+                // Each entry is the revision the log has for that document.
+                for entry in &scanned {
+                    assert!(documents.contains(entry));
+                }
+            }
+        }
+    }
+
+    let limited = reader
+        .index_scan_documents(
+            index_id,
+            tablet_id,
+            Timestamp::must(2),
+            &Interval::all(),
+            Order::Asc,
+            Some(2),
+            Arc::new(NoopRetentionValidator),
+        )
+        .try_collect::<Vec<_>>()
+        .await?;
+    assert_eq!(limited, vec![documents[5].clone(), documents[2].clone()]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_index_entry_count() -> anyhow::Result<()> {
+    let p = SqlitePersistence::new_in_memory()?;
+    let mut id_generator = TestIdGenerator::new();
+    let index_id = id_generator.system_generate(&INDEX_TABLE).internal_id();
+    let table: TableName = str::parse("table")?;
+    let tablet_id = id_generator.user_table_id(&table).tablet_id;
+
+    let ids: Vec<_> = (0..5).map(|_| id_generator.user_generate(&table)).collect();
+    let mut documents = vec![];
+    let mut indexes = vec![];
+    for (i, id) in ids.iter().enumerate() {
+        let entry = doc(*id, 1, Some(i as i64), None)?;
+        indexes.push(PersistenceIndexEntry {
+            ts: entry.ts,
+            index_id,
+            key: IndexKeyBytes(vec![i as u8]),
+            value: Some(entry.id),
+        });
+        documents.push(entry);
+    }
+    p.write(&documents, &indexes, ConflictStrategy::Error)
+        .await?;
+
+    // Delete two of the documents, and their index entries, at a later ts.
+    let mut deletes = vec![];
+    let mut index_deletes = vec![];
+    for (id, index_entry) in ids.iter().zip(&indexes).take(2) {
+        deletes.push(doc(*id, 2, None, Some(1))?);
+        index_deletes.push(PersistenceIndexEntry {
+            ts: Timestamp::must(2),
+            index_id,
+            key: index_entry.key.clone(),
+            value: None,
+        });
+    }
+    p.write(&deletes, &index_deletes, ConflictStrategy::Error)
+        .await?;
+
+    let reader = p.reader();
+    assert_eq!(
+        reader
+            .index_entry_count(index_id, tablet_id, Timestamp::must(0))
+            .await?,
+        0
+    );
+    assert_eq!(
+        reader
+            .index_entry_count(index_id, tablet_id, Timestamp::must(1))
+            .await?,
+        5
+    );
+    assert_eq!(
+        reader
+            .index_entry_count(index_id, tablet_id, Timestamp::must(2))
+            .await?,
+        3
+    );
+    // Entries of other tablets aren't counted.
+    let other: TableName = str::parse("other")?;
+    let other_tablet_id = id_generator.user_table_id(&other).tablet_id;
+    assert_eq!(
+        reader
+            .index_entry_count(index_id, other_tablet_id, Timestamp::must(2))
+            .await?,
+        0
+    );
+    Ok(())
+}
+
+fn values() -> anyhow::Result<Vec<ConvexValue>> {
+    let field: FieldName = str::parse("a")?;
+    Ok(vec![
+        ConvexValue::try_from(BTreeMap::from([(field.clone(), ConvexValue::from(1))]))?,
+        ConvexValue::try_from(BTreeMap::from([(field, ConvexValue::Null)]))?,
+        ConvexValue::try_from(vec![ConvexValue::from(2), ConvexValue::from(1)])?,
+        ConvexValue::try_from(vec![ConvexValue::from(1)])?,
+        ConvexValue::try_from(Vec::<ConvexValue>::new())?,
+        ConvexValue::try_from(vec![1u8, 2])?,
+        ConvexValue::try_from(vec![1u8])?,
+        ConvexValue::try_from("b")?,
+        ConvexValue::try_from("ab")?,
+        ConvexValue::try_from("")?,
+        ConvexValue::from(true),
+        ConvexValue::from(false),
+        ConvexValue::from(f64::INFINITY),
+        ConvexValue::from(-0.5),
+        ConvexValue::from(f64::NEG_INFINITY),
+        ConvexValue::from(i64::MAX),
+        ConvexValue::from(-1),
+        ConvexValue::from(i64::MIN),
+        ConvexValue::Null,
+    ])
+}
+
+#[tokio::test]
+async fn test_index_scan_matches_value_order() -> anyhow::Result<()> {
+    let p = SqlitePersistence::new_in_memory()?;
+    let mut id_generator = TestIdGenerator::new();
+    let index_id = id_generator.system_generate(&INDEX_TABLE).internal_id();
+    let table: TableName = str::parse("table")?;
+    let tablet_id = id_generator.user_table_id(&table).tablet_id;
+
+    let values = values()?;
+    let mut documents = vec![];
+    let mut indexes = vec![];
+    let mut value_by_key = BTreeMap::new();
+    for (i, value) in values.iter().enumerate() {
+        let entry = doc(id_generator.user_generate(&table), 1, Some(i as i64), None)?;
+        let key = values_to_bytes(&[Some(value.clone())]);
+        indexes.push(PersistenceIndexEntry {
+            ts: entry.ts,
+            index_id,
+            key: IndexKeyBytes(key.clone()),
+            value: Some(entry.id),
+        });
+        value_by_key.insert(key, value.clone());
+        documents.push(entry);
+    }
+    p.write(&documents, &indexes, ConflictStrategy::Error)
+        .await?;
+
+    let mut sorted = values.clone();
+    sorted.sort();
+    for order in [Order::Asc, Order::Desc] {
+        let scanned = p
+            .reader()
+            .index_scan(
+                index_id,
+                tablet_id,
+                Timestamp::must(1),
+                &Interval::all(),
+                order,
+                100,
+                Arc::new(NoopRetentionValidator),
+            )
+            .map_ok(|(key, _)| value_by_key[&key.0].clone())
+            .try_collect::<Vec<_>>()
+            .await?;
+        if order == Order::Desc {
+            sorted.reverse();
+        }
+        assert_eq!(scanned, sorted);
+    }
+    Ok(())
+}
+
+const STRINGS: [&str; 4] = ["b", "A", "a", "B"];
+
+/// Writes one index entry per string and returns the strings in scan order.
+async fn scan_strings(options: SqliteOptions, order: Order) -> anyhow::Result<Vec<String>> {
+    let db = TempDir::new()?;
+    let path = db.path().join("collation.sqlite3");
+    let p = SqlitePersistence::new_with_options(path.to_str().unwrap(), options)?;
+    let mut id_generator = TestIdGenerator::new();
+    let index_id = id_generator.system_generate(&INDEX_TABLE).internal_id();
+    let table: TableName = str::parse("table")?;
+    let tablet_id = id_generator.user_table_id(&table).tablet_id;
+
+    let mut documents = vec![];
+    let mut indexes = vec![];
+    let mut string_by_key = BTreeMap::new();
+    for (i, string) in STRINGS.into_iter().enumerate() {
+        let entry = doc(id_generator.user_generate(&table), 1, Some(i as i64), None)?;
+        let key = values_to_bytes(&[Some(ConvexValue::try_from(string)?)]);
+        indexes.push(PersistenceIndexEntry {
+            ts: entry.ts,
+            index_id,
+            key: IndexKeyBytes(key.clone()),
+            value: Some(entry.id),
+        });
+        string_by_key.insert(key, string.to_owned());
+        documents.push(entry);
+    }
+    p.write(&documents, &indexes, ConflictStrategy::Error)
+        .await?;
+
+    p.reader()
+        .index_scan(
+            index_id,
+            tablet_id,
+            Timestamp::must(1),
+            &Interval::all(),
+            order,
+            100,
+            Arc::new(NoopRetentionValidator),
+        )
+        .map_ok(|(key, _)| string_by_key[&key.0].clone())
+        .try_collect()
+        .await
+}
+
+fn case_insensitive() -> Collation {
+    Collation::new("case_insensitive", |a, b| {
+        a.to_lowercase()
+            .cmp(&b.to_lowercase())
+            .then_with(|| a.cmp(b))
+    })
+}
+
+#[tokio::test]
+async fn test_index_key_collation() -> anyhow::Result<()> {
+    assert_eq!(
+        scan_strings(SqliteOptions::default(), Order::Asc).await?,
+        vec!["A", "B", "a", "b"]
+    );
+    let options = SqliteOptions {
+        index_key_collation: Some(case_insensitive()),
+        ..Default::default()
+    };
+    assert_eq!(
+        scan_strings(options.clone(), Order::Asc).await?,
+        vec!["A", "a", "B", "b"]
+    );
+    assert_eq!(
+        scan_strings(options, Order::Desc).await?,
+        vec!["b", "B", "a", "A"]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_invalid_collation_name() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("collation.sqlite3");
+    let options = SqliteOptions {
+        index_key_collation: Some(Collation::new("no spaces", |a, b| a.cmp(b))),
+        ..Default::default()
+    };
+    assert!(SqlitePersistence::new_with_options(path.to_str().unwrap(), options).is_err());
+    Ok(())
+}
+
+/// Indexes documents by their `value` field.
+fn key_fn(document: &ResolvedDocument) -> Vec<u8> {
+    let value = document
+        .value()
+        .get("value")
+        .cloned()
+        .unwrap_or(ConvexValue::Null);
+    IndexKey::new(vec![value], document.developer_id())
+        .to_bytes()
+        .0
+}
+
+/// The `value` field of every document in the index at `ts`, in index order.
+async fn scan_values(
+    reader: &dyn PersistenceReader,
+    index_id: IndexId,
+    tablet_id: TabletId,
+    ts: i32,
+) -> anyhow::Result<Vec<ConvexValue>> {
+    let results = reader
+        .index_scan(
+            index_id,
+            tablet_id,
+            Timestamp::must(ts),
+            &Interval::all(),
+            Order::Asc,
+            100,
+            Arc::new(NoopRetentionValidator),
+        )
+        .try_collect::<Vec<_>>()
+        .await?;
+    Ok(results
+        .into_iter()
+        .map(|(_, document)| document.value.value().get("value").unwrap().clone())
+        .collect())
+}
+
+#[tokio::test]
+async fn test_rebuild_index() -> anyhow::Result<()> {
+    let p = SqlitePersistence::new_in_memory()?;
+    let mut id_generator = TestIdGenerator::new();
+    let index_id = id_generator.system_generate(&INDEX_TABLE).internal_id();
+    let table: TableName = str::parse("table")?;
+    let tablet_id = id_generator.user_table_id(&table).tablet_id;
+    let a = id_generator.user_generate(&table);
+    let b = id_generator.user_generate(&table);
+    let c = id_generator.user_generate(&table);
+
+    let documents = [
+        doc(a, 1, Some(3), None)?,
+        doc(b, 1, Some(1), None)?,
+        doc(c, 1, Some(2), None)?,
+        doc(a, 2, Some(0), Some(1))?,
+        doc(b, 3, None, Some(1))?,
+    ];
+    // An inconsistent index: a stray entry for `a` under the wrong key, and
+    // nothing for the other documents.
+    let corrupt = PersistenceIndexEntry {
+        ts: Timestamp::must(1),
+        index_id,
+        key: IndexKeyBytes(key_fn(documents[1].value.as_ref().unwrap())),
+        value: Some(a.into()),
+    };
+    p.write(&documents, &[corrupt], ConflictStrategy::Error)
+        .await?;
+    let reader = p.reader();
+    assert_eq!(
+        scan_values(reader.as_ref(), index_id, tablet_id, 3).await?,
+        vec![ConvexValue::Int64(3)]
+    );
+
+    // Three entries at ts 1, a deletion and an entry for the update of `a`,
+    // and a deletion for `b`.
+    assert_eq!(p.rebuild_index(index_id, tablet_id, &key_fn).await?, 6);
+    assert_eq!(
+        scan_values(reader.as_ref(), index_id, tablet_id, 1).await?,
+        vec![
+            ConvexValue::Int64(1),
+            ConvexValue::Int64(2),
+            ConvexValue::Int64(3)
+        ]
+    );
+    assert_eq!(
+        scan_values(reader.as_ref(), index_id, tablet_id, 2).await?,
+        vec![
+            ConvexValue::Int64(0),
+            ConvexValue::Int64(1),
+            ConvexValue::Int64(2)
+        ]
+    );
+    assert_eq!(
+        scan_values(reader.as_ref(), index_id, tablet_id, 3).await?,
+        vec![ConvexValue::Int64(0), ConvexValue::Int64(2)]
+    );
+
+    // Rebuilding again replaces the entries rather than adding to them.
+    assert_eq!(p.rebuild_index(index_id, tablet_id, &key_fn).await?, 6);
+    assert_eq!(
+        reader
+            .index_entry_count(index_id, tablet_id, Timestamp::must(3))
+            .await?,
+        2
+    );
+    Ok(())
+}
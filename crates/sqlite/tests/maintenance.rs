@@ -0,0 +1,732 @@
+mod helpers;
+
+use std::{
+    fs,
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+
+use common::{
+    persistence::{
+        ConflictStrategy,
+        NoopRetentionValidator,
+        Persistence,
+        TimestampRange,
+    },
+    query::Order,
+    testing::{
+        persistence_test_suite::doc,
+        TestIdGenerator,
+    },
+    types::{
+        TableName,
+        Timestamp,
+    },
+};
+use futures::TryStreamExt;
+use rusqlite::Connection;
+use sqlite::{
+    AutoVacuum,
+    CheckpointMode,
+    CheckpointResult,
+    IntegrityCheck,
+    PersistenceError,
+    SqliteOptions,
+    SqlitePersistence,
+    SCHEMA_VERSION,
+};
+use tempfile::TempDir;
+
+use crate::helpers::load_all;
+
+#[tokio::test]
+async fn test_compact_reclaims_deleted_space() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("compact.sqlite3");
+    let p = SqlitePersistence::new_with_options(
+        path.to_str().unwrap(),
+        SqliteOptions {
+            wal_mode: true,
+            ..Default::default()
+        },
+    )?;
+
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let entries = (0..2000)
+        .map(|ts| {
+            doc(
+                id_generator.user_generate(&table),
+                ts,
+                Some(ts as i64),
+                None,
+            )
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    p.write(&entries, &[], ConflictStrategy::Error).await?;
+    p.delete(entries.iter().map(|entry| (entry.ts, entry.id)).collect())
+        .await?;
+
+    assert!(p.compact()? > 0);
+    // Nothing is left to reclaim the second time around.
+    assert_eq!(p.compact()?, 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_compact_incremental_without_auto_vacuum() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("compact_incremental.sqlite3");
+    let p = SqlitePersistence::new(path.to_str().unwrap())?;
+    assert_eq!(p.compact_incremental(100)?, 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_compact_incremental_with_auto_vacuum() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("auto_vacuum.sqlite3");
+    let path = path.to_str().unwrap();
+    let options = SqliteOptions {
+        auto_vacuum: Some(AutoVacuum::Incremental),
+        ..Default::default()
+    };
+    let p = SqlitePersistence::new_with_options(path, options.clone())?;
+    assert_eq!(p.auto_vacuum()?, AutoVacuum::Incremental);
+
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let entries = (0..2000)
+        .map(|ts| {
+            doc(
+                id_generator.user_generate(&table),
+                ts,
+                Some(ts as i64),
+                None,
+            )
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    p.write(&entries, &[], ConflictStrategy::Error).await?;
+    p.delete(entries.iter().map(|entry| (entry.ts, entry.id)).collect())
+        .await?;
+    let freelist_before = p.stats()?.freelist_count;
+    assert!(freelist_before > 0);
+
+    assert!(p.compact_incremental(u32::MAX)? > 0);
+    assert!(p.stats()?.freelist_count < freelist_before);
+
+    // The mode sticks, and can't be changed once the database exists.
+    drop(p);
+    SqlitePersistence::new_with_options(path, options)?;
+    let Err(err) = SqlitePersistence::new_with_options(
+        path,
+        SqliteOptions {
+            auto_vacuum: Some(AutoVacuum::None),
+            ..Default::default()
+        },
+    ) else {
+        panic!("Changing auto_vacuum of an existing database should fail");
+    };
+    assert!(
+        err.to_string().contains("Cannot change auto_vacuum"),
+        "{err}"
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_stats() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("stats.sqlite3");
+    let p = SqlitePersistence::new_with_options(
+        path.to_str().unwrap(),
+        SqliteOptions {
+            wal_mode: true,
+            wal_autocheckpoint: Some(0),
+            ..Default::default()
+        },
+    )?;
+
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let entries = (0..500)
+        .map(|ts| {
+            doc(
+                id_generator.user_generate(&table),
+                ts,
+                Some(ts as i64),
+                None,
+            )
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    p.write(&entries, &[], ConflictStrategy::Error).await?;
+    assert!(p.stats()?.wal_pages > 0);
+
+    p.checkpoint(CheckpointMode::Truncate)?;
+    let stats = p.stats()?;
+    assert_eq!(stats.wal_pages, 0);
+    assert_eq!(stats.file_size_bytes, stats.page_count * stats.page_size);
+    let on_disk = std::fs::metadata(&path)?.len();
+    assert!(on_disk.abs_diff(stats.file_size_bytes) <= stats.page_size);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_backup_to() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("source.sqlite3");
+    let p = SqlitePersistence::new_with_options(
+        path.to_str().unwrap(),
+        SqliteOptions {
+            wal_mode: true,
+            read_pool_size: 2,
+            ..Default::default()
+        },
+    )?;
+
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let entries = (1..=100)
+        .map(|ts| {
+            doc(
+                id_generator.user_generate(&table),
+                ts,
+                Some(ts as i64),
+                None,
+            )
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    p.write(&entries, &[], ConflictStrategy::Error).await?;
+
+    let backup_path = db.path().join("backup.sqlite3");
+    p.backup_to(&backup_path)?;
+
+    // Writes after the backup shouldn't show up in it.
+    let later = doc(id_generator.user_generate(&table), 101, Some(101), None)?;
+    p.write(&[later], &[], ConflictStrategy::Error).await?;
+
+    let backup = SqlitePersistence::new(backup_path.to_str().unwrap())?;
+    assert!(!backup.is_fresh());
+    assert_eq!(load_all(&backup).await?, entries);
+    assert_eq!(load_all(&p).await?.len(), 101);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_backup_in_memory() -> anyhow::Result<()> {
+    let p = SqlitePersistence::new_in_memory()?;
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let entry = doc(id_generator.user_generate(&table), 1, Some(1), None)?;
+    p.write(&[entry.clone()], &[], ConflictStrategy::Error)
+        .await?;
+
+    let db = TempDir::new()?;
+    let backup_path = db.path().join("backup.sqlite3");
+    p.backup_to(&backup_path)?;
+    let backup = SqlitePersistence::new(backup_path.to_str().unwrap())?;
+    assert_eq!(load_all(&backup).await?, vec![entry]);
+    Ok(())
+}
+
+/// The size of the database's WAL, or 0 if it has none.
+fn wal_size(path: &Path) -> u64 {
+    fs::metadata(format!("{}-wal", path.display())).map_or(0, |metadata| metadata.len())
+}
+
+async fn write_batches(p: &SqlitePersistence, batches: i32) -> anyhow::Result<()> {
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    for batch in 0..batches {
+        let entries = (0..100)
+            .map(|i| {
+                let ts = batch * 100 + i;
+                doc(
+                    id_generator.user_generate(&table),
+                    ts,
+                    Some(ts as i64),
+                    None,
+                )
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        p.write(&entries, &[], ConflictStrategy::Error).await?;
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_checkpoint_threshold_bounds_wal() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    // Disable Sqlite's own checkpoints so only the threshold applies.
+    let options = |checkpoint_threshold_pages| SqliteOptions {
+        wal_mode: true,
+        wal_autocheckpoint: Some(0),
+        checkpoint_threshold_pages,
+        ..Default::default()
+    };
+
+    let unbounded_path = db.path().join("unbounded.sqlite3");
+    let unbounded =
+        SqlitePersistence::new_with_options(unbounded_path.to_str().unwrap(), options(None))?;
+    write_batches(&unbounded, 20).await?;
+
+    let bounded_path = db.path().join("bounded.sqlite3");
+    let bounded =
+        SqlitePersistence::new_with_options(bounded_path.to_str().unwrap(), options(Some(10)))?;
+    write_batches(&bounded, 20).await?;
+
+    // Once checkpointed, the WAL is reused from the start instead of growing.
+    assert!(wal_size(&bounded_path) < wal_size(&unbounded_path) / 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_manual_checkpoint() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("manual.sqlite3");
+    let p = SqlitePersistence::new_with_options(
+        path.to_str().unwrap(),
+        SqliteOptions {
+            wal_mode: true,
+            wal_autocheckpoint: Some(0),
+            ..Default::default()
+        },
+    )?;
+    write_batches(&p, 5).await?;
+    assert!(wal_size(&path) > 0);
+
+    let result = p.checkpoint(CheckpointMode::Passive)?;
+    assert!(!result.busy);
+    assert!(result.log_pages > 0);
+    assert_eq!(result.log_pages, result.checkpointed_pages);
+
+    p.checkpoint(CheckpointMode::Truncate)?;
+    assert_eq!(wal_size(&path), 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_checkpoint_modes() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("modes.sqlite3");
+    let p = SqlitePersistence::new_with_options(
+        path.to_str().unwrap(),
+        SqliteOptions {
+            wal_mode: true,
+            wal_autocheckpoint: Some(0),
+            ..Default::default()
+        },
+    )?;
+    for mode in [
+        CheckpointMode::Passive,
+        CheckpointMode::Full,
+        CheckpointMode::Restart,
+        CheckpointMode::Truncate,
+    ] {
+        write_batches(&p, 3).await?;
+        let result = p.checkpoint(mode)?;
+        assert!(!result.busy, "{mode:?}");
+        assert!(result.log_pages > 0, "{mode:?}");
+        assert_eq!(result.log_pages, result.checkpointed_pages, "{mode:?}");
+    }
+    // Only truncating shrinks the WAL file itself.
+    assert_eq!(wal_size(&path), 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_checkpoint_without_wal() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("rollback.sqlite3");
+    let p = SqlitePersistence::new(path.to_str().unwrap())?;
+    assert_eq!(
+        p.checkpoint(CheckpointMode::Passive)?,
+        CheckpointResult {
+            busy: false,
+            log_pages: -1,
+            checkpointed_pages: -1,
+        }
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_checkpoint_loop() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("loop.sqlite3");
+    let p = SqlitePersistence::new_with_options(
+        path.to_str().unwrap(),
+        SqliteOptions {
+            wal_mode: true,
+            wal_autocheckpoint: Some(0),
+            ..Default::default()
+        },
+    )?;
+    write_batches(&p, 5).await?;
+    assert!(wal_size(&path) > 0);
+
+    let handle = p.spawn_checkpoint_loop(Duration::from_millis(10), CheckpointMode::Truncate);
+    for _ in 0..100 {
+        if wal_size(&path) == 0 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(wal_size(&path), 0);
+
+    // Once the handle is dropped, the WAL grows again.
+    drop(handle);
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    write_batches(&p, 5).await?;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(wal_size(&path) > 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_set_wal_autocheckpoint() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let open = |name: &str| {
+        SqlitePersistence::new_with_options(
+            db.path().join(name).to_str().unwrap(),
+            SqliteOptions {
+                wal_mode: true,
+                read_pool_size: 2,
+                ..Default::default()
+            },
+        )
+    };
+
+    let unbounded = open("unbounded.sqlite3")?;
+    unbounded.set_wal_autocheckpoint(0)?;
+    write_batches(&unbounded, 20).await?;
+
+    // A snapshot holding a pooled connection doesn't hold up the change.
+    let bounded = open("bounded.sqlite3")?;
+    let snapshot = bounded.reader().snapshot_at(Timestamp::MIN)?;
+    bounded.set_wal_autocheckpoint(1)?;
+    drop(snapshot);
+    write_batches(&bounded, 20).await?;
+
+    // Checkpointing after every commit keeps the WAL to about one batch.
+    let unbounded_frames = unbounded.wal_frame_count()?;
+    let bounded_frames = bounded.wal_frame_count()?;
+    assert!(bounded_frames > 0);
+    assert!(
+        bounded_frames < unbounded_frames / 4,
+        "{bounded_frames} >= {unbounded_frames} / 4"
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_journal_size_limit() -> anyhow::Result<()> {
+    const LIMIT: u64 = 64 * 1024;
+    let db = TempDir::new()?;
+    let path = db.path().join("limit.sqlite3");
+    let p = SqlitePersistence::new_with_options(
+        path.to_str().unwrap(),
+        SqliteOptions {
+            wal_mode: true,
+            wal_autocheckpoint: Some(0),
+            journal_size_limit: Some(LIMIT),
+            ..Default::default()
+        },
+    )?;
+    write_batches(&p, 50).await?;
+    assert!(wal_size(&path) > LIMIT);
+
+    // Checkpointing alone leaves the file as it is, but the next write
+    // restarts the WAL and truncates it.
+    p.checkpoint(CheckpointMode::Full)?;
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let entry = doc(id_generator.user_generate(&table), 10_000, Some(0), None)?;
+    p.write(&[entry], &[], ConflictStrategy::Error).await?;
+    let size = wal_size(&path);
+    assert!(size <= LIMIT, "{size} > {LIMIT}");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_fsync_barrier_survives_losing_wal() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("barrier.sqlite3");
+    // Without automatic checkpoints, writes stay in the WAL until the barrier.
+    let p = SqlitePersistence::new_with_options(
+        path.to_str().unwrap(),
+        SqliteOptions {
+            wal_mode: true,
+            wal_autocheckpoint: Some(0),
+            ..Default::default()
+        },
+    )?;
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let synced = doc(id_generator.user_generate(&table), 1, Some(1), None)?;
+    p.write(&[synced.clone()], &[], ConflictStrategy::Error)
+        .await?;
+    p.fsync_barrier().await?;
+    let unsynced = doc(id_generator.user_generate(&table), 2, Some(2), None)?;
+    p.write(&[unsynced], &[], ConflictStrategy::Error).await?;
+
+    // Simulate a crash that loses the WAL by copying just the database file
+    // while the persistence is still open.
+    let copy = db.path().join("copy.sqlite3");
+    fs::copy(&path, &copy)?;
+    let reopened = SqlitePersistence::new(copy.to_str().unwrap())?;
+    assert_eq!(load_all(&reopened).await?, vec![synced]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_fsync_barrier_without_wal() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("barrier.sqlite3");
+    let p = SqlitePersistence::new(path.to_str().unwrap())?;
+    p.fsync_barrier().await?;
+    SqlitePersistence::new_in_memory()?.fsync_barrier().await?;
+    Ok(())
+}
+
+const PAGE_SIZE: usize = 4096;
+
+async fn write_database(path: &Path) -> anyhow::Result<()> {
+    let p = SqlitePersistence::new_with_options(
+        path.to_str().unwrap(),
+        SqliteOptions {
+            page_size: Some(PAGE_SIZE as u32),
+            ..Default::default()
+        },
+    )?;
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let entries = (1..=500)
+        .map(|ts| {
+            doc(
+                id_generator.user_generate(&table),
+                ts,
+                Some(ts as i64),
+                None,
+            )
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    p.write(&entries, &[], ConflictStrategy::Error).await?;
+    assert!(p.verify_integrity()?.is_ok());
+    Ok(())
+}
+
+/// Overwrites the header of every page but the first, which holds the schema.
+fn corrupt(path: &Path) -> anyhow::Result<()> {
+    let mut bytes = fs::read(path)?;
+    assert!(bytes.len() > 2 * PAGE_SIZE);
+    for page in bytes.chunks_mut(PAGE_SIZE).skip(1) {
+        page[..8].fill(0xff);
+    }
+    fs::write(path, bytes)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_open_corrupt_database() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("corrupt.sqlite3");
+    write_database(&path).await?;
+    corrupt(&path)?;
+
+    let err = SqlitePersistence::new(path.to_str().unwrap())
+        .err()
+        .expect("opening a corrupt database should fail");
+    assert!(
+        matches!(
+            err.downcast_ref::<PersistenceError>(),
+            Some(PersistenceError::Corrupt(_))
+        ),
+        "{err:#}"
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_verify_integrity_of_corrupt_database() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("corrupt.sqlite3");
+    write_database(&path).await?;
+    corrupt(&path)?;
+
+    // Skipping the check on open defers finding the corruption.
+    let p = SqlitePersistence::new_with_options(
+        path.to_str().unwrap(),
+        SqliteOptions {
+            integrity_check: IntegrityCheck::Skip,
+            ..Default::default()
+        },
+    )?;
+    assert!(!p.verify_integrity()?.is_ok());
+    Ok(())
+}
+
+fn open(path: &Path) -> anyhow::Result<SqlitePersistence> {
+    SqlitePersistence::new_with_options(
+        path.to_str().unwrap(),
+        SqliteOptions {
+            wal_mode: true,
+            read_pool_size: 2,
+            ..Default::default()
+        },
+    )
+}
+
+#[tokio::test]
+async fn test_shutdown() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("shutdown.sqlite3");
+    let p = open(&path)?;
+
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let entries = (1..=100)
+        .map(|ts| {
+            doc(
+                id_generator.user_generate(&table),
+                ts,
+                Some(ts as i64),
+                None,
+            )
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    p.write(&entries, &[], ConflictStrategy::Error).await?;
+    assert!(wal_size(&path) > 0);
+
+    // `shutdown` only checkpoints, so it can be called repeatedly.
+    p.shutdown().await?;
+    p.shutdown().await?;
+    assert_eq!(wal_size(&path), 0);
+
+    p.close()?;
+    assert_eq!(wal_size(&path), 0);
+
+    let p = open(&path)?;
+    let loaded = p
+        .reader()
+        .load_documents(
+            TimestampRange::all(),
+            Order::Asc,
+            100,
+            Arc::new(NoopRetentionValidator),
+        )
+        .try_collect::<Vec<_>>()
+        .await?;
+    assert_eq!(loaded, entries);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_health() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("health.sqlite3");
+    let path = path.to_str().unwrap();
+    for read_pool_size in [0, 2] {
+        let options = SqliteOptions {
+            wal_mode: true,
+            read_pool_size,
+            ..Default::default()
+        };
+        let health = SqlitePersistence::new_with_options(path, options)?.health();
+        assert!(health.ok, "{health:?}");
+        assert!(health.wal_pages.is_some());
+        assert_eq!(health.last_error, None);
+    }
+    assert!(SqlitePersistence::new_in_memory()?.health().ok);
+    Ok(())
+}
+
+fn stored_version(path: &str) -> anyhow::Result<u32> {
+    Ok(Connection::open(path)?
+        .query_row("SELECT version FROM schema_version", [], |row| row.get(0))?)
+}
+
+#[tokio::test]
+async fn test_fresh_database() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("fresh.sqlite3");
+    let path = path.to_str().unwrap();
+    let p = SqlitePersistence::new(path)?;
+    assert_eq!(p.schema_version()?, SCHEMA_VERSION);
+    assert_eq!(stored_version(path)?, SCHEMA_VERSION);
+
+    // Migrating an up to date database does nothing.
+    p.migrate()?;
+    drop(p);
+    SqlitePersistence::new(path)?;
+    assert_eq!(stored_version(path)?, SCHEMA_VERSION);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_migrate_unversioned_database() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("unversioned.sqlite3");
+    let path = path.to_str().unwrap();
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let entry = doc(id_generator.user_generate(&table), 1, Some(1), None)?;
+    {
+        let p = SqlitePersistence::new(path)?;
+        p.write(&[entry.clone()], &[], ConflictStrategy::Error)
+            .await?;
+    }
+    // Strip the tables added since the original schema.
+    Connection::open(path)?.execute_batch("DROP TABLE schema_version; DROP TABLE generation;")?;
+
+    let p = SqlitePersistence::new(path)?;
+    assert_eq!(p.schema_version()?, SCHEMA_VERSION);
+    // The existing data survives, and the tables added since work.
+    let reader = p.reader();
+    assert_eq!(reader.generation().await?, 0);
+    let entry2 = doc(id_generator.user_generate(&table), 2, Some(2), None)?;
+    p.write(&[entry2.clone()], &[], ConflictStrategy::Error)
+        .await?;
+    assert_eq!(reader.generation().await?, 1);
+    assert_eq!(
+        reader
+            .load_all_documents()
+            .map_ok(|entry| entry.id)
+            .try_collect::<Vec<_>>()
+            .await?,
+        vec![entry.id, entry2.id]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_newer_schema_version() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("newer.sqlite3");
+    let path = path.to_str().unwrap();
+    drop(SqlitePersistence::new(path)?);
+    Connection::open(path)?.execute(
+        "UPDATE schema_version SET version = ?",
+        [SCHEMA_VERSION + 1],
+    )?;
+
+    for result in [
+        SqlitePersistence::new(path),
+        SqlitePersistence::open_readonly(path),
+    ] {
+        let Err(err) = result else {
+            panic!("Opened a database with a newer schema");
+        };
+        assert!(
+            matches!(
+                err.downcast_ref::<PersistenceError>(),
+                Some(PersistenceError::UnsupportedSchemaVersion(v)) if *v == SCHEMA_VERSION + 1
+            ),
+            "{err:#}"
+        );
+    }
+    // Nothing was changed.
+    assert_eq!(stored_version(path)?, SCHEMA_VERSION + 1);
+    Ok(())
+}
@@ -0,0 +1,567 @@
+mod helpers;
+
+use std::{
+    ffi::CString,
+    os::raw::{
+        c_char,
+        c_int,
+    },
+    path::Path,
+    ptr,
+    sync::{
+        atomic::{
+            AtomicPtr,
+            AtomicUsize,
+            Ordering,
+        },
+        Arc,
+        Once,
+    },
+};
+
+use common::{
+    assert_obj,
+    bootstrap_model::index::INDEX_TABLE,
+    document::{
+        CreationTime,
+        ResolvedDocument,
+    },
+    index::IndexKeyBytes,
+    interval::Interval,
+    persistence::{
+        ConflictStrategy,
+        DocumentLogEntry,
+        NoopRetentionValidator,
+        Persistence,
+        PersistenceIndexEntry,
+        TimestampRange,
+    },
+    query::Order,
+    testing::{
+        persistence_test_suite::doc,
+        TestIdGenerator,
+    },
+    types::{
+        TableName,
+        Timestamp,
+    },
+};
+use futures::TryStreamExt;
+use rusqlite::{
+    ffi,
+    Connection,
+};
+use sqlite::{
+    Compression,
+    PersistenceError,
+    SqliteOptions,
+    SqlitePersistence,
+    Synchronous,
+    TempStore,
+};
+use tempfile::TempDir;
+
+use crate::helpers::{
+    load_all,
+    MockRecorder,
+};
+
+#[tokio::test]
+async fn test_page_size() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("page_size.sqlite3");
+    let path = path.to_str().unwrap();
+    let options = SqliteOptions {
+        page_size: Some(16384),
+        ..Default::default()
+    };
+    let p = SqlitePersistence::new_with_options(path, options.clone())?;
+
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let entry = doc(id_generator.user_generate(&table), 1, Some(1), None)?;
+    p.write(&[entry], &[], ConflictStrategy::Error).await?;
+
+    let conn = Connection::open(path)?;
+    let page_size: u32 = conn.pragma_query_value(None, "page_size", |row| row.get(0))?;
+    assert_eq!(page_size, 16384);
+
+    // Reopening with the same page size is fine.
+    drop(p);
+    SqlitePersistence::new_with_options(path, options)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_page_size_of_existing_database() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("page_size_existing.sqlite3");
+    let path = path.to_str().unwrap();
+    SqlitePersistence::new(path)?;
+
+    let options = SqliteOptions {
+        page_size: Some(16384),
+        ..Default::default()
+    };
+    let Err(err) = SqlitePersistence::new_with_options(path, options) else {
+        panic!("Changing the page size of an existing database should fail");
+    };
+    assert!(err.to_string().contains("Cannot change page_size"), "{err}");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_mmap_size() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("mmap_size.sqlite3");
+    let path = path.to_str().unwrap();
+    let mmap_size = 64 * 1024 * 1024;
+    for read_pool_size in [0, 2] {
+        let options = SqliteOptions {
+            mmap_size: Some(mmap_size),
+            read_pool_size,
+            ..Default::default()
+        };
+        let p = SqlitePersistence::new_with_options(path, options)?;
+        assert_eq!(p.mmap_size()?, mmap_size);
+    }
+
+    // Zero disables memory-mapped I/O.
+    let options = SqliteOptions {
+        mmap_size: Some(0),
+        ..Default::default()
+    };
+    let p = SqlitePersistence::new_with_options(path, options)?;
+    assert_eq!(p.mmap_size()?, 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_cache_size() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("cache_size.sqlite3");
+    let path = path.to_str().unwrap();
+    assert_eq!(SqlitePersistence::new(path)?.cache_size()?, -2000);
+    // Both KiB and pages, read back from a pooled connection.
+    for cache_size in [-65536, 500] {
+        let options = SqliteOptions {
+            cache_size: Some(cache_size),
+            read_pool_size: 2,
+            ..Default::default()
+        };
+        let p = SqlitePersistence::new_with_options(path, options)?;
+        assert_eq!(p.cache_size()?, cache_size);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_temp_store() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("temp_store.sqlite3");
+    let path = path.to_str().unwrap();
+    assert_eq!(
+        SqlitePersistence::new(path)?.temp_store()?,
+        TempStore::Default
+    );
+    for temp_store in [TempStore::File, TempStore::Memory] {
+        let options = SqliteOptions {
+            temp_store,
+            read_pool_size: 2,
+            temp_directory: Some(db.path().to_path_buf()),
+            ..Default::default()
+        };
+        let p = SqlitePersistence::new_with_options(path, options)?;
+        assert_eq!(p.temp_store()?, temp_store);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_synchronous() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    for wal_mode in [false, true] {
+        let path = db.path().join(format!("synchronous_{wal_mode}.sqlite3"));
+        let path = path.to_str().unwrap();
+        let options = SqliteOptions {
+            wal_mode,
+            ..Default::default()
+        };
+        let default = if wal_mode {
+            Synchronous::Normal
+        } else {
+            Synchronous::Full
+        };
+        assert_eq!(
+            SqlitePersistence::new_with_options(path, options.clone())?.synchronous()?,
+            default
+        );
+        for synchronous in [
+            Synchronous::Off,
+            Synchronous::Normal,
+            Synchronous::Full,
+            Synchronous::Extra,
+        ] {
+            let options = SqliteOptions {
+                synchronous: Some(synchronous),
+                ..options.clone()
+            };
+            let p = SqlitePersistence::new_with_options(path, options)?;
+            assert_eq!(p.synchronous()?, synchronous);
+        }
+    }
+    Ok(())
+}
+
+const CODECS: [Compression; 3] = [
+    Compression::None,
+    Compression::Zstd { level: 3 },
+    Compression::Lz4,
+];
+
+fn open(path: &Path, compression: Compression) -> anyhow::Result<SqlitePersistence> {
+    SqlitePersistence::new_with_options(
+        path.to_str().unwrap(),
+        SqliteOptions {
+            compression,
+            ..Default::default()
+        },
+    )
+}
+
+/// Documents with large, compressible values.
+fn documents(
+    id_generator: &mut TestIdGenerator,
+    timestamps: impl Iterator<Item = i32>,
+) -> anyhow::Result<Vec<DocumentLogEntry>> {
+    let table: TableName = str::parse("table")?;
+    timestamps
+        .map(|ts| {
+            let id = id_generator.user_generate(&table);
+            let value = assert_obj!("value" => format!("{ts}").repeat(1000));
+            Ok(DocumentLogEntry {
+                ts: Timestamp::must(ts),
+                id: id.into(),
+                value: Some(ResolvedDocument::new(id, CreationTime::ONE, value)?),
+                prev_ts: None,
+            })
+        })
+        .collect()
+}
+
+/// The storage class of each stored value, e.g. "text" or "blob".
+fn stored_types(path: &Path) -> anyhow::Result<Vec<String>> {
+    let connection = Connection::open(path)?;
+    let mut stmt = connection.prepare("SELECT typeof(json_value) FROM documents ORDER BY ts")?;
+    let types = stmt
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(types)
+}
+
+#[tokio::test]
+async fn test_compression_roundtrip() -> anyhow::Result<()> {
+    for compression in CODECS {
+        let db = TempDir::new()?;
+        let path = db.path().join("compressed.sqlite3");
+        let p = open(&path, compression)?;
+        let mut id_generator = TestIdGenerator::new();
+        let entries = documents(&mut id_generator, 1..=10)?;
+        p.write(&entries, &[], ConflictStrategy::Error).await?;
+
+        assert_eq!(load_all(&p).await?, entries, "{compression:?}");
+        let last = entries.last().unwrap();
+        assert_eq!(
+            p.reader().load_document(last.id, last.ts).await?.as_ref(),
+            Some(last),
+            "{compression:?}"
+        );
+        let expected_type = match compression {
+            Compression::None => "text",
+            _ => "blob",
+        };
+        assert!(
+            stored_types(&path)?.iter().all(|t| t == expected_type),
+            "{compression:?}"
+        );
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_mixed_codecs() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("mixed.sqlite3");
+    let mut id_generator = TestIdGenerator::new();
+    let mut expected = vec![];
+
+    // Each reopen writes a few rows with a different codec, starting with
+    // uncompressed rows as written before compression existed.
+    for (i, compression) in CODECS.into_iter().enumerate() {
+        let p = open(&path, compression)?;
+        let start = i as i32 * 10;
+        let entries = documents(&mut id_generator, start + 1..=start + 3)?;
+        p.write(&entries, &[], ConflictStrategy::Error).await?;
+        expected.extend(entries);
+        assert_eq!(load_all(&p).await?, expected, "{compression:?}");
+    }
+    assert_eq!(
+        stored_types(&path)?,
+        ["text", "text", "text", "blob", "blob", "blob", "blob", "blob", "blob"]
+    );
+
+    // Turning compression off again still reads every row.
+    let p = open(&path, Compression::None)?;
+    assert_eq!(load_all(&p).await?, expected);
+    Ok(())
+}
+
+const COUNTING_VFS: &str = "counting";
+const NO_SHM_VFS: &str = "no_shm";
+
+static DEFAULT_VFS: AtomicPtr<ffi::sqlite3_vfs> = AtomicPtr::new(ptr::null_mut());
+static OPENED_FILES: AtomicUsize = AtomicUsize::new(0);
+
+type OpenFn = unsafe extern "C" fn(
+    *mut ffi::sqlite3_vfs,
+    *const c_char,
+    *mut ffi::sqlite3_file,
+    c_int,
+    *mut c_int,
+) -> c_int;
+
+/// Registers a VFS that behaves like the default one, but opens files with
+/// `open`.
+fn register_vfs(name: &str, open: OpenFn) {
+    unsafe {
+        let default = ffi::sqlite3_vfs_find(ptr::null());
+        assert!(!default.is_null());
+        DEFAULT_VFS.store(default, Ordering::SeqCst);
+        let mut vfs = *default;
+        vfs.zName = CString::new(name).unwrap().into_raw();
+        vfs.pNext = ptr::null_mut();
+        vfs.xOpen = Some(open);
+        // Sqlite keeps the pointer for as long as the VFS is registered.
+        let vfs = Box::into_raw(Box::new(vfs));
+        assert_eq!(ffi::sqlite3_vfs_register(vfs, 0), ffi::SQLITE_OK);
+    }
+}
+
+/// Opens files with the default VFS, counting them.
+unsafe extern "C" fn counting_open(
+    _vfs: *mut ffi::sqlite3_vfs,
+    name: *const c_char,
+    file: *mut ffi::sqlite3_file,
+    flags: c_int,
+    out_flags: *mut c_int,
+) -> c_int {
+    OPENED_FILES.fetch_add(1, Ordering::SeqCst);
+    let default = DEFAULT_VFS.load(Ordering::SeqCst);
+    unsafe {
+        let open = (*default).xOpen.unwrap();
+        open(default, name, file, flags, out_flags)
+    }
+}
+
+/// Opens files with the default VFS, but without the shared memory methods
+/// WAL needs, like a network filesystem that doesn't support them.
+unsafe extern "C" fn no_shm_open(
+    _vfs: *mut ffi::sqlite3_vfs,
+    name: *const c_char,
+    file: *mut ffi::sqlite3_file,
+    flags: c_int,
+    out_flags: *mut c_int,
+) -> c_int {
+    let default = DEFAULT_VFS.load(Ordering::SeqCst);
+    unsafe {
+        let open = (*default).xOpen.unwrap();
+        let rc = open(default, name, file, flags, out_flags);
+        if rc == ffi::SQLITE_OK && !(*file).pMethods.is_null() {
+            let mut methods = *(*file).pMethods;
+            methods.iVersion = 1;
+            methods.xShmMap = None;
+            methods.xShmLock = None;
+            methods.xShmBarrier = None;
+            methods.xShmUnmap = None;
+            // The file keeps using these until it's closed, so leak them.
+            (*file).pMethods = Box::into_raw(Box::new(methods));
+        }
+        rc
+    }
+}
+
+#[tokio::test]
+async fn test_custom_vfs() -> anyhow::Result<()> {
+    static REGISTER: Once = Once::new();
+    REGISTER.call_once(|| register_vfs(COUNTING_VFS, counting_open));
+    let db = TempDir::new()?;
+    let path = db.path().join("vfs.sqlite3");
+    let opened_before = OPENED_FILES.load(Ordering::SeqCst);
+    let p = SqlitePersistence::new_with_options(
+        path.to_str().unwrap(),
+        SqliteOptions {
+            vfs: Some(COUNTING_VFS.to_owned()),
+            wal_mode: true,
+            read_pool_size: 2,
+            ..Default::default()
+        },
+    )?;
+    // At least the database file of each connection goes through the VFS.
+    assert!(OPENED_FILES.load(Ordering::SeqCst) >= opened_before + 3);
+
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let entry = doc(id_generator.user_generate(&table), 1, Some(1), None)?;
+    p.write(&[entry.clone()], &[], ConflictStrategy::Error)
+        .await?;
+    assert_eq!(load_all(&p).await?, vec![entry]);
+    Ok(())
+}
+
+#[test]
+fn test_unknown_vfs() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("vfs.sqlite3");
+    let Err(err) = SqlitePersistence::new_with_options(
+        path.to_str().unwrap(),
+        SqliteOptions {
+            vfs: Some("no_such_vfs".to_owned()),
+            ..Default::default()
+        },
+    ) else {
+        panic!("Opened a database with an unregistered VFS");
+    };
+    assert!(
+        matches!(
+            err.downcast_ref::<PersistenceError>(),
+            Some(PersistenceError::UnknownVfs(name)) if name == "no_such_vfs"
+        ),
+        "{err:#}"
+    );
+    assert!(!path.exists());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_wal_fallback() -> anyhow::Result<()> {
+    static REGISTER: Once = Once::new();
+    REGISTER.call_once(|| register_vfs(NO_SHM_VFS, no_shm_open));
+    let db = TempDir::new()?;
+    let path = db.path().join("wal_fallback.sqlite3");
+    let options = SqliteOptions {
+        vfs: Some(NO_SHM_VFS.to_owned()),
+        wal_mode: true,
+        ..Default::default()
+    };
+    assert!(SqlitePersistence::new_with_options(path.to_str().unwrap(), options.clone()).is_err());
+
+    let recorder = Arc::new(MockRecorder::default());
+    let p = SqlitePersistence::new_with_options(
+        path.to_str().unwrap(),
+        SqliteOptions {
+            wal_fallback: true,
+            metrics_recorder: Some(recorder.clone()),
+            ..options
+        },
+    )?;
+    assert_eq!(recorder.wal_fallbacks.lock().len(), 1);
+
+    // Still usable, just without WAL.
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let entry = doc(id_generator.user_generate(&table), 1, Some(1), None)?;
+    p.write(&[entry.clone()], &[], ConflictStrategy::Error)
+        .await?;
+    assert_eq!(load_all(&p).await?, vec![entry]);
+    drop(p);
+    let journal_mode: String =
+        Connection::open(&path)?.query_row("PRAGMA journal_mode", [], |row| row.get(0))?;
+    assert_eq!(journal_mode, "delete");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_metrics_recorder() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("metrics.sqlite3");
+    let recorder = Arc::new(MockRecorder::default());
+    let p = SqlitePersistence::new_with_options(
+        path.to_str().unwrap(),
+        SqliteOptions {
+            metrics_recorder: Some(recorder.clone()),
+            ..Default::default()
+        },
+    )?;
+
+    let mut id_generator = TestIdGenerator::new();
+    let index_id = id_generator.system_generate(&INDEX_TABLE).internal_id();
+    let table: TableName = str::parse("table")?;
+    let tablet_id = id_generator.user_table_id(&table).tablet_id;
+    let mut documents = vec![];
+    let mut indexes = vec![];
+    for i in 0..3 {
+        let entry = doc(id_generator.user_generate(&table), 1, Some(i), None)?;
+        indexes.push(PersistenceIndexEntry {
+            ts: entry.ts,
+            index_id,
+            key: IndexKeyBytes(vec![i as u8]),
+            value: Some(entry.id),
+        });
+        documents.push(entry);
+    }
+    p.write(&documents, &indexes, ConflictStrategy::Error)
+        .await?;
+    assert_eq!(*recorder.writes.lock(), vec![3]);
+
+    // Readers report to the same recorder.
+    let reader = p.reader();
+    let loaded = reader
+        .load_documents(
+            TimestampRange::all(),
+            Order::Asc,
+            100,
+            Arc::new(NoopRetentionValidator),
+        )
+        .try_collect::<Vec<_>>()
+        .await?;
+    assert_eq!(loaded.len(), 3);
+    assert_eq!(*recorder.load_documents.lock(), vec![3]);
+
+    let scanned = reader
+        .index_scan(
+            index_id,
+            tablet_id,
+            Timestamp::must(1),
+            &Interval::all(),
+            Order::Asc,
+            100,
+            Arc::new(NoopRetentionValidator),
+        )
+        .try_collect::<Vec<_>>()
+        .await?;
+    assert_eq!(scanned.len(), 3);
+    assert_eq!(*recorder.index_scans.lock(), vec![3]);
+
+    // A scan that's dropped before it finishes is recorded when it's dropped.
+    let mut scan = reader.index_scan(
+        index_id,
+        tablet_id,
+        Timestamp::must(1),
+        &Interval::all(),
+        Order::Asc,
+        100,
+        Arc::new(NoopRetentionValidator),
+    );
+    assert!(scan.try_next().await?.is_some());
+    assert_eq!(*recorder.index_scans.lock(), vec![3]);
+    drop(scan);
+    assert_eq!(*recorder.index_scans.lock(), vec![3, 3]);
+
+    // Failed writes aren't recorded.
+    assert!(p
+        .write(&documents, &indexes, ConflictStrategy::Error)
+        .await
+        .is_err());
+    assert_eq!(*recorder.writes.lock(), vec![3]);
+    Ok(())
+}
@@ -0,0 +1,985 @@
+mod helpers;
+
+use std::{
+    collections::BTreeMap,
+    sync::Arc,
+};
+
+use common::{
+    assert_obj,
+    bootstrap_model::index::INDEX_TABLE,
+    document::{
+        CreationTime,
+        ResolvedDocument,
+    },
+    index::IndexKeyBytes,
+    interval::Interval,
+    persistence::{
+        CompareOp,
+        ConflictStrategy,
+        DocumentDiff,
+        DocumentLogEntry,
+        NoopRetentionValidator,
+        Persistence,
+        PersistenceIndexEntry,
+        PersistenceReader,
+        TimestampRange,
+    },
+    query::Order,
+    testing::{
+        persistence_test_suite::doc,
+        TestIdGenerator,
+    },
+    types::{
+        IndexId,
+        TableName,
+        Timestamp,
+    },
+    value::{
+        ConvexObject,
+        ConvexValue,
+        InternalDocumentId,
+        TabletId,
+    },
+};
+use futures::TryStreamExt;
+use sqlite::{
+    Compression,
+    SqliteOptions,
+    SqlitePersistence,
+};
+use tempfile::TempDir;
+
+use crate::helpers::MockRecorder;
+
+#[tokio::test]
+async fn test_load_document() -> anyhow::Result<()> {
+    let p = SqlitePersistence::new_in_memory()?;
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let id = id_generator.user_generate(&table);
+    let other_id = id_generator.user_generate(&table);
+
+    let v1 = doc(id, 2, Some(1), None)?;
+    let v2 = doc(id, 4, Some(2), Some(2))?;
+    let deleted = doc(id, 6, None, Some(4))?;
+    let other = doc(other_id, 5, Some(3), None)?;
+    p.write(
+        &[v1.clone(), v2.clone(), deleted.clone(), other],
+        &[],
+        ConflictStrategy::Error,
+    )
+    .await?;
+
+    let reader = p.reader();
+    let id = id.into();
+    // Before the document was created.
+    assert_eq!(reader.load_document(id, Timestamp::must(1)).await?, None);
+    // Exactly at and between revisions.
+    assert_eq!(
+        reader.load_document(id, Timestamp::must(2)).await?,
+        Some(v1)
+    );
+    assert_eq!(
+        reader.load_document(id, Timestamp::must(5)).await?,
+        Some(v2)
+    );
+    // After the delete, the tombstone is returned.
+    let loaded = reader.load_document(id, Timestamp::must(7)).await?;
+    assert_eq!(loaded, Some(deleted));
+    assert!(loaded.unwrap().value.is_none());
+    Ok(())
+}
+
+async fn load_batches(
+    p: &SqlitePersistence,
+    order: Order,
+) -> anyhow::Result<Vec<Vec<DocumentLogEntry>>> {
+    p.reader()
+        .load_document_batches(
+            TimestampRange::all(),
+            order,
+            100,
+            Arc::new(NoopRetentionValidator),
+        )
+        .try_collect()
+        .await
+}
+
+#[tokio::test]
+async fn test_load_document_batches() -> anyhow::Result<()> {
+    let p = SqlitePersistence::new_in_memory()?;
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let mut entries = vec![];
+    for (ts, count) in [(1, 3), (2, 1), (3, 2)] {
+        for _ in 0..count {
+            entries.push(doc(
+                id_generator.user_generate(&table),
+                ts,
+                Some(ts as i64),
+                None,
+            )?);
+        }
+    }
+    p.write(&entries, &[], ConflictStrategy::Error).await?;
+
+    let batches = load_batches(&p, Order::Asc).await?;
+    assert_eq!(
+        batches.iter().map(|batch| batch.len()).collect::<Vec<_>>(),
+        vec![3, 1, 2]
+    );
+    for (batch, ts) in batches.iter().zip(1..) {
+        assert!(batch.iter().all(|entry| entry.ts == Timestamp::must(ts)));
+    }
+
+    let batches = load_batches(&p, Order::Desc).await?;
+    assert_eq!(
+        batches.iter().map(|batch| batch.len()).collect::<Vec<_>>(),
+        vec![2, 1, 3]
+    );
+    assert_eq!(
+        load_batches(&SqlitePersistence::new_in_memory()?, Order::Asc).await?,
+        Vec::<Vec<_>>::new()
+    );
+    Ok(())
+}
+
+async fn history(
+    p: &SqlitePersistence,
+    id: InternalDocumentId,
+    range: TimestampRange,
+    order: Order,
+) -> anyhow::Result<Vec<DocumentLogEntry>> {
+    p.reader()
+        .load_document_history(id, range, order, Arc::new(NoopRetentionValidator))
+        .try_collect()
+        .await
+}
+
+#[tokio::test]
+async fn test_load_document_history() -> anyhow::Result<()> {
+    let p = SqlitePersistence::new_in_memory()?;
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let id = id_generator.user_generate(&table);
+    let other_id = id_generator.user_generate(&table);
+
+    let entries = vec![
+        doc(id, 1, Some(1), None)?,
+        doc(other_id, 1, Some(0), None)?,
+        doc(id, 2, Some(2), Some(1))?,
+        doc(id, 3, Some(3), Some(2))?,
+        doc(other_id, 3, Some(1), Some(1))?,
+        doc(id, 4, None, Some(3))?,
+    ];
+    p.write(&entries, &[], ConflictStrategy::Error).await?;
+
+    let expected = vec![
+        entries[0].clone(),
+        entries[2].clone(),
+        entries[3].clone(),
+        entries[5].clone(),
+    ];
+    let internal_id = entries[0].id;
+    assert_eq!(
+        history(&p, internal_id, TimestampRange::all(), Order::Asc).await?,
+        expected
+    );
+    assert_eq!(
+        history(&p, internal_id, TimestampRange::all(), Order::Desc).await?,
+        expected.iter().rev().cloned().collect::<Vec<_>>()
+    );
+    assert_eq!(
+        history(
+            &p,
+            internal_id,
+            TimestampRange::new(Timestamp::must(2)..Timestamp::must(4)),
+            Order::Asc
+        )
+        .await?,
+        expected[1..3].to_vec()
+    );
+    Ok(())
+}
+
+async fn follow(
+    reader: &dyn PersistenceReader,
+    exclusive_ts: Timestamp,
+) -> anyhow::Result<Vec<DocumentLogEntry>> {
+    reader
+        .load_documents_after(
+            exclusive_ts,
+            Order::Asc,
+            10,
+            Arc::new(NoopRetentionValidator),
+        )
+        .try_collect()
+        .await
+}
+
+#[tokio::test]
+async fn test_load_documents_after() -> anyhow::Result<()> {
+    let p = SqlitePersistence::new_in_memory()?;
+    let reader = p.reader();
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let mut entries = vec![];
+    for ts in 1..=6 {
+        entries.push(doc(
+            id_generator.user_generate(&table),
+            ts,
+            Some(ts as i64),
+            None,
+        )?);
+    }
+
+    p.write(&entries[..3], &[], ConflictStrategy::Error).await?;
+    let seen = follow(reader.as_ref(), Timestamp::MIN).await?;
+    assert_eq!(seen, entries[..3]);
+    let max_seen = seen.last().unwrap().ts;
+
+    // Polling again from the latest timestamp seen yields nothing until
+    // there's something new.
+    assert_eq!(follow(reader.as_ref(), max_seen).await?, vec![]);
+    p.write(&entries[3..], &[], ConflictStrategy::Error).await?;
+    assert_eq!(follow(reader.as_ref(), max_seen).await?, entries[3..]);
+    assert_eq!(follow(reader.as_ref(), Timestamp::MAX).await?, vec![]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_documents_by_ids() -> anyhow::Result<()> {
+    let p = SqlitePersistence::new_in_memory()?;
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let updated = id_generator.user_generate(&table);
+    let deleted = id_generator.user_generate(&table);
+    let missing = id_generator.user_generate(&table);
+    let later = id_generator.user_generate(&table);
+    let updated_v1 = doc(updated, 1, Some(1), None)?;
+    let updated_v2 = doc(updated, 3, Some(2), Some(1))?;
+    let deleted_v1 = doc(deleted, 1, Some(3), None)?;
+    let deleted_v2 = doc(deleted, 2, None, Some(1))?;
+    let later_v1 = doc(later, 4, Some(4), None)?;
+    p.write(
+        &[
+            updated_v1.clone(),
+            updated_v2.clone(),
+            deleted_v1,
+            deleted_v2.clone(),
+            later_v1,
+        ],
+        &[],
+        ConflictStrategy::Error,
+    )
+    .await?;
+
+    let reader = p.reader();
+    let ids: [InternalDocumentId; 5] =
+        [updated, deleted, missing, later, updated].map(|id| id.into());
+    assert_eq!(
+        reader
+            .load_documents_by_ids(&ids, Timestamp::must(3))
+            .await?,
+        vec![
+            Some(updated_v2.clone()),
+            Some(deleted_v2.clone()),
+            None,
+            None,
+            Some(updated_v2),
+        ]
+    );
+    assert_eq!(
+        reader
+            .load_documents_by_ids(&ids, Timestamp::must(2))
+            .await?,
+        vec![
+            Some(updated_v1.clone()),
+            Some(deleted_v2),
+            None,
+            None,
+            Some(updated_v1),
+        ]
+    );
+    assert_eq!(
+        reader
+            .load_documents_by_ids(&[], Timestamp::must(2))
+            .await?,
+        vec![]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_documents_multi() -> anyhow::Result<()> {
+    let p = SqlitePersistence::new_in_memory()?;
+    let mut id_generator = TestIdGenerator::new();
+    let tables: Vec<TableName> = vec![
+        str::parse("table1")?,
+        str::parse("table2")?,
+        str::parse("table3")?,
+        str::parse("ignored")?,
+    ];
+    // Table `i % 4` gets every fourth timestamp, so each table's entries are
+    // interleaved with the others'.
+    let entries = (0..40)
+        .map(|ts| {
+            doc(
+                id_generator.user_generate(&tables[ts as usize % 4]),
+                ts,
+                Some(ts as i64),
+                None,
+            )
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    p.write(&entries, &[], ConflictStrategy::Error).await?;
+
+    let tablet_ids = tables[..3]
+        .iter()
+        .map(|table| id_generator.user_table_id(table).tablet_id)
+        .collect::<Vec<_>>();
+    let expected = entries
+        .iter()
+        .filter(|entry| tablet_ids.contains(&entry.id.table()))
+        .cloned()
+        .collect::<Vec<_>>();
+    assert_eq!(expected.len(), 30);
+
+    let reader = p.reader();
+    for order in [Order::Asc, Order::Desc] {
+        let merged = reader
+            .load_documents_multi(
+                &tablet_ids,
+                TimestampRange::all(),
+                order,
+                10,
+                Arc::new(NoopRetentionValidator),
+            )
+            .try_collect::<Vec<_>>()
+            .await?;
+        let mut expected = expected.clone();
+        if order == Order::Desc {
+            expected.reverse();
+        }
+        assert_eq!(merged, expected);
+    }
+    Ok(())
+}
+
+async fn load_where(
+    p: &SqlitePersistence,
+    op: CompareOp,
+    value: ConvexValue,
+) -> anyhow::Result<Vec<Timestamp>> {
+    let entries = p
+        .reader()
+        .load_documents_where(
+            TimestampRange::all(),
+            "status",
+            op,
+            value,
+            Order::Asc,
+            100,
+            Arc::new(NoopRetentionValidator),
+        )
+        .try_collect::<Vec<_>>()
+        .await?;
+    Ok(entries.into_iter().map(|entry| entry.ts).collect())
+}
+
+#[tokio::test]
+async fn test_load_documents_where() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    // Compressed values can't be filtered in Sqlite, so are filtered after.
+    for compression in [Compression::None, Compression::Lz4] {
+        let path = db.path().join(format!("where_{compression:?}.sqlite3"));
+        let p = SqlitePersistence::new_with_options(
+            path.to_str().unwrap(),
+            SqliteOptions {
+                compression,
+                ..Default::default()
+            },
+        )?;
+        let mut id_generator = TestIdGenerator::new();
+        let table: TableName = str::parse("table")?;
+        let values: [ConvexObject; 5] = [
+            assert_obj!("status" => "active"),
+            assert_obj!("status" => "done"),
+            assert_obj!("status" => "active", "other" => "done"),
+            assert_obj!("other" => "active"),
+            assert_obj!("status" => 1),
+        ];
+        let mut entries = vec![];
+        for (ts, value) in (1..).zip(values) {
+            let id = id_generator.user_generate(&table);
+            entries.push(DocumentLogEntry {
+                ts: Timestamp::must(ts),
+                id: id.into(),
+                value: Some(ResolvedDocument::new(id, CreationTime::ONE, value)?),
+                prev_ts: None,
+            });
+        }
+        // Deleting the first document doesn't match either.
+        entries.push(DocumentLogEntry {
+            ts: Timestamp::must(6),
+            id: entries[0].id,
+            value: None,
+            prev_ts: Some(Timestamp::must(1)),
+        });
+        p.write(&entries, &[], ConflictStrategy::Error).await?;
+
+        let ts = |timestamps: &[i32]| -> Vec<Timestamp> {
+            timestamps.iter().copied().map(Timestamp::must).collect()
+        };
+        let active = ConvexValue::try_from("active")?;
+        assert_eq!(
+            load_where(&p, CompareOp::Eq, active.clone()).await?,
+            ts(&[1, 3])
+        );
+        // Compares with the total order of values, so the number sorts before
+        // every string.
+        assert_eq!(
+            load_where(&p, CompareOp::Ne, active.clone()).await?,
+            ts(&[2, 5])
+        );
+        assert_eq!(
+            load_where(&p, CompareOp::Lt, active.clone()).await?,
+            ts(&[5])
+        );
+        assert_eq!(
+            load_where(&p, CompareOp::Gte, ConvexValue::try_from("b")?).await?,
+            ts(&[2])
+        );
+        assert_eq!(
+            load_where(&p, CompareOp::Eq, ConvexValue::Int64(1)).await?,
+            ts(&[5])
+        );
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_load_documents_with_limit() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("limit.sqlite3");
+    let recorder = Arc::new(MockRecorder::default());
+    let p = SqlitePersistence::new_with_options(
+        path.to_str().unwrap(),
+        SqliteOptions {
+            metrics_recorder: Some(recorder.clone()),
+            ..Default::default()
+        },
+    )?;
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let mut documents = vec![];
+    for ts in 1..=50 {
+        documents.push(doc(
+            id_generator.user_generate(&table),
+            ts,
+            Some(ts as i64),
+            None,
+        )?);
+    }
+    p.write(&documents, &[], ConflictStrategy::Error).await?;
+
+    let reader = p.reader();
+    for (order, expected) in [
+        (Order::Asc, documents[..10].to_vec()),
+        (Order::Desc, documents[40..].iter().rev().cloned().collect()),
+    ] {
+        recorder.load_documents.lock().clear();
+        let loaded = reader
+            .load_documents_with_limit(
+                TimestampRange::all(),
+                order,
+                100,
+                Some(10),
+                Arc::new(NoopRetentionValidator),
+            )
+            .try_collect::<Vec<_>>()
+            .await?;
+        assert_eq!(loaded, expected);
+        // A single query that only read the rows that were returned.
+        assert_eq!(*recorder.load_documents.lock(), vec![10]);
+    }
+
+    // Without a limit it's the same as `load_documents`.
+    let loaded = reader
+        .load_documents_with_limit(
+            TimestampRange::all(),
+            Order::Asc,
+            100,
+            None,
+            Arc::new(NoopRetentionValidator),
+        )
+        .try_collect::<Vec<_>>()
+        .await?;
+    assert_eq!(loaded, documents);
+    Ok(())
+}
+
+async fn load_live(
+    p: &SqlitePersistence,
+    range: TimestampRange,
+    order: Order,
+) -> anyhow::Result<Vec<DocumentLogEntry>> {
+    p.reader()
+        .load_live_documents(range, order, 100, Arc::new(NoopRetentionValidator))
+        .try_collect()
+        .await
+}
+
+#[tokio::test]
+async fn test_load_live_documents() -> anyhow::Result<()> {
+    let p = SqlitePersistence::new_in_memory()?;
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let updated_id = id_generator.user_generate(&table);
+    let deleted_id = id_generator.user_generate(&table);
+    let recreated_id = id_generator.user_generate(&table);
+
+    let entries = vec![
+        doc(updated_id, 1, Some(1), None)?,
+        doc(deleted_id, 1, Some(1), None)?,
+        doc(recreated_id, 1, Some(1), None)?,
+        doc(updated_id, 2, Some(2), Some(1))?,
+        doc(deleted_id, 2, None, Some(1))?,
+        doc(recreated_id, 2, None, Some(1))?,
+        doc(recreated_id, 3, Some(3), Some(2))?,
+    ];
+    p.write(&entries, &[], ConflictStrategy::Error).await?;
+
+    // The unfiltered stream still has every log entry.
+    let all = p
+        .reader()
+        .load_documents(
+            TimestampRange::all(),
+            Order::Asc,
+            100,
+            Arc::new(NoopRetentionValidator),
+        )
+        .try_collect::<Vec<_>>()
+        .await?;
+    assert_eq!(all.len(), entries.len());
+
+    assert_eq!(
+        load_live(&p, TimestampRange::all(), Order::Asc).await?,
+        vec![entries[3].clone(), entries[6].clone()]
+    );
+    assert_eq!(
+        load_live(&p, TimestampRange::all(), Order::Desc).await?,
+        vec![entries[6].clone(), entries[3].clone()]
+    );
+    // Only revisions within the range are considered. These all share a ts,
+    // so they're sorted by id.
+    let mut at_1 = entries[..3].to_vec();
+    at_1.sort_by_key(|entry| entry.id);
+    assert_eq!(
+        load_live(&p, TimestampRange::at(Timestamp::must(1)), Order::Asc).await?,
+        at_1
+    );
+    assert_eq!(
+        load_live(
+            &p,
+            TimestampRange::new(Timestamp::must(2)..Timestamp::must(3)),
+            Order::Asc
+        )
+        .await?,
+        vec![entries[3].clone()]
+    );
+    Ok(())
+}
+
+async fn snapshot(
+    p: &SqlitePersistence,
+    tablet_id: TabletId,
+    ts: i32,
+    order: Order,
+) -> anyhow::Result<Vec<DocumentLogEntry>> {
+    p.reader()
+        .load_snapshot(
+            tablet_id,
+            Timestamp::must(ts),
+            order,
+            100,
+            Arc::new(NoopRetentionValidator),
+        )
+        .try_collect()
+        .await
+}
+
+#[tokio::test]
+async fn test_load_snapshot() -> anyhow::Result<()> {
+    let p = SqlitePersistence::new_in_memory()?;
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let other_table: TableName = str::parse("other_table")?;
+    let tablet_id = id_generator.user_table_id(&table).tablet_id;
+    let deleted_id = id_generator.user_generate(&table);
+    let live_id = id_generator.user_generate(&table);
+    let other_id = id_generator.user_generate(&other_table);
+
+    let entries = vec![
+        doc(deleted_id, 1, Some(1), None)?,
+        doc(deleted_id, 2, Some(2), Some(1))?,
+        doc(live_id, 2, Some(0), None)?,
+        doc(deleted_id, 3, Some(3), Some(2))?,
+        doc(other_id, 3, Some(0), None)?,
+        doc(deleted_id, 4, None, Some(3))?,
+    ];
+    p.write(&entries, &[], ConflictStrategy::Error).await?;
+
+    // Once deleted, the document is excluded entirely.
+    assert_eq!(
+        snapshot(&p, tablet_id, 4, Order::Asc).await?,
+        vec![entries[2].clone()]
+    );
+    // Before the delete, only its latest version is included.
+    assert_eq!(
+        snapshot(&p, tablet_id, 3, Order::Asc).await?,
+        vec![entries[2].clone(), entries[3].clone()]
+    );
+    assert_eq!(
+        snapshot(&p, tablet_id, 3, Order::Desc).await?,
+        vec![entries[3].clone(), entries[2].clone()]
+    );
+    assert_eq!(
+        snapshot(&p, tablet_id, 1, Order::Asc).await?,
+        vec![entries[0].clone()]
+    );
+    assert_eq!(snapshot(&p, tablet_id, 0, Order::Asc).await?, vec![]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_count_documents() -> anyhow::Result<()> {
+    let p = SqlitePersistence::new_in_memory()?;
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let entries = (0..10)
+        .map(|ts| {
+            doc(
+                id_generator.user_generate(&table),
+                ts,
+                Some(ts as i64),
+                None,
+            )
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    p.write(&entries, &[], ConflictStrategy::Error).await?;
+
+    let reader = p.reader();
+    assert_eq!(reader.count_documents(TimestampRange::all()).await?, 10);
+    assert_eq!(
+        reader
+            .count_documents(TimestampRange::new(Timestamp::must(3)..Timestamp::must(7)))
+            .await?,
+        4
+    );
+    assert_eq!(
+        reader
+            .count_documents(TimestampRange::new(Timestamp::must(20)..))
+            .await?,
+        0
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_distinct_timestamps() -> anyhow::Result<()> {
+    let p = SqlitePersistence::new_in_memory()?;
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    // Five timestamps, three of which have several entries.
+    let entries = [1, 1, 2, 4, 4, 4, 7, 9, 9]
+        .into_iter()
+        .map(|ts| doc(id_generator.user_generate(&table), ts, Some(1), None))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    p.write(&entries, &[], ConflictStrategy::Error).await?;
+
+    let reader = p.reader();
+    let timestamps = |range, order| {
+        reader
+            .distinct_timestamps(range, order)
+            .try_collect::<Vec<_>>()
+    };
+    assert_eq!(
+        timestamps(TimestampRange::all(), Order::Asc).await?,
+        [1, 2, 4, 7, 9].map(Timestamp::must)
+    );
+    assert_eq!(
+        timestamps(TimestampRange::all(), Order::Desc).await?,
+        [9, 7, 4, 2, 1].map(Timestamp::must)
+    );
+    assert_eq!(
+        timestamps(
+            TimestampRange::new(Timestamp::must(2)..Timestamp::must(9)),
+            Order::Asc
+        )
+        .await?,
+        [2, 4, 7].map(Timestamp::must)
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_document_counts_by_tablet() -> anyhow::Result<()> {
+    let p = SqlitePersistence::new_in_memory()?;
+    assert_eq!(
+        p.reader().document_counts_by_tablet().await?,
+        BTreeMap::new()
+    );
+
+    let mut id_generator = TestIdGenerator::new();
+    let tables: Vec<TableName> = vec![str::parse("one")?, str::parse("two")?, str::parse("three")?];
+    let mut entries = vec![];
+    let mut expected = BTreeMap::new();
+    let mut ids = vec![];
+    let mut ts = 0;
+    for (i, table) in tables.iter().enumerate() {
+        let count = i + 1;
+        for _ in 0..count {
+            ts += 1;
+            let id = id_generator.user_generate(table);
+            ids.push(id);
+            entries.push(doc(id, ts, Some(ts as i64), None)?);
+        }
+        expected.insert(id_generator.user_table_id(table).tablet_id, count as u64);
+    }
+    // Deleting a document adds another entry to its tablet.
+    entries.push(doc(ids[0], ts + 1, None, Some(1))?);
+    *expected.get_mut(&ids[0].tablet_id).unwrap() += 1;
+    p.write(&entries, &[], ConflictStrategy::Error).await?;
+
+    assert_eq!(p.reader().document_counts_by_tablet().await?, expected);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_tablet_ids() -> anyhow::Result<()> {
+    let p = SqlitePersistence::new_in_memory()?;
+    assert_eq!(p.reader().tablet_ids().await?, vec![]);
+
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let other_table: TableName = str::parse("other_table")?;
+    let entries = vec![
+        doc(id_generator.user_generate(&table), 1, Some(1), None)?,
+        doc(id_generator.user_generate(&other_table), 1, Some(1), None)?,
+        doc(id_generator.user_generate(&table), 2, Some(2), None)?,
+        doc(id_generator.user_generate(&other_table), 3, None, None)?,
+    ];
+    p.write(&entries, &[], ConflictStrategy::Error).await?;
+
+    let mut expected = vec![
+        id_generator.user_table_id(&table).tablet_id,
+        id_generator.user_table_id(&other_table).tablet_id,
+    ];
+    expected.sort();
+    assert_eq!(p.reader().tablet_ids().await?, expected);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_timestamp_bounds_empty() -> anyhow::Result<()> {
+    let p = SqlitePersistence::new_in_memory()?;
+    let reader = p.reader();
+    assert_eq!(reader.min_timestamp().await?, None);
+    assert_eq!(reader.max_timestamp().await?, None);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_timestamp_bounds() -> anyhow::Result<()> {
+    let p = SqlitePersistence::new_in_memory()?;
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    for ts in [7, 3, 12, 5] {
+        let entry = doc(
+            id_generator.user_generate(&table),
+            ts,
+            Some(ts as i64),
+            None,
+        )?;
+        p.write(&[entry], &[], ConflictStrategy::Error).await?;
+    }
+
+    let reader = p.reader();
+    assert_eq!(reader.min_timestamp().await?, Some(Timestamp::must(3)));
+    assert_eq!(reader.max_timestamp().await?, Some(Timestamp::must(12)));
+    Ok(())
+}
+
+async fn diff(p: &SqlitePersistence, ts_a: i32, ts_b: i32) -> anyhow::Result<Vec<DocumentDiff>> {
+    p.reader()
+        .diff(Timestamp::must(ts_a), Timestamp::must(ts_b))
+        .try_collect()
+        .await
+}
+
+#[tokio::test]
+async fn test_diff() -> anyhow::Result<()> {
+    let p = SqlitePersistence::new_in_memory()?;
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let unchanged_id = id_generator.user_generate(&table);
+    let updated_id = id_generator.user_generate(&table);
+    let deleted_id = id_generator.user_generate(&table);
+    let created_id = id_generator.user_generate(&table);
+    let transient_id = id_generator.user_generate(&table);
+
+    let entries = vec![
+        doc(unchanged_id, 1, Some(1), None)?,
+        doc(updated_id, 1, Some(1), None)?,
+        doc(deleted_id, 2, Some(2), None)?,
+        doc(updated_id, 3, Some(3), Some(1))?,
+        doc(created_id, 3, Some(3), None)?,
+        doc(transient_id, 3, Some(3), None)?,
+        doc(deleted_id, 4, None, Some(2))?,
+        doc(transient_id, 4, None, Some(3))?,
+        doc(updated_id, 5, Some(5), Some(3))?,
+        doc(unchanged_id, 6, Some(6), Some(1))?,
+    ];
+    p.write(&entries, &[], ConflictStrategy::Error).await?;
+    let value = |i: usize| entries[i].value.clone();
+
+    let mut expected = vec![
+        DocumentDiff {
+            id: updated_id.into(),
+            before: value(1),
+            after: value(8),
+        },
+        DocumentDiff {
+            id: deleted_id.into(),
+            before: value(2),
+            after: None,
+        },
+        DocumentDiff {
+            id: created_id.into(),
+            before: None,
+            after: value(4),
+        },
+    ];
+    expected.sort_by_key(|diff| diff.id);
+    assert_eq!(diff(&p, 2, 5).await?, expected);
+
+    // Only changes after the first timestamp count.
+    assert_eq!(
+        diff(&p, 5, 6).await?,
+        vec![DocumentDiff {
+            id: unchanged_id.into(),
+            before: value(0),
+            after: value(9),
+        }]
+    );
+    assert_eq!(diff(&p, 6, 6).await?, vec![]);
+    assert!(diff(&p, 6, 5).await.is_err());
+    Ok(())
+}
+
+async fn index_keys(
+    p: &SqlitePersistence,
+    index_id: IndexId,
+    tablet_id: TabletId,
+) -> anyhow::Result<Vec<IndexKeyBytes>> {
+    p.reader()
+        .index_scan(
+            index_id,
+            tablet_id,
+            Timestamp::MAX,
+            &Interval::all(),
+            Order::Asc,
+            100,
+            Arc::new(NoopRetentionValidator),
+        )
+        .map_ok(|(key, _)| key)
+        .try_collect()
+        .await
+}
+
+#[tokio::test]
+async fn test_export_since() -> anyhow::Result<()> {
+    let leader = SqlitePersistence::new_in_memory()?;
+    let follower = SqlitePersistence::new_in_memory()?;
+    let mut id_generator = TestIdGenerator::new();
+    let index_id = id_generator.system_generate(&INDEX_TABLE).internal_id();
+    let table: TableName = str::parse("table")?;
+    let tablet_id = id_generator.user_table_id(&table).tablet_id;
+
+    let mut writes = vec![];
+    for ts in 1..=4 {
+        let entry = doc(
+            id_generator.user_generate(&table),
+            ts,
+            Some(ts as i64),
+            None,
+        )?;
+        let index_entry = PersistenceIndexEntry {
+            ts: entry.ts,
+            index_id,
+            key: IndexKeyBytes(vec![ts as u8]),
+            value: Some(entry.id),
+        };
+        writes.push((vec![entry], vec![index_entry]));
+    }
+    // Remove the first entry from the index after the midpoint.
+    writes.push((
+        vec![],
+        vec![PersistenceIndexEntry {
+            ts: Timestamp::must(5),
+            index_id,
+            key: IndexKeyBytes(vec![1]),
+            value: None,
+        }],
+    ));
+    for (documents, indexes) in &writes {
+        leader
+            .write(documents, indexes, ConflictStrategy::Error)
+            .await?;
+    }
+    // The follower is caught up to the midpoint.
+    for (documents, indexes) in &writes[..2] {
+        follower
+            .write(documents, indexes, ConflictStrategy::Error)
+            .await?;
+    }
+
+    let batches = leader
+        .export_since(Timestamp::must(2))
+        .try_collect::<Vec<_>>()
+        .await?;
+    assert_eq!(batches.len(), 3);
+    for batch in batches {
+        follower
+            .write(&batch.documents, &batch.indexes, ConflictStrategy::Error)
+            .await?;
+    }
+
+    assert_eq!(
+        follower
+            .reader()
+            .load_all_documents()
+            .try_collect::<Vec<_>>()
+            .await?,
+        leader
+            .reader()
+            .load_all_documents()
+            .try_collect::<Vec<_>>()
+            .await?,
+    );
+    assert_eq!(
+        index_keys(&follower, index_id, tablet_id).await?,
+        index_keys(&leader, index_id, tablet_id).await?,
+    );
+    assert_eq!(
+        index_keys(&follower, index_id, tablet_id).await?,
+        vec![
+            IndexKeyBytes(vec![2]),
+            IndexKeyBytes(vec![3]),
+            IndexKeyBytes(vec![4])
+        ]
+    );
+    Ok(())
+}
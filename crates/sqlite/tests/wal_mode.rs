@@ -8,10 +8,17 @@ use common::{
     value::{ConvexValue, InternalId},
 };
 use rusqlite::Connection;
-use sqlite::SqlitePersistence;
+use sqlite::{CheckpointMode, SqliteOptions, SqlitePersistence};
 use std::path::Path;
 use tempfile::TempDir;
 
+fn wal_options() -> SqliteOptions {
+    SqliteOptions {
+        wal_mode: true,
+        ..Default::default()
+    }
+}
+
 #[tokio::test]
 async fn test_wal_mode_is_enabled() {
     let db = TempDir::new().unwrap();
@@ -21,7 +28,7 @@ async fn test_wal_mode_is_enabled() {
         .to_str()
         .unwrap();
 
-    let _persistence = SqlitePersistence::new_with_options(db_path, true).unwrap();
+    let _persistence = SqlitePersistence::new_with_options(db_path, wal_options()).unwrap();
 
     let conn = Connection::open(db_path).unwrap();
     let journal_mode: String = conn
@@ -40,7 +47,7 @@ async fn test_wal_mode_creates_wal_files() {
         .to_str()
         .unwrap();
 
-    let persistence = SqlitePersistence::new_with_options(db_path, true).unwrap();
+    let persistence = SqlitePersistence::new_with_options(db_path, wal_options()).unwrap();
 
     let tablet_id = TabletId::min();
     let internal_id = InternalId::min();
@@ -73,7 +80,7 @@ async fn test_wal_mode_synchronous_normal() {
         .to_str()
         .unwrap();
 
-    let _persistence = SqlitePersistence::new_with_options(db_path, true).unwrap();
+    let _persistence = SqlitePersistence::new_with_options(db_path, wal_options()).unwrap();
 
     let conn = Connection::open(db_path).unwrap();
     let synchronous_mode: i32 = conn
@@ -92,7 +99,7 @@ async fn test_non_wal_mode_synchronous_full() {
         .to_str()
         .unwrap();
 
-    let _persistence = SqlitePersistence::new_with_options(db_path, false).unwrap();
+    let _persistence = SqlitePersistence::new_with_options(db_path, SqliteOptions::default()).unwrap();
 
     let conn = Connection::open(db_path).unwrap();
     let synchronous_mode: i32 = conn
@@ -111,7 +118,7 @@ async fn test_wal_mode_basic_write_read() {
         .to_str()
         .unwrap();
 
-    let persistence = SqlitePersistence::new_with_options(db_path, true).unwrap();
+    let persistence = SqlitePersistence::new_with_options(db_path, wal_options()).unwrap();
 
     let test_persistence = TestPersistence::new(persistence);
 
@@ -127,7 +134,7 @@ async fn test_wal_mode_concurrent_read_during_write() {
         .to_str()
         .unwrap();
 
-    let persistence = SqlitePersistence::new_with_options(db_path, true).unwrap();
+    let persistence = SqlitePersistence::new_with_options(db_path, wal_options()).unwrap();
     let reader = persistence.reader();
 
     let tablet_id = TabletId::min();
@@ -145,7 +152,7 @@ async fn test_wal_mode_concurrent_read_during_write() {
 
     persistence.write(&entries, &[], ConflictStrategy::Error).await.unwrap();
 
-    let range = TimestampRange::new(Timestamp::MIN, Timestamp::MAX).unwrap();
+    let range = TimestampRange::all();
     let mut document_stream = reader.load_documents(range.clone(), common::query::Order::Asc, 100, Arc::new(()));
 
     let mut documents = Vec::new();
@@ -191,7 +198,7 @@ async fn test_wal_mode_checkpoint() {
         .to_str()
         .unwrap();
 
-    let persistence = SqlitePersistence::new_with_options(db_path, true).unwrap();
+    let persistence = SqlitePersistence::new_with_options(db_path, wal_options()).unwrap();
 
     let tablet_id = TabletId::min();
     for i in 0u64..10 {
@@ -214,18 +221,11 @@ async fn test_wal_mode_checkpoint() {
             .unwrap();
     }
 
-    let conn = Connection::open(db_path).unwrap();
-    conn.execute("PRAGMA wal_checkpoint(TRUNCATE);", []).unwrap();
-
-    let checkpoint_result: (i32, i32, i32) = conn
-        .query_row(
-            "PRAGMA wal_checkpoint(TRUNCATE);",
-            [],
-            |row| (row.get(0), row.get(1), row.get(2)),
-        )
-        .unwrap();
+    persistence.checkpoint(CheckpointMode::Truncate).unwrap();
 
-    assert_eq!(checkpoint_result.2, 0);
+    // Everything was moved into the database by the first checkpoint.
+    let checkpoint_result = persistence.checkpoint(CheckpointMode::Truncate).unwrap();
+    assert_eq!(checkpoint_result.checkpointed_pages, 0);
 }
 
 #[tokio::test]
@@ -238,7 +238,7 @@ async fn test_wal_mode_persistence_over_restart() {
         .unwrap();
 
     {
-        let persistence = SqlitePersistence::new_with_options(db_path, true).unwrap();
+        let persistence = SqlitePersistence::new_with_options(db_path, wal_options()).unwrap();
 
         let tablet_id = TabletId::min();
         let internal_id = InternalId::min();
@@ -257,10 +257,10 @@ async fn test_wal_mode_persistence_over_restart() {
     }
 
     {
-        let persistence = SqlitePersistence::new_with_options(db_path, true).unwrap();
+        let persistence = SqlitePersistence::new_with_options(db_path, wal_options()).unwrap();
         let reader = persistence.reader();
 
-        let range = TimestampRange::new(Timestamp::MIN, Timestamp::MAX).unwrap();
+        let range = TimestampRange::all();
         let mut document_stream = reader.load_documents(range, common::query::Order::Asc, 100, Arc::new(()));
 
         let mut documents = Vec::new();
@@ -282,7 +282,7 @@ async fn test_wal_mode_with_indices() {
         .to_str()
         .unwrap();
 
-    let persistence = SqlitePersistence::new_with_options(db_path, true).unwrap();
+    let persistence = SqlitePersistence::new_with_options(db_path, wal_options()).unwrap();
     let reader = persistence.reader();
 
     let tablet_id = TabletId::min();
@@ -0,0 +1,1491 @@
+mod helpers;
+
+use std::{
+    sync::{
+        mpsc,
+        Arc,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use common::{
+    bootstrap_model::index::INDEX_TABLE,
+    index::IndexKeyBytes,
+    interval::Interval,
+    persistence::{
+        Conflict,
+        ConflictStrategy,
+        DocumentLogEntry,
+        NoopRetentionValidator,
+        Persistence,
+        PersistenceIndexEntry,
+        TimestampRange,
+        WriteOutcome,
+        WriteReceipt,
+    },
+    query::Order,
+    testing::{
+        persistence_test_suite::doc,
+        TestIdGenerator,
+    },
+    types::{
+        TableName,
+        Timestamp,
+    },
+};
+use futures::{
+    stream,
+    StreamExt,
+    TryStreamExt,
+};
+use rusqlite::Connection;
+use sqlite::{
+    CheckpointMode,
+    PersistenceError,
+    SqliteOptions,
+    SqlitePersistence,
+    MAX_ROWS_PER_STATEMENT,
+};
+use tempfile::TempDir;
+
+use crate::helpers::load_all;
+
+#[tokio::test]
+async fn test_append_only() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("append_only.sqlite3");
+    let p = SqlitePersistence::new_with_options(
+        path.to_str().unwrap(),
+        SqliteOptions {
+            append_only: true,
+            ..Default::default()
+        },
+    )?;
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let id = id_generator.user_generate(&table);
+
+    let created = doc(id, 1, Some(1), None)?;
+    p.write(&[created.clone()], &[], ConflictStrategy::Error)
+        .await?;
+    // A new revision of the same document is a fresh row.
+    let updated = doc(id, 2, Some(2), Some(1))?;
+    p.write(&[updated.clone()], &[], ConflictStrategy::Error)
+        .await?;
+
+    // Rewriting an existing revision fails however it's attempted.
+    let rewritten = doc(id, 1, Some(10), None)?;
+    for conflict_strategy in [
+        ConflictStrategy::Error,
+        ConflictStrategy::Overwrite,
+        ConflictStrategy::Merge,
+    ] {
+        assert!(p
+            .write(&[rewritten.clone()], &[], conflict_strategy)
+            .await
+            .is_err());
+    }
+    // Fresh rows are rejected too with anything but `Error`.
+    let fresh = doc(id_generator.user_generate(&table), 3, Some(3), None)?;
+    assert!(p
+        .write(&[fresh], &[], ConflictStrategy::Overwrite)
+        .await
+        .is_err());
+    let deleted = doc(id, 3, None, Some(2))?;
+    assert!(p
+        .write(&[deleted.clone()], &[], ConflictStrategy::Error)
+        .await
+        .is_err());
+    assert!(p.write_partial(&[deleted], &[]).await.is_err());
+    assert!(p.delete(vec![(updated.ts, updated.id)]).await.is_err());
+    assert!(p.delete_range(TimestampRange::all(), false).await.is_err());
+    assert!(p.truncate().await.is_err());
+
+    assert_eq!(load_all(&p).await?, vec![created, updated]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_check_write() -> anyhow::Result<()> {
+    let p = SqlitePersistence::new_in_memory()?;
+    let mut id_generator = TestIdGenerator::new();
+    let index_id = id_generator.system_generate(&INDEX_TABLE).internal_id();
+    let table: TableName = str::parse("table")?;
+    let existing = doc(id_generator.user_generate(&table), 1, Some(1), None)?;
+    let existing_index = PersistenceIndexEntry {
+        ts: existing.ts,
+        index_id,
+        key: IndexKeyBytes(vec![1]),
+        value: Some(existing.id),
+    };
+    p.write(
+        &[existing.clone()],
+        &[existing_index.clone()],
+        ConflictStrategy::Error,
+    )
+    .await?;
+
+    let new = doc(id_generator.user_generate(&table), 1, Some(2), None)?;
+    let new_index = PersistenceIndexEntry {
+        ts: new.ts,
+        index_id,
+        key: IndexKeyBytes(vec![2]),
+        value: Some(new.id),
+    };
+    let conflicts = p
+        .check_write(
+            &[new.clone(), existing.clone()],
+            &[new_index.clone(), existing_index.clone()],
+        )
+        .await?;
+    assert_eq!(
+        conflicts,
+        vec![
+            Conflict::Document {
+                id: existing.id,
+                ts: existing.ts,
+            },
+            Conflict::Index {
+                index_id,
+                key: existing_index.key.clone(),
+                ts: existing_index.ts,
+            },
+        ]
+    );
+
+    // Nothing was written, so the non-conflicting rows still don't conflict.
+    assert_eq!(
+        p.reader()
+            .load_all_documents()
+            .try_collect::<Vec<_>>()
+            .await?,
+        vec![existing.clone()]
+    );
+    assert_eq!(p.check_write(&[new.clone()], &[new_index]).await?, vec![]);
+
+    // Rows also conflict with earlier rows in the same call.
+    assert_eq!(
+        p.check_write(&[new.clone(), new.clone()], &[]).await?,
+        vec![Conflict::Document {
+            id: new.id,
+            ts: new.ts,
+        }]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_merge() -> anyhow::Result<()> {
+    let p = SqlitePersistence::new_in_memory()?;
+    let mut id_generator = TestIdGenerator::new();
+    let index_id = id_generator.system_generate(&INDEX_TABLE).internal_id();
+    let table: TableName = str::parse("table")?;
+    let id = id_generator.user_generate(&table);
+
+    let entry = doc(id, 1, Some(1), None)?;
+    let index_entry = PersistenceIndexEntry {
+        ts: entry.ts,
+        index_id,
+        key: IndexKeyBytes(vec![1]),
+        value: Some(entry.id),
+    };
+
+    // A fresh insert succeeds, and replaying it exactly is a no-op.
+    for _ in 0..2 {
+        p.write(
+            &[entry.clone()],
+            &[index_entry.clone()],
+            ConflictStrategy::Merge,
+        )
+        .await?;
+    }
+    assert!(p
+        .write(&[entry.clone()], &[], ConflictStrategy::Error)
+        .await
+        .is_err());
+
+    // A different value at the same (id, ts) is a conflict.
+    let conflicting = doc(id, 1, Some(2), None)?;
+    assert!(p
+        .write(&[conflicting], &[], ConflictStrategy::Merge)
+        .await
+        .is_err());
+    // So is an index entry pointing somewhere else.
+    let conflicting_index_entry = PersistenceIndexEntry {
+        value: None,
+        ..index_entry
+    };
+    assert!(p
+        .write(&[], &[conflicting_index_entry], ConflictStrategy::Merge)
+        .await
+        .is_err());
+
+    let documents = p
+        .reader()
+        .load_documents(
+            TimestampRange::all(),
+            Order::Asc,
+            10,
+            Arc::new(NoopRetentionValidator),
+        )
+        .try_collect::<Vec<_>>()
+        .await?;
+    assert_eq!(documents, vec![entry]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_primary_key_conflict() -> anyhow::Result<()> {
+    let p = SqlitePersistence::new_in_memory()?;
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let id = id_generator.user_generate(&table);
+    p.write(&[doc(id, 1, Some(1), None)?], &[], ConflictStrategy::Error)
+        .await?;
+
+    let err = p
+        .write(&[doc(id, 1, Some(1), None)?], &[], ConflictStrategy::Error)
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(
+            err.downcast_ref::<PersistenceError>(),
+            Some(PersistenceError::Conflict(_))
+        ),
+        "{err:#}"
+    );
+    // The underlying Sqlite error is still available.
+    assert_eq!(
+        err.downcast_ref::<rusqlite::Error>()
+            .and_then(|e| e.sqlite_error_code()),
+        Some(rusqlite::ErrorCode::ConstraintViolation)
+    );
+    Ok(())
+}
+
+/// The conflict that `write` with `ConflictStrategy::Error` fails on.
+async fn conflict(
+    p: &SqlitePersistence,
+    documents: &[DocumentLogEntry],
+    indexes: &[PersistenceIndexEntry],
+) -> Option<Conflict> {
+    let err = p
+        .write(documents, indexes, ConflictStrategy::Error)
+        .await
+        .unwrap_err();
+    match err.downcast_ref::<PersistenceError>() {
+        Some(PersistenceError::Conflict(conflict)) => conflict.clone(),
+        _ => panic!("expected a conflict: {err:#}"),
+    }
+}
+
+#[tokio::test]
+async fn test_conflict_details() -> anyhow::Result<()> {
+    let p = SqlitePersistence::new_in_memory()?;
+    let mut id_generator = TestIdGenerator::new();
+    let index_id = id_generator.system_generate(&INDEX_TABLE).internal_id();
+    let table: TableName = str::parse("table")?;
+    let a = id_generator.user_generate(&table);
+    let b = id_generator.user_generate(&table);
+    let index_entry = |ts: i32, key: u8| PersistenceIndexEntry {
+        ts: Timestamp::must(ts),
+        index_id,
+        key: IndexKeyBytes(vec![key]),
+        value: Some(a),
+    };
+    p.write(
+        &[doc(a, 1, Some(1), None)?],
+        &[index_entry(1, 1)],
+        ConflictStrategy::Error,
+    )
+    .await?;
+
+    // With an existing row, after rows that don't conflict.
+    assert_eq!(
+        conflict(
+            &p,
+            &[doc(b, 1, Some(1), None)?, doc(a, 1, Some(2), None)?],
+            &[]
+        )
+        .await,
+        Some(Conflict::Document {
+            id: a,
+            ts: Timestamp::must(1),
+        })
+    );
+    // With an earlier row in the same write.
+    assert_eq!(
+        conflict(
+            &p,
+            &[doc(b, 2, Some(1), None)?, doc(b, 2, Some(2), None)?],
+            &[]
+        )
+        .await,
+        Some(Conflict::Document {
+            id: b,
+            ts: Timestamp::must(2),
+        })
+    );
+    assert_eq!(
+        conflict(&p, &[], &[index_entry(2, 1), index_entry(1, 1)]).await,
+        Some(Conflict::Index {
+            index_id,
+            key: IndexKeyBytes(vec![1]),
+            ts: Timestamp::must(1),
+        })
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_merge_conflict() -> anyhow::Result<()> {
+    let p = SqlitePersistence::new_in_memory()?;
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let id = id_generator.user_generate(&table);
+    p.write(&[doc(id, 1, Some(1), None)?], &[], ConflictStrategy::Error)
+        .await?;
+
+    let err = p
+        .write(&[doc(id, 1, Some(2), None)?], &[], ConflictStrategy::Merge)
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(
+            err.downcast_ref::<PersistenceError>(),
+            Some(PersistenceError::Conflict(_))
+        ),
+        "{err:#}"
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_open_readonly_not_found() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("missing.sqlite3");
+    let err = SqlitePersistence::open_readonly(path.to_str().unwrap())
+        .err()
+        .expect("opening a missing database should fail");
+    assert!(
+        matches!(
+            err.downcast_ref::<PersistenceError>(),
+            Some(PersistenceError::NotFound(_))
+        ),
+        "{err:#}"
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_write_partial() -> anyhow::Result<()> {
+    let p = SqlitePersistence::new_in_memory()?;
+    let mut id_generator = TestIdGenerator::new();
+    let index_id = id_generator.system_generate(&INDEX_TABLE).internal_id();
+    let table: TableName = str::parse("table")?;
+    let existing_id = id_generator.user_generate(&table);
+    let existing = doc(existing_id, 1, Some(1), None)?;
+    let existing_index = PersistenceIndexEntry {
+        ts: existing.ts,
+        index_id,
+        key: IndexKeyBytes(vec![1]),
+        value: Some(existing.id),
+    };
+    p.write(
+        &[existing.clone()],
+        &[existing_index.clone()],
+        ConflictStrategy::Error,
+    )
+    .await?;
+
+    // A different revision with the same key as the existing one conflicts.
+    let conflicting = doc(existing_id, 1, Some(2), None)?;
+    let fresh = doc(id_generator.user_generate(&table), 2, Some(3), None)?;
+    let fresh_index = PersistenceIndexEntry {
+        ts: fresh.ts,
+        index_id,
+        key: IndexKeyBytes(vec![2]),
+        value: Some(fresh.id),
+    };
+    let outcome = p
+        .write_partial(
+            &[conflicting, fresh.clone()],
+            &[existing_index.clone(), fresh_index],
+        )
+        .await?;
+    assert_eq!(
+        outcome,
+        WriteOutcome {
+            documents_written: 1,
+            indexes_written: 1,
+            conflicts: vec![
+                Conflict::Document {
+                    id: existing.id,
+                    ts: existing.ts,
+                },
+                Conflict::Index {
+                    index_id,
+                    key: existing_index.key.clone(),
+                    ts: existing_index.ts,
+                },
+            ],
+        }
+    );
+
+    // The fresh document landed and the existing one is untouched.
+    assert_eq!(
+        p.reader()
+            .load_all_documents()
+            .try_collect::<Vec<_>>()
+            .await?,
+        vec![existing, fresh]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_write_with_receipt() -> anyhow::Result<()> {
+    let p = SqlitePersistence::new_in_memory()?;
+    let mut id_generator = TestIdGenerator::new();
+    let index_id = id_generator.system_generate(&INDEX_TABLE).internal_id();
+    let table: TableName = str::parse("table")?;
+    let a = id_generator.user_generate(&table);
+    let b = id_generator.user_generate(&table);
+
+    // Out of order, so the receipt can't just report the last timestamp.
+    let documents = [
+        doc(a, 3, Some(1), None)?,
+        doc(b, 7, Some(2), None)?,
+        doc(a, 5, Some(3), Some(3))?,
+    ];
+    let indexes = [PersistenceIndexEntry {
+        ts: Timestamp::must(7),
+        index_id,
+        key: IndexKeyBytes(vec![2]),
+        value: Some(b),
+    }];
+    let receipt = p
+        .write_with_receipt(&documents, &indexes, ConflictStrategy::Error)
+        .await?;
+    assert_eq!(
+        receipt,
+        WriteReceipt {
+            documents_written: 3,
+            indexes_written: 1,
+            max_ts: Some(Timestamp::must(7)),
+        }
+    );
+
+    assert_eq!(
+        p.write_with_receipt(&[], &[], ConflictStrategy::Error)
+            .await?,
+        WriteReceipt::default()
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_write_many_index_entries() -> anyhow::Result<()> {
+    let p = SqlitePersistence::new_in_memory()?;
+    let mut id_generator = TestIdGenerator::new();
+    let index_id = id_generator.system_generate(&INDEX_TABLE).internal_id();
+    let table: TableName = str::parse("table")?;
+    let tablet_id = id_generator.user_table_id(&table).tablet_id;
+    let entry = doc(id_generator.user_generate(&table), 1, Some(1), None)?;
+
+    let indexes: Vec<_> = (0..50_000u32)
+        .map(|i| PersistenceIndexEntry {
+            ts: entry.ts,
+            index_id,
+            key: IndexKeyBytes(i.to_be_bytes().to_vec()),
+            value: Some(entry.id),
+        })
+        .collect();
+    p.write(&[entry], &indexes, ConflictStrategy::Error).await?;
+
+    assert_eq!(
+        p.reader()
+            .index_entry_count(index_id, tablet_id, Timestamp::must(1))
+            .await?,
+        50_000
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_split_write_is_atomic() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("write_batching.sqlite3");
+    let options = SqliteOptions {
+        max_rows_per_statement: Some(3),
+        ..Default::default()
+    };
+    let p = SqlitePersistence::new_with_options(path.to_str().unwrap(), options)?;
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let entries = (0..10)
+        .map(|ts| {
+            doc(
+                id_generator.user_generate(&table),
+                ts,
+                Some(ts as i64),
+                None,
+            )
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    p.write(&entries[..5], &[], ConflictStrategy::Error).await?;
+
+    // The last statement conflicts with the first write, so none of the
+    // statements before it should commit either.
+    let mut conflicting = entries[5..].to_vec();
+    conflicting.push(entries[0].clone());
+    assert!(p
+        .write(&conflicting, &[], ConflictStrategy::Error)
+        .await
+        .is_err());
+    assert_eq!(
+        p.reader()
+            .load_all_documents()
+            .try_collect::<Vec<_>>()
+            .await?,
+        entries[..5]
+    );
+
+    // Merging rewrites identical rows across statements.
+    p.write(&entries, &[], ConflictStrategy::Merge).await?;
+    assert_eq!(
+        p.reader()
+            .load_all_documents()
+            .try_collect::<Vec<_>>()
+            .await?,
+        entries
+    );
+    Ok(())
+}
+
+#[test]
+fn test_max_rows_per_statement_bounds() -> anyhow::Result<()> {
+    for max_rows_per_statement in [0, MAX_ROWS_PER_STATEMENT + 1] {
+        let options = SqliteOptions {
+            max_rows_per_statement: Some(max_rows_per_statement),
+            ..Default::default()
+        };
+        let db = TempDir::new()?;
+        let path = db.path().join("bounds.sqlite3");
+        let Err(err) = SqlitePersistence::new_with_options(path.to_str().unwrap(), options) else {
+            panic!("max_rows_per_statement {max_rows_per_statement} should be rejected");
+        };
+        assert!(err.to_string().contains("max_rows_per_statement"), "{err}");
+    }
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_write_queue_backpressure() -> anyhow::Result<()> {
+    const QUEUE_DEPTH: usize = 2;
+    let p = Arc::new(SqlitePersistence::new_in_memory()?);
+    let writer = p.writer_handle(QUEUE_DEPTH)?;
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let mut entries = (1..=10)
+        .map(|ts| doc(id_generator.user_generate(&table), ts, Some(1), None))
+        .collect::<anyhow::Result<Vec<_>>>()?
+        .into_iter();
+
+    // Without a read pool, holding a read connection stalls the writer.
+    let (release, released) = mpsc::channel::<()>();
+    let (held, is_held) = mpsc::channel();
+    let reader = p.reader();
+    let blocker = tokio::task::spawn_blocking({
+        let p = p.clone();
+        move || {
+            p.with_connection(|_| {
+                held.send(()).unwrap();
+                released.recv().unwrap();
+                Ok(())
+            })
+        }
+    });
+    is_held.recv()?;
+
+    // The writer task takes one write off the queue and then blocks, so at
+    // most one more than the queue depth is accepted.
+    let mut pending = vec![];
+    let err = loop {
+        match writer.try_write(
+            vec![entries.next().unwrap()],
+            vec![],
+            ConflictStrategy::Error,
+        ) {
+            Ok(write) => pending.push(write),
+            Err(err) => break err,
+        }
+        assert!(pending.len() <= QUEUE_DEPTH + 1);
+    };
+    assert!(
+        matches!(
+            err.downcast_ref::<PersistenceError>(),
+            Some(PersistenceError::QueueFull)
+        ),
+        "{err:#}"
+    );
+    assert!(pending.len() >= QUEUE_DEPTH);
+
+    // `write` waits for room instead of failing.
+    let waiting = tokio::spawn({
+        let writer = writer.clone();
+        let entry = entries.next().unwrap();
+        async move {
+            writer
+                .write(vec![entry], vec![], ConflictStrategy::Error)
+                .await
+        }
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!waiting.is_finished());
+
+    release.send(())?;
+    blocker.await??;
+    waiting.await??;
+    let written = pending.len() + 1;
+    for write in pending {
+        write.wait().await?;
+    }
+    assert_eq!(
+        reader
+            .load_all_documents()
+            .try_collect::<Vec<_>>()
+            .await?
+            .len(),
+        written
+    );
+    Ok(())
+}
+
+fn retry_options(write_retries: u32) -> SqliteOptions {
+    SqliteOptions {
+        // Fail immediately on lock contention so that only retries can help.
+        busy_timeout: Some(Duration::ZERO),
+        write_retries,
+        write_retry_delay: Duration::from_millis(5),
+        ..Default::default()
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_concurrent_writers_both_succeed() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("retry.sqlite3");
+    let path = path.to_str().unwrap();
+    let p1 = SqlitePersistence::new_with_options(path, retry_options(100))?;
+    let p2 = SqlitePersistence::new_with_options(path, retry_options(100))?;
+
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let mut entries = vec![];
+    for ts in 0..100 {
+        entries.push(doc(
+            id_generator.user_generate(&table),
+            ts,
+            Some(ts as i64),
+            None,
+        )?);
+    }
+    let (entries1, entries2) = entries.split_at(50);
+    let (entries1, entries2) = (entries1.to_vec(), entries2.to_vec());
+
+    let writer1 = tokio::spawn(async move {
+        for entry in entries1 {
+            p1.write(&[entry], &[], ConflictStrategy::Error).await?;
+        }
+        anyhow::Ok(())
+    });
+    let writer2 = tokio::spawn(async move {
+        for entry in entries2 {
+            p2.write(&[entry], &[], ConflictStrategy::Error).await?;
+        }
+        anyhow::Ok(())
+    });
+    writer1.await??;
+    writer2.await??;
+
+    let p = SqlitePersistence::new(path)?;
+    let documents: Vec<_> = p.reader().load_all_documents().try_collect().await?;
+    assert_eq!(documents.len(), 100);
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_write_retries_until_lock_released() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("retry_locked.sqlite3");
+    let path = path.to_str().unwrap();
+    let p = SqlitePersistence::new_with_options(path, retry_options(20))?;
+
+    // Hold the write lock from another connection.
+    let blocker = Connection::open(path)?;
+    blocker.execute_batch("BEGIN IMMEDIATE;")?;
+
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let entry = doc(id_generator.user_generate(&table), 1, Some(1), None)?;
+    let writer = tokio::spawn(async move {
+        p.write(&[entry], &[], ConflictStrategy::Error).await?;
+        anyhow::Ok(p)
+    });
+
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    blocker.execute_batch("COMMIT;")?;
+    let p = writer.await??;
+
+    let documents: Vec<_> = p.reader().load_all_documents().try_collect().await?;
+    assert_eq!(documents.len(), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_write_fails_when_retries_exhausted() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("retry_exhausted.sqlite3");
+    let path = path.to_str().unwrap();
+    let p = SqlitePersistence::new_with_options(path, retry_options(2))?;
+
+    let blocker = Connection::open(path)?;
+    blocker.execute_batch("BEGIN IMMEDIATE;")?;
+
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let entry = doc(id_generator.user_generate(&table), 1, Some(1), None)?;
+    let err = p
+        .write(&[entry], &[], ConflictStrategy::Error)
+        .await
+        .unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<rusqlite::Error>()
+            .and_then(|e| e.sqlite_error_code()),
+        Some(rusqlite::ErrorCode::DatabaseBusy)
+    );
+    assert!(matches!(
+        err.downcast_ref::<PersistenceError>(),
+        Some(PersistenceError::Busy)
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_write_with_timeout_while_locked() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("timeout.sqlite3");
+    let path = path.to_str().unwrap();
+    let p = SqlitePersistence::new_with_options(
+        path,
+        SqliteOptions {
+            wal_mode: true,
+            write_retries: 3,
+            write_retry_delay: Duration::from_millis(10),
+            ..Default::default()
+        },
+    )?;
+
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let entries = (0..10)
+        .map(|ts| {
+            doc(
+                id_generator.user_generate(&table),
+                ts,
+                Some(ts as i64),
+                None,
+            )
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    // Hold the database's write lock from another connection.
+    let blocker = Connection::open(path)?;
+    blocker.execute_batch("BEGIN IMMEDIATE;")?;
+
+    let start = Instant::now();
+    let err = p
+        .write_with_timeout(
+            &entries,
+            &[],
+            ConflictStrategy::Error,
+            Duration::from_millis(200),
+        )
+        .await
+        .expect_err("Write should time out while the database is locked");
+    // Well under the default 5s busy timeout.
+    assert!(start.elapsed() < Duration::from_secs(2));
+    assert!(matches!(
+        err.downcast_ref::<PersistenceError>(),
+        Some(PersistenceError::Timeout)
+    ));
+
+    blocker.execute_batch("ROLLBACK;")?;
+    let documents = p
+        .reader()
+        .load_documents(
+            TimestampRange::all(),
+            Order::Asc,
+            100,
+            Arc::new(NoopRetentionValidator),
+        )
+        .try_collect::<Vec<_>>()
+        .await?;
+    assert!(documents.is_empty());
+
+    // Once the lock is released, the same write goes through.
+    p.write_with_timeout(
+        &entries,
+        &[],
+        ConflictStrategy::Error,
+        Duration::from_secs(5),
+    )
+    .await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_streaming_writer_flushes_on_interval() -> anyhow::Result<()> {
+    let p: Arc<dyn Persistence> = Arc::new(SqlitePersistence::new_in_memory()?);
+    let writer = p.clone().streaming_writer(100, Duration::from_millis(200));
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+
+    for ts in 1..=10 {
+        let entry = doc(
+            id_generator.user_generate(&table),
+            ts,
+            Some(ts as i64),
+            None,
+        )?;
+        writer.push([entry], []).await?;
+    }
+    // Below the count threshold, so nothing has been written yet.
+    assert_eq!(writer.buffered().await, 10);
+    assert_eq!(load_all(p.reader().as_ref()).await?.len(), 0);
+
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while writer.buffered().await > 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await?;
+    assert_eq!(load_all(p.reader().as_ref()).await?.len(), 10);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_streaming_writer_flushes_on_count() -> anyhow::Result<()> {
+    let p: Arc<dyn Persistence> = Arc::new(SqlitePersistence::new_in_memory()?);
+    let writer = p.clone().streaming_writer(5, Duration::from_secs(3600));
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+
+    for ts in 1..=7 {
+        let entry = doc(
+            id_generator.user_generate(&table),
+            ts,
+            Some(ts as i64),
+            None,
+        )?;
+        writer.push([entry], []).await?;
+    }
+    assert_eq!(writer.buffered().await, 2);
+    assert_eq!(load_all(p.reader().as_ref()).await?.len(), 5);
+
+    writer.flush().await?;
+    assert_eq!(load_all(p.reader().as_ref()).await?.len(), 7);
+
+    // Dropping the writer flushes in the background.
+    let entry = doc(id_generator.user_generate(&table), 8, Some(8), None)?;
+    writer.push([entry], []).await?;
+    drop(writer);
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while load_all(p.reader().as_ref()).await?.len() < 8 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        anyhow::Ok(())
+    })
+    .await??;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transaction() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let p = SqlitePersistence::new(db.path().join("transaction.sqlite3").to_str().unwrap())?;
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let first = vec![
+        doc(id_generator.user_generate(&table), 1, Some(1), None)?,
+        doc(id_generator.user_generate(&table), 2, Some(2), None)?,
+    ];
+    let second = vec![doc(id_generator.user_generate(&table), 3, Some(3), None)?];
+
+    let mut transaction = p.begin().await?;
+    transaction
+        .write(&first, &[], ConflictStrategy::Error)
+        .await?;
+    transaction
+        .write(&second, &[], ConflictStrategy::Error)
+        .await?;
+    transaction.rollback().await?;
+    assert!(load_all(&p).await?.is_empty());
+
+    // Dropping an uncommitted transaction rolls it back too.
+    let mut transaction = p.begin().await?;
+    transaction
+        .write(&first, &[], ConflictStrategy::Error)
+        .await?;
+    drop(transaction);
+    assert!(load_all(&p).await?.is_empty());
+
+    let mut transaction = p.begin().await?;
+    transaction
+        .write(&first, &[], ConflictStrategy::Error)
+        .await?;
+    transaction
+        .write(&second, &[], ConflictStrategy::Error)
+        .await?;
+    transaction.commit().await?;
+    assert_eq!(load_all(&p).await?, [first, second].concat());
+
+    // Writes outside of a transaction still work once it's finished.
+    let third = doc(id_generator.user_generate(&table), 4, Some(4), None)?;
+    p.write(&[third.clone()], &[], ConflictStrategy::Error)
+        .await?;
+    assert_eq!(load_all(&p).await?.len(), 4);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transaction_failed_write() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let p = SqlitePersistence::new(db.path().join("failed.sqlite3").to_str().unwrap())?;
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let id = id_generator.user_generate(&table);
+    let entry = doc(id, 1, Some(1), None)?;
+    let conflicting = doc(id, 1, Some(2), None)?;
+
+    // A failed write only undoes itself, so the transaction can still commit
+    // the writes before it.
+    let mut transaction = p.begin().await?;
+    transaction
+        .write(&[entry.clone()], &[], ConflictStrategy::Error)
+        .await?;
+    assert!(transaction
+        .write(&[conflicting], &[], ConflictStrategy::Error)
+        .await
+        .is_err());
+    transaction.commit().await?;
+    assert_eq!(load_all(&p).await?, vec![entry]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_write_waits_for_transaction() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let p = Arc::new(SqlitePersistence::new(
+        db.path().join("concurrent.sqlite3").to_str().unwrap(),
+    )?);
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let first = doc(id_generator.user_generate(&table), 1, Some(1), None)?;
+    let second = doc(id_generator.user_generate(&table), 2, Some(2), None)?;
+
+    let mut transaction = p.begin().await?;
+    transaction
+        .write(&[first.clone()], &[], ConflictStrategy::Error)
+        .await?;
+    // The test runs on a single thread, so the other write has to wait for
+    // the transaction without blocking it, or the transaction could never
+    // commit.
+    let write = tokio::spawn({
+        let p = p.clone();
+        let second = second.clone();
+        async move { p.write(&[second], &[], ConflictStrategy::Error).await }
+    });
+    tokio::task::yield_now().await;
+    assert!(!write.is_finished());
+    transaction.commit().await?;
+    write.await??;
+    assert_eq!(load_all(&p).await?, vec![first, second]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_maintenance_fails_during_transaction() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let p = SqlitePersistence::new_with_options(
+        db.path().join("maintenance.sqlite3").to_str().unwrap(),
+        SqliteOptions {
+            wal_mode: true,
+            ..Default::default()
+        },
+    )?;
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let entry = doc(id_generator.user_generate(&table), 1, Some(1), None)?;
+
+    let mut transaction = p.begin().await?;
+    transaction
+        .write(&[entry], &[], ConflictStrategy::Error)
+        .await?;
+    // These share the transaction's connection, so they'd run inside it.
+    assert!(p.compact().is_err());
+    assert!(p.checkpoint(CheckpointMode::Passive).is_err());
+    assert!(p.stats().is_err());
+    assert!(p.verify_integrity().is_err());
+    transaction.commit().await?;
+
+    p.compact()?;
+    p.checkpoint(CheckpointMode::Passive)?;
+    assert_eq!(load_all(&p).await?.len(), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_generation() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("generation.sqlite3");
+    let path = path.to_str().unwrap();
+    let p = SqlitePersistence::new(path)?;
+    let reader = p.reader();
+    assert_eq!(reader.generation().await?, 0);
+
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let entry = doc(id_generator.user_generate(&table), 1, Some(1), None)?;
+    let mut last_generation = 0;
+    for ts in 1..=3 {
+        let entry = doc(id_generator.user_generate(&table), ts, Some(1), None)?;
+        p.write(&[entry], &[], ConflictStrategy::Error).await?;
+        let generation = reader.generation().await?;
+        assert!(generation > last_generation);
+        last_generation = generation;
+    }
+
+    let (ts, id) = (entry.ts, entry.id);
+    // A failed write doesn't commit, so it doesn't bump the generation.
+    p.write(&[entry.clone()], &[], ConflictStrategy::Error)
+        .await?;
+    let generation = reader.generation().await?;
+    assert!(p
+        .write(&[entry], &[], ConflictStrategy::Error)
+        .await
+        .is_err());
+    assert_eq!(reader.generation().await?, generation);
+
+    // Deletions bump it too.
+    let mut last_generation = generation;
+    p.delete(vec![(ts, id)]).await?;
+    let generation = reader.generation().await?;
+    assert!(generation > last_generation);
+    last_generation = generation;
+    p.delete_tablet_documents(id.table(), 1).await?;
+    let generation = reader.generation().await?;
+    assert!(generation > last_generation);
+    last_generation = generation;
+    p.delete_range(TimestampRange::all(), true).await?;
+    let generation = reader.generation().await?;
+    assert!(generation > last_generation);
+
+    drop(reader);
+    drop(p);
+    let replica = SqlitePersistence::open_readonly(path)?;
+    assert_eq!(replica.reader().generation().await?, generation);
+    Ok(())
+}
+
+fn open(db: &TempDir, validate_chain: bool) -> anyhow::Result<SqlitePersistence> {
+    SqlitePersistence::new_with_options(
+        db.path().join("chain.sqlite3").to_str().unwrap(),
+        SqliteOptions {
+            validate_chain,
+            ..Default::default()
+        },
+    )
+}
+
+#[tokio::test]
+async fn test_valid_chain() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let p = open(&db, true)?;
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let id = id_generator.user_generate(&table);
+
+    // Chains may continue within a write and across writes.
+    p.write(
+        &[doc(id, 1, Some(1), None)?, doc(id, 2, Some(2), Some(1))?],
+        &[],
+        ConflictStrategy::Error,
+    )
+    .await?;
+    p.write(&[doc(id, 5, None, Some(2))?], &[], ConflictStrategy::Error)
+        .await?;
+    assert_eq!(load_all(&p).await?.len(), 3);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_broken_chain() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let p = open(&db, true)?;
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let id = id_generator.user_generate(&table);
+    p.write(
+        &[doc(id, 1, Some(1), None)?, doc(id, 2, Some(2), Some(1))?],
+        &[],
+        ConflictStrategy::Error,
+    )
+    .await?;
+
+    // Skipping over the latest revision is rejected, along with the rest of
+    // the write.
+    let other_id = id_generator.user_generate(&table);
+    assert!(p
+        .write(
+            &[
+                doc(other_id, 3, Some(3), None)?,
+                doc(id, 3, Some(3), Some(1))?
+            ],
+            &[],
+            ConflictStrategy::Error,
+        )
+        .await
+        .is_err());
+    // So is pointing at a revision that doesn't exist.
+    assert!(p
+        .write(
+            &[doc(id, 3, Some(3), Some(0))?],
+            &[],
+            ConflictStrategy::Error
+        )
+        .await
+        .is_err());
+    assert_eq!(load_all(&p).await?.len(), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_first_revision() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let p = open(&db, true)?;
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let id = id_generator.user_generate(&table);
+
+    // The first revision of a document must not have a prev_ts.
+    assert!(p
+        .write(
+            &[doc(id, 2, Some(2), Some(1))?],
+            &[],
+            ConflictStrategy::Error
+        )
+        .await
+        .is_err());
+    p.write(&[doc(id, 2, Some(2), None)?], &[], ConflictStrategy::Error)
+        .await?;
+    assert_eq!(load_all(&p).await?.len(), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_chain_not_validated_by_default() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let p = open(&db, false)?;
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let id = id_generator.user_generate(&table);
+    p.write(
+        &[doc(id, 2, Some(2), Some(1))?],
+        &[],
+        ConflictStrategy::Error,
+    )
+    .await?;
+    assert_eq!(load_all(&p).await?.len(), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_import() -> anyhow::Result<()> {
+    let p = SqlitePersistence::new_in_memory()?;
+    let mut id_generator = TestIdGenerator::new();
+    let table: TableName = str::parse("table")?;
+    let entries = (1..=1000)
+        .map(|ts| {
+            doc(
+                id_generator.user_generate(&table),
+                ts,
+                Some(ts as i64),
+                None,
+            )
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let imported = p
+        .import(
+            stream::iter(entries.clone()).boxed(),
+            128,
+            ConflictStrategy::Error,
+        )
+        .await?;
+    assert_eq!(imported, 1000);
+
+    let loaded = p
+        .reader()
+        .load_documents(
+            TimestampRange::all(),
+            Order::Asc,
+            100,
+            Arc::new(NoopRetentionValidator),
+        )
+        .try_collect::<Vec<_>>()
+        .await?;
+    assert_eq!(loaded, entries);
+
+    // Re-importing the same entries conflicts under the default strategy.
+    assert!(p
+        .import(
+            stream::iter(entries.clone()).boxed(),
+            128,
+            ConflictStrategy::Error,
+        )
+        .await
+        .is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_documents_only() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("documents_only.sqlite3");
+    let p = SqlitePersistence::new_with_options(
+        path.to_str().unwrap(),
+        SqliteOptions {
+            documents_only: true,
+            ..Default::default()
+        },
+    )?;
+    let mut id_generator = TestIdGenerator::new();
+    let index_id = id_generator.system_generate(&INDEX_TABLE).internal_id();
+    let table: TableName = str::parse("table")?;
+    let tablet_id = id_generator.user_table_id(&table).tablet_id;
+    let a = id_generator.user_generate(&table);
+    let b = id_generator.user_generate(&table);
+
+    p.write(&[doc(a, 1, Some(1), None)?], &[], ConflictStrategy::Error)
+        .await?;
+    // Index entries are ignored rather than rejected.
+    let index_entry = PersistenceIndexEntry {
+        ts: Timestamp::must(2),
+        index_id,
+        key: IndexKeyBytes(vec![1]),
+        value: Some(b),
+    };
+    p.write(
+        &[doc(b, 2, Some(2), None)?],
+        &[index_entry],
+        ConflictStrategy::Error,
+    )
+    .await?;
+
+    let reader = p.reader();
+    assert_eq!(
+        reader
+            .load_all_documents()
+            .try_collect::<Vec<_>>()
+            .await?
+            .len(),
+        2
+    );
+    let scanned = reader
+        .index_scan(
+            index_id,
+            tablet_id,
+            Timestamp::must(2),
+            &Interval::all(),
+            Order::Asc,
+            100,
+            Arc::new(NoopRetentionValidator),
+        )
+        .try_collect::<Vec<_>>()
+        .await?;
+    assert!(scanned.is_empty());
+    drop(reader);
+    drop(p);
+
+    // Nothing was written to the index, even when read back without the
+    // option.
+    let p = SqlitePersistence::new(path.to_str().unwrap())?;
+    assert_eq!(
+        p.reader()
+            .index_entry_count(index_id, tablet_id, Timestamp::must(2))
+            .await?,
+        0
+    );
+    Ok(())
+}
+
+/// Writes three revisions of a live document and a document that is later
+/// deleted, each revision with an index entry.
+async fn write_history(
+    p: &SqlitePersistence,
+) -> anyhow::Result<(Vec<DocumentLogEntry>, Vec<PersistenceIndexEntry>)> {
+    let mut id_generator = TestIdGenerator::new();
+    let index_id = id_generator.system_generate(&INDEX_TABLE).internal_id();
+    let table: TableName = str::parse("table")?;
+    let live = id_generator.user_generate(&table);
+    let deleted = id_generator.user_generate(&table);
+
+    let documents = vec![
+        doc(live, 1, Some(1), None)?,
+        doc(deleted, 1, Some(10), None)?,
+        doc(live, 2, Some(2), Some(1))?,
+        doc(deleted, 2, None, Some(1))?,
+        doc(live, 3, Some(3), Some(2))?,
+    ];
+    let indexes = documents
+        .iter()
+        .map(|entry| PersistenceIndexEntry {
+            ts: entry.ts,
+            index_id,
+            key: IndexKeyBytes(entry.id.internal_id()[..].to_vec()),
+            value: entry.value.as_ref().map(|_| entry.id),
+        })
+        .collect::<Vec<_>>();
+    p.write(&documents, &indexes, ConflictStrategy::Overwrite)
+        .await?;
+    Ok((documents, indexes))
+}
+
+#[tokio::test]
+async fn test_delete_range_retain_latest() -> anyhow::Result<()> {
+    let p = SqlitePersistence::new_in_memory()?;
+    let (documents, _) = write_history(&p).await?;
+
+    let deleted = p.delete_range(TimestampRange::all(), true).await?;
+    assert_eq!(deleted, 4);
+    assert_eq!(load_all(&p).await?, vec![documents[4].clone()]);
+
+    let index_entries = p.load_index_chunk(None, 100).await?;
+    assert_eq!(index_entries.len(), 1);
+    assert_eq!(index_entries[0].ts, Timestamp::must(3));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_delete_range_sub_range() -> anyhow::Result<()> {
+    let p = SqlitePersistence::new_in_memory()?;
+    write_history(&p).await?;
+
+    let range = TimestampRange::new(..Timestamp::must(2));
+    assert_eq!(p.delete_range(range, false).await?, 2);
+    let remaining = load_all(&p).await?;
+    assert_eq!(remaining.len(), 3);
+    assert!(remaining.iter().all(|entry| entry.ts >= Timestamp::must(2)));
+    assert_eq!(p.load_index_chunk(None, 100).await?.len(), 3);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_delete_range_keeps_tombstone_hiding_older_revisions() -> anyhow::Result<()> {
+    let p = SqlitePersistence::new_in_memory()?;
+    let (documents, _) = write_history(&p).await?;
+
+    // The deletion at 2 hides the revision at 1, which is outside the range.
+    let range = TimestampRange::new(Timestamp::must(2)..);
+    assert_eq!(p.delete_range(range, true).await?, 1);
+    assert_eq!(
+        load_all(&p).await?,
+        vec![
+            documents[0].clone(),
+            documents[1].clone(),
+            documents[3].clone(),
+            documents[4].clone(),
+        ]
+    );
+    assert_eq!(p.load_index_chunk(None, 100).await?.len(), 4);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_delete_range_refuses_to_delete_live_revision() -> anyhow::Result<()> {
+    let p = SqlitePersistence::new_in_memory()?;
+    let (documents, _) = write_history(&p).await?;
+
+    assert!(p.delete_range(TimestampRange::all(), false).await.is_err());
+    assert_eq!(load_all(&p).await?, documents);
+    assert_eq!(p.load_index_chunk(None, 100).await?.len(), 5);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_truncate() -> anyhow::Result<()> {
+    let db = TempDir::new()?;
+    let path = db.path().join("truncate.sqlite3");
+    let p = SqlitePersistence::new_with_options(
+        path.to_str().unwrap(),
+        SqliteOptions {
+            wal_mode: true,
+            ..Default::default()
+        },
+    )?;
+    let mut id_generator = TestIdGenerator::new();
+    let index_id = id_generator.system_generate(&INDEX_TABLE).internal_id();
+    let table: TableName = str::parse("table")?;
+    let tablet_id = id_generator.user_table_id(&table).tablet_id;
+    let entry = doc(id_generator.user_generate(&table), 1, Some(1), None)?;
+    let index = PersistenceIndexEntry {
+        ts: entry.ts,
+        index_id,
+        key: IndexKeyBytes(vec![1]),
+        value: Some(entry.id),
+    };
+    p.write(&[entry.clone()], &[index.clone()], ConflictStrategy::Error)
+        .await?;
+    let generation = p.reader().generation().await?;
+
+    p.truncate().await?;
+    let reader = p.reader();
+    assert_eq!(
+        reader.load_all_documents().try_collect::<Vec<_>>().await?,
+        vec![]
+    );
+    let scanned = reader
+        .index_scan(
+            index_id,
+            tablet_id,
+            Timestamp::must(1),
+            &Interval::all(),
+            Order::Asc,
+            100,
+            Arc::new(NoopRetentionValidator),
+        )
+        .try_collect::<Vec<_>>()
+        .await?;
+    assert!(scanned.is_empty());
+    assert_eq!(reader.max_timestamp().await?, None);
+    // Truncating counts as a change, so caches from before it look stale.
+    assert!(reader.generation().await? > generation);
+    let journal_mode: String =
+        rusqlite::Connection::open(&path)?
+            .query_row("PRAGMA journal_mode", [], |row| row.get(0))?;
+    assert_eq!(journal_mode, "wal");
+
+    // The schema survives, so the same rows can be written again.
+    p.write(&[entry.clone()], &[index], ConflictStrategy::Error)
+        .await?;
+    assert_eq!(
+        reader.load_all_documents().try_collect::<Vec<_>>().await?,
+        vec![entry]
+    );
+    Ok(())
+}
@@ -23,7 +23,7 @@ use crate::{
     ConvexValue,
 };
 
-const MAX_ARRAY_LEN: usize = 8192;
+pub(crate) const MAX_ARRAY_LEN: usize = 8192;
 
 /// Wrapper on `Vec<Value>` that enforces size limits.
 #[derive(Clone)]
@@ -18,6 +18,7 @@ mod tests;
 
 use std::{
     collections::BTreeMap,
+    io,
     num::FpCategory,
 };
 
@@ -67,15 +68,12 @@ impl Serialize for SerializeObject<'_> {
 }
 
 pub mod value {
-    use std::num::FpCategory;
-
     use serde::{
         ser::SerializeMap,
         Serializer,
     };
 
     use crate::{
-        numeric::is_negative_zero,
         ConvexValue,
         JsonBytes,
         JsonFloat,
@@ -91,12 +89,7 @@ pub mod value {
                 obj.end()
             },
             ConvexValue::Float64(n) => {
-                let mut is_special = is_negative_zero(*n);
-                is_special |= match n.classify() {
-                    FpCategory::Zero | FpCategory::Normal | FpCategory::Subnormal => false,
-                    FpCategory::Infinite | FpCategory::Nan => true,
-                };
-                if is_special {
+                if super::is_special_float(*n) {
                     let mut obj = serializer.serialize_map(Some(1))?;
                     obj.serialize_entry("$float", &JsonFloat::encode(*n))?;
                     obj.end()
@@ -252,6 +245,16 @@ impl From<ConvexValue> for JsonValue {
     }
 }
 
+/// Floats that JSON numbers can't represent faithfully, which are encoded as
+/// `{"$float": ...}` instead.
+fn is_special_float(n: f64) -> bool {
+    is_negative_zero(n)
+        || match n.classify() {
+            FpCategory::Zero | FpCategory::Normal | FpCategory::Subnormal => false,
+            FpCategory::Infinite | FpCategory::Nan => true,
+        }
+}
+
 impl ConvexValue {
     pub fn to_internal_json(&self) -> JsonValue {
         value::serialize(self, serde_json::value::Serializer)
@@ -261,6 +264,68 @@ impl ConvexValue {
     pub fn json_serialize(&self) -> anyhow::Result<String> {
         Ok(serde_json::to_string(&SerializeValue(self))?)
     }
+
+    /// The length in bytes of `json_serialize`'s output, computed by walking
+    /// the value rather than serializing it.
+    pub fn serialized_size(&self) -> usize {
+        match self {
+            ConvexValue::Null => "null".len(),
+            // Eight little endian bytes.
+            ConvexValue::Int64(_) => tagged_size("$integer", base64_size(8)),
+            ConvexValue::Float64(n) => {
+                if is_special_float(*n) {
+                    tagged_size("$float", base64_size(8))
+                } else {
+                    let mut counter = ByteCounter(0);
+                    serde_json::to_writer(&mut counter, n).expect("Writing to counter failed");
+                    counter.0
+                }
+            },
+            ConvexValue::Boolean(true) => "true".len(),
+            ConvexValue::Boolean(false) => "false".len(),
+            ConvexValue::String(s) => string_size(s),
+            ConvexValue::Bytes(b) => tagged_size("$bytes", base64_size(b.len())),
+            ConvexValue::Array(a) => a.serialized_size(),
+            ConvexValue::Object(o) => o.serialized_size(),
+        }
+    }
+}
+
+/// Size of a JSON string literal, including quotes and escapes.
+fn string_size(s: &str) -> usize {
+    let escapes: usize = s
+        .bytes()
+        .map(|b| match b {
+            b'"' | b'\\' | b'\x08' | b'\t' | b'\n' | b'\x0c' | b'\r' => 1,
+            0x00..=0x1f => "\\u0000".len() - 1,
+            _ => 0,
+        })
+        .sum();
+    s.len() + escapes + 2
+}
+
+/// Size of `{"<tag>":"<value>"}` where `value` has no escapes.
+fn tagged_size(tag: &str, value_size: usize) -> usize {
+    string_size(tag) + 1 + value_size + 2 + 2
+}
+
+/// Size of padded base64 for `n` bytes.
+fn base64_size(n: usize) -> usize {
+    n.div_ceil(3) * 4
+}
+
+/// Counts bytes written, for sizing output without buffering it.
+struct ByteCounter(usize);
+
+impl io::Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
 }
 
 impl From<ConvexObject> for JsonValue {
@@ -278,6 +343,15 @@ impl ConvexObject {
     pub fn json_serialize(&self) -> anyhow::Result<String> {
         Ok(serde_json::to_string(&SerializeObject(self))?)
     }
+
+    /// See [`ConvexValue::serialized_size`].
+    pub fn serialized_size(&self) -> usize {
+        let fields: usize = self
+            .iter()
+            .map(|(key, value)| string_size(key) + 1 + value.serialized_size())
+            .sum();
+        2 + fields + self.len().saturating_sub(1)
+    }
 }
 
 impl ConvexArray {
@@ -289,6 +363,12 @@ impl ConvexArray {
     pub fn json_serialize(&self) -> anyhow::Result<String> {
         Ok(serde_json::to_string(&SerializeArray(self))?)
     }
+
+    /// See [`ConvexValue::serialized_size`].
+    pub fn serialized_size(&self) -> usize {
+        let elements: usize = self.iter().map(ConvexValue::serialized_size).sum();
+        2 + elements + self.len().saturating_sub(1)
+    }
 }
 
 pub fn json_deserialize(s: &str) -> anyhow::Result<ConvexValue> {
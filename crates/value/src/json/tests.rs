@@ -38,6 +38,7 @@ mod json_serialize_roundtrip {
 
         let reserialized = right.json_serialize()?;
         assert_eq!(string, reserialized);
+        assert_eq!(right.serialized_size(), reserialized.len());
         assert_eq!(reserialized, right.to_internal_json().to_string());
 
         Ok(())
@@ -84,3 +85,61 @@ mod json_serialize_roundtrip {
         Ok(())
     }
 }
+
+mod serialized_size {
+    use crate::{
+        assert_val,
+        ConvexValue,
+    };
+
+    fn check(value: ConvexValue) -> anyhow::Result<()> {
+        assert_eq!(
+            value.serialized_size(),
+            value.json_serialize()?.len(),
+            "{value:?}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_serialized_size_scalars() -> anyhow::Result<()> {
+        check(ConvexValue::Null)?;
+        check(ConvexValue::from(true))?;
+        check(ConvexValue::from(false))?;
+        check(ConvexValue::from(i64::MIN))?;
+        for n in [0.0, -0.0, 1.5, 1e300, f64::NAN, f64::INFINITY] {
+            check(ConvexValue::from(n))?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_serialized_size_strings_and_bytes() -> anyhow::Result<()> {
+        for s in [
+            "",
+            "plain",
+            "quote\"backslash\\",
+            "\n\t\r\u{8}\u{c}",
+            "\u{1}\u{1f}",
+            "héllo 🦀",
+        ] {
+            check(ConvexValue::try_from(s.to_string())?)?;
+        }
+        for len in 0..5 {
+            check(ConvexValue::Bytes(vec![0xff; len].try_into()?))?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_serialized_size_nested() -> anyhow::Result<()> {
+        check(assert_val!([]))?;
+        check(assert_val!([1, "two", [3.0, null]]))?;
+        check(assert_val!({}))?;
+        check(assert_val!({
+            "a" => 1,
+            "nested" => { "b" => [true, false], "c" => { "d" => "e\n" } },
+        }))?;
+        Ok(())
+    }
+}
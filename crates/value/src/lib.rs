@@ -16,6 +16,8 @@ mod field_name;
 mod field_path;
 pub mod id_v6;
 mod json;
+mod merge;
+mod msgpack;
 pub mod numeric;
 mod object;
 pub mod serde;
@@ -26,6 +28,7 @@ pub mod sorting;
 mod string;
 mod table_mapping;
 mod table_name;
+mod validate;
 pub mod walk;
 
 // Helper modules we'll eventually factor out.
@@ -81,6 +84,7 @@ pub use crate::{
         object as json_object,
         value as json_value,
     },
+    merge::MergeOptions,
     object::{
         remove_boolean,
         remove_int64,
@@ -118,6 +122,10 @@ pub use crate::{
         TabletIdAndTableNumber,
         METADATA_PREFIX,
     },
+    validate::{
+        ValidationError,
+        ValueLimits,
+    },
 };
 
 #[cfg(any(test, feature = "testing"))]
@@ -164,6 +172,88 @@ impl ConvexValue {
         let Ok(ty) = self.walk();
         ty.type_name()
     }
+
+    /// Walk a path of object field names and array indices, starting at this
+    /// value. Array indices are given as decimal strings, as in a JSON Pointer.
+    /// Returns `None` if any step of the path doesn't exist.
+    pub fn get_path(&self, path: &[&str]) -> Option<&ConvexValue> {
+        let mut current = self;
+        for segment in path {
+            current = match current {
+                ConvexValue::Object(o) => o.get(*segment)?,
+                ConvexValue::Array(a) => a.get(segment.parse::<usize>().ok()?)?,
+                _ => return None,
+            };
+        }
+        Some(current)
+    }
+
+    /// Returns the integer this value holds, if it's an `Int64` or a
+    /// `Float64` that `numeric::is_integral` accepts and that fits in an
+    /// `i64`.
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            ConvexValue::Int64(i) => Some(*i),
+            // `i64::MIN` is exactly representable, but `i64::MAX` rounds up
+            // to 2^63, which is out of range.
+            ConvexValue::Float64(f) if *f >= i64::MIN as f64 && *f < i64::MAX as f64 => {
+                numeric::is_integral(*f)
+            },
+            _ => None,
+        }
+    }
+
+    /// Returns the number this value holds, if it's a `Float64` or an `Int64`.
+    /// Integers beyond 2^53 in magnitude are rounded to the nearest `f64`.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            ConvexValue::Float64(f) => Some(*f),
+            ConvexValue::Int64(i) => Some(*i as f64),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            ConvexValue::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            ConvexValue::Bytes(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            ConvexValue::Boolean(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    /// Returns the number of elements of an array, fields of an object, or
+    /// bytes of a string (in UTF-8) or bytes value. Returns `None` for other
+    /// types.
+    pub fn len(&self) -> Option<usize> {
+        match self {
+            ConvexValue::Array(a) => Some(a.len()),
+            ConvexValue::Object(o) => Some(o.len()),
+            ConvexValue::String(s) => Some(s.len()),
+            ConvexValue::Bytes(b) => Some(b.len()),
+            ConvexValue::Null
+            | ConvexValue::Int64(_)
+            | ConvexValue::Float64(_)
+            | ConvexValue::Boolean(_) => None,
+        }
+    }
+
+    /// Whether `len` is zero, or `None` if this value doesn't have a length.
+    pub fn is_empty(&self) -> Option<bool> {
+        self.len().map(|len| len == 0)
+    }
 }
 
 impl From<ConvexObject> for ConvexValue {
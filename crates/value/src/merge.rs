@@ -0,0 +1,58 @@
+//! Recursive merging of a patch into a value, as used for partial updates.
+
+use std::collections::BTreeMap;
+
+use crate::{
+    ConvexObject,
+    ConvexValue,
+};
+
+/// Options for `ConvexValue::merge`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MergeOptions {
+    /// If set, a `null` field in the patch removes the field from the result
+    /// instead of setting it to `null`.
+    pub null_deletes_field: bool,
+}
+
+impl ConvexValue {
+    /// Overlays `patch` onto this value. If both are objects, each field of
+    /// `patch` is merged into the field of the same name, recursively, and
+    /// fields missing from `patch` are kept. Otherwise `patch` replaces this
+    /// value wholesale, including when only one of them is an object.
+    ///
+    /// e.g.,
+    ///   `{ name: { first: "Mr", last: "Fantastik" }, job: "mechanic" }`
+    /// merged with
+    ///   `{ name: { first: "Mr." }, job: ["mechanic"] }`
+    /// results in
+    ///   `{ name: { first: "Mr.", last: "Fantastik" }, job: ["mechanic"] }`.
+    ///
+    /// Fails if the result exceeds the limits on values.
+    pub fn merge(&self, patch: &ConvexValue, options: MergeOptions) -> anyhow::Result<ConvexValue> {
+        match (self, patch) {
+            (ConvexValue::Object(object), ConvexValue::Object(patch)) => {
+                Ok(ConvexValue::Object(object.merge(patch, options)?))
+            },
+            _ => Ok(patch.clone()),
+        }
+    }
+}
+
+impl ConvexObject {
+    fn merge(&self, patch: &ConvexObject, options: MergeOptions) -> anyhow::Result<ConvexObject> {
+        let mut fields: BTreeMap<_, _> = self.clone().into();
+        for (field, value) in patch.iter() {
+            if options.null_deletes_field && *value == ConvexValue::Null {
+                fields.remove(field);
+                continue;
+            }
+            let merged = match fields.get(field) {
+                Some(existing) => existing.merge(value, options)?,
+                None => value.clone(),
+            };
+            fields.insert(field.clone(), merged);
+        }
+        fields.try_into()
+    }
+}
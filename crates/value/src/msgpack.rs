@@ -0,0 +1,312 @@
+//! Encoding of [`ConvexValue`]s as [MessagePack](https://msgpack.org).
+//!
+//! Each variant maps to a single MessagePack type:
+//!
+//! | `ConvexValue` | MessagePack                                  |
+//! |---------------|----------------------------------------------|
+//! | `Null`        | nil (`0xc0`)                                 |
+//! | `Int64`       | int 64 (`0xd3`), always 8 bytes big endian   |
+//! | `Float64`     | float 64 (`0xcb`), always 8 bytes big endian |
+//! | `Boolean`     | false (`0xc2`) / true (`0xc3`)               |
+//! | `String`      | str (fixstr, str 8, str 16 or str 32)        |
+//! | `Bytes`       | bin (bin 8, bin 16 or bin 32)                |
+//! | `Array`       | array (fixarray, array 16 or array 32)       |
+//! | `Object`      | map (fixmap, map 16 or map 32) of str keys   |
+//!
+//! Integers and floats are always written at full width so neither loses
+//! precision and the two stay distinguishable. When decoding, any MessagePack
+//! integer becomes an `Int64` (unsigned integers above `i64::MAX` are
+//! rejected) and float 32 is widened to a `Float64`. Extension types and
+//! non-string map keys aren't supported.
+
+use std::collections::BTreeMap;
+
+use anyhow::{
+    anyhow,
+    bail,
+    Context,
+};
+
+use crate::{
+    size::MAX_NESTING,
+    ConvexValue,
+    FieldName,
+};
+
+const NIL: u8 = 0xc0;
+const FALSE: u8 = 0xc2;
+const TRUE: u8 = 0xc3;
+const BIN8: u8 = 0xc4;
+const BIN16: u8 = 0xc5;
+const BIN32: u8 = 0xc6;
+const FLOAT32: u8 = 0xca;
+const FLOAT64: u8 = 0xcb;
+const UINT8: u8 = 0xcc;
+const UINT16: u8 = 0xcd;
+const UINT32: u8 = 0xce;
+const UINT64: u8 = 0xcf;
+const INT8: u8 = 0xd0;
+const INT16: u8 = 0xd1;
+const INT32: u8 = 0xd2;
+const INT64: u8 = 0xd3;
+const STR8: u8 = 0xd9;
+const STR16: u8 = 0xda;
+const STR32: u8 = 0xdb;
+const ARRAY16: u8 = 0xdc;
+const ARRAY32: u8 = 0xdd;
+const MAP16: u8 = 0xde;
+const MAP32: u8 = 0xdf;
+
+impl ConvexValue {
+    /// Encodes this value as MessagePack. See the module documentation for
+    /// how each variant is represented.
+    pub fn to_msgpack(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        encode(self, &mut out);
+        out
+    }
+
+    /// Decodes a value written by [`ConvexValue::to_msgpack`], or any
+    /// MessagePack document using the supported types. Fails if `bytes` is
+    /// malformed, has trailing data, or describes a value that exceeds the
+    /// usual limits on values.
+    pub fn from_msgpack(bytes: &[u8]) -> anyhow::Result<ConvexValue> {
+        let mut decoder = Decoder { bytes, pos: 0 };
+        let value = decoder.value(0)?;
+        anyhow::ensure!(
+            decoder.pos == bytes.len(),
+            "Trailing data after MessagePack value at offset {}",
+            decoder.pos
+        );
+        Ok(value)
+    }
+}
+
+fn encode(value: &ConvexValue, out: &mut Vec<u8>) {
+    match value {
+        ConvexValue::Null => out.push(NIL),
+        ConvexValue::Int64(i) => {
+            out.push(INT64);
+            out.extend_from_slice(&i.to_be_bytes());
+        },
+        ConvexValue::Float64(f) => {
+            out.push(FLOAT64);
+            out.extend_from_slice(&f.to_bits().to_be_bytes());
+        },
+        ConvexValue::Boolean(b) => out.push(if *b { TRUE } else { FALSE }),
+        ConvexValue::String(s) => encode_str(s, out),
+        ConvexValue::Bytes(b) => {
+            encode_len(b.len(), None, [BIN8, BIN16, BIN32], out);
+            out.extend_from_slice(b);
+        },
+        ConvexValue::Array(a) => {
+            encode_len(a.len(), Some(0x90), [0, ARRAY16, ARRAY32], out);
+            for v in a {
+                encode(v, out);
+            }
+        },
+        ConvexValue::Object(o) => {
+            encode_len(o.len(), Some(0x80), [0, MAP16, MAP32], out);
+            for (k, v) in o.iter() {
+                encode_str(k, out);
+                encode(v, out);
+            }
+        },
+    }
+}
+
+fn encode_str(s: &str, out: &mut Vec<u8>) {
+    // fixstr holds up to 31 bytes, unlike fixarray and fixmap which hold 15.
+    if s.len() < 32 {
+        out.push(0xa0 | s.len() as u8);
+    } else {
+        encode_len(s.len(), None, [STR8, STR16, STR32], out);
+    }
+    out.extend_from_slice(s.as_bytes());
+}
+
+/// Writes the header for a length `len`, using the fix form (which holds up to
+/// 15) if there is one and otherwise the narrowest of the 8, 16 and 32 bit
+/// forms. Arrays and maps have no 8 bit form.
+fn encode_len(len: usize, fix: Option<u8>, markers: [u8; 3], out: &mut Vec<u8>) {
+    let [marker8, marker16, marker32] = markers;
+    match fix {
+        Some(fix) if len < 16 => out.push(fix | len as u8),
+        _ if marker8 != 0 && len <= u8::MAX as usize => {
+            out.push(marker8);
+            out.push(len as u8);
+        },
+        _ if len <= u16::MAX as usize => {
+            out.push(marker16);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        },
+        _ => {
+            // Values are far smaller than 4GB, so this can't truncate.
+            out.push(marker32);
+            out.extend_from_slice(&(len as u32).to_be_bytes());
+        },
+    }
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, n: usize) -> anyhow::Result<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(n)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or_else(|| anyhow!("Unexpected end of MessagePack input at offset {}", self.pos))?;
+        let bytes = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn take_array<const N: usize>(&mut self) -> anyhow::Result<[u8; N]> {
+        Ok(self
+            .take(N)?
+            .try_into()
+            .expect("take returned the wrong length"))
+    }
+
+    fn u8(&mut self) -> anyhow::Result<u8> {
+        Ok(self.take_array::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> anyhow::Result<usize> {
+        Ok(u16::from_be_bytes(self.take_array()?) as usize)
+    }
+
+    fn u32(&mut self) -> anyhow::Result<usize> {
+        Ok(u32::from_be_bytes(self.take_array()?) as usize)
+    }
+
+    fn str(&mut self, len: usize) -> anyhow::Result<&'a str> {
+        let offset = self.pos;
+        std::str::from_utf8(self.take(len)?)
+            .with_context(|| format!("Invalid UTF-8 in MessagePack string at offset {offset}"))
+    }
+
+    fn value(&mut self, depth: usize) -> anyhow::Result<ConvexValue> {
+        // Bound the recursion before building anything, since values check
+        // their nesting only once they're constructed.
+        anyhow::ensure!(
+            depth <= MAX_NESTING,
+            "MessagePack value is nested more than {MAX_NESTING} levels deep"
+        );
+        let offset = self.pos;
+        let marker = self.u8()?;
+        let value = match marker {
+            NIL => ConvexValue::Null,
+            FALSE => ConvexValue::Boolean(false),
+            TRUE => ConvexValue::Boolean(true),
+            0x00..=0x7f => ConvexValue::Int64(marker as i64),
+            0xe0..=0xff => ConvexValue::Int64(marker as i8 as i64),
+            UINT8 => ConvexValue::Int64(self.u8()? as i64),
+            UINT16 => ConvexValue::Int64(self.u16()? as i64),
+            UINT32 => ConvexValue::Int64(self.u32()? as i64),
+            UINT64 => {
+                let u = u64::from_be_bytes(self.take_array()?);
+                let i = i64::try_from(u)
+                    .with_context(|| format!("MessagePack integer {u} doesn't fit in an Int64"))?;
+                ConvexValue::Int64(i)
+            },
+            INT8 => ConvexValue::Int64(i8::from_be_bytes(self.take_array()?) as i64),
+            INT16 => ConvexValue::Int64(i16::from_be_bytes(self.take_array()?) as i64),
+            INT32 => ConvexValue::Int64(i32::from_be_bytes(self.take_array()?) as i64),
+            INT64 => ConvexValue::Int64(i64::from_be_bytes(self.take_array()?)),
+            FLOAT32 => ConvexValue::Float64(f32::from_be_bytes(self.take_array()?) as f64),
+            FLOAT64 => ConvexValue::Float64(f64::from_be_bytes(self.take_array()?)),
+            0xa0..=0xbf => self.string((marker & 0x1f) as usize)?,
+            STR8 => {
+                let len = self.u8()? as usize;
+                self.string(len)?
+            },
+            STR16 => {
+                let len = self.u16()?;
+                self.string(len)?
+            },
+            STR32 => {
+                let len = self.u32()?;
+                self.string(len)?
+            },
+            BIN8 | BIN16 | BIN32 => {
+                let len = match marker {
+                    BIN8 => self.u8()? as usize,
+                    BIN16 => self.u16()?,
+                    _ => self.u32()?,
+                };
+                ConvexValue::Bytes(self.take(len)?.to_vec().try_into()?)
+            },
+            0x90..=0x9f => self.array((marker & 0x0f) as usize, depth)?,
+            ARRAY16 => {
+                let len = self.u16()?;
+                self.array(len, depth)?
+            },
+            ARRAY32 => {
+                let len = self.u32()?;
+                self.array(len, depth)?
+            },
+            0x80..=0x8f => self.map((marker & 0x0f) as usize, depth)?,
+            MAP16 => {
+                let len = self.u16()?;
+                self.map(len, depth)?
+            },
+            MAP32 => {
+                let len = self.u32()?;
+                self.map(len, depth)?
+            },
+            _ => bail!("Unsupported MessagePack type {marker:#04x} at offset {offset}"),
+        };
+        Ok(value)
+    }
+
+    fn string(&mut self, len: usize) -> anyhow::Result<ConvexValue> {
+        Ok(ConvexValue::String(self.str(len)?.try_into()?))
+    }
+
+    fn array(&mut self, len: usize, depth: usize) -> anyhow::Result<ConvexValue> {
+        // Don't trust `len` for preallocation, since every element takes at
+        // least a byte.
+        let mut items = Vec::with_capacity(len.min(self.bytes.len() - self.pos));
+        for _ in 0..len {
+            items.push(self.value(depth + 1)?);
+        }
+        Ok(ConvexValue::Array(items.try_into()?))
+    }
+
+    fn map(&mut self, len: usize, depth: usize) -> anyhow::Result<ConvexValue> {
+        let mut fields = BTreeMap::new();
+        for _ in 0..len {
+            let offset = self.pos;
+            let key = match self.u8()? {
+                marker @ 0xa0..=0xbf => self.str((marker & 0x1f) as usize)?,
+                STR8 => {
+                    let len = self.u8()? as usize;
+                    self.str(len)?
+                },
+                STR16 => {
+                    let len = self.u16()?;
+                    self.str(len)?
+                },
+                STR32 => {
+                    let len = self.u32()?;
+                    self.str(len)?
+                },
+                marker => bail!(
+                    "MessagePack map key at offset {offset} has type {marker:#04x}, not a string"
+                ),
+            };
+            let field: FieldName = key.parse()?;
+            let value = self.value(depth + 1)?;
+            anyhow::ensure!(
+                fields.insert(field, value).is_none(),
+                "Duplicate MessagePack map key {key:?}"
+            );
+        }
+        Ok(ConvexValue::Object(fields.try_into()?))
+    }
+}
@@ -172,6 +172,18 @@ impl Hasher for Sha256 {
     }
 }
 
+impl ConvexValue {
+    /// A SHA-256 hash of the value that's stable across processes and
+    /// platforms, unlike `Hash`. Values that compare equal hash equally: the
+    /// value is hashed via its sort key, which visits object fields in sorted
+    /// order and writes numbers in a fixed big-endian encoding. As with
+    /// `Eq`, `Int64(1)` and `Float64(1.0)` are different values, as are `0.0`
+    /// and `-0.0`.
+    pub fn content_hash(&self) -> [u8; 32] {
+        *Sha256::hash(&self.sort_key())
+    }
+}
+
 impl TryFrom<Sha256Digest> for ConvexValue {
     type Error = anyhow::Error;
 
@@ -404,7 +404,8 @@ pub fn write_sort_key_or_undefined<V: ConvexValueWalker>(
 }
 
 // Manual implementation of `Ord` that is proptested to be equivalent to
-// comparing sort keys.
+// comparing sort keys, and so to comparing index keys built with
+// `values_to_bytes`. Across types, values sort by `type_tag`.
 impl Ord for ConvexValue {
     fn cmp(&self, other: &Self) -> Ordering {
         // This function is structured to make it hard to add another variant without
@@ -627,6 +628,16 @@ mod tests {
             assert_eq!(ord1, ord2);
         }
 
+        #[test]
+        fn test_compatible_with_index_key(
+            l in any::<ConvexValue>(),
+            r in any::<ConvexValue>(),
+        ) {
+            let ord1 = l.cmp(&r);
+            let ord2 = values_to_bytes(&[Some(l)]).cmp(&values_to_bytes(&[Some(r)]));
+            assert_eq!(ord1, ord2);
+        }
+
         #[test]
         fn test_compatible_with_float(l in any::<f64>(), r in any::<f64>()) {
             test_compatible_with_ord(TotalOrdF64(l), TotalOrdF64(r));
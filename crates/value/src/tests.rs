@@ -5,11 +5,15 @@ use std::{
 
 use crate::{
     assert_obj,
+    assert_val,
     obj,
     ConvexObject,
     ConvexValue,
+    MergeOptions,
     ResolvedDocumentId,
     Size,
+    ValidationError,
+    ValueLimits,
 };
 
 #[test]
@@ -174,3 +178,368 @@ fn test_filter_system_fields() {
         )
     )
 }
+
+#[test]
+fn test_get_path() {
+    let value = assert_val!({
+        "user" => {
+            "name" => "Nicolas",
+            "tags" => ["a", { "b" => 2 }],
+        },
+        "count" => 3,
+    });
+    assert_eq!(value.get_path(&[]), Some(&value));
+    assert_eq!(
+        value.get_path(&["user", "name"]),
+        Some(&assert_val!("Nicolas"))
+    );
+    assert_eq!(
+        value.get_path(&["user", "tags", "0"]),
+        Some(&assert_val!("a"))
+    );
+    assert_eq!(
+        value.get_path(&["user", "tags", "1", "b"]),
+        Some(&assert_val!(2))
+    );
+
+    // Absent object fields and out of bounds or malformed indices.
+    assert_eq!(value.get_path(&["user", "email"]), None);
+    assert_eq!(value.get_path(&["user", "tags", "2"]), None);
+    assert_eq!(value.get_path(&["user", "tags", "-1"]), None);
+    assert_eq!(value.get_path(&["user", "tags", "first"]), None);
+
+    // Scalars have no children.
+    assert_eq!(value.get_path(&["count", "0"]), None);
+    assert_eq!(assert_val!(null).get_path(&["a"]), None);
+}
+
+#[test]
+fn test_accessors() -> anyhow::Result<()> {
+    assert_eq!(assert_val!(3).as_i64(), Some(3));
+    assert_eq!(ConvexValue::from(-4.0).as_i64(), Some(-4));
+    assert_eq!(ConvexValue::from(i64::MIN as f64).as_i64(), Some(i64::MIN));
+    assert_eq!(assert_val!(3).as_f64(), Some(3.0));
+    assert_eq!(ConvexValue::from(2.5).as_f64(), Some(2.5));
+    assert_eq!(assert_val!("a").as_str(), Some("a"));
+    assert_eq!(
+        ConvexValue::try_from(vec![1u8, 2])?.as_bytes(),
+        Some(&[1u8, 2][..])
+    );
+    assert_eq!(assert_val!(true).as_bool(), Some(true));
+
+    // Floats that aren't integers, or don't fit in an `i64`.
+    for f in [2.5, -0.0, f64::NAN, f64::INFINITY, i64::MAX as f64, 1e300] {
+        assert_eq!(ConvexValue::from(f).as_i64(), None, "{f}");
+    }
+    // Other types don't coerce, including numeric strings.
+    assert_eq!(assert_val!("3").as_i64(), None);
+    assert_eq!(assert_val!(true).as_f64(), None);
+    assert_eq!(assert_val!(null).as_f64(), None);
+    assert_eq!(assert_val!(3).as_str(), None);
+    assert_eq!(assert_val!("ab").as_bytes(), None);
+    assert_eq!(assert_val!(1).as_bool(), None);
+    assert_eq!(assert_val!([true]).as_bool(), None);
+    Ok(())
+}
+
+#[test]
+fn test_len() -> anyhow::Result<()> {
+    for (value, len) in [
+        (assert_val!([]), 0),
+        (assert_val!([1, [2, 3]]), 2),
+        (assert_val!({}), 0),
+        (assert_val!({ "a" => 1, "b" => { "c" => 2 } }), 2),
+        (assert_val!(""), 0),
+        // Strings are measured in UTF-8 bytes, not characters.
+        (assert_val!("né"), 3),
+        (ConvexValue::try_from(Vec::<u8>::new())?, 0),
+        (ConvexValue::try_from(vec![1u8, 2, 3])?, 3),
+    ] {
+        assert_eq!(value.len(), Some(len), "{value}");
+        assert_eq!(value.is_empty(), Some(len == 0), "{value}");
+    }
+    for value in [
+        assert_val!(null),
+        assert_val!(0),
+        ConvexValue::from(0.0),
+        assert_val!(false),
+    ] {
+        assert_eq!(value.len(), None, "{value}");
+        assert_eq!(value.is_empty(), None, "{value}");
+    }
+    Ok(())
+}
+
+#[test]
+fn test_validate() -> anyhow::Result<()> {
+    let limits = ValueLimits {
+        max_nesting: 4,
+        max_size: 100,
+        max_string_len: 10,
+        max_array_len: 3,
+        max_object_fields: 2,
+    };
+    let value = assert_val!({
+        "name" => "Nicolas",
+        "tags" => ["a", { "b" => 2 }],
+    });
+    value.validate(&limits)?;
+    value.validate(&ValueLimits::default())?;
+
+    let mut deep = assert_val!(1);
+    for _ in 0..5 {
+        deep = ConvexValue::try_from(vec![deep])?;
+    }
+    assert_eq!(
+        deep.validate(&limits),
+        Err(ValidationError::TooNested { nesting: 5, max: 4 })
+    );
+
+    let large = ConvexValue::try_from(vec![assert_val!(1); 3])?;
+    let large = ConvexValue::try_from(vec![large.clone(), large.clone(), large])?;
+    assert_eq!(
+        large.validate(&ValueLimits {
+            max_size: 20,
+            ..limits
+        }),
+        Err(ValidationError::TooLarge {
+            size: large.size(),
+            max: 20
+        })
+    );
+
+    assert_eq!(
+        assert_val!(["a", "abcdefghijk"]).validate(&limits),
+        Err(ValidationError::StringTooLong { len: 11, max: 10 })
+    );
+    assert_eq!(
+        assert_val!({ "a" => [1, 2, 3, 4] }).validate(&limits),
+        Err(ValidationError::ArrayTooLong { len: 4, max: 3 })
+    );
+    assert_eq!(
+        assert_val!([{ "a" => 1, "b" => 2, "c" => 3 }]).validate(&limits),
+        Err(ValidationError::TooManyFields { len: 3, max: 2 })
+    );
+    Ok(())
+}
+
+#[test]
+fn test_merge() -> anyhow::Result<()> {
+    let existing = assert_val!({
+        "name" => { "first" => "Mr", "last" => "Fantastik" },
+        "job" => "mechanic",
+        "age" => 41,
+    });
+    let patch = assert_val!({
+        "name" => { "first" => "Mr." },
+        "age" => 42,
+        "city" => "Metropolis",
+    });
+    assert_eq!(
+        existing.merge(&patch, MergeOptions::default())?,
+        assert_val!({
+            "name" => { "first" => "Mr.", "last" => "Fantastik" },
+            "job" => "mechanic",
+            "age" => 42,
+            "city" => "Metropolis",
+        })
+    );
+
+    // Nested objects merge all the way down.
+    let existing = assert_val!({ "a" => { "b" => { "c" => 1, "d" => 2 } } });
+    let patch = assert_val!({ "a" => { "b" => { "d" => 3, "e" => 4 } } });
+    assert_eq!(
+        existing.merge(&patch, MergeOptions::default())?,
+        assert_val!({ "a" => { "b" => { "c" => 1, "d" => 3, "e" => 4 } } })
+    );
+    assert_eq!(
+        existing.merge(&assert_val!({}), MergeOptions::default())?,
+        existing
+    );
+    Ok(())
+}
+
+#[test]
+fn test_merge_null() -> anyhow::Result<()> {
+    let existing = assert_val!({
+        "keep" => 1,
+        "remove" => 2,
+        "nested" => { "keep" => 3, "remove" => 4 },
+    });
+    let patch = assert_val!({
+        "remove" => null,
+        "nested" => { "remove" => null },
+        "missing" => null,
+    });
+    // By default `null` is just another value.
+    assert_eq!(
+        existing.merge(&patch, MergeOptions::default())?,
+        assert_val!({
+            "keep" => 1,
+            "remove" => null,
+            "nested" => { "keep" => 3, "remove" => null },
+            "missing" => null,
+        })
+    );
+    let deleting = MergeOptions {
+        null_deletes_field: true,
+    };
+    assert_eq!(
+        existing.merge(&patch, deleting)?,
+        assert_val!({ "keep" => 1, "nested" => { "keep" => 3 } })
+    );
+    Ok(())
+}
+
+#[test]
+fn test_merge_replaces_non_objects() -> anyhow::Result<()> {
+    let options = MergeOptions::default();
+    // Arrays are replaced, not merged element by element.
+    assert_eq!(
+        assert_val!({ "a" => [1, 2, 3] }).merge(&assert_val!({ "a" => [4] }), options)?,
+        assert_val!({ "a" => [4] })
+    );
+    // A type mismatch replaces the field, in either direction.
+    assert_eq!(
+        assert_val!({ "a" => { "b" => 1 } }).merge(&assert_val!({ "a" => "b" }), options)?,
+        assert_val!({ "a" => "b" })
+    );
+    assert_eq!(
+        assert_val!({ "a" => "b" }).merge(&assert_val!({ "a" => { "b" => 1 } }), options)?,
+        assert_val!({ "a" => { "b" => 1 } })
+    );
+    // So does merging at the top level.
+    assert_eq!(
+        assert_val!(1).merge(&assert_val!({ "a" => 1 }), options)?,
+        assert_val!({ "a" => 1 })
+    );
+    assert_eq!(
+        assert_val!({ "a" => 1 }).merge(&assert_val!(null), options)?,
+        assert_val!(null)
+    );
+    Ok(())
+}
+
+#[test]
+fn test_msgpack_roundtrip() -> anyhow::Result<()> {
+    let values = [
+        ConvexValue::Null,
+        assert_val!(i64::MIN),
+        assert_val!(i64::MAX),
+        assert_val!(-1),
+        assert_val!(0.1),
+        assert_val!(f64::NEG_INFINITY),
+        ConvexValue::Float64(-0.0),
+        assert_val!(true),
+        assert_val!(false),
+        assert_val!(""),
+        assert_val!("a string that's too long for a fixstr"),
+        ConvexValue::Bytes(vec![0, 1, 2, 255].try_into()?),
+        ConvexValue::Bytes(vec![7; 70_000].try_into()?),
+        assert_val!([]),
+        ConvexValue::try_from(vec![assert_val!(1); 20])?,
+        assert_val!({}),
+        assert_val!({
+            "nested" => [{ "a" => null }, 1.5],
+            "int" => 9_007_199_254_740_993,
+        }),
+    ];
+    for value in values {
+        let bytes = value.to_msgpack();
+        assert_eq!(ConvexValue::from_msgpack(&bytes)?, value, "{bytes:?}");
+    }
+
+    // Compact integer and float encodings from other writers are accepted.
+    assert_eq!(ConvexValue::from_msgpack(&[0x05])?, assert_val!(5));
+    assert_eq!(ConvexValue::from_msgpack(&[0xff])?, assert_val!(-1));
+    assert_eq!(
+        ConvexValue::from_msgpack(&[0xca, 0x3f, 0xc0, 0x00, 0x00])?,
+        assert_val!(1.5)
+    );
+    Ok(())
+}
+
+#[test]
+fn test_msgpack_malformed() {
+    let inputs: &[&[u8]] = &[
+        // Empty input.
+        &[],
+        // Truncated int 64.
+        &[0xd3, 0, 0, 0],
+        // Trailing data.
+        &[0xc0, 0xc0],
+        // Reserved marker.
+        &[0xc1],
+        // fixstr with invalid UTF-8.
+        &[0xa1, 0xff],
+        // fixarray missing its element.
+        &[0x91],
+        // Map with an integer key.
+        &[0x81, 0x01, 0xc0],
+        // Map with a duplicate key.
+        &[0x82, 0xa1, b'a', 0xc0, 0xa1, b'a', 0xc0],
+        // uint 64 above i64::MAX.
+        &[0xcf, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+        // str 32 claiming more bytes than there are.
+        &[0xdb, 0xff, 0xff, 0xff, 0xff, b'a'],
+    ];
+    for input in inputs {
+        assert!(
+            ConvexValue::from_msgpack(input).is_err(),
+            "{input:?} should fail to decode"
+        );
+    }
+
+    // Deeply nested arrays are rejected without recursing all the way down.
+    let deep = vec![0x91; 10_000];
+    assert!(ConvexValue::from_msgpack(&deep).is_err());
+}
+
+#[test]
+fn test_content_hash() -> anyhow::Result<()> {
+    let v1 = assert_val!({
+        "name" => "ghostface",
+        "albums" => [{ "title" => "ironman", "year" => 1996 }],
+    });
+    let v2 = assert_val!({
+        "albums" => [{ "year" => 1996, "title" => "ironman" }],
+        "name" => "ghostface",
+    });
+    assert_eq!(v1.content_hash(), v2.content_hash());
+
+    let v3 = assert_val!({
+        "name" => "ghostface",
+        "albums" => [{ "title" => "ironman", "year" => 1997 }],
+    });
+    assert_ne!(v1.content_hash(), v3.content_hash());
+
+    // Hashing agrees with equality on numbers.
+    assert_ne!(
+        assert_val!(1).content_hash(),
+        assert_val!(1.0).content_hash()
+    );
+    assert_ne!(
+        ConvexValue::Float64(0.0).content_hash(),
+        ConvexValue::Float64(-0.0).content_hash()
+    );
+    Ok(())
+}
+
+mod msgpack_roundtrip {
+    use cmd_util::env::env_config;
+    use proptest::prelude::*;
+
+    use crate::ConvexValue;
+
+    proptest! {
+        #![proptest_config(
+            ProptestConfig { cases: 256 * env_config("CONVEX_PROPTEST_MULTIPLIER", 1), failure_persistence: None, ..ProptestConfig::default() }
+        )]
+
+        #[test]
+        fn proptest_msgpack_roundtrip(v in any::<ConvexValue>()) {
+            let bytes = v.to_msgpack();
+            prop_assert_eq!(ConvexValue::from_msgpack(&bytes).unwrap(), v);
+        }
+    }
+}
@@ -0,0 +1,110 @@
+//! Caller-chosen limits on the shape of a value, for rejecting pathological
+//! inputs more strictly than the limits enforced when values are built.
+
+use thiserror::Error;
+
+use crate::{
+    array::MAX_ARRAY_LEN,
+    size::{
+        Size,
+        MAX_NESTING,
+        MAX_SIZE,
+    },
+    ConvexValue,
+    MAX_OBJECT_FIELDS,
+};
+
+/// Limits checked by `ConvexValue::validate`. The defaults are the limits
+/// every value already satisfies.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ValueLimits {
+    /// Maximum nesting, as defined by `Size::nesting`.
+    pub max_nesting: usize,
+    /// Maximum total size, as defined by `Size::size`.
+    pub max_size: usize,
+    /// Maximum length of any string in the value, in bytes.
+    pub max_string_len: usize,
+    /// Maximum number of elements in any array in the value.
+    pub max_array_len: usize,
+    /// Maximum number of fields in any object in the value.
+    pub max_object_fields: usize,
+}
+
+impl Default for ValueLimits {
+    fn default() -> Self {
+        Self {
+            max_nesting: MAX_NESTING,
+            max_size: MAX_SIZE,
+            max_string_len: MAX_SIZE,
+            max_array_len: MAX_ARRAY_LEN,
+            max_object_fields: MAX_OBJECT_FIELDS,
+        }
+    }
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ValidationError {
+    #[error("Value is too nested ({nesting} > maximum nesting {max})")]
+    TooNested { nesting: usize, max: usize },
+    #[error("Value is too large ({size} > maximum size {max})")]
+    TooLarge { size: usize, max: usize },
+    #[error("String is too long ({len} bytes > maximum length {max})")]
+    StringTooLong { len: usize, max: usize },
+    #[error("Array is too long ({len} > maximum length {max})")]
+    ArrayTooLong { len: usize, max: usize },
+    #[error("Object has too many fields ({len} > maximum number {max})")]
+    TooManyFields { len: usize, max: usize },
+}
+
+impl ConvexValue {
+    /// Checks that this value is within `limits`.
+    pub fn validate(&self, limits: &ValueLimits) -> Result<(), ValidationError> {
+        // Nesting and size are precomputed, so check them up front. Bounding
+        // the nesting also bounds how deep `check_elements` recurses.
+        let nesting = self.nesting();
+        if nesting > limits.max_nesting {
+            return Err(ValidationError::TooNested {
+                nesting,
+                max: limits.max_nesting,
+            });
+        }
+        let size = self.size();
+        if size > limits.max_size {
+            return Err(ValidationError::TooLarge {
+                size,
+                max: limits.max_size,
+            });
+        }
+        self.check_elements(limits)
+    }
+
+    fn check_elements(&self, limits: &ValueLimits) -> Result<(), ValidationError> {
+        match self {
+            ConvexValue::String(s) if s.len() > limits.max_string_len => {
+                Err(ValidationError::StringTooLong {
+                    len: s.len(),
+                    max: limits.max_string_len,
+                })
+            },
+            ConvexValue::Array(a) => {
+                if a.len() > limits.max_array_len {
+                    return Err(ValidationError::ArrayTooLong {
+                        len: a.len(),
+                        max: limits.max_array_len,
+                    });
+                }
+                a.iter().try_for_each(|v| v.check_elements(limits))
+            },
+            ConvexValue::Object(o) => {
+                if o.len() > limits.max_object_fields {
+                    return Err(ValidationError::TooManyFields {
+                        len: o.len(),
+                        max: limits.max_object_fields,
+                    });
+                }
+                o.values().try_for_each(|v| v.check_elements(limits))
+            },
+            _ => Ok(()),
+        }
+    }
+}